@@ -9,13 +9,14 @@ use tempfile::{tempdir, NamedTempFile};
 use cmdproxy::configs::{CmdProxyClientConf, CmdProxyClientConfFile};
 use cmdproxy::params::Param;
 use cmdproxy::protocol::RunRequest;
+use cmdproxy::retry::RunOptions;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// Uri to the redis broker
-    #[arg(short, long)]
-    redis_url: Option<String>,
+    /// Uri to the broker, either redis://... or amqp://...
+    #[arg(short = 'r', long)]
+    broker_url: Option<String>,
 
     /// Uri to the mongo remote-fs
     #[arg(short, long)]
@@ -24,6 +25,14 @@ pub struct Cli {
     /// Name of database where stores the remote-fs
     #[arg(long)]
     mongo_dbname: Option<String>,
+
+    /// Prefix prepended to the queue the request is sent to
+    #[arg(long)]
+    queue_prefix: Option<String>,
+
+    /// Namespace isolating the queues and the remote-fs from other deployments
+    #[arg(long)]
+    namespace: Option<String>,
 }
 
 #[tokio::main]
@@ -61,10 +70,12 @@ async fn main() {
         .build();
 
     println!("running through the proxy...");
-    let client = cmdproxy::client::Client::new(conf).await;
-    let response = client.run(req, Some("sh".to_string())).await;
+    let client = cmdproxy::client::Client::new(conf).await.unwrap();
+    let response = client
+        .run(req, Some("sh".to_string()), RunOptions::default())
+        .await;
 
-    assert_eq!(0, response.unwrap());
+    assert!(response.unwrap().success());
 
     println!(
         "received stdout: {}",
@@ -89,8 +100,9 @@ async fn main() {
 fn parse_client_conf() -> CmdProxyClientConf {
     let cli: Cli = Cli::parse();
 
-    let redis_url = cli
-        .redis_url
+    let broker_url = cli
+        .broker_url
+        .or_ok(std::env::var("CMDPROXY_BROKER_URL"))
         .or_ok(std::env::var("CMDPROXY_REDIS_URL"))
         .or_wrap("redis://localhost:6379/".to_owned())
         .unwrap();
@@ -107,13 +119,27 @@ fn parse_client_conf() -> CmdProxyClientConf {
         .or_wrap("cmdproxy-db".to_owned())
         .unwrap();
 
+    let queue_prefix = cli
+        .queue_prefix
+        .or_ok(std::env::var("CMDPROXY_QUEUE_PREFIX"))
+        .unwrap_or_default();
+
+    let namespace = cli
+        .namespace
+        .or_ok(std::env::var("CMDPROXY_NAMESPACE"))
+        .unwrap_or_default();
+
     let conf = CmdProxyClientConf::new(CmdProxyClientConfFile {
-        redis_url: redis_url.clone(),
+        broker_url: broker_url.clone(),
         mongo_url: mongo_url.clone(),
         mongo_dbname: mongo_dbname.clone(),
+        queue_prefix,
+        namespace,
+        client_id: None,
+        ..Default::default()
     });
 
-    println!("redis run on: {}", redis_url);
+    println!("broker run on: {}", broker_url);
     println!("mongo run on: {}", mongo_url);
     println!("mongo dbname: {}", mongo_dbname);
 
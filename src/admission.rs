@@ -0,0 +1,203 @@
+//! Admission of the runs by the resources of the host of the worker.
+//!
+//! A worker short of disk or memory refuses a run before downloading anything for it, rather
+//! than failing halfway through the downloads, so that the client can try it again later,
+//! possibly on another worker. The disk for the inputs of the admitted runs is reserved until
+//! they are downloaded, so that the runs admitted at once do not count on the same free disk.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::units;
+
+/// What must be left on the host for a run to be admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdmissionPolicy {
+    /// Bytes of the disk of the workspaces left free once the inputs are downloaded.
+    #[serde(default, deserialize_with = "units::deserialize_size")]
+    pub min_free_disk: u64,
+    /// Bytes of memory available for new processes without swapping.
+    #[serde(default, deserialize_with = "units::deserialize_size")]
+    pub min_available_memory: u64,
+}
+
+/// Error of a run refused for the lack of resources on the worker.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourcesUnavailable {
+    /// Which resource is short, `disk` or `memory`.
+    pub resource: String,
+    /// Bytes required of the resource.
+    pub required: u64,
+    /// Bytes available of the resource.
+    pub available: u64,
+}
+
+impl fmt::Display for ResourcesUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Resources unavailable: {} bytes of {} required, only {} available",
+            self.required, self.resource, self.available
+        )
+    }
+}
+
+impl std::error::Error for ResourcesUnavailable {}
+
+/// Bytes of the disk reserved for the inputs of the admitted runs, which are yet to be
+/// downloaded and hence not yet taken from the free disk told by the filesystem.
+static RESERVED_DISK: AtomicU64 = AtomicU64::new(0);
+
+/// Disk reserved for the inputs of an admitted run, given back as they are downloaded, or
+/// all at once when dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Reserve `bytes`, returning the reservation and the bytes reserved by the others.
+    fn new(bytes: u64) -> (Reservation, u64) {
+        let others = RESERVED_DISK.fetch_add(bytes, Ordering::SeqCst);
+        (Reservation { bytes }, others)
+    }
+
+    /// Give back the reserved space taken by `bytes` just downloaded.
+    pub(crate) fn consume(&mut self, bytes: u64) {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+        RESERVED_DISK.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        RESERVED_DISK.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+impl AdmissionPolicy {
+    /// Admit a run downloading `input_bytes` into `workspace`, reserving the disk for them
+    /// against the runs admitted later.
+    pub(crate) fn admit(
+        &self,
+        workspace: &Path,
+        input_bytes: u64,
+    ) -> Result<Reservation, ResourcesUnavailable> {
+        let (reservation, others) = Reservation::new(input_bytes);
+        self.check(workspace, input_bytes.saturating_add(others))?;
+        Ok(reservation)
+    }
+
+    /// Check if a run downloading `input_bytes` into `workspace` can be admitted.
+    ///
+    /// A resource whose availability cannot be told is taken as sufficient.
+    fn check(&self, workspace: &Path, input_bytes: u64) -> Result<(), ResourcesUnavailable> {
+        if let Some(available) = free_disk(workspace) {
+            let required = input_bytes.saturating_add(self.min_free_disk);
+            if available < required {
+                return Err(ResourcesUnavailable {
+                    resource: "disk".to_owned(),
+                    required,
+                    available,
+                });
+            }
+        }
+        if let Some(available) = available_memory() {
+            if available < self.min_available_memory {
+                return Err(ResourcesUnavailable {
+                    resource: "memory".to_owned(),
+                    required: self.min_available_memory,
+                    available,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Free bytes of the filesystem `path` is on, as told by `df`.
+pub(crate) fn free_disk(path: &Path) -> Option<u64> {
+    let output = std::process::Command::new("df")
+        .arg("-Pk")
+        .arg(path)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    parse_df(stdout.as_str())
+}
+
+/// Parse the available kilobytes in the POSIX output of `df -Pk` into bytes.
+fn parse_df(output: &str) -> Option<u64> {
+    let line = output.lines().nth(1)?;
+    let available_kb: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(available_kb * 1024)
+}
+
+/// Bytes of memory available for new processes, which is only told on linux.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    parse_meminfo(meminfo.as_str())
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let available_kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(available_kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let df = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                  /dev/sda1 102400 51200 40960 56% /\n";
+        assert_eq!(parse_df(df), Some(40960 * 1024));
+        assert_eq!(parse_df("Filesystem 1024-blocks Used Available\n"), None);
+
+        let meminfo = "MemTotal:       16384000 kB\nMemFree:         1024000 kB\n\
+                       MemAvailable:    8192000 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some(8192000 * 1024));
+        assert_eq!(parse_meminfo("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let workspace = tempfile::tempdir().unwrap();
+        let lenient = AdmissionPolicy {
+            min_free_disk: 0,
+            min_available_memory: 0,
+        };
+        assert!(lenient.check(workspace.path(), 0).is_ok());
+
+        // no disk can hold that much, if told at all
+        if free_disk(workspace.path()).is_some() {
+            let err = lenient.check(workspace.path(), u64::MAX).unwrap_err();
+            assert_eq!(err.resource, "disk");
+        }
+    }
+
+    #[test]
+    fn test_reservation() {
+        let (mut reservation, _) = Reservation::new(1000);
+        let (other, reserved) = Reservation::new(0);
+        assert!(reserved >= 1000);
+        drop(other);
+
+        reservation.consume(400);
+        assert_eq!(reservation.bytes, 600);
+        // never gives back more than reserved
+        reservation.consume(1000);
+        assert_eq!(reservation.bytes, 0);
+    }
+}
@@ -1,38 +1,61 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use celery::prelude::*;
+use anyhow::Context;
 use chain_ext::io::DeExt;
 use chain_ext::option::OptionExt;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use directories::UserDirs;
-use log::debug;
+use log::{debug, info, warn};
 
-use crate::configs::{CmdProxyServerConf, CmdProxyServerConfFile};
-use crate::tasks::{run, SERVER_CONF};
+use crate::admission::AdmissionPolicy;
+use crate::broker::{CeleryApp, ReconnectPolicy};
+use crate::canary::{self, CanaryPolicy};
+use crate::client::Client;
+use crate::command_policy::CommandPolicy;
+use crate::commands;
+use crate::composite::parse_composites;
+use crate::configs::{
+    urgent_lane, CmdProxyClientConf, CmdProxyClientConfFile, CmdProxyServerConf,
+    CmdProxyServerConfFile,
+};
+use crate::fairness::{parse_weights, FairSharePolicy};
+use crate::fsck::{self, Fsck};
+use crate::middles::auth::HmacAuth;
+use crate::palette::{PaletteKey, PaletteSource};
+use crate::preemption::{PreemptionMode, PreemptionPolicy};
+use crate::registry::WorkerInfo;
+use crate::retry::parse_max_retries;
+use crate::schedule;
+use crate::server;
+use crate::sla::SlaPolicy;
+use crate::storage::{EncryptionKey, S3Conf, S3Replica};
+use crate::tasks::{SERVER_APP, SERVER_CONF};
+use crate::tls::TlsConf;
+use crate::units;
+use crate::workspace;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// Uri to the redis broker
-    #[arg(short, long)]
-    redis_url: Option<String>,
-
-    /// Uri to the mongo remote-fs
-    #[arg(short, long)]
-    mongo_url: Option<String>,
-
-    /// Name of database where stores the remote-fs
-    #[arg(long)]
-    mongo_dbname: Option<String>,
+    #[command(flatten)]
+    conn: ConnArgs,
 
     /// Log level
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     loglevel: Option<String>,
 
-    /// Path to a command palette file mapping program name to their paths
+    /// Command palette mapping program name to their paths, either a path to a local file,
+    /// an http(s) url, or `cloud:<name>` for one stored on the cloud
     #[arg(short, long)]
-    command_palette: Option<PathBuf>,
+    command_palette: Option<PaletteSource>,
+
+    /// Public key in base64 trusted to sign the command palette, which is refused if not
+    /// signed by it
+    #[arg(long)]
+    palette_key: Option<PaletteKey>,
 
     /// Path to a environment file
     #[arg(short, long)]
@@ -41,12 +64,714 @@ pub struct Cli {
     /// Extension queues separated by comma.
     #[arg(long)]
     ext_queues: Option<String>,
+
+    /// Delegate the transfers of the runs to the transfer workers consuming this queue, which
+    /// are workers given the same queue in --ext-queues
+    #[arg(long, requires = "shared_dir")]
+    transfer_queue: Option<String>,
+
+    /// Let the urgent runs preempt the running ones of the same command in this way
+    #[arg(long, value_enum)]
+    preemption: Option<PreemptionMode>,
+
+    /// Min difference of priorities for a run to preempt another
+    #[arg(long, default_value_t = 1)]
+    preemption_gap: i32,
+
+    /// Run at most this many commands at once, sharing them fairly among the tenants, who
+    /// are told apart by --fair-label, or else by the clients signing the requests
+    #[arg(long)]
+    fair_slots: Option<usize>,
+
+    /// Label of the runs telling their tenants apart, such as `team`
+    #[arg(long, requires = "fair_slots")]
+    fair_label: Option<String>,
+
+    /// Weight of a tenant in sharing the slots, as TENANT=WEIGHT, default to 1
+    #[arg(long = "fair-weight", requires = "fair_slots")]
+    fair_weights: Vec<String>,
+
+    /// Tags of this worker separated by comma, such as `gpu,licensed`, deciding which
+    /// commands in the palette it serves
+    #[arg(long)]
+    tags: Option<String>,
+
+    /// Pre-processors applied to the requests of a queue, as QUEUE=NAME[,NAME...], where the
+    /// names are those the pre-processors are registered by, and which apply to the requests
+    /// of the commands named by the queue, or of any command out of the palette
+    #[arg(long = "pre-process")]
+    pre_processors: Vec<String>,
+
+    /// Max number of retries of a class of failures, as CLASS=MAX_RETRIES, such as
+    /// `out-of-memory=2`, overriding the default of the class
+    #[arg(long)]
+    retry: Vec<String>,
+
+    /// Refuse the requests larger than this many bytes once serialized, such as 16M
+    #[arg(long, value_parser = units::parse_size::<usize>)]
+    max_request_bytes: Option<usize>,
+
+    /// Replace the responses larger than this many bytes once serialized with an error
+    #[arg(long, value_parser = units::parse_size::<usize>)]
+    max_response_bytes: Option<usize>,
+
+    /// Composite commands running commands of the palette in turn, as
+    /// NAME=STEP[;STEP...], where a step is a command followed by its arguments, such as
+    /// `build-and-test=cmake -S {0} -B build; make -C build`
+    #[arg(long = "composite")]
+    composites: Vec<String>,
+
+    /// Folder shared with the transfer workers, required if delegating the transfers
+    #[arg(long, requires = "transfer_queue")]
+    shared_dir: Option<PathBuf>,
+
+    /// Remove the workspace of a run by force once older than this many seconds, or a
+    /// duration such as 12h, even if the run is not done, counting it as leaked
+    #[arg(long, value_parser = units::parse_secs)]
+    max_workspace_lifetime: Option<u64>,
+
+    /// Delete the outputs staged by the runs which crashed before committing them, once older
+    /// than this many seconds, or a duration such as 1d, leaving the committable ones to
+    /// `cmdproxy storage fsck --repair`
+    #[arg(long, value_parser = units::parse_secs)]
+    sweep_staged_after: Option<u64>,
+
+    /// Supervise each command together with the processes it forks, such as daemons, waiting
+    /// up to this many seconds for them to exit after the command does, and killing them then,
+    /// before the outputs are collected
+    #[arg(long, value_parser = units::parse_secs)]
+    group_grace: Option<u64>,
+
+    /// Seconds a command cancelled or timed out is given to exit on SIGTERM before being
+    /// killed, 10 by default
+    #[arg(long, value_parser = units::parse_secs)]
+    kill_grace: Option<u64>,
+
+    /// Upload the first this many bytes of the core dumped by a crashed command, and attach
+    /// it to the post-mortem in the response
+    #[arg(long, value_parser = units::parse_size::<u64>)]
+    core_bytes: Option<u64>,
+
+    /// Cgroup v2 delegated to the worker, such as /sys/fs/cgroup/cmdproxy, under which the
+    /// command of a run limited in memory is confined, so that exceeding the limit is told
+    /// apart from other kills
+    #[arg(long)]
+    cgroup_root: Option<PathBuf>,
+
+    /// Check the worker by a canary run every this many seconds, marking it unhealthy in the
+    /// registry of the workers once --canary-failures runs fail in a row
+    #[arg(long, value_parser = units::parse_secs)]
+    canary_interval: Option<u64>,
+
+    /// Command of the canary runs, split by whitespaces, default to `true`
+    #[arg(long, requires = "canary_interval")]
+    canary_command: Option<String>,
+
+    /// Number of the canary runs failing in a row for the worker to be unhealthy
+    #[arg(long, default_value_t = 3, requires = "canary_interval")]
+    canary_failures: u32,
+
+    /// Refuse to run the commands on the host, serving only those sandboxed in containers by
+    /// their palette entries
+    #[arg(long)]
+    require_container: bool,
+
+    /// Refuse to run the commands out of the palette unless resolved to a path under this
+    /// path, repeatable
+    #[arg(long = "allow-command")]
+    allow_commands: Vec<String>,
+
+    /// Refuse to run the commands out of the palette resolved to a path under this path,
+    /// repeatable
+    #[arg(long = "deny-command")]
+    deny_commands: Vec<String>,
+
+    /// Preserve the ownership of the inputs as sent by the clients asking to preserve their
+    /// attributes, which is kept by the worker otherwise
+    #[arg(long)]
+    preserve_ownership: bool,
+
+    /// Alert once a request has waited in the queue for longer than this many seconds
+    #[arg(long, value_parser = units::parse_secs)]
+    sla_max_queue_wait: Option<u64>,
+
+    /// Alert once more than this percent of the latest --sla-window runs have failed
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100))]
+    sla_max_failure_percent: Option<u32>,
+
+    /// Number of the latest runs the failure rate is taken over
+    #[arg(long, default_value_t = 20, requires = "sla_max_failure_percent")]
+    sla_window: usize,
+
+    /// Post the SLA alerts in json to this url, besides logging them
+    #[arg(long)]
+    sla_webhook: Option<String>,
+
+    /// Refuse a run unless this many bytes of the disk of the workspaces, or a size such as
+    /// 10G, are left free once its inputs are downloaded, so that the client tries it again
+    /// later or elsewhere
+    #[arg(long, value_parser = units::parse_size::<u64>)]
+    min_free_disk: Option<u64>,
+
+    /// Refuse a run unless this many bytes of memory are available
+    #[arg(long, value_parser = units::parse_size::<u64>)]
+    min_available_memory: Option<u64>,
+
+    /// Fire the recurring runs defined in the cloud once due, sending them as a client, which
+    /// one worker of the deployment is enough to do, though more do no harm
+    #[arg(long)]
+    scheduler: bool,
+
+    /// Run as a client with the given command, or serve as a worker if not given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve the local processes, such as the shims, through one long-living client
+    #[cfg(unix)]
+    Agent(commands::agent::AgentArgs),
+    /// Follow the output of a task until it is done
+    Attach(commands::attach::AttachArgs),
+    /// Stress a deployment with synthetic runs and report its throughput and latency
+    Bench(commands::bench::BenchArgs),
+    /// Try the proxy out on a disposable redis and mongodb in docker, with a worker of its own
+    #[cfg(feature = "demo")]
+    Demo(commands::demo::DemoArgs),
+    /// Run a command through the proxy as if it were local, exiting with its exit code
+    Exec(commands::exec::ExecArgs),
+    /// Run a command through the proxy and report the outcome
+    Run(commands::run::RunArgs),
+    /// Query the past runs
+    History(commands::history::HistoryArgs),
+    /// Show which run produced a downloaded output
+    Provenance(commands::provenance::ProvenanceArgs),
+    /// Replay a recorded run locally in a fresh workspace, comparing it with the recording
+    Replay(commands::replay::ReplayArgs),
+    /// Resubmit a past run by the id of its task
+    Rerun(commands::rerun::RerunArgs),
+    /// Run a command described in a job file and report the outcome
+    Submit(commands::submit::SubmitArgs),
+    /// Run a command described in a job file again whenever its local inputs change
+    Watch(commands::watch::WatchArgs),
+    /// Install or uninstall local wrappers of the remote commands
+    #[command(subcommand)]
+    Shims(commands::shims::ShimsCommand),
+    /// Define, list, or remove the recurring runs fired by the schedulers
+    #[command(subcommand)]
+    Schedule(commands::schedule::ScheduleCommand),
+    /// Audit or clean up the storage
+    #[command(subcommand)]
+    Storage(commands::storage::StorageCommand),
+    /// List, inspect, or cancel the in-flight tasks
+    #[command(subcommand)]
+    Tasks(commands::tasks::TasksCommand),
+}
+
+/// Arguments locating the shared infrastructure, common to both the server and the client.
+#[derive(Args, Debug)]
+pub(crate) struct ConnArgs {
+    /// Uri to the broker, either redis://... or amqp://... for RabbitMQ
+    #[arg(short = 'r', long, alias = "redis-url", global = true)]
+    broker_url: Option<String>,
+
+    /// Uri to the mongo remote-fs
+    #[arg(short, long, global = true)]
+    mongo_url: Option<String>,
+
+    /// Name of database where stores the remote-fs
+    #[arg(long, global = true)]
+    mongo_dbname: Option<String>,
+
+    /// Prefix prepended to all the queues
+    #[arg(long, global = true)]
+    queue_prefix: Option<String>,
+
+    /// Namespace isolating the queues, the results and the remote-fs from other deployments
+    #[arg(long, global = true)]
+    namespace: Option<String>,
+
+    /// Stable identity of the client used in place of its hostname
+    #[arg(long, global = true)]
+    client_id: Option<String>,
+
+    /// Url of an object store keeping the files instead of the remote-fs, as
+    /// azure://<account>/<container> or gs://<bucket>, if built with its feature
+    #[arg(long, global = true)]
+    storage_url: Option<String>,
+
+    /// Url to an S3-compatible object store keeping the files instead of the remote-fs,
+    /// with the credentials from $AWS_ACCESS_KEY_ID and $AWS_SECRET_ACCESS_KEY
+    #[arg(long, global = true)]
+    s3_endpoint: Option<String>,
+
+    /// Bucket of the object store keeping the files
+    #[arg(long, global = true)]
+    s3_bucket: Option<String>,
+
+    /// Url to the S3-compatible object store replicating the bucket near this host, from
+    /// which the files are read instead, with the region from $CMDPROXY_S3_READ_REGION
+    #[arg(long, global = true)]
+    s3_read_endpoint: Option<String>,
+
+    /// Bucket replicating the bucket keeping the files, default to the same name
+    #[arg(long, global = true)]
+    s3_read_bucket: Option<String>,
+
+    /// Members of the replica set of the mongo remote-fs the files are read from, such as
+    /// nearest or secondaryPreferred, or only the primary by default
+    #[arg(long, global = true)]
+    read_preference: Option<String>,
+
+    /// Mount point of a filesystem shared by the clients and the servers, such as an NFS
+    /// mount, on which the local files are passed by their paths instead of being transferred
+    #[arg(long, global = true)]
+    shared_fs: Option<PathBuf>,
+
+    /// Name of a dictionary on the storage compressing the small files uploaded, as
+    /// published by `storage train-dictionary`
+    #[arg(long, global = true)]
+    dictionary: Option<String>,
+
+    /// Times to retry connecting to the broker once the connection is lost, 5 by default
+    #[arg(long, global = true)]
+    reconnect_retries: Option<u32>,
+
+    /// Number of the submissions held locally while the broker is unreachable, waiting for
+    /// it to come back rather than failing once out of retries
+    #[arg(long, global = true)]
+    reconnect_buffer: Option<usize>,
+
+    /// Template of the queues in place of the namespace and the prefix, such as
+    /// {namespace}-{command}, which must be the same for the clients and the servers
+    #[arg(long, global = true)]
+    queue_template: Option<String>,
+
+    /// Name the runs are sent under, run by default, which must be the same for the clients
+    /// and the servers
+    #[arg(long, global = true)]
+    task_name: Option<String>,
+
+    /// Database of the mongo keeping the results of the runs, apart from those of other
+    /// deployments, or the one in the mongo url by default
+    #[arg(long, global = true)]
+    result_db: Option<String>,
+
+    /// Template of the hostnames in the cloud urls of the local files in place of the client
+    /// id, such as {namespace}/{command}/{date}/{client}
+    #[arg(long, global = true)]
+    hostname_template: Option<String>,
+
+    /// File of the key encrypting the files at rest, 32 bytes in base64, or else the key in
+    /// $CMDPROXY_ENCRYPTION_KEY, which must be the same for the clients and the servers
+    #[arg(long, global = true)]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Folder of the named keys, one file each named by the key, encrypting the files of the
+    /// params picking them instead of the default key, or else the folder in
+    /// $CMDPROXY_ENCRYPTION_KEYRING, which must be the same for the clients and the servers
+    #[arg(long, global = true)]
+    encryption_keyring: Option<PathBuf>,
+
+    /// File of the secret signing the requests by HMAC, or else the secret in
+    /// $CMDPROXY_HMAC_SECRET, which must be the same for the clients and the servers, and
+    /// without which the servers accept the unsigned requests
+    #[arg(long, global = true)]
+    hmac_secret_file: Option<PathBuf>,
+
+    /// File of the secret of the operators signing the approvals, kept from the clients
+    /// submitting the runs, without which the servers refuse the runs requiring approvals
+    #[arg(long, global = true)]
+    approval_secret_file: Option<PathBuf>,
+
+    /// Bundle of the CAs in PEM verifying the broker, connecting to it over TLS
+    #[arg(long, global = true)]
+    broker_tls_ca_file: Option<PathBuf>,
+
+    /// Connect to the broker over TLS without verifying it, only for redis
+    #[arg(long, global = true)]
+    broker_tls_insecure: bool,
+
+    /// Bundle of the CAs in PEM verifying the mongodb, connecting to it over TLS
+    #[arg(long, global = true)]
+    mongo_tls_ca_file: Option<PathBuf>,
+
+    /// Certificate of this host followed by its private key in PEM, presented to the mongodb
+    /// over TLS
+    #[arg(long, global = true)]
+    mongo_tls_cert_file: Option<PathBuf>,
+
+    /// Connect to the mongodb over TLS without verifying it
+    #[arg(long, global = true)]
+    mongo_tls_insecure: bool,
+}
+
+impl ConnArgs {
+    pub(crate) fn broker_url(&self) -> String {
+        self.broker_url
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_BROKER_URL"))
+            .or_ok(std::env::var("CMDPROXY_REDIS_URL"))
+            .or_wrap("redis://localhost:6379/".into())
+            .unwrap()
+    }
+
+    pub(crate) fn mongo_url(&self) -> String {
+        self.mongo_url
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_MONGO_URL"))
+            .or_wrap("mongodb://localhost:27017/".into())
+            .unwrap()
+    }
+
+    pub(crate) fn mongo_dbname(&self) -> String {
+        self.mongo_dbname
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_MONGO_DBNAME"))
+            .or_wrap("cmdproxy-db".to_owned())
+            .unwrap()
+    }
+
+    pub(crate) fn queue_prefix(&self) -> String {
+        self.queue_prefix
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_QUEUE_PREFIX"))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn namespace(&self) -> String {
+        self.namespace
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_NAMESPACE"))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn storage_url(&self) -> Option<String> {
+        self.storage_url
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_STORAGE_URL"))
+    }
+
+    /// The object store keeping the files, if any endpoint is given.
+    pub(crate) fn s3(&self) -> Option<S3Conf> {
+        let endpoint = self
+            .s3_endpoint
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_S3_ENDPOINT"))?;
+        let env = |name: &str| std::env::var(name).unwrap_or_default();
+        let bucket = self
+            .s3_bucket
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_S3_BUCKET"))
+            .or_wrap("cmdproxy".to_owned())
+            .unwrap();
+        let region = std::env::var("CMDPROXY_S3_REGION").unwrap_or_else(|_| "us-east-1".to_owned());
+        let read_replica = self
+            .s3_read_endpoint
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_S3_READ_ENDPOINT"))
+            .map(|endpoint| S3Replica {
+                endpoint,
+                bucket: self
+                    .s3_read_bucket
+                    .clone()
+                    .or_ok(std::env::var("CMDPROXY_S3_READ_BUCKET"))
+                    .unwrap_or_else(|| bucket.clone()),
+                region: std::env::var("CMDPROXY_S3_READ_REGION").unwrap_or_else(|_| region.clone()),
+            });
+        Some(S3Conf {
+            endpoint,
+            bucket,
+            region,
+            access_key: env("AWS_ACCESS_KEY_ID"),
+            secret_key: env("AWS_SECRET_ACCESS_KEY"),
+            read_replica,
+        })
+    }
+
+    pub(crate) fn shared_fs(&self) -> Option<PathBuf> {
+        self.shared_fs
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_SHARED_FS").map(PathBuf::from))
+    }
+
+    pub(crate) fn read_preference(&self) -> Option<String> {
+        self.read_preference
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_READ_PREFERENCE"))
+    }
+
+    pub(crate) fn dictionary(&self) -> Option<String> {
+        self.dictionary
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_DICTIONARY"))
+    }
+
+    pub(crate) fn reconnect(&self) -> ReconnectPolicy {
+        let default = ReconnectPolicy::default();
+        ReconnectPolicy {
+            max_retries: self.reconnect_retries.unwrap_or(default.max_retries),
+            buffer: self.reconnect_buffer.unwrap_or(default.buffer),
+            ..default
+        }
+    }
+
+    pub(crate) fn queue_template(&self) -> Option<String> {
+        self.queue_template
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_QUEUE_TEMPLATE"))
+    }
+
+    pub(crate) fn task_name(&self) -> Option<String> {
+        self.task_name
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_TASK_NAME"))
+    }
+
+    pub(crate) fn result_db(&self) -> Option<String> {
+        self.result_db
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_RESULT_DB"))
+    }
+
+    pub(crate) fn hostname_template(&self) -> Option<String> {
+        self.hostname_template
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_HOSTNAME_TEMPLATE"))
+    }
+
+    /// The key encrypting the files at rest, if any, failing if malformed rather than leaving
+    /// the files in plaintext.
+    pub(crate) fn encryption_key(&self) -> anyhow::Result<Option<EncryptionKey>> {
+        let key = match &self.encryption_key_file {
+            Some(path) => EncryptionKey::load(path).with_context(|| {
+                format!(
+                    "Malformed encryption key in --encryption-key-file {}",
+                    path.display()
+                )
+            })?,
+            None => match std::env::var("CMDPROXY_ENCRYPTION_KEY") {
+                Ok(key) => key
+                    .parse()
+                    .context("Malformed encryption key in CMDPROXY_ENCRYPTION_KEY")?,
+                Err(_) => return Ok(None),
+            },
+        };
+        Ok(Some(key))
+    }
+
+    /// The named keys encrypting the files of the params picking them, if any, failing if any
+    /// is malformed.
+    pub(crate) fn encryption_keyring(&self) -> anyhow::Result<HashMap<String, EncryptionKey>> {
+        let dir = self
+            .encryption_keyring
+            .clone()
+            .or_ok(std::env::var("CMDPROXY_ENCRYPTION_KEYRING").map(PathBuf::from));
+        match dir {
+            Some(dir) => EncryptionKey::load_keyring(dir.as_path())
+                .with_context(|| format!("Malformed encryption keyring {}", dir.display())),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// The secret signing the requests, if any, failing if malformed rather than accepting the
+    /// unsigned requests.
+    pub(crate) fn request_signing(&self) -> anyhow::Result<Option<HmacAuth>> {
+        let auth = match &self.hmac_secret_file {
+            Some(path) => HmacAuth::load(path).with_context(|| {
+                format!(
+                    "Malformed HMAC secret in --hmac-secret-file {}",
+                    path.display()
+                )
+            })?,
+            None => match std::env::var("CMDPROXY_HMAC_SECRET") {
+                Ok(secret) => secret
+                    .parse()
+                    .context("Malformed HMAC secret in CMDPROXY_HMAC_SECRET")?,
+                Err(_) => return Ok(None),
+            },
+        };
+        Ok(Some(auth))
+    }
+
+    /// The secret of the operators signing the approvals, if any.
+    pub(crate) fn approval_signing(&self) -> anyhow::Result<Option<HmacAuth>> {
+        self.approval_secret_file
+            .as_ref()
+            .map(|path| {
+                HmacAuth::load(path).with_context(|| {
+                    format!(
+                        "Malformed approval secret in --approval-secret-file {}",
+                        path.display()
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    /// The TLS of the connection to the broker, if any option of it is given.
+    pub(crate) fn broker_tls(&self) -> Option<TlsConf> {
+        let tls = TlsConf {
+            ca_file: self
+                .broker_tls_ca_file
+                .clone()
+                .or_ok(std::env::var("CMDPROXY_BROKER_TLS_CA_FILE").map(PathBuf::from)),
+            cert_file: None,
+            insecure: self.broker_tls_insecure
+                || std::env::var("CMDPROXY_BROKER_TLS_INSECURE").is_ok(),
+        };
+        (tls != TlsConf::default()).then_some(tls)
+    }
+
+    /// The TLS of the connections to the mongodb, if any option of it is given.
+    pub(crate) fn mongo_tls(&self) -> Option<TlsConf> {
+        let tls = TlsConf {
+            ca_file: self
+                .mongo_tls_ca_file
+                .clone()
+                .or_ok(std::env::var("CMDPROXY_MONGO_TLS_CA_FILE").map(PathBuf::from)),
+            cert_file: self
+                .mongo_tls_cert_file
+                .clone()
+                .or_ok(std::env::var("CMDPROXY_MONGO_TLS_CERT_FILE").map(PathBuf::from)),
+            insecure: self.mongo_tls_insecure
+                || std::env::var("CMDPROXY_MONGO_TLS_INSECURE").is_ok(),
+        };
+        (tls != TlsConf::default()).then_some(tls)
+    }
+
+    /// The arguments explicitly given, so that they can be passed on to another invocation.
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        [
+            ("--broker-url", &self.broker_url),
+            ("--mongo-url", &self.mongo_url),
+            ("--mongo-dbname", &self.mongo_dbname),
+            ("--queue-prefix", &self.queue_prefix),
+            ("--namespace", &self.namespace),
+            ("--client-id", &self.client_id),
+            ("--storage-url", &self.storage_url),
+            ("--s3-endpoint", &self.s3_endpoint),
+            ("--s3-bucket", &self.s3_bucket),
+            ("--s3-read-endpoint", &self.s3_read_endpoint),
+            ("--s3-read-bucket", &self.s3_read_bucket),
+            ("--read-preference", &self.read_preference),
+            ("--dictionary", &self.dictionary),
+            ("--queue-template", &self.queue_template),
+            ("--task-name", &self.task_name),
+            ("--result-db", &self.result_db),
+            ("--hostname-template", &self.hostname_template),
+            (
+                "--reconnect-retries",
+                &self.reconnect_retries.map(|retries| retries.to_string()),
+            ),
+            (
+                "--reconnect-buffer",
+                &self.reconnect_buffer.map(|buffer| buffer.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(flag, value)| value.as_ref().map(|value| [flag.to_owned(), value.clone()]))
+        .flatten()
+        .chain(self.shared_fs.iter().flat_map(|path| {
+            [
+                "--shared-fs".to_owned(),
+                path.to_string_lossy().into_owned(),
+            ]
+        }))
+        .chain(self.encryption_key_file.iter().flat_map(|path| {
+            [
+                "--encryption-key-file".to_owned(),
+                path.to_string_lossy().into_owned(),
+            ]
+        }))
+        .chain(self.encryption_keyring.iter().flat_map(|path| {
+            [
+                "--encryption-keyring".to_owned(),
+                path.to_string_lossy().into_owned(),
+            ]
+        }))
+        .chain(self.hmac_secret_file.iter().flat_map(|path| {
+            [
+                "--hmac-secret-file".to_owned(),
+                path.to_string_lossy().into_owned(),
+            ]
+        }))
+        .chain(
+            [
+                ("--approval-secret-file", &self.approval_secret_file),
+                ("--broker-tls-ca-file", &self.broker_tls_ca_file),
+                ("--mongo-tls-ca-file", &self.mongo_tls_ca_file),
+                ("--mongo-tls-cert-file", &self.mongo_tls_cert_file),
+            ]
+            .into_iter()
+            .filter_map(|(flag, path)| {
+                path.as_ref()
+                    .map(|path| [flag.to_owned(), path.to_string_lossy().into_owned()])
+            })
+            .flatten(),
+        )
+        .chain(
+            [
+                ("--broker-tls-insecure", self.broker_tls_insecure),
+                ("--mongo-tls-insecure", self.mongo_tls_insecure),
+            ]
+            .into_iter()
+            .filter(|(_, given)| *given)
+            .map(|(flag, _)| flag.to_owned()),
+        )
+        .collect()
+    }
+
+    pub(crate) fn client_conf(&self) -> anyhow::Result<CmdProxyClientConf> {
+        Ok(CmdProxyClientConf::new(CmdProxyClientConfFile {
+            broker_url: self.broker_url(),
+            mongo_url: self.mongo_url(),
+            mongo_dbname: self.mongo_dbname(),
+            queue_prefix: self.queue_prefix(),
+            namespace: self.namespace(),
+            client_id: self
+                .client_id
+                .clone()
+                .or_ok(std::env::var("CMDPROXY_CLIENT_ID")),
+            storage_url: self.storage_url(),
+            s3: self.s3(),
+            shared_fs: self.shared_fs(),
+            dictionary: self.dictionary(),
+            read_preference: self.read_preference(),
+            reconnect: self.reconnect(),
+            queue_template: self.queue_template(),
+            task_name: self.task_name(),
+            result_db: self.result_db(),
+            hostname_template: self.hostname_template(),
+            encryption_key: self.encryption_key()?,
+            encryption_keyring: self.encryption_keyring()?,
+            request_signing: self.request_signing()?,
+            approval_signing: self.approval_signing()?,
+            broker_tls: self.broker_tls(),
+            mongo_tls: self.mongo_tls(),
+        }))
+    }
+}
+
+/// Path to the command palette, from the argument, the environment, or the default location.
+pub(crate) fn command_palette_path(command_palette: Option<PathBuf>) -> Option<PathBuf> {
+    command_palette
+        .or_ok(std::env::var("CMDPROXY_COMMAND_PALETTE").map(PathBuf::from))
+        .or_else(|| {
+            UserDirs::new().map(|dirs| {
+                dirs.home_dir()
+                    .join(".cmdproxy")
+                    .join("commands-palette.yaml")
+            })
+        })
 }
 
 pub async fn app(cli: Cli) -> anyhow::Result<()> {
     env_logger::Builder::new()
         .parse_filters(
             cli.loglevel
+                .clone()
                 .or_ok(std::env::var("CMDPROXY_LOGLEVEL"))
                 .or_wrap("info".into())
                 .unwrap()
@@ -54,34 +779,60 @@ pub async fn app(cli: Cli) -> anyhow::Result<()> {
         )
         .init();
 
-    let redis_url = cli
-        .redis_url
-        .or_ok(std::env::var("CMDPROXY_REDIS_URL"))
-        .or_wrap("redis://localhost:6379/".into())
-        .unwrap();
-
-    let mongo_url = cli
-        .mongo_url
-        .or_ok(std::env::var("CMDPROXY_MONGO_URL"))
-        .or_wrap("mongodb://localhost:27017/".into())
-        .unwrap();
+    match cli.command {
+        Some(Command::Attach(args)) => {
+            commands::attach::attach(cli.conn.client_conf()?, args).await
+        }
+        Some(Command::Bench(args)) => commands::bench::bench(cli.conn.client_conf()?, args).await,
+        #[cfg(feature = "demo")]
+        Some(Command::Demo(args)) => commands::demo::demo(args).await,
+        Some(Command::Exec(args)) => commands::exec::exec(cli.conn.client_conf()?, args).await,
+        #[cfg(unix)]
+        Some(Command::Agent(args)) => commands::agent::agent(cli.conn.client_conf()?, args).await,
+        Some(Command::Run(args)) => commands::run::run(cli.conn.client_conf()?, args).await,
+        Some(Command::History(args)) => {
+            commands::history::history(cli.conn.client_conf()?, args).await
+        }
+        Some(Command::Provenance(args)) => {
+            commands::provenance::provenance(cli.conn.client_conf()?, args).await
+        }
+        Some(Command::Replay(args)) => {
+            commands::replay::replay(cli.conn.client_conf()?, args).await
+        }
+        Some(Command::Rerun(args)) => commands::rerun::rerun(cli.conn.client_conf()?, args).await,
+        Some(Command::Submit(args)) => {
+            commands::submit::submit(cli.conn.client_conf()?, args).await
+        }
+        Some(Command::Watch(args)) => commands::watch::watch(cli.conn.client_conf()?, args).await,
+        Some(Command::Shims(command)) => commands::shims::shims(&cli.conn, command),
+        Some(Command::Schedule(command)) => {
+            commands::schedule::schedule(cli.conn.client_conf()?, command).await
+        }
+        Some(Command::Storage(command)) => {
+            commands::storage::storage(cli.conn.client_conf()?, command).await
+        }
+        Some(Command::Tasks(command)) => {
+            commands::tasks::tasks(cli.conn.client_conf()?, command).await
+        }
+        None => serve(cli).await,
+    }
+}
 
-    let mongo_dbname = cli
-        .mongo_dbname
-        .or_ok(std::env::var("CMDPROXY_MONGO_DBNAME"))
-        .or_wrap("cmdproxy-db".to_owned())
-        .unwrap();
-
-    let command_palette = cli
-        .command_palette
-        .or_ok(std::env::var("CMDPROXY_COMMAND_PALETTE").map(PathBuf::from))
-        .or_else(|| {
-            UserDirs::new().map(|dirs| {
-                dirs.home_dir()
-                    .join(".cmdproxy")
-                    .join("commands-palette.yaml")
-            })
-        });
+async fn serve(cli: Cli) -> anyhow::Result<()> {
+    let command_palette = match cli.command_palette {
+        Some(source) => Some(source),
+        None => match std::env::var("CMDPROXY_COMMAND_PALETTE") {
+            Ok(source) => Some(source.parse()?),
+            Err(_) => command_palette_path(None).map(PaletteSource::File),
+        },
+    };
+    let palette_key = match cli.palette_key {
+        Some(key) => Some(key),
+        None => std::env::var("CMDPROXY_PALETTE_KEY")
+            .ok()
+            .map(|key| key.parse())
+            .transpose()?,
+    };
 
     let ext_queues = cli
         .ext_queues
@@ -90,20 +841,111 @@ pub async fn app(cli: Cli) -> anyhow::Result<()> {
 
     SERVER_CONF
         .set(CmdProxyServerConf::new(CmdProxyServerConfFile {
-            redis_url,
-            mongo_url,
-            mongo_dbname,
+            broker_url: cli.conn.broker_url(),
+            mongo_url: cli.conn.mongo_url(),
+            mongo_dbname: cli.conn.mongo_dbname(),
             command_palette,
+            palette_key,
+            queue_prefix: cli.conn.queue_prefix(),
+            namespace: cli.conn.namespace(),
+            transfer_queue: cli
+                .transfer_queue
+                .or_ok(std::env::var("CMDPROXY_TRANSFER_QUEUE")),
+            shared_dir: cli
+                .shared_dir
+                .or_ok(std::env::var("CMDPROXY_SHARED_DIR").map(PathBuf::from)),
+            max_workspace_lifetime: cli.max_workspace_lifetime,
+            sweep_staged_after: cli.sweep_staged_after,
+            group_grace: cli.group_grace,
+            kill_grace: cli.kill_grace,
+            core_bytes: cli.core_bytes,
+            cgroup_root: cli.cgroup_root,
+            canary: cli.canary_interval.map(|interval| CanaryPolicy {
+                command: cli
+                    .canary_command
+                    .map(|command| command.split_whitespace().map(str::to_owned).collect())
+                    .unwrap_or_else(|| CanaryPolicy::default().command),
+                interval,
+                max_failures: cli.canary_failures,
+                ..CanaryPolicy::default()
+            }),
+            require_container: cli.require_container,
+            command_policy: (!cli.allow_commands.is_empty() || !cli.deny_commands.is_empty()).then(
+                || CommandPolicy {
+                    allow: cli.allow_commands,
+                    deny: cli.deny_commands,
+                },
+            ),
+            preserve_ownership: cli.preserve_ownership,
+            sla: (cli.sla_max_queue_wait.is_some() || cli.sla_max_failure_percent.is_some()).then(
+                || SlaPolicy {
+                    max_queue_wait: cli.sla_max_queue_wait,
+                    max_failure_percent: cli.sla_max_failure_percent,
+                    window: cli.sla_window,
+                    webhook: cli.sla_webhook,
+                },
+            ),
+            admission: (cli.min_free_disk.is_some() || cli.min_available_memory.is_some()).then(
+                || AdmissionPolicy {
+                    min_free_disk: cli.min_free_disk.unwrap_or_default(),
+                    min_available_memory: cli.min_available_memory.unwrap_or_default(),
+                },
+            ),
+            tags: cli
+                .tags
+                .or_ok(std::env::var("CMDPROXY_TAGS"))
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            preemption: cli.preemption.map(|mode| PreemptionPolicy {
+                mode,
+                min_gap: cli.preemption_gap,
+            }),
+            fair_share: match cli.fair_slots {
+                Some(slots) => {
+                    anyhow::ensure!(slots > 0, "Expect at least one slot to share");
+                    Some(FairSharePolicy {
+                        slots,
+                        label: cli.fair_label,
+                        weights: parse_weights(&cli.fair_weights)?,
+                    })
+                }
+                None => None,
+            },
+            pre_processors: parse_pre_processors(&cli.pre_processors)?,
+            retry: parse_max_retries(&cli.retry)?,
+            max_request_bytes: cli.max_request_bytes,
+            max_response_bytes: cli.max_response_bytes,
+            composites: parse_composites(&cli.composites)?,
+            storage_url: cli.conn.storage_url(),
+            s3: cli.conn.s3(),
+            shared_fs: cli.conn.shared_fs(),
+            dictionary: cli.conn.dictionary(),
+            read_preference: cli.conn.read_preference(),
+            reconnect: cli.conn.reconnect(),
+            queue_template: cli.conn.queue_template(),
+            task_name: cli.conn.task_name(),
+            result_db: cli.conn.result_db(),
+            encryption_key: cli.conn.encryption_key()?,
+            encryption_keyring: cli.conn.encryption_keyring()?,
+            request_signing: cli.conn.request_signing()?,
+            approval_signing: cli.conn.approval_signing()?,
+            broker_tls: cli.conn.broker_tls(),
+            mongo_tls: cli.conn.mongo_tls(),
         }))
         .unwrap();
 
     let conf = SERVER_CONF.get().unwrap();
     debug!("Server config:\n{:#?}", conf);
 
-    // insert command palette into environ, so that we can resolve command path via EnvParam
-    conf.command_palette
-        .iter()
-        .for_each(|(key, val)| std::env::set_var(key, val));
+    // fail fast on a misconfigured storage rather than on every run
+    conf.cloud.storage().await?;
+    conf.reload_palette().await?;
+    #[cfg(unix)]
+    tokio::spawn(reload_palette_on_hangup(conf));
 
     cli.environments
         .or_ok(std::env::var("CMDPROXY_ENVIRONMENTS").map(PathBuf::from))
@@ -123,30 +965,137 @@ pub async fn app(cli: Cli) -> anyhow::Result<()> {
         })
         .unwrap_or_default();
 
-    let app = celery::app!(
-        broker = RedisBroker { conf.celery.broker_url },
-        backend = MongoDbBackend { conf.celery.backend_url },
-        tasks = [run],
-        task_routes = [
-            // this bin will only run in server mode, hence no task needs to be routed
-            // "*" => "proxy-queue",
-        ],
-    )
-    .await?;
-
-    let command_queues: Vec<_> = SERVER_CONF
-        .get()
-        .unwrap()
-        .command_palette
+    let app = CeleryApp::server(&conf.celery).await?;
+
+    SERVER_APP
+        .set(app.clone())
+        .ok()
+        .expect("Server app has been set");
+
+    // the queues are fixed once consuming, hence a reload only updates the paths of the
+    // commands served already
+    let command_palette = conf.command_palette();
+    let prefixed_queues: Vec<_> = command_palette
         .keys()
         .map(String::as_str)
         .chain(ext_queues.split(','))
         .filter(|queue| !queue.is_empty())
+        .map(|queue| conf.celery.queue(queue))
+        .collect();
+    assert!(!prefixed_queues.is_empty(), "No queues to be consumed!");
+    // along with the lanes of the urgent runs
+    let lanes: Vec<_> = prefixed_queues
+        .iter()
+        .map(|queue| urgent_lane(queue))
         .collect();
-    assert!(!command_queues.is_empty(), "No queues to be consumed!");
+    let command_queues: Vec<_> = prefixed_queues
+        .iter()
+        .chain(lanes.iter())
+        .map(String::as_str)
+        .collect();
+
+    let hostname = hostname::get()?.to_string_lossy().into_owned();
+    let worker = WorkerInfo::this_worker(hostname.as_str(), prefixed_queues.clone());
+    let registry = conf.cloud.workers().await?;
+    if let Some(policy) = conf.canary.clone() {
+        let storage = conf.cloud.storage().await?;
+        let (registry, worker) = (registry.clone(), worker.clone());
+        tokio::spawn(canary::run_periodically(policy, storage, registry, worker));
+    }
+    tokio::spawn(async move { registry.keep_alive(worker).await });
+
+    tokio::spawn(cancel_runs_on_shutdown());
+    if cli.scheduler {
+        let client = Arc::new(Client::new(cli.conn.client_conf()?).await?);
+        let schedules = conf.cloud.schedules().await?;
+        tokio::spawn(schedule::fire_periodically(client, schedules));
+    }
+    if let Some(stale_after) = conf.sweep_staged_after {
+        anyhow::ensure!(
+            conf.cloud.is_grid_fs(),
+            "Cannot sweep a storage other than the GridFS"
+        );
+        let scanner = Fsck {
+            bucket: conf.cloud.grid_fs().await?,
+            files: conf.cloud.bucket_files().await?,
+            chunks: conf.cloud.bucket_chunks().await?,
+            history: conf.cloud.tasks().await?,
+        };
+        let stale_after = Duration::from_secs(stale_after);
+        tokio::spawn(async move { fsck::sweep_staged_periodically(scanner, stale_after).await });
+    }
+    if let Some(lifetime) = conf.max_workspace_lifetime {
+        let root = conf.workspace_root();
+        let lifetime = Duration::from_secs(lifetime);
+        tokio::spawn(async move { workspace::sweep_periodically(&root, lifetime).await });
+    }
+
+    app.consume_from(command_queues.as_slice()).await
+}
+
+fn parse_pre_processors(specs: &[String]) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let mut pre_processors = HashMap::<_, Vec<_>>::new();
+    for spec in specs {
+        let (queue, names) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expect QUEUE=NAME[,NAME...], got {}", spec))?;
+        pre_processors.entry(queue.to_owned()).or_default().extend(
+            names
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    Ok(pre_processors)
+}
+
+/// Cancel the runs in progress once the worker is asked to stop, instead of letting them
+/// be cut off with no response.
+async fn cancel_runs_on_shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminates) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminates.recv() => {}
+            },
+            Err(err) => {
+                warn!("Failed to listen to SIGTERM: {}", err);
+                tokio::signal::ctrl_c().await.unwrap_or_default();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or_default();
+
+    info!("Shutting down, cancel the runs in progress...");
+    server::shut_down();
+}
 
-    app.display_pretty().await;
-    app.consume_from(command_queues.as_slice()).await?;
+/// Reload the command palette whenever the worker is sent a SIGHUP.
+#[cfg(unix)]
+async fn reload_palette_on_hangup(conf: &'static CmdProxyServerConf) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    Ok(())
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!(
+                "Failed to listen to SIGHUP, palette will never be reloaded: {}",
+                err
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match conf.reload_palette().await {
+            Ok(()) => info!("Reloaded command palette"),
+            Err(err) => warn!(
+                "Failed to reload command palette, keep the old one: {}",
+                err
+            ),
+        }
+    }
 }
@@ -0,0 +1,115 @@
+//! Approval gates, by which the destructive commands, such as the migrations of databases,
+//! are proxied through the same infrastructure but only run once an operator says so.
+//!
+//! A request labeled `approval: required` is parked by the worker picking it, in the state
+//! [`crate::history::TaskState::Parked`], until approved by `cmdproxy tasks approve` or
+//! [`crate::client::Client::approve_task`], then run as usual. A parked run is given up if
+//! cancelled meanwhile, but never timed out by waiting, just as in the queue. It holds the
+//! worker nonetheless, with its inputs downloaded, hence is better sent to a queue of its own.
+//!
+//! As the task history is written by the clients, an approval is signed by the secret of the
+//! operators, given by `--approval-secret-file` and kept from the clients submitting the runs,
+//! and verified by the worker, which refuses the runs requiring approvals if not given the
+//! secret. An approval is taken only while the task is parked, and only from an operator, by
+//! their client id, other than the client which submitted the task. The submitter is told by
+//! the id the request is signed on behalf of, as verified by the worker, rather than by the
+//! task history, hence the runs requiring approvals must be sent signed.
+
+use std::collections::HashMap;
+
+use crate::history::TaskRecord;
+use crate::middles::auth::{AuthMiddle, HmacAuth};
+
+/// Label of the runs gated by approvals.
+pub const APPROVAL_LABEL: &str = "approval";
+/// Value of [`APPROVAL_LABEL`] of a run which must be approved before it runs.
+pub const REQUIRED: &str = "required";
+
+/// Whether the run of the `labels` must be approved before it runs.
+pub fn requires_approval(labels: &HashMap<String, String>) -> bool {
+    labels.get(APPROVAL_LABEL).map(String::as_str) == Some(REQUIRED)
+}
+
+/// What an operator signs to approve the task of `task_id`.
+fn approval_payload(task_id: &str, operator: &str) -> String {
+    format!("approve {}\n{}", task_id, operator)
+}
+
+/// Sign the approval of the task of `task_id` by `operator`.
+pub(crate) async fn sign(auth: &HmacAuth, task_id: &str, operator: &str) -> anyhow::Result<String> {
+    let payload = approval_payload(task_id, operator);
+    auth.credentials_stored(payload.as_str())
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Approvals must be signed"))
+}
+
+/// Whether the task of `record` has been approved, by an operator other than its `submitter`
+/// as verified by the worker, failing if the approval is not signed by the secret of the
+/// operators.
+pub(crate) async fn is_approved(
+    auth: &HmacAuth,
+    record: &TaskRecord,
+    submitter: &str,
+) -> anyhow::Result<bool> {
+    let operator = match &record.approved_by {
+        Some(operator) => operator,
+        None => return Ok(false),
+    };
+    anyhow::ensure!(
+        operator != submitter,
+        "Refused approval by {}, who submitted the task",
+        operator
+    );
+    let payload = approval_payload(record.task_id.as_str(), operator);
+    auth.verify_stored(payload.as_str(), record.approval.as_deref())
+        .await
+        .map_err(|err| anyhow::anyhow!("Refused approval by {}: {}", operator, err))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::TaskState;
+
+    fn record(client: &str) -> TaskRecord {
+        serde_json::from_value(serde_json::json!({
+            "_id": "task",
+            "state": TaskState::Parked,
+            "client": client,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_is_approved() {
+        let operators = HmacAuth::new("operators");
+        let mut parked = record("ci");
+        assert!(!is_approved(&operators, &parked, "ci").await.unwrap());
+
+        parked.approved_by = Some("alice".to_owned());
+        parked.approval = Some(sign(&operators, "task", "alice").await.unwrap());
+        assert!(is_approved(&operators, &parked, "ci").await.unwrap());
+
+        // signed by the clients rather than the operators
+        let clients = HmacAuth::new("clients");
+        parked.approval = Some(sign(&clients, "task", "alice").await.unwrap());
+        assert!(is_approved(&operators, &parked, "ci").await.is_err());
+
+        // signed for another operator, or by the submitter
+        parked.approval = Some(sign(&operators, "task", "bob").await.unwrap());
+        assert!(is_approved(&operators, &parked, "ci").await.is_err());
+        parked.approval = Some(sign(&operators, "task", "alice").await.unwrap());
+        assert!(is_approved(&operators, &parked, "alice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_is_approved_by_verified_submitter() {
+        let operators = HmacAuth::new("operators");
+        // the submitter rewrote its client id in the history to approve its own task
+        let mut forged = record("ci");
+        forged.approved_by = Some("alice".to_owned());
+        forged.approval = Some(sign(&operators, "task", "alice").await.unwrap());
+        assert!(is_approved(&operators, &forged, "alice").await.is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Formats of the archives packing the folders of the directory params into single files.
+//!
+//! The format of an archive is tagged as its content type in the metadata of the file on the
+//! storage, so that a reader unpacks whatever the writer picked. Zip is read by every
+//! version, while tar+zstd streams without buffering the files and keeps the unix
+//! permissions and symlinks, but cannot be read by the versions before it. Hence the clients
+//! pack the input folders in zip unless told otherwise, and ask the servers for the format of
+//! the output folders, which the servers before it ignore and pack in zip.
+
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// Level of the zstd compression of the tar archives, which is zstd's default.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Format of the archives packing the folders, see the module doc.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    #[value(name = "zip")]
+    Zip,
+    #[serde(rename = "tar.zst")]
+    #[value(name = "tar.zst")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Content type tagging the archives of this format on the storage.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/directory+zip",
+            ArchiveFormat::TarZst => "application/directory+tar+zstd",
+        }
+    }
+
+    /// The format of the archives tagged with `content_type`, if any.
+    pub fn from_content_type(content_type: &str) -> Option<ArchiveFormat> {
+        [ArchiveFormat::Zip, ArchiveFormat::TarZst]
+            .into_iter()
+            .find(|format| format.content_type() == content_type)
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Zip => write!(f, "zip"),
+            ArchiveFormat::TarZst => write!(f, "tar.zst"),
+        }
+    }
+}
+
+/// Pack the folder at `src` into a tar+zstd archive at `dst`, keeping the symlinks as is.
+pub(crate) fn tar_zst_dir(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let encoder = zstd::Encoder::new(File::create(dst)?, ZSTD_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", src)?;
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Unpack the tar+zstd archive at `src` into the folder at `dst`, with the permissions.
+///
+/// The entries escaping the folder, such as by `..`, are skipped by the tar crate.
+pub(crate) fn untar_zst_all(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    debug!("  untar - extract to {:#?}...", dst);
+    let decoder = zstd::Decoder::new(File::open(src)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+    std::fs::create_dir_all(dst)?;
+    archive.unpack(dst)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        for format in [ArchiveFormat::Zip, ArchiveFormat::TarZst] {
+            let content_type = format.content_type();
+            assert_eq!(ArchiveFormat::from_content_type(content_type), Some(format));
+        }
+        assert_eq!(ArchiveFormat::from_content_type("text/plain"), None);
+    }
+
+    #[test]
+    fn test_tar_zst() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(src.path().join("sub")).unwrap();
+        std::fs::write(src.path().join("a.txt"), "a").unwrap();
+        std::fs::write(src.path().join("sub").join("b.txt"), "b").unwrap();
+
+        let archive = tempfile::NamedTempFile::new().unwrap();
+        tar_zst_dir(src.path(), archive.path()).unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        untar_zst_all(archive.path(), dst.path()).unwrap();
+
+        let a = std::fs::read_to_string(dst.path().join("a.txt")).unwrap();
+        let b = std::fs::read_to_string(dst.path().join("sub").join("b.txt")).unwrap();
+        assert_eq!((a.as_str(), b.as_str()), ("a", "b"));
+    }
+}
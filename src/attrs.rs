@@ -0,0 +1,151 @@
+//! Attributes of the files besides their contents, preserved across the transfers if asked.
+//!
+//! Some tools, such as the build systems checking whether their outputs are up to date by the
+//! timestamps, need the files on one end to look as they do on the other. The attributes of
+//! the params a request asks to preserve are read where the files are uploaded, sent along
+//! in the request for the inputs or in the response for the outputs, and applied where the
+//! files are downloaded. The contents of the folders keep theirs only if packed in tar.zst.
+//!
+//! Only the extended attributes of the `user.` namespace are preserved, and only the
+//! permission bits of the mode, never setuid, setgid or sticky. The ownership is preserved
+//! only on the workers whose operators opt in by `--preserve-ownership`, as far as they are
+//! allowed to change it, such as run as root, and never to root; the clients keep theirs.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Attributes of a file or a folder, each of which is left as is where not known.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAttrs {
+    /// Modification time in nanoseconds since the unix epoch.
+    #[serde(default)]
+    pub mtime_ns: Option<u64>,
+    /// Unix permission bits.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Extended attributes by their names, with their values in base64.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileAttrs {
+    /// The attributes of the file or the folder at `path`.
+    pub fn read(path: &Path) -> anyhow::Result<FileAttrs> {
+        let metadata = path.metadata()?;
+        let attrs = FileAttrs {
+            mtime_ns: metadata
+                .modified()
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map(|mtime| mtime.as_nanos() as u64),
+            ..FileAttrs::default()
+        };
+
+        #[cfg(unix)]
+        let attrs = {
+            use base64::engine::general_purpose::STANDARD as BASE64;
+            use base64::Engine;
+            use std::os::unix::fs::MetadataExt;
+
+            let mut attrs = attrs;
+            attrs.mode = Some(metadata.mode() & 0o7777);
+            attrs.uid = Some(metadata.uid());
+            attrs.gid = Some(metadata.gid());
+            for name in xattr::list(path)? {
+                let name = match name.to_str() {
+                    Some(name) if name.starts_with("user.") => name.to_owned(),
+                    _ => continue,
+                };
+                if let Some(value) = xattr::get(path, name.as_str())? {
+                    attrs.xattrs.insert(name, BASE64.encode(value));
+                }
+            }
+            attrs
+        };
+        Ok(attrs)
+    }
+
+    /// Apply the attributes to the file or the folder at `path`, with the ownership only if
+    /// `chown`.
+    ///
+    /// The ownership and the extended attributes are applied as far as allowed, as they may
+    /// be beyond the permissions of the process or the support of the filesystem.
+    pub fn apply(&self, path: &Path, chown: bool) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use base64::engine::general_purpose::STANDARD as BASE64;
+            use base64::Engine;
+            use std::os::unix::fs::PermissionsExt;
+
+            if let (true, Some(uid), Some(gid)) = (chown, self.uid, self.gid) {
+                if uid != 0 && gid != 0 {
+                    if let Err(err) = std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+                        log::debug!("  keep the ownership of {}: {}", path.display(), err);
+                    }
+                }
+            }
+            for (name, value) in &self.xattrs {
+                if let Err(err) = xattr::set(path, name, BASE64.decode(value)?.as_slice()) {
+                    log::debug!("  skip xattr {} of {}: {}", name, path.display(), err);
+                }
+            }
+            if let Some(mtime_ns) = self.mtime_ns {
+                File::open(path)?.set_modified(UNIX_EPOCH + Duration::from_nanos(mtime_ns))?;
+            }
+            // the last, as it may take the write permission away
+            if let Some(mode) = self.mode {
+                let mode = mode & 0o777;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+
+        #[cfg(not(unix))]
+        if let Some(mtime_ns) = self.mtime_ns {
+            // folders cannot be opened for writing, nor have their times set, on windows
+            if path.is_file() {
+                let file = File::options().write(true).open(path)?;
+                file.set_modified(UNIX_EPOCH + Duration::from_nanos(mtime_ns))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_apply() {
+        let src = tempfile::NamedTempFile::new().unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        src.as_file().set_modified(mtime).unwrap();
+        let attrs = FileAttrs::read(src.path()).unwrap();
+        assert_eq!(attrs.mtime_ns, Some(1_600_000_000 * 1_000_000_000));
+
+        let dst = tempfile::NamedTempFile::new().unwrap();
+        attrs.apply(dst.path(), false).unwrap();
+        assert_eq!(dst.path().metadata().unwrap().modified().unwrap(), mtime);
+        assert_eq!(FileAttrs::read(dst.path()).unwrap().mode, attrs.mode);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_masks_mode() {
+        let dst = tempfile::NamedTempFile::new().unwrap();
+        let attrs = FileAttrs {
+            mode: Some(0o6755),
+            ..FileAttrs::default()
+        };
+        attrs.apply(dst.path(), false).unwrap();
+        assert_eq!(FileAttrs::read(dst.path()).unwrap().mode, Some(0o755));
+    }
+}
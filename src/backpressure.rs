@@ -0,0 +1,164 @@
+use std::fmt;
+use std::time::Duration;
+
+/// What the client does when the queue a request is going to is deeper than the watermark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressureMode {
+    /// Wait until the queue drains below the watermark, polling at the given interval, and
+    /// give up with [`Backpressure`] after `timeout` if given.
+    Block {
+        poll_interval: Duration,
+        timeout: Option<Duration>,
+    },
+    /// Fail with [`Backpressure`] immediately.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackpressurePolicy {
+    /// Max number of pending tasks in the queue at which a request is still submitted.
+    pub watermark: u64,
+    pub mode: BackpressureMode,
+}
+
+impl BackpressurePolicy {
+    pub fn block(watermark: u64) -> BackpressurePolicy {
+        BackpressurePolicy {
+            watermark,
+            mode: BackpressureMode::Block {
+                poll_interval: Duration::from_secs(1),
+                timeout: None,
+            },
+        }
+    }
+
+    pub fn reject(watermark: u64) -> BackpressurePolicy {
+        BackpressurePolicy {
+            watermark,
+            mode: BackpressureMode::Reject,
+        }
+    }
+
+    /// Decide on a request to `queue` of `depth` pending tasks, after waiting `waited` for it
+    /// to drain already.
+    pub(crate) fn decide(&self, queue: &str, depth: u64, waited: Duration) -> Decision {
+        if depth <= self.watermark {
+            return Decision::Submit;
+        }
+        let backpressure = Backpressure {
+            queue: queue.to_owned(),
+            depth,
+            watermark: self.watermark,
+        };
+        match self.mode {
+            BackpressureMode::Block {
+                poll_interval,
+                timeout,
+            } => {
+                if matches!(timeout, Some(timeout) if waited >= timeout) {
+                    Decision::Refuse(backpressure)
+                } else {
+                    Decision::Wait(backpressure, poll_interval)
+                }
+            }
+            BackpressureMode::Reject => Decision::Refuse(backpressure),
+        }
+    }
+}
+
+/// What the client does about a request, see [`BackpressurePolicy::decide`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Submit,
+    /// Poll the depth of the queue again after the interval.
+    Wait(Backpressure, Duration),
+    Refuse(Backpressure),
+}
+
+/// Error of a request not submitted because its queue is too deep.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backpressure {
+    pub queue: String,
+    pub depth: u64,
+    pub watermark: u64,
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Queue `{}' has {} pending tasks, above the watermark {}",
+            self.queue, self.depth, self.watermark
+        )
+    }
+}
+
+impl std::error::Error for Backpressure {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backpressure(depth: u64) -> Backpressure {
+        Backpressure {
+            queue: "gcc".to_owned(),
+            depth,
+            watermark: 10,
+        }
+    }
+
+    #[test]
+    fn test_reject() {
+        let policy = BackpressurePolicy::reject(10);
+        assert_eq!(policy.decide("gcc", 0, Duration::ZERO), Decision::Submit);
+        // the watermark is still submitted at
+        assert_eq!(policy.decide("gcc", 10, Duration::ZERO), Decision::Submit);
+        assert_eq!(
+            policy.decide("gcc", 11, Duration::ZERO),
+            Decision::Refuse(backpressure(11))
+        );
+    }
+
+    #[test]
+    fn test_block() {
+        let policy = BackpressurePolicy::block(10);
+        assert_eq!(policy.decide("gcc", 10, Duration::ZERO), Decision::Submit);
+        // forever without a timeout
+        let waited = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(
+            policy.decide("gcc", 11, waited),
+            Decision::Wait(backpressure(11), Duration::from_secs(1))
+        );
+
+        let policy = BackpressurePolicy {
+            watermark: 10,
+            mode: BackpressureMode::Block {
+                poll_interval: Duration::from_millis(100),
+                timeout: Some(Duration::from_secs(5)),
+            },
+        };
+        assert_eq!(
+            policy.decide("gcc", 20, Duration::from_secs(4)),
+            Decision::Wait(backpressure(20), Duration::from_millis(100))
+        );
+        assert_eq!(
+            policy.decide("gcc", 20, Duration::from_secs(5)),
+            Decision::Refuse(backpressure(20))
+        );
+        // drained meanwhile
+        assert_eq!(
+            policy.decide("gcc", 3, Duration::from_secs(5)),
+            Decision::Submit
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            backpressure(11).to_string(),
+            "Queue `gcc' has 11 pending tasks, above the watermark 10"
+        );
+    }
+}
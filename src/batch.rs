@@ -0,0 +1,262 @@
+//! Coalescing of many small requests of a batchable command into one invocation.
+//!
+//! A batchable command takes many items at once, such as a linter taking many files. The
+//! requests arriving within a short window are run together as one invocation with all their
+//! arguments, and then each gets its share of the outputs back: a line of the output belongs
+//! to the request one of whose arguments the line starts with, e.g. `a.rs:3: unused import`
+//! belongs to the request of `a.rs`, and a line belonging to none is given to every request.
+//!
+//! Only requests of the same command, working directory and environment are coalesced.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::protocol::{ExitStatus, RunRecipe};
+
+/// How the requests of a command are coalesced, as configured in its palette entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchConf {
+    /// Milliseconds to wait for more requests after the first one of a batch.
+    #[serde(default = "BatchConf::default_window_ms")]
+    pub window_ms: u64,
+    /// Max number of requests in a batch, beyond which a new batch is started.
+    #[serde(default = "BatchConf::default_max_size")]
+    pub max_size: usize,
+}
+
+impl BatchConf {
+    fn default_window_ms() -> u64 {
+        50
+    }
+
+    fn default_max_size() -> usize {
+        64
+    }
+}
+
+/// The share of a request in the outcome of the batch it was run in.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchShare {
+    pub(crate) status: ExitStatus,
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+}
+
+struct Pending {
+    args: Vec<String>,
+    reply: oneshot::Sender<BatchShare>,
+}
+
+struct Batch {
+    generation: u64,
+    pending: Vec<Pending>,
+}
+
+/// Requests of the same command, working directory and environment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BatchKey {
+    command: String,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+}
+
+impl BatchKey {
+    fn of(recipe: &RunRecipe) -> BatchKey {
+        let mut env: Vec<_> = recipe.env.clone().unwrap_or_default().into_iter().collect();
+        env.sort();
+        BatchKey {
+            command: recipe.command.clone(),
+            cwd: recipe.cwd.clone(),
+            env,
+        }
+    }
+}
+
+static BATCHES: Lazy<Mutex<(u64, HashMap<BatchKey, Batch>)>> =
+    Lazy::new(|| Mutex::new((0, HashMap::new())));
+
+/// Run the request together with the others arriving within the window.
+pub(crate) async fn run_batched(conf: BatchConf, recipe: &RunRecipe) -> anyhow::Result<BatchShare> {
+    let key = BatchKey::of(recipe);
+    let (reply, share) = oneshot::channel();
+    let pending = Pending {
+        args: recipe.args.clone(),
+        reply,
+    };
+
+    let full = {
+        let mut batches = BATCHES.lock().unwrap();
+        let (generations, batches) = &mut *batches;
+        match batches.get_mut(&key) {
+            Some(batch) => batch.pending.push(pending),
+            None => {
+                *generations += 1;
+                let generation = *generations;
+                batches.insert(
+                    key.clone(),
+                    Batch {
+                        generation,
+                        pending: vec![pending],
+                    },
+                );
+                tokio::spawn(flush_after(conf, key.clone(), generation));
+            }
+        }
+        match batches.get(&key) {
+            Some(batch) if batch.pending.len() >= conf.max_size => batches.remove(&key),
+            _ => None,
+        }
+    };
+    if let Some(batch) = full {
+        tokio::spawn(run_batch(key, batch.pending));
+    }
+
+    Ok(share.await?)
+}
+
+/// Run the batch once its window is over, unless it has been run already for being full.
+async fn flush_after(conf: BatchConf, key: BatchKey, generation: u64) {
+    tokio::time::sleep(Duration::from_millis(conf.window_ms)).await;
+    let batch = {
+        let mut batches = BATCHES.lock().unwrap();
+        match batches.1.get(&key) {
+            Some(batch) if batch.generation == generation => batches.1.remove(&key),
+            _ => None,
+        }
+    };
+    if let Some(batch) = batch {
+        run_batch(key, batch.pending).await;
+    }
+}
+
+async fn run_batch(key: BatchKey, pending: Vec<Pending>) {
+    debug!(
+        "Run {} coalesced requests of {}...",
+        pending.len(),
+        key.command
+    );
+    let output = tokio::process::Command::new(key.command.as_str())
+        .args(pending.iter().flat_map(|pending| pending.args.iter()))
+        .stdin(Stdio::null())
+        .current_dir(key.cwd.as_deref().unwrap_or("."))
+        .envs(key.env.iter().cloned())
+        .output()
+        .await;
+
+    let (status, stdout, stderr) = match output {
+        Ok(output) => (
+            ExitStatus::from(output.status),
+            output.stdout,
+            output.stderr,
+        ),
+        Err(err) => (
+            ExitStatus::SpawnFailed {
+                reason: err.to_string(),
+            },
+            Vec::new(),
+            Vec::new(),
+        ),
+    };
+
+    let args: Vec<_> = pending
+        .iter()
+        .map(|pending| pending.args.as_slice())
+        .collect();
+    let shares = split(
+        &status,
+        stdout.as_slice(),
+        stderr.as_slice(),
+        args.as_slice(),
+    );
+    for (pending, share) in pending.into_iter().zip(shares) {
+        // the request may have been given up, e.g. revoked, which is fine
+        pending.reply.send(share).unwrap_or_default();
+    }
+}
+
+/// Split the outcome of a batch into the shares of its requests, given their arguments.
+///
+/// If the batch failed, only the requests with some output of their own are failed, unless
+/// none has any, in which case the failure cannot be attributed and all are failed.
+fn split(status: &ExitStatus, stdout: &[u8], stderr: &[u8], args: &[&[String]]) -> Vec<BatchShare> {
+    let (stdouts, stdout_owned) = split_lines(stdout, args);
+    let (stderrs, stderr_owned) = split_lines(stderr, args);
+    let owned: Vec<_> = stdout_owned
+        .iter()
+        .zip(&stderr_owned)
+        .map(|(a, b)| *a || *b)
+        .collect();
+    let attributable = matches!(status, ExitStatus::Exited { .. }) && owned.iter().any(|o| *o);
+
+    stdouts
+        .into_iter()
+        .zip(stderrs)
+        .zip(owned)
+        .map(|((stdout, stderr), owned)| BatchShare {
+            status: if status.success() || !attributable || owned {
+                status.clone()
+            } else {
+                ExitStatus::Exited { code: 0 }
+            },
+            stdout,
+            stderr,
+        })
+        .collect()
+}
+
+/// Split the lines of the output by the requests they belong to, and tell whether each
+/// request has any line of its own.
+fn split_lines(output: &[u8], args: &[&[String]]) -> (Vec<Vec<u8>>, Vec<bool>) {
+    let mut shares = vec![Vec::new(); args.len()];
+    let mut owned = vec![false; args.len()];
+    for line in output.split_inclusive(|byte| *byte == b'\n') {
+        let owner = args.iter().position(|args| {
+            args.iter()
+                .any(|arg| !arg.is_empty() && line.starts_with(arg.as_bytes()))
+        });
+        match owner {
+            Some(owner) => {
+                shares[owner].extend_from_slice(line);
+                owned[owner] = true;
+            }
+            None => shares
+                .iter_mut()
+                .for_each(|share| share.extend_from_slice(line)),
+        }
+    }
+    (shares, owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let a = vec!["a.rs".to_owned()];
+        let b = vec!["b.rs".to_owned()];
+        let c = vec!["c.rs".to_owned()];
+        let args = [a.as_slice(), b.as_slice(), c.as_slice()];
+
+        let stdout = b"checking...\na.rs:3: unused import\nc.rs:1: missing docs\n";
+        let shares = split(&ExitStatus::Exited { code: 1 }, stdout, b"", &args);
+        assert_eq!(shares[0].stdout, b"checking...\na.rs:3: unused import\n");
+        assert_eq!(shares[0].status, ExitStatus::Exited { code: 1 });
+        assert_eq!(shares[1].stdout, b"checking...\n");
+        assert_eq!(shares[1].status, ExitStatus::Exited { code: 0 });
+        assert_eq!(shares[2].status, ExitStatus::Exited { code: 1 });
+
+        // a failure owned by nobody fails everyone
+        let shares = split(&ExitStatus::Exited { code: 2 }, b"", b"oops\n", &args);
+        assert!(shares.iter().all(
+            |share| share.status == ExitStatus::Exited { code: 2 } && share.stderr == b"oops\n"
+        ));
+    }
+}
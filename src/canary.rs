@@ -0,0 +1,173 @@
+//! Canary runs, by which a worker checks periodically that it is still fit to serve.
+//!
+//! A canary run spawns a tiny command on the worker itself, `true` by default, and writes a
+//! small file to the storage, reads it back and removes it, failing if any step fails or
+//! takes longer than the timeout. The outcomes are kept in [`crate::metrics::canary_stats`]
+//! and advertised in the registry of the workers, where a worker of which too many canary
+//! runs failed in a row marks itself unhealthy, so that it can be taken out of rotation,
+//! such as by the automation listing [`crate::registry::WorkerRegistry::unhealthy`].
+
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+use crate::params::Param;
+use crate::registry::{WorkerInfo, WorkerRegistry};
+use crate::storage::Storage;
+use crate::units;
+
+/// Hostname of the files written by the canary runs on the storage.
+pub const CANARY_HOSTNAME: &str = "(canary)";
+
+/// How a worker runs its canaries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanaryPolicy {
+    /// Program and arguments of the command run on the worker.
+    #[serde(default = "default_command")]
+    pub command: Vec<String>,
+    /// Seconds between the canary runs.
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "units::deserialize_secs"
+    )]
+    pub interval: u64,
+    /// Seconds a canary run may take before taken as failed.
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "units::deserialize_secs"
+    )]
+    pub timeout: u64,
+    /// Number of the canary runs failing in a row for the worker to mark itself unhealthy.
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+}
+
+fn default_command() -> Vec<String> {
+    vec!["true".to_owned()]
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_max_failures() -> u32 {
+    3
+}
+
+impl Default for CanaryPolicy {
+    fn default() -> Self {
+        CanaryPolicy {
+            command: default_command(),
+            interval: default_interval(),
+            timeout: default_timeout(),
+            max_failures: default_max_failures(),
+        }
+    }
+}
+
+/// Run the canaries of the `worker` by the `policy` forever, refreshing the worker in the
+/// `registry` at once whenever its health changes.
+pub(crate) async fn run_periodically(
+    policy: CanaryPolicy,
+    storage: Storage,
+    registry: WorkerRegistry,
+    worker: WorkerInfo,
+) {
+    let interval = Duration::from_secs(policy.interval);
+    let timeout = Duration::from_secs(policy.timeout);
+    let probe = Param::OutCloudFileParam {
+        filepath: format!("{}/probe", worker.worker_id.replace(':', "-")),
+        hostname: CANARY_HOSTNAME.to_owned(),
+    };
+    loop {
+        let started = Instant::now();
+        let res = match tokio::time::timeout(timeout, run_once(&policy, &storage, &probe)).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!("timed out after {:?}", timeout)),
+        };
+        let latency = started.elapsed();
+        if let Err(err) = &res {
+            warn!("Canary run failed: {}", err);
+        }
+        let (stats, changed) = metrics::record_canary(res.is_ok(), latency, policy.max_failures);
+        debug!("Canary run took {:?}: {:?}", latency, stats);
+        if changed {
+            if stats.healthy {
+                warn!("Worker {} is healthy again", worker.worker_id);
+            } else {
+                warn!(
+                    "Worker {} is unhealthy, failing {} canary runs in a row",
+                    worker.worker_id, stats.failures_in_row
+                );
+            }
+            registry
+                .heartbeat(&worker)
+                .await
+                .unwrap_or_else(|err| warn!("Failed to report the health: {}", err));
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Run the canary command, then the round trip of the `probe` through the storage.
+async fn run_once(policy: &CanaryPolicy, storage: &Storage, probe: &Param) -> anyhow::Result<()> {
+    run_command(&policy.command).await?;
+
+    let content = format!("canary of {}", std::process::id());
+    probe.upload_from_string(storage.clone(), &content).await?;
+    let read = probe.download_to_string(storage.clone()).await;
+    probe.remove_from_cloud(storage.clone()).await?;
+    anyhow::ensure!(read? == content, "Canary file read back altered");
+    Ok(())
+}
+
+/// Run the command of `program` followed by the arguments, expecting it to succeed.
+async fn run_command(command: &[String]) -> anyhow::Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("Empty canary command"))?;
+    let status = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await?;
+    anyhow::ensure!(status.success(), "Canary command {} {}", program, status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::CanaryStats;
+
+    #[test]
+    fn test_record() {
+        let mut stats = CanaryStats::default();
+        let latency = Duration::from_millis(20);
+        assert!(!stats.record(false, latency, 2));
+        assert!(stats.record(false, latency, 2));
+        assert!(!stats.healthy);
+        assert!(!stats.record(false, latency, 2));
+        assert!(stats.record(true, latency, 2));
+        assert_eq!((stats.runs, stats.failures, stats.healthy), (4, 3, true));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_command() {
+        assert!(run_command(&default_command()).await.is_ok());
+        assert!(run_command(&["false".to_owned()]).await.is_err());
+        assert!(run_command(&[]).await.is_err());
+    }
+}
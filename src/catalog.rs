@@ -0,0 +1,242 @@
+//! Catalog of the artifacts produced by the runs, registered into a store of their own, so
+//! that the downstream pipelines discover the outputs by querying it rather than by parsing
+//! the responses.
+//!
+//! Once a run succeeds, the client registers every local output it has put in place, or
+//! every file under an output folder: its path, tagged with the identity of the client, its
+//! sha256 and size, and the task, the command and the labels of the request producing it.
+//! The catalog is either the collection of the deployment, see [`MongoCatalog`], a REST
+//! endpoint taking the entries posted in json, see [`RestCatalog`], or any [`ArtifactCatalog`]
+//! of the caller. Failing to register an artifact is warned about, but never fails the run.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use celery::export::async_trait;
+use mongodb::bson::{doc, Document, Regex};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::params::content_digest;
+use crate::protocol::now_millis;
+
+/// An artifact as registered in the catalog.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// Path of the artifact on the host of the client.
+    pub path: String,
+    /// Identity of the client, as tagged in the cloud urls of its files.
+    pub hostname: String,
+    /// Hex of the sha256 of the content.
+    pub digest: String,
+    pub size: u64,
+    /// Id of the task whose run produced the artifact.
+    pub task_id: String,
+    /// Name of the command producing the artifact, as requested.
+    pub command: String,
+    /// Labels of the request producing the artifact.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Milliseconds since the unix epoch when the artifact was registered.
+    pub produced_at: u64,
+}
+
+/// Store the artifacts produced by the runs are registered into.
+#[async_trait]
+pub trait ArtifactCatalog: Send + Sync {
+    async fn register(&self, entries: &[CatalogEntry]) -> anyhow::Result<()>;
+}
+
+/// The catalog kept in a collection of the deployment, queried by [`ArtifactQuery`].
+#[derive(Clone)]
+pub struct MongoCatalog {
+    coll: Collection<CatalogEntry>,
+}
+
+impl MongoCatalog {
+    pub fn new(coll: Collection<CatalogEntry>) -> MongoCatalog {
+        MongoCatalog { coll }
+    }
+
+    /// The artifacts matching the `query`, the latest first.
+    pub async fn find(&self, query: &ArtifactQuery) -> anyhow::Result<Vec<CatalogEntry>> {
+        use futures::TryStreamExt;
+
+        let options = FindOptions::builder()
+            .sort(doc! { "produced_at": -1 })
+            .limit(query.limit)
+            .build();
+        Ok(self
+            .coll
+            .find(query.filter(), options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl ArtifactCatalog for MongoCatalog {
+    async fn register(&self, entries: &[CatalogEntry]) -> anyhow::Result<()> {
+        if !entries.is_empty() {
+            self.coll.insert_many(entries, None).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The catalog behind a REST endpoint, which takes the entries posted as a json array.
+pub struct RestCatalog {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl RestCatalog {
+    pub fn new(url: impl Into<String>) -> RestCatalog {
+        RestCatalog {
+            url: url.into(),
+            http: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ArtifactCatalog for RestCatalog {
+    async fn register(&self, entries: &[CatalogEntry]) -> anyhow::Result<()> {
+        self.http
+            .post(self.url.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(entries)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Which artifacts to find in a [`MongoCatalog`], matching all of the criteria given.
+#[derive(Debug, Clone, Default)]
+pub struct ArtifactQuery {
+    pub digest: Option<String>,
+    pub task_id: Option<String>,
+    pub command: Option<String>,
+    /// Prefix of the paths of the artifacts, such as the folder they are under.
+    pub path_prefix: Option<String>,
+    /// Labels the request producing the artifacts was given, among others.
+    pub labels: HashMap<String, String>,
+    /// Max number of the artifacts found, or all if not given.
+    pub limit: Option<i64>,
+}
+
+impl ArtifactQuery {
+    fn filter(&self) -> Document {
+        let mut filter = Document::new();
+        if let Some(digest) = &self.digest {
+            filter.insert("digest", digest);
+        }
+        if let Some(task_id) = &self.task_id {
+            filter.insert("task_id", task_id);
+        }
+        if let Some(command) = &self.command {
+            filter.insert("command", command);
+        }
+        if let Some(prefix) = &self.path_prefix {
+            let pattern = format!("^{}", regex::escape(prefix));
+            filter.insert(
+                "path",
+                Regex {
+                    pattern,
+                    options: String::new(),
+                },
+            );
+        }
+        for (name, value) in &self.labels {
+            filter.insert(format!("labels.{}", name), value);
+        }
+        filter
+    }
+}
+
+/// What is known of a run which produced the artifacts, besides the artifacts themselves.
+pub(crate) struct Producer {
+    pub(crate) hostname: String,
+    pub(crate) task_id: String,
+    pub(crate) command: String,
+    pub(crate) labels: HashMap<String, String>,
+}
+
+impl Producer {
+    /// The entries of the files at `paths`, or under them if folders, produced by this run.
+    pub(crate) async fn entries(&self, paths: &[PathBuf]) -> anyhow::Result<Vec<CatalogEntry>> {
+        let files = paths
+            .iter()
+            .flat_map(|path| WalkDir::new(path).into_iter().filter_map(Result::ok))
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path());
+        let produced_at = now_millis();
+        let mut entries = Vec::new();
+        for path in files {
+            entries.push(CatalogEntry {
+                path: path.to_string_lossy().into_owned(),
+                hostname: self.hostname.clone(),
+                digest: content_digest(path.as_path()).await?,
+                size: path.metadata()?.len(),
+                task_id: self.task_id.clone(),
+                command: self.command.clone(),
+                labels: self.labels.clone(),
+                produced_at,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::Bson;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entries_and_query() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("logs")).unwrap();
+        std::fs::write(dir.path().join("logs").join("a.log"), "abc").unwrap();
+        std::fs::write(dir.path().join("report.csv"), "").unwrap();
+        let producer = Producer {
+            hostname: "ci-7".to_owned(),
+            task_id: "task".to_owned(),
+            command: "gcc".to_owned(),
+            labels: HashMap::from([("team".to_owned(), "infra".to_owned())]),
+        };
+        let mut entries = producer
+            .entries(&[dir.path().join("logs"), dir.path().join("report.csv")])
+            .await
+            .unwrap();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].size, 3);
+        assert_eq!(
+            entries[0].digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(entries[1].path.ends_with("report.csv"));
+
+        let query = ArtifactQuery {
+            digest: Some(entries[0].digest.clone()),
+            path_prefix: Some("/tmp/a.b".to_owned()),
+            labels: HashMap::from([("team".to_owned(), "infra".to_owned())]),
+            ..ArtifactQuery::default()
+        };
+        let filter = query.filter();
+        assert_eq!(filter.get_str("digest").unwrap(), entries[0].digest);
+        assert_eq!(filter.get_str("labels.team").unwrap(), "infra");
+        let pattern = Regex {
+            pattern: "^/tmp/a\\.b".to_owned(),
+            options: String::new(),
+        };
+        assert_eq!(filter.get("path"), Some(&Bson::RegularExpression(pattern)));
+    }
+}
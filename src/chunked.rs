@@ -0,0 +1,233 @@
+//! Chunked uploads of the large files, which resume from the chunks uploaded already.
+//!
+//! A file larger than a chunk is uploaded chunk by chunk, each to an object of its own at
+//! `<url>.chunk-<index>` stamped in its metadata with which chunk of which version of the
+//! file it is, and then published as an empty head object at `<url>` listing the chunks in
+//! its metadata. An upload dropped halfway, such as by a lost connection to the storage,
+//! skips the chunks already there when tried again, instead of starting over. The readers
+//! assemble the chunks back as told by the head.
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use log::debug;
+use mongodb::bson::{doc, Document};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::storage::FileStorage;
+
+/// Bytes of a chunk, and the size above which the files are uploaded in chunks.
+pub(crate) const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Key of the metadata of a head object listing its chunks.
+const CHUNKED_KEY: &str = "chunked";
+
+/// Key of the metadata of a chunk stamping which chunk it is.
+const CHUNK_KEY: &str = "chunk";
+
+fn chunk_url(url: &str, index: u64) -> String {
+    format!("{}.chunk-{}", url, index)
+}
+
+/// Whether the file at `url` is a chunk of another file.
+pub(crate) fn is_chunk(url: &str) -> bool {
+    url.rsplit_once(".chunk-")
+        .map_or(false, |(_, index)| index.parse::<u64>().is_ok())
+}
+
+/// Url of the file the file at `url` is a chunk of, or `url` itself if not a chunk.
+pub(crate) fn head_url(url: &str) -> &str {
+    match url.rsplit_once(".chunk-") {
+        Some((head, index)) if index.parse::<u64>().is_ok() => head,
+        _ => url,
+    }
+}
+
+/// Whether the head object of `metadata` lists chunks of its file.
+pub(crate) fn is_chunked(metadata: Option<&Document>) -> bool {
+    chunk_count(metadata).is_some()
+}
+
+/// Number of chunks of the head object of `metadata`, if chunked.
+fn chunk_count(metadata: Option<&Document>) -> Option<u64> {
+    let chunked = metadata?.get_document(CHUNKED_KEY).ok()?;
+    chunked.get_i64("count").ok().map(|count| count as u64)
+}
+
+/// Upload the file at `path` to `url` in chunks of `chunk_size` bytes, skipping those of the
+/// same version of the file uploaded already.
+pub(crate) async fn upload(
+    storage: &dyn FileStorage,
+    url: &str,
+    path: &Path,
+    metadata: Option<Document>,
+    chunk_size: u64,
+) -> anyhow::Result<()> {
+    let meta = tokio::fs::metadata(path).await?;
+    let length = meta.len();
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let count = (length + chunk_size - 1) / chunk_size;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    for index in 0..count {
+        let chunk_url = chunk_url(url, index);
+        let stamp = doc! { "index": index as i64, "length": length as i64, "mtime": mtime };
+        let uploaded = match storage.metadata(chunk_url.as_str()).await {
+            Ok(Some(metadata)) => metadata.get_document(CHUNK_KEY).ok() == Some(&stamp),
+            _ => false,
+        };
+        if uploaded {
+            debug!("  chunk {}/{} uploaded already, skip", index + 1, count);
+            continue;
+        }
+
+        let part = tempfile::NamedTempFile::new()?;
+        let mut sink = tokio::fs::File::create(part.path()).await?;
+        file.seek(SeekFrom::Start(index * chunk_size)).await?;
+        tokio::io::copy(&mut (&mut file).take(chunk_size), &mut sink).await?;
+        sink.flush().await?;
+        storage
+            .upload(
+                chunk_url.as_str(),
+                part.path(),
+                Some(doc! { CHUNK_KEY: stamp }),
+            )
+            .await?;
+        debug!("  uploaded chunk {}/{}", index + 1, count);
+    }
+
+    let mut metadata = metadata.unwrap_or_default();
+    metadata.insert(
+        CHUNKED_KEY,
+        doc! { "count": count as i64, "length": length as i64 },
+    );
+    let head = tempfile::NamedTempFile::new()?;
+    storage.upload(url, head.path(), Some(metadata)).await
+}
+
+/// Assemble the chunks of the file at `url` into `path`, if its head of `metadata` says it
+/// is chunked, returning whether it is.
+pub(crate) async fn download(
+    storage: &dyn FileStorage,
+    url: &str,
+    metadata: Option<&Document>,
+    path: &Path,
+) -> anyhow::Result<bool> {
+    let count = match chunk_count(metadata) {
+        Some(count) => count,
+        None => return Ok(false),
+    };
+
+    let mut file = tokio::fs::File::create(path).await?;
+    let part = tempfile::NamedTempFile::new()?;
+    for index in 0..count {
+        storage
+            .download(chunk_url(url, index).as_str(), part.path())
+            .await?;
+        let mut chunk = tokio::fs::File::open(part.path()).await?;
+        tokio::io::copy(&mut chunk, &mut file).await?;
+        debug!("  downloaded chunk {}/{}", index + 1, count);
+    }
+    file.flush().await?;
+
+    let chunked = metadata.unwrap().get_document(CHUNKED_KEY)?;
+    let expected = chunked.get_i64("length")? as u64;
+    let length = file.metadata().await?.len();
+    anyhow::ensure!(
+        length == expected,
+        "Assembled {} bytes of {}, but expected {}",
+        length,
+        url,
+        expected
+    );
+    Ok(true)
+}
+
+/// Move the chunks of the file at `from` to `to`, if its head of `metadata` is chunked.
+///
+/// The head is left to the caller, to be moved after the chunks.
+pub(crate) async fn rename(
+    storage: &dyn FileStorage,
+    from: &str,
+    to: &str,
+    metadata: Option<&Document>,
+) -> anyhow::Result<()> {
+    for index in 0..chunk_count(metadata).unwrap_or_default() {
+        let to = chunk_url(to, index);
+        // left behind by a file once there
+        if storage.exists(to.as_str()).await? {
+            storage.delete(to.as_str()).await?;
+        }
+        storage
+            .rename(chunk_url(from, index).as_str(), to.as_str())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Delete the chunks of the file at `url`, if its head of `metadata` is chunked.
+pub(crate) async fn delete(
+    storage: &dyn FileStorage,
+    url: &str,
+    metadata: Option<&Document>,
+) -> anyhow::Result<()> {
+    for index in 0..chunk_count(metadata).unwrap_or_default() {
+        storage.delete(chunk_url(url, index).as_str()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::storage::SharedFsStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upload_resume() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = SharedFsStorage::new(root.path().to_path_buf(), "");
+        let content: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        input.write_all(content.as_slice()).unwrap();
+
+        let url = "@host:/data/big.bin";
+        upload(&storage, url, input.path(), None, 300)
+            .await
+            .unwrap();
+        let metadata = storage.metadata(url).await.unwrap();
+        assert_eq!(chunk_count(metadata.as_ref()), Some(4));
+        assert!(is_chunk(chunk_url(url, 3).as_str()) && !is_chunk(url));
+
+        // a dropped upload resumes from the chunks there, which are not uploaded again
+        storage.delete(chunk_url(url, 2).as_str()).await.unwrap();
+        let first = storage.metadata(chunk_url(url, 0).as_str()).await.unwrap();
+        upload(&storage, url, input.path(), None, 300)
+            .await
+            .unwrap();
+        let again = storage.metadata(chunk_url(url, 0).as_str()).await.unwrap();
+        assert_eq!(first, again);
+        assert!(storage.exists(chunk_url(url, 2).as_str()).await.unwrap());
+
+        let output = tempfile::NamedTempFile::new().unwrap();
+        assert!(download(&storage, url, metadata.as_ref(), output.path())
+            .await
+            .unwrap());
+        assert_eq!(std::fs::read(output.path()).unwrap(), content);
+
+        let moved = "@host:/data/moved.bin";
+        rename(&storage, url, moved, metadata.as_ref())
+            .await
+            .unwrap();
+        assert!(storage.exists(chunk_url(moved, 0).as_str()).await.unwrap());
+        delete(&storage, moved, metadata.as_ref()).await.unwrap();
+        assert!(!storage.exists(chunk_url(moved, 3).as_str()).await.unwrap());
+    }
+}
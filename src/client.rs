@@ -1,41 +1,418 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
-use celery::backend::MongoDbBackend;
-use celery::broker::RedisBroker;
+use anyhow::{anyhow, Context};
 use celery::prelude::*;
 use celery::result::BaseResult;
 use celery::task::Signature;
-use celery::Celery;
-use log::debug;
+use futures::future::{select_ok, BoxFuture};
+use futures::FutureExt;
+use log::{debug, warn};
+use mongodb::bson::oid::ObjectId;
+use tokio::sync::Semaphore;
 
 use crate::apply_middles;
-use crate::configs::CmdProxyClientConf;
-use crate::middles::{invoke, serde, Middle};
-use crate::params::Param;
-use crate::protocol::RunRequest;
-use crate::tasks::run;
+use crate::approval;
+use crate::backpressure::{BackpressurePolicy, Decision};
+use crate::broker::{self, on_app, CeleryApp};
+use crate::catalog::{ArtifactCatalog, ArtifactQuery, CatalogEntry, Producer};
+use crate::configs::{lane_of, CmdProxyClientConf};
+use crate::fsck::{Fsck, FsckOptions, FsckReport};
+use crate::history::{HistoryQuery, TaskHistory, TaskRecord, UsageSummary};
+use crate::metrics::{MetricsSink, RunMetrics, TransferStats};
+use crate::middles::auth::{AuthMiddle, NoAuth};
+use crate::middles::serde::PayloadLimits;
+use crate::middles::{auth, invoke, serde, Middle};
+use crate::outcome::RunOutcome;
+use crate::params::{local_hostname, Param};
+use crate::paths::{to_mirrored_relpath, HostPath};
+use crate::pipeline::{Pipeline, PipelineOutcome};
+use crate::precheck::OutputCheck;
+use crate::protocol::{
+    Artifact, ArtifactStatus, ExitStatus, Provenance, RunRecipe, RunRequest, Stdin,
+};
+use crate::recording::recording_param;
+use crate::registry::{Incompatible, VersionCheck};
+use crate::retry::{
+    classify_error, classify_outcome, BrokerFailed, RetryPolicies, RunOptions, WorkerLost,
+};
+use crate::schedule::{self, ScheduledRun};
+use crate::storage::Storage;
+use crate::streams::{OutputChunk, StreamKind};
 
 pub struct Client {
     conf: CmdProxyClientConf,
-    app: Arc<Celery<RedisBroker, MongoDbBackend>>,
+    app: CeleryApp,
+    auth: Arc<dyn AuthMiddle>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    catalog: Option<Arc<dyn ArtifactCatalog>>,
+    run_dir: Option<PathBuf>,
+    backpressure: Option<BackpressurePolicy>,
+    version_check: VersionCheck,
+    retry: RetryPolicies,
+    payload_limits: PayloadLimits,
+    output_check: Option<OutputCheck>,
+    /// Slots of the submissions held while the broker is unreachable.
+    buffered: Semaphore,
 }
 
 impl Client {
-    pub async fn new(conf: CmdProxyClientConf) -> Client {
-        let app: Arc<Celery<RedisBroker, MongoDbBackend>> = celery::app!(
-            broker = RedisBroker { conf.celery.broker_url.clone() },
-            backend = MongoDbBackend { conf.celery.backend_url.clone() },
-            tasks = [run],
-            task_routes = ["*" => "celery"],
+    pub async fn new(conf: CmdProxyClientConf) -> anyhow::Result<Client> {
+        let app = CeleryApp::client(&conf.celery).await?;
+        let buffered = Semaphore::new(conf.celery.reconnect.buffer);
+        let auth: Arc<dyn AuthMiddle> = match conf.request_signing.clone() {
+            Some(auth) => Arc::new(auth),
+            None => Arc::new(NoAuth),
+        };
+
+        Ok(Client {
+            conf,
+            app,
+            auth,
+            metrics: None,
+            catalog: None,
+            run_dir: None,
+            backpressure: None,
+            version_check: VersionCheck::default(),
+            retry: RetryPolicies::default(),
+            payload_limits: PayloadLimits::default(),
+            output_check: None,
+            buffered,
+        })
+    }
+
+    /// Attach credentials of the given scheme to every request sent by this client.
+    pub fn with_auth(mut self, auth: Arc<dyn AuthMiddle>) -> Client {
+        self.auth = auth;
+        self
+    }
+
+    /// Publish the metrics of every run to the given sink.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> Client {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Register the local outputs of every successful run into the catalog, see
+    /// [`crate::catalog`].
+    pub fn with_catalog(mut self, catalog: Arc<dyn ArtifactCatalog>) -> Client {
+        self.catalog = Some(catalog);
+        self
+    }
+
+    /// Put all the local outputs of each run under a fresh folder in `run_dir` named after the
+    /// run, mirroring the layout of the paths they were requested at.
+    pub fn with_run_dir(mut self, run_dir: PathBuf) -> Client {
+        self.run_dir = Some(run_dir);
+        self
+    }
+
+    /// Hold back the requests to a queue deeper than the watermark of the policy.
+    pub fn with_backpressure(mut self, policy: BackpressurePolicy) -> Client {
+        self.backpressure = Some(policy);
+        self
+    }
+
+    /// Check the protocol versions of the workers serving the queue before submitting to it,
+    /// [`VersionCheck::Warn`] by default.
+    pub fn with_version_check(mut self, version_check: VersionCheck) -> Client {
+        self.version_check = version_check;
+        self
+    }
+
+    /// Retry the failed runs as the policies of their classes of failures say, by default
+    /// [`RetryPolicies::default`], which never retries a command failing by itself.
+    pub fn with_retry(mut self, retry: RetryPolicies) -> Client {
+        self.retry = retry;
+        self
+    }
+
+    /// Refuse the requests and responses larger than the limits, by default
+    /// [`PayloadLimits::default`].
+    pub fn with_payload_limits(mut self, limits: PayloadLimits) -> Client {
+        self.payload_limits = limits;
+        self
+    }
+
+    /// Check that the local outputs of every request can be written before sending it, rather
+    /// than finding out once the remote run is over.
+    pub fn with_output_check(mut self, check: OutputCheck) -> Client {
+        self.output_check = Some(check);
+        self
+    }
+
+    /// Run the request and return how the remote command finished, or fail with
+    /// [`crate::protocol::TimedOut`] if it was killed for taking longer than its timeout.
+    ///
+    /// The run is retried as the policies of the client say, and further as the `options`
+    /// say, such as on the exit codes of a flaky command.
+    ///
+    /// The run is cancel-safe: dropping its future, such as on a timeout of the caller by
+    /// `tokio::select!`, revokes the tasks sent and removes the uploaded inputs in the
+    /// background, as long as the runtime keeps running.
+    pub async fn run(
+        &self,
+        run_request: RunRequest,
+        queue: Option<String>,
+        options: RunOptions,
+    ) -> anyhow::Result<ExitStatus> {
+        let outcome = self
+            .run_with_options(run_request, queue, &|_| {}, &options, None)
+            .await?;
+        outcome.check_timed_out()?;
+        Ok(outcome.status)
+    }
+
+    /// Tasks which are either pending or running, optionally only those in `queue`.
+    pub async fn list_tasks(&self, queue: Option<&str>) -> anyhow::Result<Vec<TaskRecord>> {
+        let queue = queue.map(|queue| self.conf.celery.queue(queue));
+        self.conf
+            .cloud
+            .tasks()
+            .await?
+            .in_flight(queue.as_deref())
+            .await
+    }
+
+    pub async fn inspect_task(&self, task_id: &str) -> anyhow::Result<Option<TaskRecord>> {
+        self.conf.cloud.tasks().await?.get(task_id).await
+    }
+
+    /// Past and in-flight tasks matching the query, latest submitted first.
+    pub async fn history(&self, mut query: HistoryQuery) -> anyhow::Result<Vec<TaskRecord>> {
+        query.queue = query
+            .queue
+            .map(|queue| self.conf.celery.queue(queue.as_str()));
+        self.conf.cloud.tasks().await?.query(&query).await
+    }
+
+    /// Resources used by the runs of each command finished within `since` until now.
+    pub async fn usage_summary(&self, since: Duration) -> anyhow::Result<Vec<UsageSummary>> {
+        self.conf.cloud.tasks().await?.usage_summary(since).await
+    }
+
+    /// Define the recurring run of `name`, fired by the schedulers on the cron expression
+    /// `cron`, replacing the run of the name if any, and return the time it is due first.
+    ///
+    /// The request is stored signed by this client, and refused if taking local params, see
+    /// [`crate::schedule`].
+    pub async fn schedule(
+        &self,
+        name: &str,
+        cron: &str,
+        run_request: &RunRequest,
+        queue: Option<String>,
+    ) -> anyhow::Result<mongodb::bson::DateTime> {
+        schedule::check_cloud_only(run_request)?;
+        let request = auth::client_end::MiddleImpl::stored(self.auth.clone())
+            .transform_request(serde_json::to_string(run_request)?)
+            .await?;
+        let schedules = self.conf.cloud.schedules().await?;
+        schedules.put(name, cron, request, queue).await
+    }
+
+    /// The request of the recurring run, verified to be signed to be stored under the secret
+    /// of the requests this client sends.
+    pub(crate) async fn scheduled_request(&self, run: &ScheduledRun) -> anyhow::Result<RunRequest> {
+        let request = auth::server_end::MiddleImpl::stored(self.auth.clone())
+            .transform_request(run.request.clone())
+            .await?;
+        let request = serde_json::from_str(request.as_str())?;
+        schedule::check_cloud_only(&request)?;
+        Ok(request)
+    }
+
+    /// Remove the recurring run of `name`, returning false if there was none.
+    pub async fn unschedule(&self, name: &str) -> anyhow::Result<bool> {
+        self.conf.cloud.schedules().await?.remove(name).await
+    }
+
+    /// The recurring runs, the soonest due first.
+    pub async fn list_schedules(&self) -> anyhow::Result<Vec<ScheduledRun>> {
+        self.conf.cloud.schedules().await?.list().await
+    }
+
+    /// Total size of the files on the cloud, including those left by past runs.
+    pub async fn storage_usage(&self) -> anyhow::Result<u64> {
+        self.conf.cloud.storage_size().await
+    }
+
+    /// Which run produced the output at the local path `filepath`, if any.
+    pub async fn provenance(&self, filepath: &str) -> anyhow::Result<Option<Provenance>> {
+        let client_id = self.conf.client_id.as_str();
+        // tagged by the hostname template, the url of the output varies by the run
+        let mut hostnames = self.conf.cloud.tasks().await?.hostnames(client_id).await?;
+        if !hostnames.iter().any(|hostname| hostname == client_id) {
+            hostnames.push(client_id.to_owned());
+        }
+        let storage = self.conf.cloud.storage().await?;
+        find_provenance(storage, filepath, hostnames.as_slice()).await
+    }
+
+    /// The artifacts registered into the catalog of the deployment matching the `query`.
+    pub async fn find_artifacts(&self, query: &ArtifactQuery) -> anyhow::Result<Vec<CatalogEntry>> {
+        self.conf.cloud.artifacts().await?.find(query).await
+    }
+
+    /// Scan the storage for junk and broken references, and fix them as the options say.
+    ///
+    /// Only the GridFS storage can be scanned, as the others have no chunks to check.
+    pub async fn fsck(&self, options: &FsckOptions) -> anyhow::Result<FsckReport> {
+        anyhow::ensure!(
+            self.conf.cloud.is_grid_fs(),
+            "Cannot check a storage other than the GridFS"
+        );
+        Fsck {
+            bucket: self.conf.cloud.grid_fs().await?,
+            files: self.conf.cloud.bucket_files().await?,
+            chunks: self.conf.cloud.bucket_chunks().await?,
+            history: self.conf.cloud.tasks().await?,
+        }
+        .run(options)
+        .await
+    }
+
+    /// Cancel a task: a pending one will be skipped, and a running one will be killed.
+    ///
+    /// Return false if there is no such unfinished task.
+    pub async fn cancel_task(&self, task_id: &str) -> anyhow::Result<bool> {
+        self.conf.cloud.tasks().await?.revoke(task_id).await
+    }
+
+    /// Approve a parked task gated by an approval on behalf of this client as an operator,
+    /// signed by the secret of the operators, see [`crate::approval`].
+    ///
+    /// Return false if there is no such parked task submitted by another client.
+    pub async fn approve_task(&self, task_id: &str) -> anyhow::Result<bool> {
+        let auth = self.conf.approval_signing.as_ref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Approving takes the secret of the operators, see --approval-secret-file"
+            )
+        })?;
+        let operator = self.conf.client_id.as_str();
+        let approval = approval::sign(auth, task_id, operator).await?;
+        self.conf
+            .cloud
+            .tasks()
+            .await?
+            .approve(task_id, operator, approval.as_str())
+            .await
+    }
+
+    /// Download the recording of the task into the folder `bundle`, for replaying it by
+    /// [`crate::recording::replay`], see [`crate::recording`].
+    pub async fn fetch_recording(&self, task_id: &str, bundle: &Path) -> anyhow::Result<()> {
+        let param = recording_param(task_id);
+        let storage = self.conf.cloud.storage().await?;
+        anyhow::ensure!(
+            param.exists_on_cloud(storage.clone()).await?,
+            "Task {} has no recording",
+            task_id
+        );
+        param.download(storage, bundle).await
+    }
+
+    /// Output of the task published after the chunk `seq`, or from the beginning if `None`.
+    pub async fn task_output(
+        &self,
+        task_id: &str,
+        kind: StreamKind,
+        seq: Option<i64>,
+    ) -> anyhow::Result<Vec<OutputChunk>> {
+        self.conf
+            .cloud
+            .outputs()
+            .await?
+            .after(task_id, kind, seq)
+            .await
+    }
+
+    /// Same as [`Client::run`], but report everything known about the run.
+    pub async fn run_outcome(
+        &self,
+        run_request: RunRequest,
+        queue: Option<String>,
+    ) -> anyhow::Result<RunOutcome> {
+        self.run_watched(run_request, queue, &|_| {}).await
+    }
+
+    /// Resolve the request on a worker as if to run it, downloading the inputs there, and
+    /// return the recipe resolved, with the commands, the args, the env and the temp paths,
+    /// instead of running it. No outputs are produced, hence none are downloaded.
+    pub async fn dry_run(
+        &self,
+        mut run_request: RunRequest,
+        queue: Option<String>,
+    ) -> anyhow::Result<RunRecipe> {
+        run_request.dry_run = true;
+        let outcome = self.run_outcome(run_request, queue).await?;
+        outcome
+            .recipe
+            .ok_or_else(|| anyhow!("Dry run came back without a recipe: {}", outcome.status))
+    }
+
+    /// Same as [`Client::run_outcome`], but call `on_submitted` with the id of the task once
+    /// it has been sent, so that the task can be followed or cancelled while running.
+    pub async fn run_watched(
+        &self,
+        run_request: RunRequest,
+        queue: Option<String>,
+        on_submitted: &(dyn Fn(&str) + Sync),
+    ) -> anyhow::Result<RunOutcome> {
+        self.run_with_options(
+            run_request,
+            queue,
+            on_submitted,
+            &RunOptions::default(),
+            None,
         )
         .await
-        .unwrap();
+    }
+
+    /// Same as [`Client::run_outcome`], but have the run start no earlier than `eta`, such as
+    /// in the off-peak hours of the workers.
+    ///
+    /// The inputs are uploaded at once, while the outputs are downloaded only once the run
+    /// completes, hence the client waits for the run till then. A run retried after `eta`
+    /// starts at once.
+    pub async fn run_at(
+        &self,
+        run_request: RunRequest,
+        queue: Option<String>,
+        eta: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<RunOutcome> {
+        self.run_with_options(
+            run_request,
+            queue,
+            &|_| {},
+            &RunOptions::default(),
+            Some(eta),
+        )
+        .await
+    }
 
-        Client { conf, app }
+    /// Same as [`Client::run_at`], but have the run start no earlier than `delay` from now.
+    pub async fn run_after(
+        &self,
+        run_request: RunRequest,
+        queue: Option<String>,
+        delay: Duration,
+    ) -> anyhow::Result<RunOutcome> {
+        let eta = chrono::Utc::now() + chrono::Duration::from_std(delay)?;
+        self.run_at(run_request, queue, eta).await
     }
 
-    pub async fn run(&self, run_request: RunRequest, queue: Option<String>) -> anyhow::Result<i32> {
+    async fn run_with_options(
+        &self,
+        run_request: RunRequest,
+        queue: Option<String>,
+        on_submitted: &(dyn Fn(&str) + Sync),
+        options: &RunOptions,
+        eta: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<RunOutcome> {
+        let started_at = Instant::now();
         let queue = match &run_request.command {
             Param::CmdNameParam { name } => queue.unwrap_or_else(|| name.clone()),
             Param::CmdPathParam { .. } => queue.ok_or_else(|| {
@@ -49,22 +426,796 @@ impl Client {
             }
         };
 
-        let app = self.app.clone();
-        let bucket = self.conf.cloud.grid_fs().await;
+        let queue = self.conf.celery.queue(queue.as_str());
+        self.submit(
+            run_request,
+            vec![queue],
+            started_at,
+            on_submitted,
+            options,
+            eta,
+        )
+        .await
+    }
+
+    /// Run the steps of the `pipeline` in the order of their dependencies, passing the
+    /// intermediate files between them through the storage, see [`crate::pipeline`].
+    pub async fn run_pipeline(&self, pipeline: Pipeline) -> anyhow::Result<PipelineOutcome> {
+        let storage = self.conf.cloud.storage().await?;
+        pipeline.run(self, storage).await
+    }
+
+    /// Send the same request to all the `queues` at once, and take the first successful
+    /// response, which masks slow or flaky workers at the cost of the duplicated work.
+    ///
+    /// The other runs are cancelled, and their outputs are discarded instead of overwriting
+    /// those of the winner.
+    pub async fn run_speculative(
+        &self,
+        run_request: RunRequest,
+        queues: Vec<String>,
+    ) -> anyhow::Result<RunOutcome> {
+        let started_at = Instant::now();
+        anyhow::ensure!(!queues.is_empty(), "No queues to send the request to");
+        let queues = queues
+            .iter()
+            .map(|queue| self.conf.celery.queue(queue.as_str()))
+            .collect();
+        self.submit(
+            run_request,
+            queues,
+            started_at,
+            &|_| {},
+            &RunOptions::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Resubmit a past request recorded in the history.
+    ///
+    /// Inputs which are still on the cloud are reused as they are, and missing ones are
+    /// uploaded again if they can be found on this host. Outputs are downloaded to where they
+    /// were requested, if they were requested from this host.
+    pub async fn rerun(&self, task_id: &str) -> anyhow::Result<RunOutcome> {
+        let started_at = Instant::now();
+        let record = self
+            .inspect_task(task_id)
+            .await?
+            .ok_or_else(|| anyhow!("No such task: {}", task_id))?;
+        let (request, queue) = record
+            .run_request()?
+            .zip(record.queue)
+            .ok_or_else(|| anyhow!("Request of task {} has not been recorded", task_id))?;
+
+        // the local files are those of this client, tagged by the hostname it recorded
+        let client_id = self.conf.client_id.as_str();
+        let hostname = match (&record.client, &record.hostname) {
+            (Some(client), Some(hostname)) if client == client_id => hostname.clone(),
+            _ => client_id.to_owned(),
+        };
+        let storage = self.conf.cloud.storage().await?;
+        let restore = |param| restore_param(storage.clone(), hostname.as_str(), param);
+        let mut env = None;
+        if let Some(recorded_env) = request.env {
+            let mut restored_env = HashMap::new();
+            for (key, param) in recorded_env {
+                restored_env.insert(key, restore(param).await?);
+            }
+            env = Some(restored_env);
+        }
+        let mut args = vec![];
+        for param in request.args {
+            args.push(restore(param).await?);
+        }
+        let request = RunRequest {
+            command: restore(request.command).await?,
+            args,
+            cwd: request.cwd,
+            env,
+            stdin: match request.stdin {
+                Some(Stdin::File(param)) => Some(Stdin::File(restore(param).await?)),
+                stdin => stdin,
+            },
+            stdout: match request.stdout {
+                Some(param) => Some(restore(param).await?),
+                None => None,
+            },
+            stderr: match request.stderr {
+                Some(param) => Some(restore(param).await?),
+                None => None,
+            },
+            priority: request.priority,
+            timeout: request.timeout,
+            precheck: None,
+            labels: request.labels,
+            input_bytes: request.input_bytes,
+            input_sizes: request.input_sizes,
+            archive: request.archive,
+            preserve: request.preserve,
+            input_attrs: request.input_attrs,
+            enqueued_at: None,
+            limits: request.limits,
+            features: vec![],
+            required_features: vec![],
+            delta: request.delta,
+            output_digests: HashMap::new(),
+            compression: request.compression,
+            encryption_keys: request.encryption_keys,
+            dry_run: false,
+        };
+
+        debug!("Rerun task {} as:\n{:#?}", task_id, request);
+        self.submit(
+            request,
+            vec![queue],
+            started_at,
+            &|_| {},
+            &RunOptions::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Download the outputs of a finished run again, such as the ones failed to be
+    /// downloaded by the run itself.
+    ///
+    /// Only the outputs in `targets` are fetched, each to the path it maps to, or all the
+    /// outputs to where they were requested if `targets` is empty. The outputs which are no
+    /// longer on the cloud, e.g. fetched already, are reported as skipped.
+    pub async fn fetch_outputs(
+        &self,
+        source: OutputSource,
+        targets: &HashMap<String, PathBuf>,
+    ) -> anyhow::Result<Vec<Artifact>> {
+        let outputs = match source {
+            OutputSource::Manifest(artifacts) => artifacts,
+            OutputSource::Task(task_id) => {
+                let record = self
+                    .inspect_task(task_id.as_str())
+                    .await?
+                    .ok_or_else(|| anyhow!("No such task: {}", task_id))?;
+                let mut outputs = vec![];
+                if let Some(request) = record.run_request()? {
+                    request.map_params(|param| collect_outputs(param, &mut outputs));
+                }
+                outputs.extend(record.artifacts);
+                outputs
+            }
+        };
+
+        let storage = self.conf.cloud.storage().await?;
+        let mut fetched = vec![];
+        for mut output in outputs {
+            let filepath = match targets.get(&output.cloud_url) {
+                Some(filepath) => filepath.clone(),
+                None if targets.is_empty() => {
+                    match Param::from_cloud_url(output.cloud_url.as_str()) {
+                        Some(param) => PathBuf::from(param.filepath()),
+                        None => continue,
+                    }
+                }
+                None => continue,
+            };
+            output.status = fetch_output(storage.clone(), output.cloud_url.as_str(), &filepath)
+                .await
+                .unwrap_or_else(|err| ArtifactStatus::Failed {
+                    cause: format!("failed to download: {}", err),
+                });
+            fetched.push(output);
+        }
+        Ok(fetched)
+    }
+
+    /// Wait until `queue` is shallow enough to take one more request, as the policy says.
+    async fn throttle(&self, queue: &str) -> anyhow::Result<()> {
+        let policy = match self.backpressure {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+
+        let history = self.conf.cloud.tasks().await?;
+        let started_at = Instant::now();
+        loop {
+            let depth = history.depth(queue).await?;
+            match policy.decide(queue, depth, started_at.elapsed()) {
+                Decision::Submit => return Ok(()),
+                Decision::Wait(backpressure, poll_interval) => {
+                    debug!("{}, wait...", backpressure);
+                    tokio::time::sleep(poll_interval).await;
+                }
+                Decision::Refuse(backpressure) => return Err(backpressure.into()),
+            }
+        }
+    }
+
+    /// Make sure the workers serving `queue` speak the same protocol, as the policy says.
+    async fn check_version(&self, queue: &str) -> anyhow::Result<()> {
+        if self.version_check == VersionCheck::Ignore {
+            return Ok(());
+        }
+
+        let workers = self.conf.cloud.workers().await?.serving(queue).await?;
+        let incompatible = match Incompatible::among(queue, workers) {
+            Some(incompatible) => incompatible,
+            None => return Ok(()),
+        };
+        match self.version_check {
+            VersionCheck::Refuse => Err(incompatible.into()),
+            _ => {
+                warn!("{}", incompatible);
+                Ok(())
+            }
+        }
+    }
+
+    async fn submit(
+        &self,
+        mut run_request: RunRequest,
+        queues: Vec<String>,
+        started_at: Instant,
+        on_submitted: &(dyn Fn(&str) + Sync),
+        options: &RunOptions,
+        eta: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<RunOutcome> {
+        // fail fast on an obviously invalid request, before taking any shared resource
+        if let Some(precheck) = run_request.precheck.take() {
+            debug!("Precheck the request by {}...", precheck.command);
+            precheck.run().await?;
+        }
+
+        let mut retries = 0;
+        loop {
+            let res = self
+                .submit_once(
+                    run_request.clone(),
+                    &queues,
+                    started_at,
+                    retries,
+                    on_submitted,
+                    eta,
+                )
+                .await;
+            let class = match &res {
+                Ok(outcome) => classify_outcome(outcome),
+                Err(err) => Some(classify_error(err)),
+            };
+            let backoff =
+                class.and_then(|class| options.policy(&res, class, &self.retry).backoff(retries));
+            match (class, backoff) {
+                (Some(class), Some(backoff)) => {
+                    warn!("Run failed by {}, retry in {:?}...", class, backoff);
+                    tokio::time::sleep(backoff).await;
+                    retries += 1;
+                }
+                _ => return res,
+            }
+        }
+    }
+
+    /// Send the serialized run to the `lane`, and return its task id with the future of its
+    /// result.
+    ///
+    /// A send failing as the broker blips is retried after reconnecting, by the policy of
+    /// the conf, and once out of retries, held for as long as the broker is unreachable if
+    /// the buffer of the policy has room.
+    async fn send_run(
+        &self,
+        serialized: &str,
+        lane: &str,
+        eta: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(String, BoxFuture<'static, Result<String, String>>), String> {
+        let policy = self.conf.celery.reconnect;
+        let mut buffered = None;
+        let mut retries = 0;
+        loop {
+            let mut sig: Signature<_> =
+                broker::run_signature(&self.conf.celery, serialized.to_owned()).with_queue(lane);
+            if let Some(eta) = eta {
+                sig = sig.with_eta(eta);
+            }
+            // the results differ in type by the brokers, hence are waited for in place
+            let sent = on_app!(&self.app, |app| {
+                match app.send_task(sig).await {
+                    Ok(async_result) => {
+                        let task_id = async_result.task_id.clone();
+                        let result = async move {
+                            match async_result.wait(None).await {
+                                Ok(res) => res.map_err(|err| err.to_string()),
+                                Err(err) => Err(err.to_string()),
+                            }
+                        };
+                        Ok((task_id, result.boxed()))
+                    }
+                    Err(err) => Err(err.to_string()),
+                }
+            });
+            let cause = match sent {
+                Ok(sent) => return Ok(sent),
+                Err(cause) => cause,
+            };
+            if retries >= policy.max_retries && buffered.is_none() {
+                buffered = Some(self.buffered.try_acquire().map_err(|_| cause.clone())?);
+                warn!("Hold the run until the broker comes back...");
+            }
+            let backoff = policy.backoff(retries);
+            warn!(
+                "Failed to send to {}, reconnect in {:?}: {}",
+                lane, backoff, cause
+            );
+            tokio::time::sleep(backoff).await;
+            if let Err(err) = self.app.reconnect(policy.connection_timeout).await {
+                debug!("Failed to reconnect to the broker: {}", err);
+            }
+            retries += 1;
+        }
+    }
+
+    async fn submit_once(
+        &self,
+        run_request: RunRequest,
+        queues: &[String],
+        started_at: Instant,
+        retries: u32,
+        on_submitted: &(dyn Fn(&str) + Sync),
+        eta: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<RunOutcome> {
+        for queue in queues {
+            self.check_version(queue.as_str()).await?;
+            self.throttle(queue.as_str()).await?;
+        }
+
+        let run_dir = self
+            .run_dir
+            .as_ref()
+            .map(|run_dir| run_dir.join(ObjectId::new().to_hex()));
+        let run_request = match &run_dir {
+            Some(run_dir) => relocate_outputs(run_request, run_dir)?,
+            None => run_request,
+        };
+        if let Some(check) = &self.output_check {
+            check.run(run_request.local_paths(Param::is_output).as_slice())?;
+        }
+        // tag the local files with the identity of the client instead of the transient hostname
+        let hostname = local_hostname();
+        let tagged = self
+            .conf
+            .hostname(command_name(&run_request.command).as_str());
+        let run_request =
+            run_request.map_params(|param| param.with_hostname(hostname.as_str(), tagged.as_str()));
+
+        let storage = self.conf.cloud.storage().await?;
+        let stats = Arc::new(TransferStats::default());
+        let remote = Mutex::new(None);
+        let history = self.conf.cloud.tasks().await?;
+        let priority = run_request.priority;
+        let timeout = run_request.timeout;
+        let produced = self.catalog.as_ref().map(|catalog| {
+            let producer = Producer {
+                hostname: tagged.clone(),
+                task_id: String::new(),
+                command: command_name(&run_request.command),
+                labels: run_request.labels.clone(),
+            };
+            (catalog, producer, run_request.local_paths(Param::is_output))
+        });
 
         let proxy_run = |serialized: String| async {
-            debug!("Sending RunRequest to queue `{queue}'...");
+            let submitted_at = Instant::now();
+            let mut waits = vec![];
+            let mut in_flight = InFlight {
+                history: history.clone(),
+                task_ids: vec![],
+            };
+            for queue in queues {
+                debug!("Sending RunRequest to queue `{queue}'...");
+
+                let lane = lane_of(queue.as_str(), priority);
+                let sent = self.send_run(serialized.as_str(), lane.as_str(), eta).await;
+                let (task_id, result) = sent.map_err(|cause| BrokerFailed {
+                    queue: queue.clone(),
+                    cause,
+                })?;
+                on_submitted(task_id.as_str());
+                history
+                    .submitted(
+                        task_id.as_str(),
+                        queue.as_str(),
+                        serialized.as_str(),
+                        self.conf.client_id.as_str(),
+                        tagged.as_str(),
+                        eta,
+                    )
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to record the submission: {}", err));
 
-            let sig: Signature<_> = run::new(serialized).with_queue(queue.as_str());
-            Ok(app.send_task(sig).await.unwrap().wait(None).await??)
+                in_flight.task_ids.push(task_id.clone());
+                waits.push(Box::pin(async move {
+                    match result.await {
+                        Ok(serialized) => Ok((queue.clone(), task_id, serialized)),
+                        Err(cause) => Err(anyhow::Error::from(WorkerLost { task_id, cause })),
+                    }
+                }));
+            }
+
+            // the first successful response wins, and the others are cancelled
+            let winner = select_ok(waits).await;
+            let task_ids = std::mem::take(&mut in_flight.task_ids);
+            let ((winner_queue, winner_id, serialized), _) = winner?;
+            for task_id in task_ids.iter().filter(|task_id| **task_id != winner_id) {
+                debug!("Cancel task {} outrun by task {}...", task_id, winner_id);
+                history
+                    .revoke(task_id.as_str())
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to cancel task {}: {}", task_id, err));
+            }
+            *remote.lock().unwrap() = Some((submitted_at, Instant::now(), winner_queue, winner_id));
+            Ok(serialized)
         };
 
+        // signed on behalf of this client, which the worker tells the submitter by
+        let signer = self.conf.client_id.clone();
         let res = apply_middles!(
             run_request,
-            >=< [ invoke::client_end::MiddleImpl::new(bucket) ]
-            >=< [ serde::client_end::MiddleImpl::new() ]
+            >=< [ invoke::client_end::MiddleImpl::with_stats(storage.clone(), stats.clone()) ]
+            >=< [ serde::client_end::MiddleImpl::new(storage, self.payload_limits, stats.clone()) ]
+            >=< [ auth::client_end::MiddleImpl::new(self.auth.clone()).signed_by(signer) ]
             >>= proxy_run
         );
-        res.map(|r| r.return_code)
+
+        let finished_at = Instant::now();
+        let (submitted_at, completed_at, queue, task_id) = remote
+            .into_inner()
+            .unwrap()
+            .unwrap_or_else(|| (finished_at, finished_at, queues.join(","), String::new()));
+        let timing = res.as_ref().ok().and_then(|response| response.timing);
+        let metrics = RunMetrics {
+            queue,
+            prepare: submitted_at - started_at,
+            remote: completed_at - submitted_at,
+            finalize: finished_at - completed_at,
+            queue_wait: timing.and_then(|timing| timing.queue_wait()),
+            execution: timing.map(|timing| timing.execution()),
+            total: finished_at - started_at,
+            uploaded_bytes: stats.uploaded_bytes(),
+            downloaded_bytes: stats.downloaded_bytes(),
+            request_bytes: stats.request_bytes(),
+            response_bytes: stats.response_bytes(),
+            // the command run again by the worker is retried as well as the run by the client
+            retries: retries + res.as_ref().map_or(0, |response| response.retries),
+            failed: !matches!(&res, Ok(response) if response.status.success()),
+        };
+        if let Some(sink) = self.metrics.as_ref() {
+            sink.record_run(&metrics);
+        }
+        if let (Some((catalog, producer, outputs)), false) = (produced, metrics.failed) {
+            let producer = Producer {
+                task_id,
+                ..producer
+            };
+            let registered = match producer.entries(outputs.as_slice()).await {
+                Ok(entries) => catalog.register(entries.as_slice()).await,
+                Err(err) => Err(err),
+            };
+            registered.unwrap_or_else(|err| warn!("Failed to register the artifacts: {}", err));
+        }
+
+        res.map(|response| RunOutcome {
+            status: response.status,
+            artifacts: response.artifacts,
+            cancellation: response.cancellation,
+            warnings: response.warnings,
+            postmortem: response.postmortem,
+            limit_exceeded: response.limit_exceeded,
+            recording_url: response.recording_url,
+            recipe: response.recipe,
+            timeout,
+            metrics,
+            run_dir,
+        })
+    }
+}
+
+/// The tasks sent for a run and not answered yet, revoked if the run is dropped meanwhile.
+struct InFlight {
+    history: TaskHistory,
+    task_ids: Vec<String>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.task_ids.is_empty() {
+            return;
+        }
+        let (history, task_ids) = (self.history.clone(), std::mem::take(&mut self.task_ids));
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    for task_id in task_ids {
+                        debug!("Cancel task {} of the abandoned run...", task_id);
+                        history
+                            .revoke(task_id.as_str())
+                            .await
+                            .unwrap_or_else(|err| {
+                                warn!("Failed to cancel task {}: {}", task_id, err)
+                            });
+                    }
+                });
+            }
+            Err(_) => warn!("Failed to cancel tasks {}: no runtime", task_ids.join(", ")),
+        }
+    }
+}
+
+/// Where the outputs of a finished run are listed.
+#[derive(Debug, Clone)]
+pub enum OutputSource {
+    /// The artifacts reported in the outcome of the run.
+    Manifest(Vec<Artifact>),
+    /// The outputs recorded in the history of the task.
+    Task(String),
+}
+
+fn collect_outputs(param: Param, outputs: &mut Vec<Artifact>) {
+    match param {
+        Param::FormatParam { args, .. } => {
+            for arg in args.into_values() {
+                collect_outputs(arg, outputs);
+            }
+        }
+        // the matches of a glob are collected by the worker as artifacts instead
+        param @ (Param::OutCloudFileParam { .. } | Param::OutCloudDirParam { .. }) => {
+            let filepath = HostPath::parse(param.filepath());
+            outputs.push(Artifact {
+                cloud_url: param.cloud_url(),
+                relpath: filepath.file_name().unwrap_or_default().to_owned(),
+                status: ArtifactStatus::Ok,
+            });
+        }
+        _ => {}
+    }
+}
+
+/// Put the local outputs of the request under `run_dir`, mirroring their paths, and make the
+/// folders they go to before anything is submitted.
+fn relocate_outputs(run_request: RunRequest, run_dir: &Path) -> anyhow::Result<RunRequest> {
+    let mut relocated = HashMap::new();
+    for path in run_request.local_paths(Param::is_output) {
+        let filepath = path.to_string_lossy().into_owned();
+        let target = run_dir.join(to_mirrored_relpath(filepath.as_str())?);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create output folder {}", parent.display()))?;
+        }
+        let target = target
+            .to_str()
+            .with_context(|| format!("Output path {} is not valid unicode", target.display()))?
+            .to_owned();
+        relocated.insert(filepath, target);
+    }
+
+    let relocate = |filepath: &str| {
+        relocated
+            .get(filepath)
+            .cloned()
+            .unwrap_or_else(|| filepath.to_owned())
+    };
+    let mut run_request = run_request.map_params(|param| param.relocate_outputs(&relocate));
+    // the keys picked for the outputs follow them
+    run_request.encryption_keys = std::mem::take(&mut run_request.encryption_keys)
+        .into_iter()
+        .map(|(filepath, key)| (relocate(filepath.as_str()), key))
+        .collect();
+    Ok(run_request)
+}
+
+/// Name of the command of a run, as the file name of its path if given by the path.
+fn command_name(command: &Param) -> String {
+    match command {
+        Param::CmdNameParam { name } => name.clone(),
+        Param::CmdPathParam { path } => HostPath::parse(path)
+            .file_name()
+            .unwrap_or(path.as_str())
+            .to_owned(),
+        _ => String::new(),
+    }
+}
+
+async fn fetch_output(
+    storage: Storage,
+    cloud_url: &str,
+    filepath: &Path,
+) -> anyhow::Result<ArtifactStatus> {
+    let param = Param::from_cloud_url(cloud_url)
+        .ok_or_else(|| anyhow!("Malformed cloud url: {}", cloud_url))?;
+    // an output shared in place is the very file of the user rather than a copy on the store,
+    // which a download would overwrite by itself and a removal would delete
+    if param.is_shared() {
+        return Ok(ArtifactStatus::Skipped {
+            reason: "shared in place".to_owned(),
+        });
+    }
+    if !param.exists_on_cloud(storage.clone()).await? {
+        return Ok(ArtifactStatus::Skipped {
+            reason: "not on the cloud".to_owned(),
+        });
+    }
+
+    debug!("Fetch output {} to {}...", cloud_url, filepath.display());
+    if let Some(parent) = filepath.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    param.download(storage.clone(), filepath).await?;
+    param.remove_from_cloud(storage).await.unwrap_or_default();
+    Ok(ArtifactStatus::Ok)
+}
+
+/// Turn a recorded param back into one sendable from this client, whose local files are
+/// those tagged by `hostname`.
+fn restore_param(
+    storage: Storage,
+    hostname: &str,
+    param: Param,
+) -> BoxFuture<'_, anyhow::Result<Param>> {
+    async move {
+        Ok(match param {
+            Param::FormatParam { tmpl, args } => {
+                let mut restored_args = HashMap::new();
+                for (key, arg) in args {
+                    restored_args.insert(key, restore_param(storage.clone(), hostname, arg).await?);
+                }
+                Param::FormatParam {
+                    tmpl,
+                    args: restored_args,
+                }
+            }
+            param @ (Param::InCloudFileParam { .. } | Param::InCloudDirParam { .. }) => {
+                let local = param.as_local();
+                if param.exists_on_cloud(storage).await? {
+                    param
+                } else if param.hostname() == hostname && Path::new(param.filepath()).exists() {
+                    local
+                } else {
+                    anyhow::bail!(
+                        "Input {} is neither on the cloud nor local",
+                        param.cloud_url()
+                    )
+                }
+            }
+            // the matched files are uploaded again, as they were removed after the run
+            param @ Param::InCloudGlobParam { .. } if param.hostname() == hostname => {
+                param.as_local()
+            }
+            param if param.is_output() && param.is_cloud() && param.hostname() == hostname => {
+                param.as_local()
+            }
+            param => param,
+        })
+    }
+    .boxed()
+}
+
+/// Which run produced the output at the local path `filepath`, looked up under each of the
+/// `hostnames` it may be tagged by, in order.
+async fn find_provenance(
+    storage: Storage,
+    filepath: &str,
+    hostnames: &[String],
+) -> anyhow::Result<Option<Provenance>> {
+    let local = Param::opath(filepath);
+    for hostname in hostnames {
+        let param = local
+            .clone()
+            .with_hostname(local_hostname().as_str(), hostname.as_str())
+            .as_cloud();
+        if param.exists_on_cloud(storage.clone()).await? {
+            return param.provenance(storage).await;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::archive::ArchiveFormat;
+    use crate::naming;
+    use crate::storage::SharedFsStorage;
+
+    use super::*;
+
+    const TEMPLATE: &str = "{namespace}/{command}/{date}/{client}";
+
+    fn tagged(param: Param, hostname: &str) -> Param {
+        param
+            .with_hostname(local_hostname().as_str(), hostname)
+            .as_cloud()
+    }
+
+    #[tokio::test]
+    async fn test_restore_by_templated_hostname() {
+        let root = tempdir().unwrap();
+        let storage: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let workdir = tempdir().unwrap();
+        let input = workdir.path().join("in.txt");
+        std::fs::write(&input, "in").unwrap();
+        let input = input.to_str().unwrap();
+        let output = workdir.path().join("out.txt");
+        let output = output.to_str().unwrap();
+        let hostname = naming::hostname(TEMPLATE, "ci", "client", "cat", chrono::Utc::now());
+
+        // the input no longer on the cloud is uploaded again from this host
+        let restored = restore_param(
+            storage.clone(),
+            hostname.as_str(),
+            tagged(Param::ipath(input), hostname.as_str()),
+        )
+        .await
+        .unwrap();
+        assert!(restored.is_local());
+        assert_eq!(restored.filepath(), input);
+
+        // the output is downloaded to where it was requested
+        let restored = restore_param(
+            storage.clone(),
+            hostname.as_str(),
+            tagged(Param::opath(output), hostname.as_str()),
+        )
+        .await
+        .unwrap();
+        assert!(restored.is_local() && restored.is_output());
+
+        // the files tagged otherwise are not those of this client
+        let param = tagged(Param::ipath(input), hostname.as_str());
+        assert!(restore_param(storage.clone(), "client", param)
+            .await
+            .is_err());
+        let param = tagged(Param::opath(output), hostname.as_str());
+        let restored = restore_param(storage.clone(), "client", param)
+            .await
+            .unwrap();
+        assert!(restored.is_cloud());
+    }
+
+    #[tokio::test]
+    async fn test_find_provenance_by_templated_hostname() {
+        let root = tempdir().unwrap();
+        let storage: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let workdir = tempdir().unwrap();
+        let output = workdir.path().join("out.txt");
+        std::fs::write(&output, "out").unwrap();
+        let output = output.to_str().unwrap();
+        let hostname = naming::hostname(TEMPLATE, "ci", "client", "cat", chrono::Utc::now());
+
+        let provenance = Provenance {
+            task_id: "task".to_owned(),
+            command: "cat".to_owned(),
+            worker: "worker".to_owned(),
+        };
+        let param = tagged(Param::opath(output), hostname.as_str());
+        let staged_url = param
+            .upload_staged(
+                storage.clone(),
+                output,
+                "a",
+                Some(&provenance),
+                ArchiveFormat::default(),
+            )
+            .await
+            .unwrap();
+        param
+            .commit_staged(storage.clone(), staged_url.as_str(), "a")
+            .await
+            .unwrap();
+
+        // never found under the client id alone
+        let hostnames = vec!["client".to_owned()];
+        let found = find_provenance(storage.clone(), output, hostnames.as_slice()).await;
+        assert_eq!(found.unwrap(), None);
+
+        let hostnames = vec!["client".to_owned(), hostname];
+        let found = find_provenance(storage.clone(), output, hostnames.as_slice()).await;
+        assert_eq!(found.unwrap(), Some(provenance));
     }
 }
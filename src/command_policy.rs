@@ -0,0 +1,176 @@
+//! Policy of the commands a worker runs by their paths, for the workers serving the clients
+//! which are trusted with some of the binaries of the host but not all of them.
+//!
+//! The commands named in the palette are up to the operator already, hence the policy applies
+//! to any other command as resolved on the worker, whichever param gives it, such as a path,
+//! a string, a format or an uploaded binary. A command is allowed if it is an absolute path
+//! under any entry of the allowlist, or if the allowlist is empty, and it is not under any
+//! entry of the denylist. The entries match whole components, so that `/usr/bin` allows
+//! `/usr/bin/gcc` but not `/usr/bin2/gcc`, and the paths climbing up by `..` are never
+//! allowed. A request outside the policy is refused with [`CommandNotAllowed`] once its
+//! command is resolved, before any other param is.
+//!
+//! Since an allowed binary runs whatever the loader or the shell is told to load, a request
+//! to a worker with a policy is also refused with [`EnvNotAllowed`] for setting any variable
+//! of [`is_hijacking_env`], such as `LD_PRELOAD` or `PATH`, whichever its command is.
+
+use std::fmt;
+use std::path::{Component, Path};
+
+use serde::{Deserialize, Serialize};
+
+/// Which commands may be run by their paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandPolicy {
+    /// Paths, or prefixes of the paths, of the commands allowed, or any if empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Paths, or prefixes of the paths, of the commands denied even if allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Error of a request refused for running a command outside the policy of the worker.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandNotAllowed {
+    pub command: String,
+}
+
+impl fmt::Display for CommandNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Command {} is not allowed on this worker", self.command)
+    }
+}
+
+impl std::error::Error for CommandNotAllowed {}
+
+/// Error of a request refused for setting an env var which could hijack the command.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvNotAllowed {
+    pub key: String,
+}
+
+impl fmt::Display for EnvNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Env {} is not allowed on this worker", self.key)
+    }
+}
+
+impl std::error::Error for EnvNotAllowed {}
+
+/// Prefixes of the env vars read by the dynamic loaders, on Linux and on macOS.
+const LOADER_ENV_PREFIXES: &[&str] = &["LD_", "DYLD_"];
+
+/// Env vars making the shells or the interpreters look up or load other code.
+const LOOKUP_ENV: &[&str] = &[
+    "PATH",
+    "IFS",
+    "BASH_ENV",
+    "ENV",
+    "GCONV_PATH",
+    "PYTHONPATH",
+    "PYTHONSTARTUP",
+    "PYTHONHOME",
+    "PERL5LIB",
+    "PERL5OPT",
+    "RUBYLIB",
+    "RUBYOPT",
+    "NODE_OPTIONS",
+    "NODE_PATH",
+];
+
+/// Whether the env var `key` changes what code is loaded along with the command.
+pub fn is_hijacking_env(key: &str) -> bool {
+    LOADER_ENV_PREFIXES
+        .iter()
+        .any(|prefix| key.starts_with(prefix))
+        || LOOKUP_ENV.contains(&key)
+}
+
+impl CommandPolicy {
+    /// Check if the command resolved to `path` may be run, which is never if not absolute.
+    pub fn check(&self, path: &str) -> Result<(), CommandNotAllowed> {
+        let command = Path::new(path);
+        let under = |prefixes: &[String]| {
+            prefixes
+                .iter()
+                .any(|prefix| command.starts_with(Path::new(prefix)))
+        };
+        let climbing = command
+            .components()
+            .any(|component| component == Component::ParentDir);
+        if climbing
+            || !command.is_absolute()
+            || (!self.allow.is_empty() && !under(&self.allow))
+            || under(&self.deny)
+        {
+            return Err(CommandNotAllowed {
+                command: path.to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check if the request may set the env var `key`, which is never if it could hijack the
+    /// command.
+    pub fn check_env(&self, key: &str) -> Result<(), EnvNotAllowed> {
+        if is_hijacking_env(key) {
+            return Err(EnvNotAllowed {
+                key: key.to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let policy = CommandPolicy {
+            allow: vec!["/usr/bin".to_owned(), "/opt/tools/build.sh".to_owned()],
+            deny: vec!["/usr/bin/rm".to_owned()],
+        };
+        assert!(policy.check("/usr/bin/gcc").is_ok());
+        assert!(policy.check("/opt/tools/build.sh").is_ok());
+        assert!(policy.check("/usr/bin2/gcc").is_err());
+        assert!(policy.check("/opt/tools/build.sh.bak").is_err());
+        assert!(policy.check("/usr/bin/../../bin/sh").is_err());
+        assert!(policy.check("gcc").is_err());
+        assert_eq!(
+            policy.check("/usr/bin/rm").unwrap_err(),
+            CommandNotAllowed {
+                command: "/usr/bin/rm".to_owned()
+            }
+        );
+
+        let policy = CommandPolicy {
+            deny: vec!["/bin".to_owned()],
+            ..CommandPolicy::default()
+        };
+        assert!(policy.check("/usr/local/bin/clang").is_ok());
+        assert!(policy.check("/bin/sh").is_err());
+    }
+
+    #[test]
+    fn test_check_env() {
+        let policy = CommandPolicy::default();
+        assert!(policy.check_env("HOME").is_ok());
+        assert!(policy.check_env("PASSWORD").is_ok());
+        assert!(policy.check_env("MY_PATH").is_ok());
+        assert!(policy.check_env("PATH").is_err());
+        assert!(policy.check_env("DYLD_INSERT_LIBRARIES").is_err());
+        assert_eq!(
+            policy.check_env("LD_PRELOAD").unwrap_err(),
+            EnvNotAllowed {
+                key: "LD_PRELOAD".to_owned()
+            }
+        );
+    }
+}
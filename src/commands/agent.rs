@@ -0,0 +1,355 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::Args;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::client::Client;
+use crate::commands::attach::{OutputFollower, POLL_INTERVAL};
+use crate::configs::CmdProxyClientConf;
+use crate::params::Param;
+use crate::protocol::{ExitStatus, RunRequest};
+use crate::streams::StreamKind;
+
+#[derive(Args, Debug)]
+pub(crate) struct AgentArgs {
+    /// Unix socket to listen on, default to $CMDPROXY_AGENT_SOCKET, or else a socket of the
+    /// user in the temp folder
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Max number of results of the successful runs kept to answer the identical requests,
+    /// or 0 to never answer from the cache
+    #[arg(long, default_value_t = 256)]
+    cache_size: usize,
+}
+
+/// The socket the agent listens on, and `exec` looks for, unless told otherwise.
+pub(crate) fn default_socket() -> PathBuf {
+    std::env::var_os("CMDPROXY_AGENT_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".to_owned());
+            std::env::temp_dir().join(format!("cmdproxy-agent-{}.sock", user))
+        })
+}
+
+/// A request sent to the agent, as one line of json.
+#[derive(Debug, Serialize, Deserialize)]
+struct AgentRequest {
+    request: RunRequest,
+    queue: Option<String>,
+}
+
+/// What the agent sends back, as lines of json, ending with either `Exit` or `Error`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AgentEvent {
+    /// A piece of the output of the command, encoded in base64.
+    Output {
+        kind: StreamKind,
+        data: String,
+    },
+    Exit {
+        status: ExitStatus,
+        cached: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl AgentEvent {
+    fn output(kind: StreamKind, data: &[u8]) -> AgentEvent {
+        AgentEvent::Output {
+            kind,
+            data: BASE64.encode(data),
+        }
+    }
+}
+
+/// The result of a successful run, replayed to the identical requests.
+#[derive(Debug, Clone)]
+struct CachedResult {
+    status: ExitStatus,
+    output: Vec<(StreamKind, Vec<u8>)>,
+    /// The local outputs of the run, without any of which the result is stale.
+    outputs: Vec<PathBuf>,
+}
+
+/// Results of the successful runs, by their requests and the states of their local inputs,
+/// evicting the oldest ones beyond the capacity.
+#[derive(Debug)]
+struct ResultCache {
+    capacity: usize,
+    results: HashMap<String, CachedResult>,
+    order: VecDeque<String>,
+}
+
+impl ResultCache {
+    fn new(capacity: usize) -> ResultCache {
+        ResultCache {
+            capacity,
+            results: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The key of the request, or none if it cannot be cached.
+    ///
+    /// Only the requests with local outputs are cached, as the others are run for their side
+    /// effects or their output. The inputs are told apart by their sizes and modification
+    /// times, which is what build systems go by as well.
+    fn key(agent_request: &AgentRequest) -> Option<String> {
+        if agent_request
+            .request
+            .local_paths(Param::is_output)
+            .is_empty()
+        {
+            return None;
+        }
+        let mut key = serde_json::to_string(agent_request).ok()?;
+        for path in agent_request.request.local_paths(Param::is_input) {
+            let meta = std::fs::metadata(&path).ok()?;
+            let modified = meta.modified().ok()?;
+            write!(key, "\n{}:{}:{:?}", path.display(), meta.len(), modified).ok()?;
+        }
+        Some(key)
+    }
+
+    fn get(&self, key: &str) -> Option<CachedResult> {
+        self.results
+            .get(key)
+            .filter(|result| result.outputs.iter().all(|path| path.exists()))
+            .cloned()
+    }
+
+    fn put(&mut self, key: String, result: CachedResult) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.results.insert(key.clone(), result).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.results.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A long-living client serving the requests of the local processes, such as the shims
+/// invoked by a build system, so that they share its connections and uploads, instead of
+/// each paying for its own.
+struct Agent {
+    client: Client,
+    cache: Mutex<ResultCache>,
+}
+
+impl Agent {
+    /// Serve the connections concurrently until interrupted.
+    async fn serve(&self, listener: UnixListener) -> anyhow::Result<()> {
+        let mut connections = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => connections.push(self.serve_connection(stream)),
+                    Err(err) => warn!("Failed to accept a connection: {}", err),
+                },
+                Some(res) = connections.next(), if !connections.is_empty() => {
+                    if let Err(err) = res {
+                        debug!("Failed to serve a connection: {:#}", err);
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            }
+        }
+    }
+
+    async fn serve_connection(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let agent_request: AgentRequest = serde_json::from_str(line.as_str())?;
+
+        let event = match self.run(agent_request, &mut reader, &mut writer).await {
+            Ok(event) => event,
+            Err(err) => AgentEvent::Error {
+                message: format!("{:#}", err),
+            },
+        };
+        send(&mut writer, &event).await
+    }
+
+    /// Run the request, or replay its cached result, sending the output as it comes.
+    async fn run<R, W>(
+        &self,
+        agent_request: AgentRequest,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> anyhow::Result<AgentEvent>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let key = ResultCache::key(&agent_request);
+        let cached = key
+            .as_deref()
+            .and_then(|key| self.cache.lock().unwrap().get(key));
+        if let Some(cached) = cached {
+            debug!(
+                "Replay the cached result of {:?}",
+                agent_request.request.command
+            );
+            for (kind, data) in &cached.output {
+                send(writer, &AgentEvent::output(*kind, data)).await?;
+            }
+            return Ok(AgentEvent::Exit {
+                status: cached.status,
+                cached: true,
+            });
+        }
+
+        let outputs = agent_request.request.local_paths(Param::is_output);
+        let task_id = Mutex::new(None);
+        let on_submitted = |id: &str| *task_id.lock().unwrap() = Some(id.to_owned());
+        let run =
+            self.client
+                .run_watched(agent_request.request, agent_request.queue, &on_submitted);
+        tokio::pin!(run);
+
+        let mut follower = OutputFollower::default();
+        let mut output = vec![];
+        let mut hangup = [0u8; 1];
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut run => break outcome?,
+                _ = tokio::time::sleep(POLL_INTERVAL) => {
+                    self.forward(&mut follower, &task_id, writer, &mut output).await?;
+                }
+                // the caller sends nothing after the request, so it has gone if readable
+                _ = reader.read(&mut hangup) => {
+                    let id = task_id.lock().unwrap().clone();
+                    if let Some(id) = id {
+                        self.client.cancel_task(id.as_str()).await?;
+                    }
+                    anyhow::bail!("The caller has gone, cancel the run");
+                }
+            }
+        };
+
+        // the worker publishes all the output before responding, so it is complete by now
+        self.forward(&mut follower, &task_id, writer, &mut output)
+            .await?;
+        if let Some(key) = key.filter(|_| outcome.status.success()) {
+            let result = CachedResult {
+                status: outcome.status.clone(),
+                output,
+                outputs,
+            };
+            self.cache.lock().unwrap().put(key, result);
+        }
+        Ok(AgentEvent::Exit {
+            status: outcome.status,
+            cached: false,
+        })
+    }
+
+    /// Send the output published since the last time, keeping it for the cache.
+    async fn forward<W: AsyncWrite + Unpin>(
+        &self,
+        follower: &mut OutputFollower,
+        task_id: &Mutex<Option<String>>,
+        writer: &mut W,
+        output: &mut Vec<(StreamKind, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        let id = task_id.lock().unwrap().clone();
+        if let Some(id) = id {
+            for chunk in follower.fetch(&self.client, id.as_str()).await? {
+                send(writer, &AgentEvent::output(chunk.kind, &chunk.data)).await?;
+                output.push((chunk.kind, chunk.data));
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, msg: &T) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(msg)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+pub(crate) async fn agent(conf: CmdProxyClientConf, args: AgentArgs) -> anyhow::Result<()> {
+    let socket = args.socket.unwrap_or_else(default_socket);
+    // the socket left by a dead agent refuses the connections, and can be replaced
+    if socket.exists() {
+        anyhow::ensure!(
+            UnixStream::connect(&socket).await.is_err(),
+            "An agent is listening on {} already",
+            socket.display()
+        );
+        std::fs::remove_file(&socket)?;
+    }
+    let listener = UnixListener::bind(&socket)?;
+    let agent = Agent {
+        client: Client::new(conf).await?,
+        cache: Mutex::new(ResultCache::new(args.cache_size)),
+    };
+
+    info!("Listening on {}", socket.display());
+    let res = agent.serve(listener).await;
+    std::fs::remove_file(&socket).unwrap_or_default();
+    res
+}
+
+/// Run the request by the agent listening on `socket`, printing the output as it comes, and
+/// return how the command finished, or none if no agent is listening.
+pub(crate) async fn run_by_agent(
+    socket: &Path,
+    request: RunRequest,
+    queue: Option<String>,
+) -> anyhow::Result<Option<ExitStatus>> {
+    let stream = match UnixStream::connect(socket).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            debug!("No agent listening on {}: {}", socket.display(), err);
+            return Ok(None);
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+    send(&mut writer, &AgentRequest { request, queue }).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(line.as_str())? {
+            AgentEvent::Output { kind, data } => {
+                let data = BASE64.decode(data)?;
+                match kind {
+                    StreamKind::Stdout => std::io::stdout().write_all(&data)?,
+                    StreamKind::Stderr => std::io::stderr().write_all(&data)?,
+                }
+                std::io::stdout().flush()?;
+            }
+            AgentEvent::Exit { status, cached } => {
+                debug!("Finished by the agent, cached: {}", cached);
+                return Ok(Some(status));
+            }
+            AgentEvent::Error { message } => anyhow::bail!(message),
+        }
+    }
+    anyhow::bail!("The agent hung up before the run finished")
+}
@@ -0,0 +1,107 @@
+use std::io::Write;
+use std::time::Duration;
+
+use clap::Args;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::history::TaskState;
+use crate::streams::{OutputChunk, StreamKind};
+
+/// Interval of polling the new output of the task.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Args, Debug)]
+pub(crate) struct AttachArgs {
+    /// Id of the task to attach to
+    task_id: String,
+
+    /// Cancel the task on Ctrl-C instead of just detaching from it
+    #[arg(long)]
+    cancel_on_interrupt: bool,
+}
+
+/// Print the output of a task to the local stdout and stderr as it comes.
+#[derive(Debug, Default)]
+pub(crate) struct OutputFollower {
+    stdout_seq: Option<i64>,
+    stderr_seq: Option<i64>,
+}
+
+impl OutputFollower {
+    /// Fetch the output published since the last fetch.
+    pub(crate) async fn fetch(
+        &mut self,
+        client: &Client,
+        task_id: &str,
+    ) -> anyhow::Result<Vec<OutputChunk>> {
+        let stdout = client
+            .task_output(task_id, StreamKind::Stdout, self.stdout_seq)
+            .await?;
+        let stderr = client
+            .task_output(task_id, StreamKind::Stderr, self.stderr_seq)
+            .await?;
+        if let Some(chunk) = stdout.last() {
+            self.stdout_seq = Some(chunk.seq);
+        }
+        if let Some(chunk) = stderr.last() {
+            self.stderr_seq = Some(chunk.seq);
+        }
+        Ok(stdout.into_iter().chain(stderr).collect())
+    }
+
+    /// Print the output published since the last poll.
+    pub(crate) async fn poll(&mut self, client: &Client, task_id: &str) -> anyhow::Result<()> {
+        for chunk in self.fetch(client, task_id).await? {
+            match chunk.kind {
+                StreamKind::Stdout => std::io::stdout().write_all(&chunk.data)?,
+                StreamKind::Stderr => std::io::stderr().write_all(&chunk.data)?,
+            }
+        }
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+pub(crate) async fn attach(conf: CmdProxyClientConf, args: AttachArgs) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    let task_id = args.task_id.as_str();
+
+    let mut follower = OutputFollower::default();
+    let mut interrupted = false;
+    loop {
+        // check the state before fetching, so that no output is missed after the task is done
+        let record = client
+            .inspect_task(task_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No such task: {}", task_id))?;
+
+        follower.poll(&client, task_id).await?;
+
+        if !matches!(
+            record.state,
+            TaskState::Pending | TaskState::Started | TaskState::Parked
+        ) {
+            if let Some(exc) = record.exc {
+                anyhow::bail!("Task {} {}: {}", task_id, record.state, exc);
+            }
+            let status = record.status.unwrap_or_default();
+            anyhow::ensure!(status.success(), "Remote command {}", status);
+            return Ok(());
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = tokio::signal::ctrl_c(), if !interrupted => {
+                if !args.cancel_on_interrupt {
+                    eprintln!("Detached from task {}", task_id);
+                    return Ok(());
+                }
+                interrupted = true;
+                if client.cancel_task(task_id).await? {
+                    eprintln!("Task {} has been cancelled", task_id);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use futures::StreamExt;
+use serde_json::json;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::outcome::{format_bytes, format_duration};
+use crate::params::Param;
+use crate::protocol::RunRequest;
+use crate::units;
+
+#[derive(Args, Debug)]
+pub(crate) struct BenchArgs {
+    /// Number of runs to be sent in total
+    #[arg(long, default_value_t = 100)]
+    requests: usize,
+
+    /// Number of runs in flight at the same time
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Size of the input file of each run, such as 512, 64K or 4M
+    #[arg(long, value_parser = units::parse_size::<u64>, default_value = "64K")]
+    payload_size: u64,
+
+    /// Command reading the input file and writing its stdout, which is echoed back
+    #[arg(long, default_value = "cat")]
+    command: String,
+
+    /// Queue to send the runs to, default to the name of the command
+    #[arg(short, long)]
+    queue: Option<String>,
+
+    /// Print the report in json
+    #[arg(long)]
+    json: bool,
+}
+
+/// Send synthetic runs, each echoing a payload back, and report how the deployment copes.
+pub(crate) async fn bench(conf: CmdProxyClientConf, args: BenchArgs) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    let workdir = tempfile::tempdir()?;
+    let storage_before = client.storage_usage().await?;
+
+    let started_at = Instant::now();
+    let results: Vec<_> = futures::stream::iter(0..args.requests)
+        .map(|i| {
+            let client = &client;
+            let args = &args;
+            let input = workdir.path().join(format!("in-{}", i));
+            let output = workdir.path().join(format!("out-{}", i));
+            async move {
+                let payload = (0..args.payload_size)
+                    .map(|j| (j as usize).wrapping_mul(31).wrapping_add(i) as u8)
+                    .collect::<Vec<_>>();
+                std::fs::write(&input, payload)?;
+                let request = RunRequest::builder()
+                    .command(Param::cmd_name(args.command.as_str()))
+                    .args(vec![Param::ipath(input.to_str().unwrap())])
+                    .stdout(Param::opath(output.to_str().unwrap()))
+                    .build();
+                client.run_outcome(request, args.queue.clone()).await
+            }
+        })
+        .buffer_unordered(args.concurrency.max(1))
+        .collect()
+        .await;
+    let elapsed = started_at.elapsed();
+    let storage_after = client.storage_usage().await?;
+
+    let mut latencies = vec![];
+    let mut failures = 0;
+    let mut transferred = 0;
+    for result in results {
+        match result {
+            Ok(outcome) if outcome.status.success() => {
+                latencies.push(outcome.metrics.total);
+                transferred += outcome.metrics.uploaded_bytes + outcome.metrics.downloaded_bytes;
+            }
+            _ => failures += 1,
+        }
+    }
+    latencies.sort();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+    let throughput = args.requests as f64 / elapsed.as_secs_f64();
+    let overhead = storage_after.saturating_sub(storage_before);
+
+    if args.json {
+        let ms = |duration: Duration| duration.as_millis() as u64;
+        let report = json!({
+            "requests": args.requests,
+            "concurrency": args.concurrency,
+            "payload_size": args.payload_size,
+            "failures": failures,
+            "elapsed_ms": ms(elapsed),
+            "throughput": throughput,
+            "latency_ms": {
+                "p50": ms(percentile(50)),
+                "p90": ms(percentile(90)),
+                "p99": ms(percentile(99)),
+                "max": ms(latencies.last().copied().unwrap_or_default()),
+            },
+            "transferred_bytes": transferred,
+            "storage_overhead_bytes": overhead,
+        });
+        println!("{}", report);
+    } else {
+        println!("requests   : {} ({} failed)", args.requests, failures);
+        println!("elapsed    : {}", format_duration(elapsed));
+        println!("throughput : {:.2} runs/s", throughput);
+        println!(
+            "latency    : p50 {}, p90 {}, p99 {}, max {}",
+            format_duration(percentile(50)),
+            format_duration(percentile(90)),
+            format_duration(percentile(99)),
+            format_duration(latencies.last().copied().unwrap_or_default()),
+        );
+        println!("transfer   : {}", format_bytes(transferred));
+        println!("storage    : {} left on the cloud", format_bytes(overhead));
+    }
+
+    anyhow::ensure!(
+        failures == 0,
+        "{} of {} runs failed",
+        failures,
+        args.requests
+    );
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! Self-contained demo of the proxy, for evaluating it without any infrastructure but docker.
+//!
+//! A disposable redis and a disposable mongodb are launched in docker, a worker serving a
+//! palette of only `sh` is spawned from this very executable, and an example run uploading an
+//! input, transforming it, and downloading the output and the stdout back is sent through it.
+//! Everything is torn down afterwards, whether the run succeeds or not, the containers once
+//! dropped and the worker once killed.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+
+use clap::Args;
+use test_utilities::docker;
+
+use crate::client::Client;
+use crate::configs::{CmdProxyClientConf, CmdProxyClientConfFile};
+use crate::params::Param;
+use crate::protocol::RunRequest;
+use crate::retry::RunOptions;
+
+/// Database of the remote-fs of the demo.
+const DEMO_DBNAME: &str = "cmdproxy-demo-db";
+
+#[derive(Args, Debug)]
+pub(crate) struct DemoArgs {
+    /// Text passed through the example run
+    #[arg(long, default_value = "hello from cmdproxy")]
+    text: String,
+}
+
+pub(crate) async fn demo(args: DemoArgs) -> anyhow::Result<()> {
+    println!("Launching a disposable redis and mongodb in docker...");
+    let redis = docker::Builder::new("redis")
+        .name("cmdproxy-demo-redis")
+        .bind_port_as_default(Some("0"), "6379")
+        .build_disposable()
+        .await;
+    let mongo = docker::Builder::new("mongo")
+        .name("cmdproxy-demo-mongo")
+        .bind_port_as_default(Some("0"), "27017")
+        .build_disposable()
+        .await;
+
+    let workdir = tempfile::tempdir()?;
+    let palette = workdir.path().join("commands-palette.yaml");
+    std::fs::write(&palette, "sh: /bin/sh\n")?;
+
+    println!("Spawning a worker serving `sh`...");
+    let mut worker = tokio::process::Command::new(std::env::current_exe()?);
+    // the deployment of the user is not to leak into the demo, except for the log level
+    for (name, _) in std::env::vars() {
+        if name.starts_with("CMDPROXY_") && name != "CMDPROXY_LOGLEVEL" {
+            worker.env_remove(name);
+        }
+    }
+    let mut worker = worker
+        .arg("--broker-url")
+        .arg(redis.url())
+        .arg("--mongo-url")
+        .arg(mongo.url())
+        .arg("--mongo-dbname")
+        .arg(DEMO_DBNAME)
+        .arg("--command-palette")
+        .arg(&palette)
+        .arg("--environments")
+        .arg(workdir.path().join("environments.yaml"))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let conf = CmdProxyClientConf::new(CmdProxyClientConfFile {
+        broker_url: redis.url().to_owned(),
+        mongo_url: mongo.url().to_owned(),
+        mongo_dbname: DEMO_DBNAME.to_owned(),
+        ..CmdProxyClientConfFile::default()
+    });
+    let input = workdir.path().join("input.txt");
+    let output = workdir.path().join("output.txt");
+    let stdout = workdir.path().join("stdout.txt");
+    std::fs::write(&input, format!("{}\n", args.text))?;
+    let request = RunRequest::builder()
+        .command(Param::cmd_name("sh"))
+        .args(vec![
+            Param::str("-c"),
+            Param::format(
+                "echo \"running on $(hostname)\" && tr a-z A-Z < {input} > {output}",
+                HashMap::from([
+                    ("input", Param::ipath(input.to_string_lossy())),
+                    ("output", Param::opath(output.to_string_lossy())),
+                ]),
+            ),
+        ])
+        .stdout(Param::opath(stdout.to_string_lossy()))
+        .build();
+
+    println!("Sending an example run uppercasing {}...", input.display());
+    let client = Client::new(conf).await?;
+    let status = tokio::select! {
+        status = client.run(request, Some("sh".to_owned()), RunOptions::default()) => status?,
+        exited = worker.wait() => anyhow::bail!("Worker exited unexpectedly: {}", exited?),
+    };
+
+    println!("Remote command {}", status);
+    anyhow::ensure!(status.success(), "Remote command {}", status);
+    println!("stdout: {}", std::fs::read_to_string(&stdout)?.trim_end());
+    println!("output: {}", std::fs::read_to_string(&output)?.trim_end());
+
+    println!("Tearing down the worker, the redis and the mongodb...");
+    worker.kill().await?;
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use clap::Args;
+use log::debug;
+
+use crate::app::command_palette_path;
+use crate::archive::ArchiveFormat;
+use crate::client::Client;
+#[cfg(unix)]
+use crate::commands::agent;
+use crate::commands::attach::{OutputFollower, POLL_INTERVAL};
+use crate::configs::{load_command_palette, CmdProxyClientConf};
+use crate::params::Param;
+use crate::protocol::{ExitStatus, RunRequest};
+
+#[derive(Args, Debug)]
+pub(crate) struct ExecArgs {
+    /// Queue to send the request to, default to the name of the command
+    #[arg(short, long)]
+    queue: Option<String>,
+
+    /// Working directory of the command on the server, where ${WORKSPACE} is the temp
+    /// folder of the run
+    #[arg(long)]
+    cwd: Option<String>,
+
+    /// Path to the command palette, where the heuristics of the command are looked up
+    #[arg(short, long)]
+    command_palette: Option<PathBuf>,
+
+    /// Extra flag followed by an output path, in addition to those of the heuristics
+    #[arg(long = "output-flag")]
+    output_flags: Vec<String>,
+
+    /// Do not take the existing local files as inputs
+    #[arg(long)]
+    no_detect_inputs: bool,
+
+    /// Pass all the arguments as they are, without guessing the files among them
+    #[arg(long)]
+    raw: bool,
+
+    /// Run by this process even if an agent is listening, see `cmdproxy agent`
+    #[arg(long)]
+    no_agent: bool,
+
+    /// Name of the command in the command palette of the server
+    command: String,
+
+    /// Arguments passed to the command
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+/// Run the command as if it were local: follow its output, cancel it on Ctrl-C, and exit
+/// with its exit code.
+pub(crate) async fn exec(conf: CmdProxyClientConf, args: ExecArgs) -> anyhow::Result<()> {
+    let params = if args.raw {
+        args.args.into_iter().map(Param::str).collect()
+    } else {
+        let mut heuristics = command_palette_path(args.command_palette)
+            .filter(|path| path.exists())
+            .map(|path| load_command_palette(&path))
+            .transpose()?
+            .and_then(|palette| palette.get(args.command.as_str()).cloned())
+            .map(|entry| entry.heuristics())
+            .unwrap_or_default();
+        heuristics.output_flags.extend(args.output_flags);
+        heuristics.detect_inputs &= !args.no_detect_inputs;
+        heuristics.to_params(args.args)
+    };
+    debug!("Guessed params:\n{:#?}", params);
+
+    let request = RunRequest {
+        command: Param::cmd_name(args.command),
+        args: params,
+        cwd: args.cwd,
+        env: None,
+        stdin: None,
+        stdout: None,
+        stderr: None,
+        priority: 0,
+        timeout: None,
+        precheck: None,
+        labels: HashMap::new(),
+        input_bytes: None,
+        input_sizes: HashMap::new(),
+        archive: ArchiveFormat::default(),
+        preserve: HashSet::new(),
+        input_attrs: HashMap::new(),
+        enqueued_at: None,
+        limits: None,
+        features: vec![],
+        required_features: vec![],
+        delta: false,
+        output_digests: HashMap::new(),
+        compression: None,
+        encryption_keys: HashMap::new(),
+        dry_run: false,
+    };
+
+    #[cfg(unix)]
+    if !args.no_agent {
+        let socket = agent::default_socket();
+        let status = agent::run_by_agent(&socket, request.clone(), args.queue.clone()).await?;
+        if let Some(status) = status {
+            std::process::exit(exit_code(&status))
+        }
+    }
+
+    let client = Client::new(conf).await?;
+    let task_id = Mutex::new(None);
+    let on_submitted = |id: &str| *task_id.lock().unwrap() = Some(id.to_owned());
+    let run = client.run_watched(request, args.queue, &on_submitted);
+    tokio::pin!(run);
+
+    let mut follower = OutputFollower::default();
+    let mut interrupted = false;
+    let outcome = loop {
+        tokio::select! {
+            outcome = &mut run => break outcome,
+            _ = tokio::time::sleep(POLL_INTERVAL) => {
+                let id = task_id.lock().unwrap().clone();
+                if let Some(id) = id {
+                    follower.poll(&client, id.as_str()).await?;
+                }
+            }
+            _ = tokio::signal::ctrl_c(), if !interrupted => {
+                interrupted = true;
+                let id = task_id.lock().unwrap().clone();
+                if let Some(id) = id {
+                    client.cancel_task(id.as_str()).await?;
+                }
+            }
+        }
+    };
+
+    // the worker publishes all the output before responding, so it is complete by now
+    let id = task_id.lock().unwrap().clone();
+    if let Some(id) = id {
+        follower.poll(&client, id.as_str()).await?;
+    }
+
+    std::process::exit(exit_code(&outcome?.status))
+}
+
+/// Exit code of the command as a shell would report it.
+fn exit_code(status: &ExitStatus) -> i32 {
+    match status {
+        ExitStatus::Exited { code } => *code,
+        ExitStatus::Signaled { signal, .. } => 128 + signal,
+        // as reported by timeout(1)
+        ExitStatus::TimedOut => 124,
+        ExitStatus::SpawnFailed { reason } => {
+            eprintln!("cmdproxy: failed to spawn: {}", reason);
+            127
+        }
+        ExitStatus::Unknown => 1,
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use clap::Args;
+use serde_json::json;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::history::{format_time, HistoryQuery};
+use crate::outcome::format_bytes;
+use crate::units;
+
+#[derive(Args, Debug)]
+pub(crate) struct HistoryArgs {
+    /// Only show the runs sent to this queue
+    #[arg(short, long)]
+    queue: Option<String>,
+
+    /// Only show the runs submitted within this duration, such as 30m, 12h or 2d
+    #[arg(long, value_parser = units::parse_duration)]
+    since: Option<Duration>,
+
+    /// Only show the runs which failed, errored or were cancelled
+    #[arg(long)]
+    failed: bool,
+
+    /// Show at most this many runs
+    #[arg(short = 'n', long, default_value_t = 50)]
+    limit: i64,
+
+    /// Summarize the resources used by the runs of each command instead, within --since or
+    /// else the last week
+    #[arg(long, conflicts_with_all = ["queue", "failed"])]
+    usage: bool,
+
+    /// Print the runs in json
+    #[arg(long)]
+    json: bool,
+}
+
+pub(crate) async fn history(conf: CmdProxyClientConf, args: HistoryArgs) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    if args.usage {
+        return usage(client, args).await;
+    }
+    let records = client
+        .history(HistoryQuery {
+            queue: args.queue,
+            since: args.since,
+            failed: args.failed,
+            limit: Some(args.limit),
+        })
+        .await?;
+
+    if args.json {
+        let records: Vec<_> = records
+            .iter()
+            .map(|record| {
+                json!({
+                    "task_id": record.task_id,
+                    "state": record.state,
+                    "queue": record.queue,
+                    "worker": record.worker,
+                    "submitted_at": format_time(record.submitted_at),
+                    "duration_ms": record.duration().map(|d| d.as_millis() as u64),
+                    "status": record.status,
+                    "exc": record.exc,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<36}  {:<8}  {:<20}  {:<24}  {:>10}  {:<19}",
+        "TASK", "STATE", "QUEUE", "STATUS", "DURATION", "SUBMITTED"
+    );
+    for record in records {
+        println!(
+            "{:<36}  {:<8}  {:<20}  {:<24}  {:>10}  {:<19}",
+            record.task_id,
+            record.state,
+            record.queue.clone().unwrap_or_default(),
+            record
+                .status
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "-".to_owned()),
+            record
+                .duration()
+                .map(|d| format!("{:.3}s", d.as_secs_f64()))
+                .unwrap_or_else(|| "-".to_owned()),
+            format_time(record.submitted_at),
+        );
+    }
+    Ok(())
+}
+
+async fn usage(client: Client, args: HistoryArgs) -> anyhow::Result<()> {
+    let since = args.since.unwrap_or(Duration::from_secs(7 * 24 * 60 * 60));
+    let summaries = client.usage_summary(since).await?;
+
+    if args.json {
+        let summaries: Vec<_> = summaries
+            .iter()
+            .map(|summary| {
+                json!({
+                    "command": summary.command,
+                    "runs": summary.runs,
+                    "avg_duration_ms": summary.avg_duration.as_millis() as u64,
+                    "max_duration_ms": summary.max_duration.as_millis() as u64,
+                    "avg_cpu_ms": summary.avg_cpu_time.map(|d| d.as_millis() as u64),
+                    "max_rss_bytes": summary.max_rss_bytes,
+                    "downloaded_bytes": summary.downloaded_bytes,
+                    "uploaded_bytes": summary.uploaded_bytes,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<20}  {:>6}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "COMMAND", "RUNS", "AVG", "MAX", "AVG CPU", "MAX RSS", "DOWNLOADED", "UPLOADED"
+    );
+    let secs = |d: Duration| format!("{:.3}s", d.as_secs_f64());
+    for summary in summaries {
+        println!(
+            "{:<20}  {:>6}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+            summary.command,
+            summary.runs,
+            secs(summary.avg_duration),
+            secs(summary.max_duration),
+            summary
+                .avg_cpu_time
+                .map(secs)
+                .unwrap_or_else(|| "-".to_owned()),
+            summary
+                .max_rss_bytes
+                .map(format_bytes)
+                .unwrap_or_else(|| "-".to_owned()),
+            format_bytes(summary.downloaded_bytes),
+            format_bytes(summary.uploaded_bytes),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,18 @@
+#[cfg(unix)]
+pub(crate) mod agent;
+pub(crate) mod attach;
+pub(crate) mod bench;
+#[cfg(feature = "demo")]
+pub(crate) mod demo;
+pub(crate) mod exec;
+pub(crate) mod history;
+pub(crate) mod provenance;
+pub(crate) mod replay;
+pub(crate) mod rerun;
+pub(crate) mod run;
+pub(crate) mod schedule;
+pub(crate) mod shims;
+pub(crate) mod storage;
+pub(crate) mod submit;
+pub(crate) mod tasks;
+pub(crate) mod watch;
@@ -0,0 +1,35 @@
+use clap::Args;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::history::format_time;
+
+#[derive(Args, Debug)]
+pub(crate) struct ProvenanceArgs {
+    /// Local path the output was downloaded to
+    path: String,
+}
+
+pub(crate) async fn provenance(
+    conf: CmdProxyClientConf,
+    args: ProvenanceArgs,
+) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    let provenance = client
+        .provenance(args.path.as_str())
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("No provenance recorded for {}", args.path))?;
+
+    println!("task      : {}", provenance.task_id);
+    println!("command   : {}", provenance.command);
+    println!("worker    : {}", provenance.worker);
+    if let Some(record) = client.inspect_task(provenance.task_id.as_str()).await? {
+        println!("state     : {}", record.state);
+        println!("submitted : {}", format_time(record.submitted_at));
+        println!("finished  : {}", format_time(record.finished_at));
+        if let Some(status) = record.status {
+            println!("status    : {}", status);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::recording;
+
+#[derive(Args, Debug)]
+pub(crate) struct ReplayArgs {
+    /// Folder of the recording, as fetched by --task
+    bundle: PathBuf,
+
+    /// Fetch the recording of this task into the folder first
+    #[arg(long)]
+    task: Option<String>,
+
+    /// Keep the workspace of the replay for inspecting, rather than removing it
+    #[arg(long)]
+    keep: bool,
+}
+
+pub(crate) async fn replay(conf: CmdProxyClientConf, args: ReplayArgs) -> anyhow::Result<()> {
+    if let Some(task_id) = args.task.as_deref() {
+        anyhow::ensure!(
+            !args.bundle.exists(),
+            "Cannot fetch the recording into {}, which exists already",
+            args.bundle.display()
+        );
+        let client = Client::new(conf).await?;
+        client.fetch_recording(task_id, &args.bundle).await?;
+        println!("Fetched the recording of task {}", task_id);
+    }
+
+    let sandbox = tempfile::Builder::new()
+        .prefix("cmdproxy-replay-")
+        .tempdir()?;
+    let replay = recording::replay(&args.bundle, sandbox.path()).await?;
+    println!("recorded  : {}", replay.recorded);
+    println!("replayed  : {}", replay.status);
+    for relpath in &replay.differing {
+        println!("differing : {}", relpath);
+    }
+    if args.keep {
+        println!("workspace : {}", sandbox.into_path().display());
+    }
+
+    anyhow::ensure!(replay.reproduced(), "Replay differs from the recorded run");
+    Ok(())
+}
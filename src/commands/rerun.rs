@@ -0,0 +1,31 @@
+use clap::Args;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+
+#[derive(Args, Debug)]
+pub(crate) struct RerunArgs {
+    /// Id of the task to be resubmitted
+    task_id: String,
+
+    /// Print the report in json
+    #[arg(long)]
+    json: bool,
+}
+
+pub(crate) async fn rerun(conf: CmdProxyClientConf, args: RerunArgs) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    let outcome = client.rerun(args.task_id.as_str()).await?;
+    if args.json {
+        println!("{}", outcome.summary_json());
+    } else {
+        print!("{}", outcome.summary());
+    }
+
+    anyhow::ensure!(
+        outcome.status.success(),
+        "Remote command {}",
+        outcome.status
+    );
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Args;
+
+use crate::approval;
+use crate::archive::ArchiveFormat;
+use crate::backpressure::BackpressurePolicy;
+use crate::catalog::{ArtifactCatalog, RestCatalog};
+use crate::client::Client;
+use crate::commands::submit::report;
+use crate::configs::CmdProxyClientConf;
+use crate::limits::ResourceLimits;
+use crate::middles::serde::PayloadLimits;
+use crate::params::Param;
+use crate::precheck::{OutputCheck, Precheck};
+use crate::protocol::{RunRequest, Stdin};
+use crate::recording::{self, RecordMode};
+use crate::registry::VersionCheck;
+use crate::retry::{parse_max_retries, RetryPolicies};
+use crate::storage::compression::{Codec, Compression};
+use crate::units;
+
+#[derive(Args, Debug)]
+pub(crate) struct RunArgs {
+    /// Queue to send the request to, default to the name of the command
+    #[arg(short, long)]
+    queue: Option<String>,
+
+    /// Also send the run to this queue, taking whichever response comes first
+    #[arg(long = "speculate")]
+    speculative_queues: Vec<String>,
+
+    /// Priority of the run, the higher the more urgent
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    priority: i32,
+
+    /// Seconds the run may take on the worker before being cancelled, or a duration such
+    /// as 1h30m
+    #[arg(long, value_parser = units::parse_secs)]
+    timeout: Option<u64>,
+
+    /// Seconds of cpu time each process of the command may take on the worker
+    #[arg(long, value_parser = units::parse_secs)]
+    cpu_seconds: Option<u64>,
+
+    /// Bytes of memory the command may take on the worker, or a size such as 512M
+    #[arg(long, value_parser = units::parse_size::<u64>)]
+    memory_bytes: Option<u64>,
+
+    /// Number of the files each process of the command may open at once on the worker
+    #[arg(long)]
+    max_files: Option<u64>,
+
+    /// Local file fed to the stdin of the command
+    #[arg(long)]
+    stdin: Option<String>,
+
+    /// Text fed to the stdin of the command
+    #[arg(long, conflicts_with = "stdin")]
+    stdin_str: Option<String>,
+
+    /// Local path receiving the stdout of the command
+    #[arg(long)]
+    stdout: Option<String>,
+
+    /// Local path receiving the stderr of the command
+    #[arg(long)]
+    stderr: Option<String>,
+
+    /// Working directory of the command on the server, where ${WORKSPACE} is the temp
+    /// folder of the run
+    #[arg(long)]
+    cwd: Option<String>,
+
+    /// Put all the outputs under a fresh folder named after the run in this folder
+    #[arg(long)]
+    run_dir: Option<PathBuf>,
+
+    /// Transfer only the outputs changed since the previous run, keeping the others as is
+    #[arg(long)]
+    delta: bool,
+
+    /// Park the run on the worker until approved by `tasks approve`, such as a migration
+    #[arg(long)]
+    require_approval: bool,
+
+    /// Record the inputs and the outputs of the run on the worker, for `cmdproxy replay`
+    #[arg(long, value_enum)]
+    record: Option<RecordMode>,
+
+    /// Print the recipe resolved by the worker, with the temp paths of the params, instead
+    /// of running the command
+    #[arg(long, conflicts_with = "speculative_queues")]
+    dry_run: bool,
+
+    /// Hold the run back while the queue has more pending tasks than this
+    #[arg(long)]
+    max_queue_depth: Option<u64>,
+
+    /// Fail instead of waiting when the queue is deeper than --max-queue-depth
+    #[arg(long, requires = "max_queue_depth")]
+    reject_when_busy: bool,
+
+    /// What to do if the queue is served by workers of another protocol version
+    #[arg(long, value_enum, default_value_t = VersionCheck::Warn)]
+    version_check: VersionCheck,
+
+    /// Max number of retries of a class of failures, as CLASS=MAX_RETRIES, such as
+    /// `command=1`, overriding the default of the class
+    #[arg(long)]
+    retry: Vec<String>,
+
+    /// Refuse to send the request if larger than this many bytes once serialized
+    #[arg(long, value_parser = units::parse_size::<usize>)]
+    max_request_bytes: Option<usize>,
+
+    /// Local command validating the run before anything is sent, such as "tool --dry-run",
+    /// split by whitespaces
+    #[arg(long)]
+    precheck: Option<String>,
+
+    /// Archive format packing the folders transferred, of which tar.zst keeps the unix
+    /// permissions and symlinks, but cannot be read by the servers before it
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Zip)]
+    archive: ArchiveFormat,
+
+    /// Codec compressing the files transferred, which the servers before it cannot read
+    #[arg(long, value_enum)]
+    compress: Option<Codec>,
+
+    /// Bytes of the smallest file compressed
+    #[arg(long, value_parser = units::parse_size::<u64>, default_value = "64K")]
+    compress_min_size: u64,
+
+    /// Local path of an input or an output keeping its mtime, permissions and extended
+    /// attributes across the transfers, such as one checked by a build tool
+    #[arg(long)]
+    preserve: Vec<String>,
+
+    /// Encrypt the local input or output at a path by a named key of the keyring instead of
+    /// the default key, as PATH=KEY, such as the key of a tenant for its confidential files
+    #[arg(long)]
+    encrypt_with: Vec<String>,
+
+    /// Check that the local outputs can be written before sending the request
+    #[arg(long)]
+    check_outputs: bool,
+
+    /// Bytes to be left free where the local outputs are written, checked along with
+    /// --check-outputs
+    #[arg(long, value_parser = units::parse_size::<u64>, requires = "check_outputs")]
+    min_free_disk: Option<u64>,
+
+    /// Register the local outputs of the run into the catalog, either `cloud` for the one of
+    /// the deployment, or the url of a REST endpoint taking them posted in json
+    #[arg(long)]
+    catalog: Option<String>,
+
+    /// Print the report in json
+    #[arg(long)]
+    json: bool,
+
+    /// Name of the command in the command palette of the server
+    command: String,
+
+    /// Arguments passed to the command
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+pub(crate) async fn run(conf: CmdProxyClientConf, args: RunArgs) -> anyhow::Result<()> {
+    let limits = ResourceLimits {
+        cpu_seconds: args.cpu_seconds,
+        memory_bytes: args.memory_bytes,
+        max_files: args.max_files,
+    };
+    let request = RunRequest {
+        command: Param::cmd_name(args.command.as_str()),
+        args: args.args.into_iter().map(Param::str).collect(),
+        cwd: args.cwd,
+        env: None,
+        stdin: match (args.stdin, args.stdin_str) {
+            (Some(filepath), _) => Some(Stdin::File(Param::ipath(filepath))),
+            (None, Some(content)) => Some(Stdin::Content(content)),
+            (None, None) => None,
+        },
+        stdout: args.stdout.map(Param::opath),
+        stderr: args.stderr.map(Param::opath),
+        priority: args.priority,
+        timeout: args.timeout.map(Duration::from_secs),
+        precheck: args.precheck.map(|precheck| {
+            let mut words = precheck.split_whitespace().map(str::to_owned);
+            Precheck::new(words.next().unwrap_or_default(), words.collect())
+        }),
+        labels: args
+            .require_approval
+            .then(|| {
+                (
+                    approval::APPROVAL_LABEL.to_owned(),
+                    approval::REQUIRED.to_owned(),
+                )
+            })
+            .into_iter()
+            .chain(
+                args.record
+                    .map(|mode| (recording::RECORD_LABEL.to_owned(), mode.to_string())),
+            )
+            .collect(),
+        input_bytes: None,
+        input_sizes: HashMap::new(),
+        archive: args.archive,
+        preserve: args
+            .preserve
+            .iter()
+            .map(|filepath| Param::ipath(filepath).cloud_url())
+            .collect(),
+        input_attrs: HashMap::new(),
+        enqueued_at: None,
+        limits: (limits != ResourceLimits::default()).then_some(limits),
+        features: vec![],
+        required_features: vec![],
+        delta: args.delta,
+        output_digests: HashMap::new(),
+        compression: args
+            .compress
+            .map(|codec| Compression::new(codec).with_min_size(args.compress_min_size)),
+        encryption_keys: parse_encryption_keys(&args.encrypt_with)?,
+        dry_run: false,
+    };
+
+    let catalog: Option<Arc<dyn ArtifactCatalog>> = match args.catalog.as_deref() {
+        Some("cloud") => Some(Arc::new(conf.cloud.artifacts().await?)),
+        Some(url) => Some(Arc::new(RestCatalog::new(url))),
+        None => None,
+    };
+    let mut client = Client::new(conf).await?;
+    if let Some(catalog) = catalog {
+        client = client.with_catalog(catalog);
+    }
+    if let Some(run_dir) = args.run_dir {
+        client = client.with_run_dir(run_dir);
+    }
+    client = client
+        .with_version_check(args.version_check)
+        .with_retry(RetryPolicies::default().with_max_retries(&parse_max_retries(&args.retry)?));
+    if let Some(max_request_bytes) = args.max_request_bytes {
+        client = client.with_payload_limits(PayloadLimits {
+            max_request_bytes,
+            ..PayloadLimits::default()
+        });
+    }
+    if args.check_outputs {
+        client = client.with_output_check(OutputCheck {
+            min_free_disk: args.min_free_disk,
+        });
+    }
+    if let Some(watermark) = args.max_queue_depth {
+        client = client.with_backpressure(if args.reject_when_busy {
+            BackpressurePolicy::reject(watermark)
+        } else {
+            BackpressurePolicy::block(watermark)
+        });
+    }
+    if args.dry_run {
+        let recipe = client.dry_run(request, args.queue).await?;
+        println!("{}", serde_json::to_string_pretty(&recipe)?);
+        return Ok(());
+    }
+    let outcome = if args.speculative_queues.is_empty() {
+        client.run_outcome(request, args.queue).await?
+    } else {
+        let mut queues = vec![args.queue.unwrap_or_else(|| args.command.clone())];
+        queues.extend(args.speculative_queues);
+        client.run_speculative(request, queues).await?
+    };
+    report(&outcome, args.json)
+}
+
+/// The keys picked for the params by their paths, given as PATH=KEY.
+fn parse_encryption_keys(specs: &[String]) -> anyhow::Result<HashMap<String, String>> {
+    specs
+        .iter()
+        .map(|spec| {
+            let (path, key) = spec
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Expect PATH=KEY, got {}", spec))?;
+            Ok((path.to_owned(), key.to_owned()))
+        })
+        .collect()
+}
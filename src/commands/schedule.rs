@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use clap::Subcommand;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::history::format_time;
+use crate::job::JobFile;
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ScheduleCommand {
+    /// Define a recurring run of a job file, replacing the run of the same name if any
+    Add {
+        /// Name of the recurring run
+        name: String,
+        /// When to fire the run, as a cron expression in UTC, such as `30 9 * * Mon-Fri`
+        #[arg(long)]
+        cron: String,
+        /// Queue to send the run to, overriding the one of the job file
+        #[arg(short, long)]
+        queue: Option<String>,
+        /// Job file describing the run, which may take no local params
+        job: PathBuf,
+    },
+    /// List the recurring runs, the soonest due first
+    List,
+    /// Remove a recurring run
+    Remove {
+        /// Name of the recurring run
+        name: String,
+    },
+}
+
+pub(crate) async fn schedule(
+    conf: CmdProxyClientConf,
+    command: ScheduleCommand,
+) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    match command {
+        ScheduleCommand::Add {
+            name,
+            cron,
+            queue,
+            job,
+        } => {
+            let job = JobFile::load(&job)?;
+            let queue = queue.unwrap_or_else(|| job.queue());
+            let next_at = client
+                .schedule(name.as_str(), cron.as_str(), &job.to_request(), Some(queue))
+                .await?;
+            println!("{} is due first at {}", name, format_time(Some(next_at)));
+        }
+        ScheduleCommand::List => {
+            println!(
+                "{:<24}  {:<20}  {:<20}  {:<19}  {:<19}",
+                "NAME", "CRON", "QUEUE", "NEXT", "LAST FIRED"
+            );
+            for run in client.list_schedules().await? {
+                println!(
+                    "{:<24}  {:<20}  {:<20}  {:<19}  {:<19}",
+                    run.name,
+                    run.cron,
+                    run.queue.unwrap_or_default(),
+                    format_time(run.next_at),
+                    format_time(run.last_fired_at),
+                );
+            }
+        }
+        ScheduleCommand::Remove { name } => {
+            if !client.unschedule(name.as_str()).await? {
+                anyhow::bail!("No such recurring run: {}", name);
+            }
+        }
+    }
+    Ok(())
+}
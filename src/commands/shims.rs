@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+
+use crate::app::{command_palette_path, ConnArgs};
+use crate::configs::load_command_palette;
+
+/// Marker line identifying the files generated as shims, so that only they get uninstalled.
+const SHIM_MARKER: &str = "generated by cmdproxy shims";
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ShimsCommand {
+    /// Generate a wrapper for each command, which runs the command through the proxy
+    Install {
+        /// Folder where the wrappers are put, which is expected to be on the PATH
+        #[arg(long)]
+        dir: PathBuf,
+
+        /// Path to the command palette listing the commands to be wrapped
+        #[arg(short, long)]
+        command_palette: Option<PathBuf>,
+
+        /// Extra commands to be wrapped, which are not in the command palette
+        #[arg(long = "command")]
+        commands: Vec<String>,
+
+        /// Overwrite the existing files which are not generated as shims
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove all the wrappers generated in the folder
+    Uninstall {
+        /// Folder where the wrappers were put
+        #[arg(long)]
+        dir: PathBuf,
+    },
+}
+
+pub(crate) fn shims(conn: &ConnArgs, command: ShimsCommand) -> anyhow::Result<()> {
+    match command {
+        ShimsCommand::Install {
+            dir,
+            command_palette,
+            commands,
+            force,
+        } => {
+            let mut names = commands;
+            if let Some(path) = command_palette_path(command_palette).filter(|p| p.exists()) {
+                names.extend(load_command_palette(&path)?.into_keys());
+            }
+            names.sort();
+            names.dedup();
+            anyhow::ensure!(!names.is_empty(), "No commands to be wrapped");
+
+            std::fs::create_dir_all(&dir)?;
+            let exe = std::env::current_exe()?;
+            let conn_args = conn.to_args();
+            for name in names {
+                let path = shim_path(&dir, name.as_str());
+                if path.exists() && !is_shim(&path) && !force {
+                    anyhow::bail!("{} exists and is not a shim", path.display());
+                }
+                install_shim(&path, &exe, &conn_args, name.as_str())?;
+                println!("Installed {}", path.display());
+            }
+        }
+        ShimsCommand::Uninstall { dir } => {
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_file() && is_shim(&path) {
+                    std::fs::remove_file(&path)?;
+                    println!("Removed {}", path.display());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_shim(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .map(|content| content.contains(SHIM_MARKER))
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn shim_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name)
+}
+
+#[cfg(unix)]
+fn install_shim(path: &Path, exe: &Path, conn_args: &[String], name: &str) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let quote = |arg: &str| format!("'{}'", arg.replace('\'', r"'\''"));
+    let args = conn_args
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = format!(
+        "#!/bin/sh\n# {}\nexec {} {} exec -- {} \"$@\"\n",
+        SHIM_MARKER,
+        quote(&exe.to_string_lossy()),
+        args,
+        quote(name),
+    );
+    std::fs::write(path, script)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shim_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.cmd", name))
+}
+
+#[cfg(windows)]
+fn install_shim(path: &Path, exe: &Path, conn_args: &[String], name: &str) -> anyhow::Result<()> {
+    let quote = |arg: &str| format!("\"{}\"", arg);
+    let args = conn_args
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = format!(
+        "@echo off\r\nrem {}\r\n{} {} exec -- {} %*\r\nexit /b %ERRORLEVEL%\r\n",
+        SHIM_MARKER,
+        quote(&exe.to_string_lossy()),
+        args,
+        quote(name),
+    );
+    std::fs::write(path, script)?;
+    Ok(())
+}
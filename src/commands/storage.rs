@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Subcommand;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::fsck::FsckOptions;
+use crate::storage::dictionary;
+use crate::units;
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum StorageCommand {
+    /// Scan the storage for junk and references to missing files
+    Fsck {
+        /// Commit the staged outputs of the runs which finished successfully
+        #[arg(long)]
+        repair: bool,
+
+        /// Delete the malformed, incomplete and stale staged files, orphan chunks, and expired
+        /// blobs
+        #[arg(long)]
+        purge: bool,
+
+        /// Age after which an uncommitted output is considered stale, such as 30m or 2d
+        #[arg(long, value_parser = units::parse_duration, default_value = "1d")]
+        stale_after: Duration,
+
+        /// Age after which a blob of the inputs taken by no task in flight expires, such as 7d,
+        /// or never if not given
+        #[arg(long, value_parser = units::parse_duration)]
+        blob_retention: Option<Duration>,
+    },
+    /// Train a dictionary on sample files and publish it for compressing the similar ones
+    TrainDictionary {
+        /// Name to publish the dictionary under, which is never to be reused for another
+        name: String,
+
+        /// Maximal size of the dictionary in bytes
+        #[arg(long, default_value_t = 112640)]
+        max_size: usize,
+
+        /// Sample files, such as a few hundreds of those to be compressed
+        #[arg(required = true)]
+        samples: Vec<PathBuf>,
+    },
+}
+
+pub(crate) async fn storage(
+    conf: CmdProxyClientConf,
+    command: StorageCommand,
+) -> anyhow::Result<()> {
+    match command {
+        StorageCommand::Fsck {
+            repair,
+            purge,
+            stale_after,
+            blob_retention,
+        } => {
+            let client = Client::new(conf).await?;
+            let report = client
+                .fsck(&FsckOptions {
+                    repair,
+                    purge,
+                    stale_after,
+                    blob_retention,
+                })
+                .await?;
+            for issue in &report.issues {
+                println!("{}", issue);
+            }
+            println!(
+                "{} issues found, {} repaired, {} purged",
+                report.issues.len(),
+                report.repaired,
+                report.purged
+            );
+        }
+        StorageCommand::TrainDictionary {
+            name,
+            max_size,
+            samples,
+        } => {
+            let trained = dictionary::train(samples.as_slice(), max_size)?;
+            let storage = conf.cloud.raw_storage().await?;
+            dictionary::publish(storage.as_ref(), name.as_str(), trained.as_slice()).await?;
+            println!(
+                "Published dictionary {} of {} bytes trained on {} samples",
+                name,
+                trained.len(),
+                samples.len()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::backpressure::BackpressurePolicy;
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::job::JobFile;
+use crate::middles::serde::PayloadLimits;
+use crate::outcome::RunOutcome;
+use crate::retry::RetryPolicies;
+
+#[derive(Args, Debug)]
+pub(crate) struct SubmitArgs {
+    /// Queue to send the request to, overriding the one of the job file
+    #[arg(short, long)]
+    queue: Option<String>,
+
+    /// Print the report in json
+    #[arg(long)]
+    json: bool,
+
+    /// Job file describing the run, in YAML, or in JSON if named *.json
+    job: PathBuf,
+}
+
+pub(crate) async fn submit(conf: CmdProxyClientConf, args: SubmitArgs) -> anyhow::Result<()> {
+    let job = JobFile::load(&args.job)?;
+    let request = job.to_request();
+    let queue = args.queue.unwrap_or_else(|| job.queue());
+
+    let client = job_client(conf, &job).await?;
+    let outcome = client.run_outcome(request, Some(queue)).await?;
+    report(&outcome, args.json)
+}
+
+/// A client trying as hard as the limits of the job say.
+pub(crate) async fn job_client(conf: CmdProxyClientConf, job: &JobFile) -> anyhow::Result<Client> {
+    let mut client = Client::new(conf)
+        .await?
+        .with_retry(RetryPolicies::default().with_max_retries(&job.limits.retry));
+    if let Some(max_request_bytes) = job.limits.max_request_bytes {
+        client = client.with_payload_limits(PayloadLimits {
+            max_request_bytes,
+            ..PayloadLimits::default()
+        });
+    }
+    if let Some(watermark) = job.limits.max_queue_depth {
+        client = client.with_backpressure(BackpressurePolicy::block(watermark));
+    }
+    Ok(client)
+}
+
+/// Print the report of the run, and fail unless it succeeded.
+pub(crate) fn report(outcome: &RunOutcome, json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", outcome.summary_json());
+    } else {
+        print!("{}", outcome.summary());
+    }
+
+    outcome.check_timed_out()?;
+    anyhow::ensure!(
+        outcome.status.success(),
+        "Remote command {}",
+        outcome.status
+    );
+    Ok(())
+}
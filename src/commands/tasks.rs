@@ -0,0 +1,103 @@
+use clap::Subcommand;
+
+use crate::client::Client;
+use crate::configs::CmdProxyClientConf;
+use crate::history::format_time;
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum TasksCommand {
+    /// List the tasks which are either pending or running
+    List {
+        /// Only list the tasks sent to this queue
+        #[arg(short, long)]
+        queue: Option<String>,
+    },
+    /// Show the metadata and the status of a task
+    Inspect {
+        /// Id of the task
+        task_id: String,
+    },
+    /// Cancel a task, which will be skipped if pending, or killed if running
+    Cancel {
+        /// Id of the task
+        task_id: String,
+    },
+    /// Approve a parked task requiring approval, as an operator given --approval-secret-file
+    Approve {
+        /// Id of the task
+        task_id: String,
+    },
+}
+
+pub(crate) async fn tasks(conf: CmdProxyClientConf, command: TasksCommand) -> anyhow::Result<()> {
+    let client = Client::new(conf).await?;
+    match command {
+        TasksCommand::List { queue } => {
+            let records = client.list_tasks(queue.as_deref()).await?;
+            println!(
+                "{:<36}  {:<8}  {:<20}  {:<20}  {:<19}",
+                "TASK", "STATE", "QUEUE", "WORKER", "SUBMITTED"
+            );
+            for record in records {
+                println!(
+                    "{:<36}  {:<8}  {:<20}  {:<20}  {:<19}",
+                    record.task_id,
+                    record.state,
+                    record.queue.unwrap_or_default(),
+                    record.worker.unwrap_or_default(),
+                    format_time(record.submitted_at),
+                );
+            }
+        }
+        TasksCommand::Inspect { task_id } => {
+            let record = client
+                .inspect_task(task_id.as_str())
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("No such task: {}", task_id))?;
+            println!("task      : {}", record.task_id);
+            println!("state     : {}", record.state);
+            println!("queue     : {}", record.queue.unwrap_or_default());
+            println!("client    : {}", record.client.unwrap_or_default());
+            println!("worker    : {}", record.worker.unwrap_or_default());
+            println!("submitted : {}", format_time(record.submitted_at));
+            if record.scheduled_at.is_some() {
+                println!("scheduled : {}", format_time(record.scheduled_at));
+            }
+            println!("started   : {}", format_time(record.started_at));
+            println!("finished  : {}", format_time(record.finished_at));
+            if let Some(approved_by) = record.approved_by {
+                println!(
+                    "approved  : {} by {}",
+                    format_time(record.approved_at),
+                    approved_by
+                );
+            }
+            if let Some(status) = record.status {
+                println!("status    : {}", status);
+            }
+            if let Some(exc) = record.exc {
+                println!("exception : {}", exc);
+            }
+            if let Some(request) = record.request {
+                println!("request   : {}", request);
+            }
+        }
+        TasksCommand::Cancel { task_id } => {
+            anyhow::ensure!(
+                client.cancel_task(task_id.as_str()).await?,
+                "No such unfinished task: {}",
+                task_id
+            );
+            println!("Task {} has been cancelled", task_id);
+        }
+        TasksCommand::Approve { task_id } => {
+            anyhow::ensure!(
+                client.approve_task(task_id.as_str()).await?,
+                "No such parked task submitted by another client: {}",
+                task_id
+            );
+            println!("Task {} has been approved", task_id);
+        }
+    }
+    Ok(())
+}
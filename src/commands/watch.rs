@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use clap::Args;
+use walkdir::WalkDir;
+
+use crate::client::Client;
+use crate::commands::attach::{OutputFollower, POLL_INTERVAL};
+use crate::commands::submit::{job_client, report};
+use crate::configs::CmdProxyClientConf;
+use crate::job::JobFile;
+
+#[derive(Args, Debug)]
+pub(crate) struct WatchArgs {
+    /// Queue to send the requests to, overriding the one of the job file
+    #[arg(short, long)]
+    queue: Option<String>,
+
+    /// Milliseconds the inputs must stay unchanged before the job is resubmitted
+    #[arg(long, default_value_t = 300)]
+    debounce_ms: u64,
+
+    /// Print the reports in json
+    #[arg(long)]
+    json: bool,
+
+    /// Job file describing the run, which is watched as well
+    job: PathBuf,
+}
+
+/// Modification times of the files under the watched paths.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Snapshot(BTreeMap<PathBuf, Option<SystemTime>>);
+
+impl Snapshot {
+    /// Take the snapshot of the files under `watched`, except those under `ignored`.
+    ///
+    /// A missing path is simply absent, so that creating it later counts as a change.
+    fn take(watched: &[PathBuf], ignored: &[PathBuf]) -> Snapshot {
+        let mut files = BTreeMap::new();
+        for path in watched {
+            let entries = WalkDir::new(path)
+                .into_iter()
+                .filter_entry(|entry| !ignored.iter().any(|out| entry.path().starts_with(out)))
+                .flatten();
+            for entry in entries {
+                let modified = entry.metadata().ok().and_then(|meta| meta.modified().ok());
+                files.insert(entry.into_path(), modified);
+            }
+        }
+        Snapshot(files)
+    }
+}
+
+/// The paths to watch for the job, and those to ignore under them.
+///
+/// The outputs are ignored, so that a run downloading them does not trigger the next one,
+/// unless ignoring them would hide an input as well.
+fn watched_paths(job_file: &Path, job: Option<&JobFile>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut watched = vec![job_file.to_path_buf()];
+    let mut ignored = vec![];
+    if let Some(job) = job {
+        watched.extend(job.local_inputs());
+        ignored = job.local_outputs();
+        ignored.retain(|out| !watched.iter().any(|path| path.starts_with(out)));
+    }
+    (watched, ignored)
+}
+
+pub(crate) async fn watch(conf: CmdProxyClientConf, args: WatchArgs) -> anyhow::Result<()> {
+    // the limits are those of the job file when started, as the client is made only once
+    let client = job_client(conf, &JobFile::load(&args.job)?).await?;
+    let debounce = Duration::from_millis(args.debounce_ms);
+    loop {
+        let job = JobFile::load(&args.job);
+        let (watched, ignored) = watched_paths(args.job.as_path(), job.as_ref().ok());
+        // taken before the run, so that the changes made while running are not missed
+        let snapshot = Snapshot::take(&watched, &ignored);
+
+        let res = match job {
+            Ok(job) => run_job(&client, &job, args.queue.clone(), args.json).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            eprintln!("cmdproxy: {:#}", err);
+        }
+
+        eprintln!("cmdproxy: watching {} paths for changes...", watched.len());
+        wait_for_change(&watched, &ignored, snapshot, debounce).await;
+    }
+}
+
+/// Wait until the watched files change, and then until they stay unchanged for `debounce`,
+/// so that a burst of changes, such as saving many files, triggers only one run.
+async fn wait_for_change(
+    watched: &[PathBuf],
+    ignored: &[PathBuf],
+    mut snapshot: Snapshot,
+    debounce: Duration,
+) {
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let current = Snapshot::take(watched, ignored);
+        if current != snapshot {
+            snapshot = current;
+            break;
+        }
+    }
+    loop {
+        tokio::time::sleep(debounce).await;
+        let current = Snapshot::take(watched, ignored);
+        if current == snapshot {
+            return;
+        }
+        snapshot = current;
+    }
+}
+
+/// Run the job once, streaming its output as it comes, and report its outcome.
+async fn run_job(
+    client: &Client,
+    job: &JobFile,
+    queue: Option<String>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let request = job.to_request();
+    let queue = queue.unwrap_or_else(|| job.queue());
+
+    let task_id = Mutex::new(None);
+    let on_submitted = |id: &str| *task_id.lock().unwrap() = Some(id.to_owned());
+    let run = client.run_watched(request, Some(queue), &on_submitted);
+    tokio::pin!(run);
+
+    let mut follower = OutputFollower::default();
+    let outcome = loop {
+        tokio::select! {
+            outcome = &mut run => break outcome?,
+            _ = tokio::time::sleep(POLL_INTERVAL) => {
+                let id = task_id.lock().unwrap().clone();
+                if let Some(id) = id {
+                    follower.poll(client, id.as_str()).await?;
+                }
+            }
+        }
+    };
+
+    // the worker publishes all the output before responding, so it is complete by now
+    let id = task_id.lock().unwrap().clone();
+    if let Some(id) = id {
+        follower.poll(client, id.as_str()).await?;
+    }
+    report(&outcome, json)
+}
@@ -0,0 +1,163 @@
+//! Composite commands, running several commands of the palette in turn as one request.
+//!
+//! A composite command is defined in the palette by its steps, such as
+//!
+//! ```yaml
+//! build-and-test:
+//!   steps:
+//!     - command: cmake
+//!       args: ["-S", "{0}", "-B", "build"]
+//!     - command: make
+//!       args: ["-C", "build"]
+//!     - command: ctest
+//!       args: ["--test-dir", "build", "{args}"]
+//! ```
+//!
+//! The steps are run in the same working directory, and stop at the first failing one. The
+//! arguments of a step are templates of the arguments of the request: `{0}`, `{1}`, ... are
+//! replaced with the arguments at those positions, `{cwd}` with the working directory, and an
+//! argument of exactly `{args}` is replaced with all the arguments.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use strfmt::strfmt;
+
+/// A step of a composite command, as defined in the palette.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompositeStep {
+    /// Name of the command in the palette.
+    pub command: String,
+    /// Templates of the arguments.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl CompositeStep {
+    /// Parse a step given as the name of the command followed by the templates of the
+    /// arguments, separated by whitespaces.
+    pub fn parse(spec: &str) -> anyhow::Result<CompositeStep> {
+        let mut words = spec.split_whitespace().map(str::to_owned);
+        let command = words
+            .next()
+            .ok_or_else(|| anyhow!("Expect a command in step `{}'", spec))?;
+        Ok(CompositeStep {
+            command,
+            args: words.collect(),
+        })
+    }
+}
+
+/// Parse the composite commands given as NAME=STEP[;STEP...].
+pub fn parse_composites(specs: &[String]) -> anyhow::Result<HashMap<String, Vec<CompositeStep>>> {
+    let mut composites = HashMap::new();
+    for spec in specs {
+        let (name, steps) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expect NAME=STEP[;STEP...], got {}", spec))?;
+        let steps = steps
+            .split(';')
+            .filter(|step| !step.trim().is_empty())
+            .map(CompositeStep::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        composites.insert(name.trim().to_owned(), steps);
+    }
+    Ok(composites)
+}
+
+/// A step whose command has been resolved to its path on this worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResolvedStep {
+    pub(crate) path: String,
+    pub(crate) args: Vec<String>,
+}
+
+impl ResolvedStep {
+    /// The arguments of the step for a request of the given arguments.
+    pub(crate) fn expand_args(&self, args: &[String], cwd: &str) -> anyhow::Result<Vec<String>> {
+        let mut vars: HashMap<_, _> = args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| (i.to_string(), arg.clone()))
+            .collect();
+        vars.insert("cwd".to_owned(), cwd.to_owned());
+
+        let mut expanded = vec![];
+        for template in &self.args {
+            if template == "{args}" {
+                expanded.extend(args.iter().cloned());
+            } else {
+                expanded.push(strfmt(template.as_str(), &vars).map_err(|err| {
+                    anyhow!(
+                        "Failed to expand argument `{}' of {}: {}",
+                        template,
+                        self.path,
+                        err
+                    )
+                })?);
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// The path the composite command of `name` is served at, which is never a real file.
+pub(crate) fn path_of(name: &str) -> String {
+    format!("composite:{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_args() {
+        let step = ResolvedStep {
+            path: "/usr/bin/ctest".to_owned(),
+            args: vec![
+                "--test-dir".to_owned(),
+                "{cwd}/{0}".to_owned(),
+                "{args}".to_owned(),
+            ],
+        };
+        let args = vec!["build".to_owned(), "-V".to_owned()];
+        assert_eq!(
+            step.expand_args(&args, "/work").unwrap(),
+            vec!["--test-dir", "/work/build", "build", "-V"]
+        );
+
+        // referring to a missing argument
+        let step = ResolvedStep {
+            path: "/usr/bin/make".to_owned(),
+            args: vec!["{2}".to_owned()],
+        };
+        assert!(step.expand_args(&args, "/work").is_err());
+    }
+
+    #[test]
+    fn test_parse_composites() {
+        let composites =
+            parse_composites(&["build-and-test=cmake -S {0} -B build; make -C build".to_owned()])
+                .unwrap();
+        assert_eq!(
+            composites["build-and-test"],
+            vec![
+                CompositeStep {
+                    command: "cmake".to_owned(),
+                    args: vec![
+                        "-S".to_owned(),
+                        "{0}".to_owned(),
+                        "-B".to_owned(),
+                        "build".to_owned()
+                    ],
+                },
+                CompositeStep {
+                    command: "make".to_owned(),
+                    args: vec!["-C".to_owned(), "build".to_owned()],
+                },
+            ]
+        );
+        assert!(parse_composites(&["oops".to_owned()]).is_err());
+    }
+}
@@ -1,113 +1,1020 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 use chain_ext::io::DeExt;
 use chain_ext::mongodb_gridfs::DatabaseExt;
+use futures::TryStreamExt;
+use log::{debug, warn};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{DatabaseOptions, ReadPreference, ReadPreferenceOptions, SelectionCriteria};
+use mongodb::Collection;
+use mongodb_gridfs::options::GridFSBucketOptions;
 use mongodb_gridfs::GridFSBucket;
 use serde::{Deserialize, Serialize};
 
+use crate::admission::AdmissionPolicy;
+use crate::batch::BatchConf;
+use crate::broker::{self, ReconnectPolicy};
+use crate::canary::CanaryPolicy;
+use crate::catalog::MongoCatalog;
+use crate::command_policy::CommandPolicy;
+use crate::composite::{self, CompositeStep, ResolvedStep};
+use crate::container::ContainerConf;
+use crate::fairness::FairSharePolicy;
+use crate::heuristics::ParamHeuristics;
+use crate::history::TaskHistory;
+use crate::middles::auth::HmacAuth;
+use crate::middles::serde::PayloadLimits;
+use crate::naming;
+use crate::palette::{PaletteKey, PaletteSource};
+use crate::params::local_hostname;
+use crate::preemption::PreemptionPolicy;
+use crate::registry::WorkerRegistry;
+use crate::retry::{FailureClass, RetryPolicies};
+use crate::schedule::Schedules;
+use crate::sla::SlaPolicy;
+use crate::storage::{
+    self, CompressingStorage, DictionaryStorage, EncryptingStorage, EncryptionKey, GridFsStorage,
+    ReplicaStorage, S3Conf, S3Storage, SharedFsStorage, Storage,
+};
+use crate::streams::OutputStreams;
+use crate::tls::TlsConf;
+use crate::units;
+use crate::warm::WarmConf;
+
 #[derive(Clone, Debug)]
 pub struct CeleryConf {
     pub broker_url: String,
     pub backend_url: String,
+    /// Prefix prepended to every queue, so that deployments can share one broker.
+    pub queue_prefix: String,
+    pub namespace: String,
+    pub reconnect: ReconnectPolicy,
+    /// Template of the queues in place of the namespace and the prefix, see [`crate::naming`].
+    pub queue_template: Option<String>,
+    /// TLS of the connection to the broker, if not by its url alone.
+    pub broker_tls: Option<TlsConf>,
+    /// TLS of the connection to the result backend on the mongodb, if not by its url alone.
+    pub backend_tls: Option<TlsConf>,
+    /// Name the runs are sent under and served by, see [`crate::broker::run_signature`].
+    pub task_name: String,
+    /// Database of the mongodb keeping the results of the tasks, or the one of the namespace
+    /// if not given, or else the one in its url.
+    pub result_db: Option<String>,
+}
+
+impl CeleryConf {
+    /// The url of the broker, connecting over TLS if configured.
+    pub(crate) fn broker_url_with_tls(&self) -> anyhow::Result<String> {
+        match &self.broker_tls {
+            Some(tls) => {
+                tls.trust_ca_file();
+                tls.broker_url(self.broker_url.as_str())
+            }
+            None => Ok(self.broker_url.clone()),
+        }
+    }
+
+    /// The database of the results, the one of the namespace unless named by the conf.
+    fn result_db(&self) -> Option<String> {
+        if self.result_db.is_some() || self.namespace.is_empty() {
+            return self.result_db.clone();
+        }
+        // the names of the databases of the mongodb must be free of these characters
+        let namespace = self.namespace.replace(['/', '\\', '.', ' ', '"', '$'], "_");
+        Some(format!("{}-results", namespace))
+    }
+
+    /// The url of the result backend in its database, connecting over TLS if configured.
+    pub(crate) fn backend_url_with_tls(&self) -> String {
+        let backend_url = match self.result_db() {
+            Some(db) => broker::with_database(self.backend_url.as_str(), db.as_str()),
+            None => self.backend_url.clone(),
+        };
+        match &self.backend_tls {
+            Some(tls) => tls.mongo_url(backend_url.as_str()),
+            None => backend_url,
+        }
+    }
+
+    pub(crate) fn queue(&self, name: &str) -> String {
+        if let Some(tmpl) = &self.queue_template {
+            naming::queue(tmpl, &self.namespace, &self.queue_prefix, name)
+        } else if self.namespace.is_empty() {
+            format!("{}{}", self.queue_prefix, name)
+        } else {
+            format!("{}.{}{}", self.namespace, self.queue_prefix, name)
+        }
+    }
+}
+
+/// Parse the mode of a read preference of the mongodb, as named in its connection strings.
+fn parse_read_preference(mode: &str) -> anyhow::Result<ReadPreference> {
+    let options = ReadPreferenceOptions::default();
+    Ok(match mode {
+        "primary" => ReadPreference::Primary,
+        "primaryPreferred" => ReadPreference::PrimaryPreferred { options },
+        "secondary" => ReadPreference::Secondary { options },
+        "secondaryPreferred" => ReadPreference::SecondaryPreferred { options },
+        "nearest" => ReadPreference::Nearest { options },
+        _ => anyhow::bail!("Unknown read preference {}", mode),
+    })
+}
+
+/// Suffix of the lane of a queue taking its urgent runs, see [`urgent_lane`].
+const URGENT_LANE_SUFFIX: &str = ".urgent";
+
+/// The lane of the prefixed `queue` taking the urgent runs, those of positive priorities.
+///
+/// The brokers deliver the tasks of a queue in order, and celery has no priority across them
+/// on every broker, hence the urgent runs are sent to a lane of their own consumed along with
+/// the queue, so that they never wait behind the backlog of the queue.
+pub(crate) fn urgent_lane(queue: &str) -> String {
+    format!("{}{}", queue, URGENT_LANE_SUFFIX)
+}
+
+/// The queue or its lane to send a run of `priority` to, see [`urgent_lane`].
+pub(crate) fn lane_of(queue: &str, priority: i32) -> String {
+    if priority > 0 {
+        urgent_lane(queue)
+    } else {
+        queue.to_owned()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct CloudFSConf {
     pub mongo_url: String,
     pub mongo_dbname: String,
+    pub namespace: String,
+    /// Url of the object store keeping the files instead of any other, such as
+    /// `azure://<account>/<container>` or `gs://<bucket>`, see [`storage::open`].
+    pub storage_url: Option<String>,
+    /// Object store keeping the files instead of the GridFS, if given.
+    pub s3: Option<S3Conf>,
+    /// Mount point of the filesystem shared with the peers, keeping the files instead of the
+    /// GridFS or the object store, if given.
+    pub shared_fs: Option<PathBuf>,
+    /// Name of the dictionary on the storage compressing the small files uploaded, if any.
+    pub dictionary: Option<String>,
+    /// Members of the replica set of the mongodb the files of the GridFS are read from, such
+    /// as `nearest`, or only the primary if not given.
+    pub read_preference: Option<String>,
+    /// Key encrypting the files at rest, or stored in plaintext if not given.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Named keys encrypting the files of the params picking them instead of the default key.
+    pub encryption_keyring: HashMap<String, EncryptionKey>,
+    /// TLS of the connection to the mongodb, if not by its url alone.
+    pub mongo_tls: Option<TlsConf>,
 }
 
 impl CloudFSConf {
-    pub(crate) async fn client(&self) -> mongodb::Client {
-        mongodb::Client::with_uri_str(self.mongo_url.as_str())
-            .await
-            .unwrap()
+    pub(crate) async fn client(&self) -> anyhow::Result<mongodb::Client> {
+        let mongo_url = match &self.mongo_tls {
+            Some(tls) => tls.mongo_url(self.mongo_url.as_str()),
+            None => self.mongo_url.clone(),
+        };
+        Ok(mongodb::Client::with_uri_str(mongo_url.as_str()).await?)
+    }
+
+    pub(crate) async fn db(&self) -> anyhow::Result<mongodb::Database> {
+        Ok(self.client().await?.database(self.mongo_dbname.as_str()))
+    }
+
+    pub(crate) async fn grid_fs(&self) -> anyhow::Result<GridFSBucket> {
+        Ok(self.bucket_of(self.db().await?))
+    }
+
+    /// The GridFS read from the members of the replica set by the read preference, if any.
+    async fn replica_grid_fs(&self) -> anyhow::Result<Option<GridFSBucket>> {
+        let mode = match &self.read_preference {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+        let options = DatabaseOptions::builder()
+            .selection_criteria(SelectionCriteria::ReadPreference(parse_read_preference(
+                mode.as_str(),
+            )?))
+            .build();
+        let db = self
+            .client()
+            .await?
+            .database_with_options(self.mongo_dbname.as_str(), options);
+        Ok(Some(self.bucket_of(db)))
+    }
+
+    fn bucket_of(&self, db: mongodb::Database) -> GridFSBucket {
+        // each namespace owns a separated bucket, so that files never cross namespaces
+        let options = if self.namespace.is_empty() {
+            None
+        } else {
+            Some(
+                GridFSBucketOptions::builder()
+                    .bucket_name(self.namespace.clone())
+                    .build(),
+            )
+        };
+        db.bucket(options)
     }
 
-    pub(crate) async fn db(&self) -> mongodb::Database {
-        self.client().await.database(self.mongo_dbname.as_str())
+    /// The storage of the files, which is the object store at the storage url, the shared
+    /// filesystem, or the S3 object store if configured, or else the GridFS, compressing the
+    /// small files by the dictionary if configured, and decompressing the compressed files,
+    /// which are encrypted by the key if configured, or by the key of the keyring picked.
+    pub(crate) async fn storage(&self) -> anyhow::Result<Storage> {
+        let storage = Arc::new(
+            EncryptingStorage::new(self.raw_storage().await?, self.encryption_key.clone())
+                .with_keyring(self.encryption_keyring.clone()),
+        );
+        let storage = Arc::new(CompressingStorage::new(storage, None));
+        Ok(Arc::new(DictionaryStorage::new(
+            storage,
+            self.dictionary.clone(),
+        )))
     }
 
-    pub(crate) async fn grid_fs(&self) -> GridFSBucket {
-        self.db().await.bucket(None)
+    /// The storage of the files as is, where the dictionaries themselves are kept, reading
+    /// them from the replicas if configured.
+    pub(crate) async fn raw_storage(&self) -> anyhow::Result<Storage> {
+        let namespace = self.namespace.as_str();
+        if let Some(url) = &self.storage_url {
+            return storage::open(url, namespace);
+        }
+        let (primary, replica): (Storage, Option<Storage>) = match (&self.shared_fs, &self.s3) {
+            (Some(root), _) => (
+                Arc::new(SharedFsStorage::new(root.clone(), namespace)),
+                None,
+            ),
+            (None, Some(s3)) => (
+                Arc::new(S3Storage::new(s3.clone(), namespace)),
+                s3.replica()
+                    .map(|replica| -> Storage { Arc::new(S3Storage::new(replica, namespace)) }),
+            ),
+            (None, None) => (
+                GridFsStorage::shared(self.grid_fs().await?),
+                self.replica_grid_fs().await?.map(GridFsStorage::shared),
+            ),
+        };
+        Ok(match replica {
+            Some(replica) => Arc::new(ReplicaStorage::new(primary, replica)),
+            None => primary,
+        })
+    }
+
+    /// Whether the files are kept in the GridFS.
+    pub(crate) fn is_grid_fs(&self) -> bool {
+        self.storage_url.is_none() && self.shared_fs.is_none() && self.s3.is_none()
+    }
+
+    pub(crate) async fn tasks(&self) -> anyhow::Result<TaskHistory> {
+        Ok(TaskHistory::new(
+            self.db()
+                .await?
+                .collection(self.collection("tasks").as_str()),
+        ))
+    }
+
+    /// The catalog of the artifacts kept in the deployment, see [`crate::catalog`].
+    pub(crate) async fn artifacts(&self) -> anyhow::Result<MongoCatalog> {
+        Ok(MongoCatalog::new(
+            self.db()
+                .await?
+                .collection(self.collection("artifacts").as_str()),
+        ))
+    }
+
+    pub(crate) async fn schedules(&self) -> anyhow::Result<Schedules> {
+        Ok(Schedules::new(
+            self.db()
+                .await?
+                .collection(self.collection("schedules").as_str()),
+        ))
+    }
+
+    pub(crate) async fn workers(&self) -> anyhow::Result<WorkerRegistry> {
+        Ok(WorkerRegistry::new(
+            self.db()
+                .await?
+                .collection(self.collection("workers").as_str()),
+        ))
+    }
+
+    /// The collection of the command palettes distributed through the cloud.
+    pub(crate) async fn palettes(&self) -> anyhow::Result<Collection<Document>> {
+        Ok(self
+            .db()
+            .await?
+            .collection(self.collection("palettes").as_str()))
+    }
+
+    pub(crate) async fn outputs(&self) -> anyhow::Result<OutputStreams> {
+        Ok(OutputStreams::new(
+            self.db()
+                .await?
+                .collection(self.collection("outputs").as_str()),
+        ))
+    }
+
+    /// Total size of the files stored in the GridFS bucket of the namespace.
+    pub(crate) async fn storage_size(&self) -> anyhow::Result<u64> {
+        let mut cursor = self
+            .bucket_files()
+            .await?
+            .aggregate(
+                [doc! { "$group": { "_id": null, "size": { "$sum": "$length" } } }],
+                None,
+            )
+            .await?;
+        Ok(match cursor.try_next().await? {
+            Some(group) => match group.get("size") {
+                Some(Bson::Int32(size)) => *size as u64,
+                Some(Bson::Int64(size)) => *size as u64,
+                Some(Bson::Double(size)) => *size as u64,
+                _ => 0,
+            },
+            None => 0,
+        })
+    }
+
+    /// The raw collection of the file documents of the bucket.
+    pub(crate) async fn bucket_files(&self) -> anyhow::Result<Collection<Document>> {
+        let name = format!("{}.files", self.bucket_name());
+        Ok(self.db().await?.collection(name.as_str()))
+    }
+
+    /// The raw collection of the chunks of the files of the bucket.
+    pub(crate) async fn bucket_chunks(&self) -> anyhow::Result<Collection<Document>> {
+        let name = format!("{}.chunks", self.bucket_name());
+        Ok(self.db().await?.collection(name.as_str()))
+    }
+
+    fn bucket_name(&self) -> &str {
+        if self.namespace.is_empty() {
+            "fs"
+        } else {
+            self.namespace.as_str()
+        }
+    }
+
+    fn collection(&self, name: &str) -> String {
+        if self.namespace.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}.{}", self.namespace, name)
+        }
     }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct CmdProxyClientConfFile {
-    pub redis_url: String,
+    /// Url of the broker, either `redis://...` or `amqp://...`
+    #[serde(alias = "redis_url")]
+    pub broker_url: String,
     pub mongo_url: String,
     pub mongo_dbname: String,
+    #[serde(default)]
+    pub queue_prefix: String,
+    /// Namespace isolating queues and cloud files from other deployments on the same infra
+    #[serde(default)]
+    pub namespace: String,
+    /// Stable identity of the client used in place of its hostname, which may change, e.g.,
+    /// across containers
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Url of the object store keeping the files instead of the GridFS, such as
+    /// `azure://<account>/<container>` or `gs://<bucket>`
+    #[serde(default)]
+    pub storage_url: Option<String>,
+    /// Object store keeping the files instead of the GridFS
+    #[serde(default)]
+    pub s3: Option<S3Conf>,
+    /// Mount point of the filesystem shared with the peers, on which the local files are
+    /// passed by their paths instead of being transferred
+    #[serde(default)]
+    pub shared_fs: Option<PathBuf>,
+    /// Name of the dictionary on the storage compressing the small files uploaded
+    #[serde(default)]
+    pub dictionary: Option<String>,
+    /// Members of the replica set of the mongodb the files of the GridFS are read from, such
+    /// as `nearest`, or only the primary if not given
+    #[serde(default)]
+    pub read_preference: Option<String>,
+    /// How the connection to the broker is retried once lost
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
+    /// Template of the queues, such as `{namespace}-{command}`, in place of the namespace
+    /// and the prefix
+    #[serde(default)]
+    pub queue_template: Option<String>,
+    /// Name the runs are sent under, `run` by default, which must be the same for the
+    /// clients and the servers
+    #[serde(default)]
+    pub task_name: Option<String>,
+    /// Database of the mongodb keeping the results of the runs, apart from those of the other
+    /// deployments on the same mongodb, or the one of the namespace if not given
+    #[serde(default)]
+    pub result_db: Option<String>,
+    /// TLS of the connection to the broker, such as to a managed one requiring it
+    #[serde(default)]
+    pub broker_tls: Option<TlsConf>,
+    /// TLS of the connections to the mongodb, such as to a managed one requiring it
+    #[serde(default)]
+    pub mongo_tls: Option<TlsConf>,
+    /// Key encrypting the files at rest, which must be the same for the clients and the
+    /// servers
+    #[serde(default, skip)]
+    pub encryption_key: Option<EncryptionKey>,
+    /// Named keys encrypting the files of the params picking them, which must be the same
+    /// for the clients and the servers
+    #[serde(default, skip)]
+    pub encryption_keyring: HashMap<String, EncryptionKey>,
+    /// Path of the secret signing the requests, which must be the same for the clients and
+    /// the servers
+    #[serde(default, skip_serializing)]
+    pub request_signing: Option<HmacAuth>,
+    /// Path of the secret of the operators signing the approvals, see [`crate::approval`]
+    #[serde(default, skip_serializing)]
+    pub approval_signing: Option<HmacAuth>,
+    /// Template of the hostnames in the cloud urls of the local files, such as
+    /// `{namespace}/{command}/{date}/{client}`, in place of the client id
+    #[serde(default)]
+    pub hostname_template: Option<String>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct CmdProxyServerConfFile {
-    pub redis_url: String,
+    /// Url of the broker, either `redis://...` or `amqp://...`
+    #[serde(alias = "redis_url")]
+    pub broker_url: String,
     pub mongo_url: String,
     pub mongo_dbname: String,
-    pub command_palette: Option<PathBuf>,
+    pub command_palette: Option<PaletteSource>,
+    /// Key trusted to sign the command palette, which must be signed if given
+    #[serde(default, skip)]
+    pub palette_key: Option<PaletteKey>,
+    #[serde(default)]
+    pub queue_prefix: String,
+    /// Namespace isolating queues and cloud files from other deployments on the same infra
+    #[serde(default)]
+    pub namespace: String,
+    /// Queue of the transfer workers staging the files, if transferring is delegated
+    #[serde(default)]
+    pub transfer_queue: Option<String>,
+    /// Folder shared with the transfer workers, where the workspaces of the runs are put
+    #[serde(default)]
+    pub shared_dir: Option<PathBuf>,
+    /// Seconds after which a workspace is removed by force, even if its run is not done, or
+    /// never if not given
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub max_workspace_lifetime: Option<u64>,
+    /// Seconds after which the outputs staged by the crashed runs are deleted, or never if not
+    /// given
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub sweep_staged_after: Option<u64>,
+    /// Seconds the processes left by a command, such as the daemons it forks, may run on
+    /// after it exits before being killed, or never supervised if not given
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub group_grace: Option<u64>,
+    /// Seconds a command cancelled or timed out is given to exit on SIGTERM before being
+    /// killed, 10 by default
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub kill_grace: Option<u64>,
+    /// Bytes of the cores dumped by the commands to upload for the post-mortems attached to
+    /// the responses, or never uploaded if not given
+    #[serde(default, deserialize_with = "units::deserialize_opt_size")]
+    pub core_bytes: Option<u64>,
+    /// Cgroup v2 delegated to the worker, under which a cgroup of its own is made for the
+    /// command of each run limited in memory, or the memory is limited by rlimits if not given
+    #[serde(default)]
+    pub cgroup_root: Option<PathBuf>,
+    /// How the worker checks itself periodically by canary runs, or never if not given
+    #[serde(default)]
+    pub canary: Option<CanaryPolicy>,
+    /// Refuse to run the commands not sandboxed in containers by their palette entries
+    #[serde(default)]
+    pub require_container: bool,
+    /// Which commands out of the palette may be run, or any if not given
+    #[serde(default)]
+    pub command_policy: Option<CommandPolicy>,
+    /// Preserve the ownership of the inputs as sent by the clients, if asked to preserve
+    #[serde(default)]
+    pub preserve_ownership: bool,
+    /// Thresholds of the service level watched by the worker, or none if not given
+    #[serde(default)]
+    pub sla: Option<SlaPolicy>,
+    /// What must be left on the host for a run to be admitted, or anything goes if not given
+    #[serde(default)]
+    pub admission: Option<AdmissionPolicy>,
+    /// How the runs of low priority give way to the urgent ones, or never if not given
+    #[serde(default)]
+    pub preemption: Option<PreemptionPolicy>,
+    /// How the runs of the tenants share the worker, or first come first served if not given
+    #[serde(default)]
+    pub fair_share: Option<FairSharePolicy>,
+    /// Tags of the worker, deciding which commands in the palette it serves
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Names of the pre-processors applied in order to the requests of each queue, told by the
+    /// commands named by the queues, and all of which apply to the commands out of the palette
+    #[serde(default)]
+    pub pre_processors: HashMap<String, Vec<String>>,
+    /// Max numbers of retries by the classes of failures, overriding the defaults
+    #[serde(default)]
+    pub retry: HashMap<FailureClass, u32>,
+    /// Cap on the size of a serialized request, overriding the default
+    #[serde(default, deserialize_with = "units::deserialize_opt_size")]
+    pub max_request_bytes: Option<usize>,
+    /// Cap on the size of a serialized response, overriding the default
+    #[serde(default, deserialize_with = "units::deserialize_opt_size")]
+    pub max_response_bytes: Option<usize>,
+    /// Composite commands defined besides the palette, by their names
+    #[serde(default)]
+    pub composites: HashMap<String, Vec<CompositeStep>>,
+    /// Url of the object store keeping the files instead of the GridFS, such as
+    /// `azure://<account>/<container>` or `gs://<bucket>`
+    #[serde(default)]
+    pub storage_url: Option<String>,
+    /// Object store keeping the files instead of the GridFS
+    #[serde(default)]
+    pub s3: Option<S3Conf>,
+    /// Mount point of the filesystem shared with the peers, on which the local files are
+    /// passed by their paths instead of being transferred
+    #[serde(default)]
+    pub shared_fs: Option<PathBuf>,
+    /// Name of the dictionary on the storage compressing the small files uploaded
+    #[serde(default)]
+    pub dictionary: Option<String>,
+    /// Members of the replica set of the mongodb the files of the GridFS are read from, such
+    /// as `nearest`, or only the primary if not given
+    #[serde(default)]
+    pub read_preference: Option<String>,
+    /// How the connection to the broker is retried once lost
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
+    /// Template of the queues, such as `{namespace}-{command}`, in place of the namespace
+    /// and the prefix
+    #[serde(default)]
+    pub queue_template: Option<String>,
+    /// Name the runs are sent under, `run` by default, which must be the same for the
+    /// clients and the servers
+    #[serde(default)]
+    pub task_name: Option<String>,
+    /// Database of the mongodb keeping the results of the runs, apart from those of the other
+    /// deployments on the same mongodb, or the one of the namespace if not given
+    #[serde(default)]
+    pub result_db: Option<String>,
+    /// TLS of the connection to the broker, such as to a managed one requiring it
+    #[serde(default)]
+    pub broker_tls: Option<TlsConf>,
+    /// TLS of the connections to the mongodb, such as to a managed one requiring it
+    #[serde(default)]
+    pub mongo_tls: Option<TlsConf>,
+    /// Key encrypting the files at rest, which must be the same for the clients and the
+    /// servers
+    #[serde(default, skip)]
+    pub encryption_key: Option<EncryptionKey>,
+    /// Named keys encrypting the files of the params picking them, which must be the same
+    /// for the clients and the servers
+    #[serde(default, skip)]
+    pub encryption_keyring: HashMap<String, EncryptionKey>,
+    /// Path of the secret signing the requests, which must be the same for the clients and
+    /// the servers
+    #[serde(default, skip_serializing)]
+    pub request_signing: Option<HmacAuth>,
+    /// Path of the secret of the operators the approvals are signed by, without which the
+    /// runs requiring approvals are refused, see [`crate::approval`]
+    #[serde(default, skip_serializing)]
+    pub approval_signing: Option<HmacAuth>,
 }
 
 pub struct CmdProxyClientConf {
     pub celery: CeleryConf,
     pub cloud: CloudFSConf,
+    /// Identity of the client in the cloud urls and the task history, free of colons, which
+    /// end the hostnames of the urls.
+    pub client_id: String,
+    /// Template of the hostnames in the cloud urls, see [`crate::naming`].
+    pub hostname_template: Option<String>,
+    /// Secret signing the requests sent, or sent unsigned if not given.
+    pub request_signing: Option<HmacAuth>,
+    /// Secret of the operators signing the approvals, without which nothing is approved.
+    pub approval_signing: Option<HmacAuth>,
 }
 
 impl CmdProxyClientConf {
     pub fn new(conf: CmdProxyClientConfFile) -> CmdProxyClientConf {
         CmdProxyClientConf {
             celery: CeleryConf {
-                broker_url: conf.redis_url,
+                broker_url: conf.broker_url,
                 backend_url: conf.mongo_url.clone(),
+                queue_prefix: conf.queue_prefix,
+                namespace: conf.namespace.clone(),
+                reconnect: conf.reconnect,
+                queue_template: conf.queue_template,
+                broker_tls: conf.broker_tls,
+                backend_tls: conf.mongo_tls.clone(),
+                task_name: conf
+                    .task_name
+                    .unwrap_or_else(|| broker::DEFAULT_TASK_NAME.to_owned()),
+                result_db: conf.result_db,
             },
             cloud: CloudFSConf {
                 mongo_url: conf.mongo_url,
                 mongo_dbname: conf.mongo_dbname,
+                namespace: conf.namespace,
+                storage_url: conf.storage_url,
+                s3: conf.s3,
+                shared_fs: conf.shared_fs,
+                dictionary: conf.dictionary,
+                read_preference: conf.read_preference,
+                encryption_key: conf.encryption_key,
+                encryption_keyring: conf.encryption_keyring,
+                mongo_tls: conf.mongo_tls,
             },
+            client_id: naming::as_hostname(conf.client_id.unwrap_or_else(local_hostname).as_str()),
+            hostname_template: conf.hostname_template,
+            request_signing: conf.request_signing,
+            approval_signing: conf.approval_signing,
+        }
+    }
+
+    /// Hostname in the cloud urls of the local files of a run of `command` submitted now.
+    pub(crate) fn hostname(&self, command: &str) -> String {
+        match &self.hostname_template {
+            Some(tmpl) => naming::hostname(
+                tmpl,
+                self.celery.namespace.as_str(),
+                self.client_id.as_str(),
+                command,
+                chrono::Utc::now(),
+            ),
+            None => self.client_id.clone(),
+        }
+    }
+}
+
+/// An entry of the command palette: the path to the command on the server, optionally with
+/// the heuristics the clients use to convert its raw arguments into params, and the tags a
+/// worker must have to serve it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PaletteEntry {
+    Path(String),
+    Detailed {
+        path: String,
+        #[serde(default)]
+        heuristics: ParamHeuristics,
+        /// Tags such as `gpu` or `os=linux`, all of which the worker must have.
+        #[serde(default)]
+        requires: Vec<String>,
+        /// Serve the command by a pool of warm daemons instead of spawning it per request.
+        #[serde(default)]
+        warm: Option<WarmConf>,
+        /// Coalesce the requests arriving close together into one invocation.
+        #[serde(default)]
+        batch: Option<BatchConf>,
+        /// Run the command inside a container of an image, see [`crate::container`].
+        #[serde(default)]
+        container: Option<ContainerConf>,
+    },
+    /// Other commands of the palette run in turn, see [`crate::composite`].
+    Composite {
+        steps: Vec<CompositeStep>,
+        #[serde(default)]
+        requires: Vec<String>,
+    },
+}
+
+impl PaletteEntry {
+    /// Path to the command, or none if composite.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            PaletteEntry::Path(path) => Some(path),
+            PaletteEntry::Detailed { path, .. } => Some(path),
+            PaletteEntry::Composite { .. } => None,
+        }
+    }
+
+    pub fn heuristics(&self) -> ParamHeuristics {
+        match self {
+            PaletteEntry::Detailed { heuristics, .. } => heuristics.clone(),
+            _ => ParamHeuristics::default(),
+        }
+    }
+
+    pub fn warm(&self) -> Option<WarmConf> {
+        match self {
+            PaletteEntry::Detailed { warm, .. } => *warm,
+            _ => None,
+        }
+    }
+
+    pub fn batch(&self) -> Option<BatchConf> {
+        match self {
+            PaletteEntry::Detailed { batch, .. } => *batch,
+            _ => None,
+        }
+    }
+
+    pub fn container(&self) -> Option<ContainerConf> {
+        match self {
+            PaletteEntry::Detailed { container, .. } => container.clone(),
+            _ => None,
+        }
+    }
+
+    pub fn steps(&self) -> Option<&[CompositeStep]> {
+        match self {
+            PaletteEntry::Composite { steps, .. } => Some(steps),
+            _ => None,
+        }
+    }
+
+    /// Whether a worker of the tags can serve the command.
+    pub fn is_served_by(&self, tags: &HashSet<String>) -> bool {
+        match self {
+            PaletteEntry::Path(_) => true,
+            PaletteEntry::Detailed { requires, .. } | PaletteEntry::Composite { requires, .. } => {
+                requires.iter().all(|tag| tags.contains(tag))
+            }
         }
     }
 }
 
+pub(crate) fn load_command_palette(path: &Path) -> anyhow::Result<HashMap<String, PaletteEntry>> {
+    Ok(std::fs::read_to_string(path)?.as_bytes().de_yaml()?)
+}
+
 #[derive(Clone, Debug)]
 pub struct CmdProxyServerConf {
     pub(crate) celery: CeleryConf,
     pub(crate) cloud: CloudFSConf,
-    pub command_palette: HashMap<String, String>,
-    pub command_palette_path: Option<PathBuf>,
+    /// Paths of the commands by their names, swapped as a whole on reload.
+    command_palette: Arc<RwLock<HashMap<String, String>>>,
+    /// Confs of the commands kept warm, by their paths, swapped together with the palette.
+    warm_commands: Arc<RwLock<HashMap<String, WarmConf>>>,
+    /// Confs of the batchable commands, by their paths, swapped together with the palette.
+    batch_commands: Arc<RwLock<HashMap<String, BatchConf>>>,
+    /// Steps of the composite commands, by their paths, swapped together with the palette.
+    composite_commands: Arc<RwLock<HashMap<String, Vec<ResolvedStep>>>>,
+    /// Confs of the commands run in containers, by their paths, swapped together with the
+    /// palette.
+    container_commands: Arc<RwLock<HashMap<String, ContainerConf>>>,
+    pub palette_source: Option<PaletteSource>,
+    pub palette_key: Option<PaletteKey>,
+    /// Tags of the worker, including the implied `os=<os>` and `arch=<arch>`.
+    pub tags: HashSet<String>,
+    pub transfer: Option<TransferConf>,
+    /// Seconds after which a workspace is removed by force, or never if not given.
+    pub max_workspace_lifetime: Option<u64>,
+    /// Seconds after which the outputs staged by the crashed runs are deleted, if swept.
+    pub sweep_staged_after: Option<u64>,
+    /// Seconds the processes left by a command may run on after it exits, if supervised.
+    pub group_grace: Option<u64>,
+    /// Seconds a command cancelled or timed out is given to exit on SIGTERM, if not default.
+    pub kill_grace: Option<u64>,
+    /// Bytes of the cores dumped by the commands to upload for the post-mortems, if any.
+    pub core_bytes: Option<u64>,
+    /// Cgroup v2 delegated to the worker for limiting the memory of the commands, if any.
+    pub cgroup_root: Option<PathBuf>,
+    pub canary: Option<CanaryPolicy>,
+    /// Whether the commands not run in containers are refused.
+    pub require_container: bool,
+    pub command_policy: Option<CommandPolicy>,
+    /// Whether the ownership of the inputs sent by the clients is preserved.
+    pub preserve_ownership: bool,
+    pub sla: Option<SlaPolicy>,
+    pub admission: Option<AdmissionPolicy>,
+    pub preemption: Option<PreemptionPolicy>,
+    pub fair_share: Option<FairSharePolicy>,
+    /// Names of the pre-processors by the queues, prefixed already.
+    pub pre_processors: HashMap<String, Vec<String>>,
+    /// How the runs failed by the infrastructure are retried on this worker.
+    pub retry: RetryPolicies,
+    pub payload_limits: PayloadLimits,
+    /// Composite commands defined besides the palette, taking precedence over its entries.
+    pub composites: HashMap<String, Vec<CompositeStep>>,
+    /// Secret the requests must be signed by, or accepted unsigned if not given.
+    pub request_signing: Option<HmacAuth>,
+    /// Secret the approvals must be signed by, or the runs requiring them are refused.
+    pub approval_signing: Option<HmacAuth>,
+}
+
+/// Where a worker delegates the transfers of its runs to.
+#[derive(Clone, Debug)]
+pub struct TransferConf {
+    /// Queue of the transfer workers, not prefixed yet.
+    pub queue: String,
+    /// Folder on the storage shared with the transfer workers.
+    pub shared_dir: PathBuf,
 }
 
 impl CmdProxyServerConf {
+    /// Make the conf, with an empty command palette until [`Self::reload_palette`].
     pub fn new(conf: CmdProxyServerConfFile) -> CmdProxyServerConf {
-        let command_palette = conf
-            .command_palette
-            .as_ref()
-            .and_then(|p| {
-                if p.exists() {
-                    Some(
-                        std::fs::read_to_string(p)
-                            .unwrap()
-                            .as_bytes()
-                            .de_yaml()
-                            .unwrap(),
-                    )
-                } else {
-                    None
-                }
-            })
-            .unwrap_or_default();
+        let celery = CeleryConf {
+            broker_url: conf.broker_url,
+            backend_url: conf.mongo_url.clone(),
+            queue_prefix: conf.queue_prefix,
+            namespace: conf.namespace.clone(),
+            reconnect: conf.reconnect,
+            queue_template: conf.queue_template,
+            broker_tls: conf.broker_tls,
+            backend_tls: conf.mongo_tls.clone(),
+            task_name: conf
+                .task_name
+                .unwrap_or_else(|| broker::DEFAULT_TASK_NAME.to_owned()),
+            result_db: conf.result_db,
+        };
+        let pre_processors = conf
+            .pre_processors
+            .into_iter()
+            .map(|(queue, names)| (celery.queue(queue.as_str()), names))
+            .collect();
 
         CmdProxyServerConf {
-            celery: CeleryConf {
-                broker_url: conf.redis_url,
-                backend_url: conf.mongo_url.clone(),
-            },
+            celery,
             cloud: CloudFSConf {
                 mongo_url: conf.mongo_url,
                 mongo_dbname: conf.mongo_dbname,
+                namespace: conf.namespace,
+                storage_url: conf.storage_url,
+                s3: conf.s3,
+                shared_fs: conf.shared_fs,
+                dictionary: conf.dictionary,
+                read_preference: conf.read_preference,
+                encryption_key: conf.encryption_key,
+                encryption_keyring: conf.encryption_keyring,
+                mongo_tls: conf.mongo_tls,
+            },
+            command_palette: Arc::default(),
+            warm_commands: Arc::default(),
+            batch_commands: Arc::default(),
+            composite_commands: Arc::default(),
+            container_commands: Arc::default(),
+            palette_source: conf.command_palette,
+            palette_key: conf.palette_key,
+            tags: conf
+                .tags
+                .into_iter()
+                .chain([
+                    format!("os={}", std::env::consts::OS),
+                    format!("arch={}", std::env::consts::ARCH),
+                ])
+                .collect(),
+            transfer: conf
+                .transfer_queue
+                .zip(conf.shared_dir)
+                .map(|(queue, shared_dir)| TransferConf { queue, shared_dir }),
+            max_workspace_lifetime: conf.max_workspace_lifetime,
+            sweep_staged_after: conf.sweep_staged_after,
+            group_grace: conf.group_grace,
+            kill_grace: conf.kill_grace,
+            core_bytes: conf.core_bytes,
+            cgroup_root: conf.cgroup_root,
+            canary: conf.canary,
+            require_container: conf.require_container,
+            command_policy: conf.command_policy,
+            preserve_ownership: conf.preserve_ownership,
+            sla: conf.sla,
+            admission: conf.admission,
+            preemption: conf.preemption,
+            fair_share: conf.fair_share,
+            pre_processors,
+            retry: RetryPolicies::default().with_max_retries(&conf.retry),
+            payload_limits: PayloadLimits {
+                max_request_bytes: conf
+                    .max_request_bytes
+                    .unwrap_or(PayloadLimits::default().max_request_bytes),
+                max_response_bytes: conf
+                    .max_response_bytes
+                    .unwrap_or(PayloadLimits::default().max_response_bytes),
             },
-            command_palette,
-            command_palette_path: conf.command_palette,
+            composites: conf.composites,
+            request_signing: conf.request_signing,
+            approval_signing: conf.approval_signing,
+        }
+    }
+
+    /// A snapshot of the command palette.
+    pub fn command_palette(&self) -> HashMap<String, String> {
+        self.command_palette.read().unwrap().clone()
+    }
+
+    /// A snapshot of the commands kept warm.
+    pub fn warm_commands(&self) -> HashMap<String, WarmConf> {
+        self.warm_commands.read().unwrap().clone()
+    }
+
+    /// A snapshot of the batchable commands.
+    pub fn batch_commands(&self) -> HashMap<String, BatchConf> {
+        self.batch_commands.read().unwrap().clone()
+    }
+
+    /// A snapshot of the composite commands.
+    pub(crate) fn composite_commands(&self) -> HashMap<String, Vec<ResolvedStep>> {
+        self.composite_commands.read().unwrap().clone()
+    }
+
+    /// A snapshot of the commands run in containers.
+    pub fn container_commands(&self) -> HashMap<String, ContainerConf> {
+        self.container_commands.read().unwrap().clone()
+    }
+
+    /// Where the workspaces of the runs are put, which is the folder shared with the transfer
+    /// workers if any, since they can only reach the workspaces there.
+    pub(crate) fn workspace_root(&self) -> PathBuf {
+        match &self.transfer {
+            Some(transfer) => transfer.shared_dir.clone(),
+            None => std::env::temp_dir(),
         }
     }
+
+    /// Fetch the command palette from its source again, and take it in place of the old one
+    /// only if it is fetched and trusted.
+    ///
+    /// Only the commands whose requirements are satisfied by the tags of this worker are
+    /// taken, so that one palette can be shared by different kinds of workers. So are the
+    /// composite commands, whose steps must all be taken as well.
+    ///
+    /// The commands are also exported as environment variables, so that their paths can be
+    /// resolved via [`crate::params::Param::EnvParam`].
+    pub async fn reload_palette(&self) -> anyhow::Result<()> {
+        let source = match &self.palette_source {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        let mut palette = source.load(&self.cloud, self.palette_key.as_ref()).await?;
+        palette.extend(self.composites.iter().map(|(name, steps)| {
+            let entry = PaletteEntry::Composite {
+                steps: steps.clone(),
+                requires: vec![],
+            };
+            (name.clone(), entry)
+        }));
+        let entries: Vec<_> = palette
+            .into_iter()
+            .filter(|(name, entry)| {
+                let served = entry.is_served_by(&self.tags);
+                if !served {
+                    debug!("Skip command {} requiring tags this worker lacks", name);
+                }
+                served
+            })
+            .collect();
+        let warm_commands = entries
+            .iter()
+            .filter_map(|(_, entry)| Some((entry.path()?.to_owned(), entry.warm()?)))
+            .collect();
+        let batch_commands = entries
+            .iter()
+            .filter_map(|(_, entry)| Some((entry.path()?.to_owned(), entry.batch()?)))
+            .collect();
+        let container_commands = entries
+            .iter()
+            .filter_map(|(_, entry)| Some((entry.path()?.to_owned(), entry.container()?)))
+            .collect();
+        let mut command_palette: HashMap<_, _> = entries
+            .iter()
+            .filter_map(|(name, entry)| Some((name.clone(), entry.path()?.to_owned())))
+            .collect();
+
+        // only the real commands can be resolved via the environment variables
+        command_palette
+            .iter()
+            .for_each(|(key, val)| std::env::set_var(key, val));
+
+        let mut composite_commands = HashMap::new();
+        for (name, entry) in &entries {
+            let steps = match entry.steps() {
+                Some(steps) => steps,
+                None => continue,
+            };
+            let resolved: Option<Vec<_>> = steps
+                .iter()
+                .map(|step| {
+                    Some(ResolvedStep {
+                        path: command_palette.get(step.command.as_str())?.clone(),
+                        args: step.args.clone(),
+                    })
+                })
+                .collect();
+            match resolved {
+                Some(resolved) => {
+                    let path = composite::path_of(name);
+                    command_palette.insert(name.clone(), path.clone());
+                    composite_commands.insert(path, resolved);
+                }
+                None => warn!("Skip composite command {} with steps not served here", name),
+            }
+        }
+
+        *self.command_palette.write().unwrap() = command_palette;
+        *self.warm_commands.write().unwrap() = warm_commands;
+        *self.batch_commands.write().unwrap() = batch_commands;
+        *self.composite_commands.write().unwrap() = composite_commands;
+        *self.container_commands.write().unwrap() = container_commands;
+        Ok(())
+    }
 }
@@ -0,0 +1,206 @@
+//! Sandboxed execution of the commands inside containers, by Docker or Podman.
+//!
+//! A command whose palette entry names an image is run as `<runtime> run --rm` of the image
+//! instead of on the worker host, such as
+//!
+//! ```yaml
+//! convert:
+//!   path: /usr/bin/convert
+//!   container:
+//!     image: dpokidov/imagemagick:7.1
+//! ```
+//!
+//! where the path is the one of the command inside the image. The workspace of the run is
+//! bind-mounted at the same path inside the container, so that the params resolved to the
+//! local files of the workspace stay valid, and the command runs as the user of the worker
+//! so that its outputs can be collected. The container has no network unless configured to,
+//! and sees only the environment variables of the request.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::limits::{LimitExceeded, ResourceLimits};
+use crate::protocol::ExitStatus;
+
+/// How a command is sandboxed, as configured in its palette entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerConf {
+    /// Image to run the command in.
+    pub image: String,
+    /// Container runtime, either `docker` or `podman`, or anything taking the same args.
+    #[serde(default = "ContainerConf::default_runtime")]
+    pub runtime: String,
+    /// Network to attach the container to, or `none` to cut it off.
+    #[serde(default = "ContainerConf::default_network")]
+    pub network: String,
+    /// Extra args of `run`, such as `--cpus=2` or `--read-only`.
+    #[serde(default)]
+    pub run_args: Vec<String>,
+}
+
+impl ContainerConf {
+    fn default_runtime() -> String {
+        "docker".to_owned()
+    }
+
+    fn default_network() -> String {
+        "none".to_owned()
+    }
+}
+
+/// The uid and gid of the worker, which the commands in the containers run as.
+static USER: Lazy<Option<String>> = Lazy::new(|| {
+    let id = |flag: &str| {
+        let output = std::process::Command::new("id").arg(flag).output().ok()?;
+        let id = String::from_utf8(output.stdout).ok()?;
+        output.status.success().then(|| id.trim().to_owned())
+    };
+    Some(format!("{}:{}", id("-u")?, id("-g")?))
+});
+
+/// The container of the command of a run.
+pub(crate) struct Container {
+    conf: ContainerConf,
+    name: String,
+    limits: Option<ResourceLimits>,
+}
+
+impl Container {
+    pub(crate) fn new(conf: ContainerConf, task_id: &str, limits: Option<ResourceLimits>) -> Self {
+        Container {
+            conf,
+            name: format!("cmdproxy-{}", task_id),
+            limits,
+        }
+    }
+
+    /// The program and the arguments running `command` with `args` in the container, with
+    /// `workspace` mounted, in the folder `cwd` if given or else in the workspace, and with the
+    /// variables named by `env`, whose values are taken from the environment of the runtime.
+    pub(crate) fn wrap(
+        &self,
+        command: &str,
+        args: &[String],
+        workspace: &Path,
+        cwd: Option<&str>,
+        env: &HashMap<String, String>,
+        interactive: bool,
+    ) -> (String, Vec<String>) {
+        let workspace = workspace.to_string_lossy();
+        let mut wrapped = vec!["run".to_owned(), "--rm".to_owned()];
+        wrapped.extend(["--name".to_owned(), self.name.clone()]);
+        wrapped.push(format!("--network={}", self.conf.network));
+        wrapped.extend(["-v".to_owned(), format!("{}:{}", workspace, workspace)]);
+        wrapped.extend([
+            "-w".to_owned(),
+            cwd.unwrap_or(workspace.as_ref()).to_owned(),
+        ]);
+        if interactive {
+            wrapped.push("-i".to_owned());
+        }
+        if let Some(user) = USER.as_ref() {
+            wrapped.extend(["--user".to_owned(), user.clone()]);
+        }
+        // only the names, so that the values are not exposed in the list of the processes
+        let mut keys: Vec<_> = env.keys().collect();
+        keys.sort();
+        for key in keys {
+            wrapped.extend(["-e".to_owned(), key.clone()]);
+        }
+        if let Some(limits) = self.limits {
+            if let Some(memory_bytes) = limits.memory_bytes {
+                wrapped.push(format!("--memory={}", memory_bytes));
+                wrapped.push(format!("--memory-swap={}", memory_bytes));
+            }
+            if let Some(cpu_seconds) = limits.cpu_seconds {
+                wrapped.push(format!("--ulimit=cpu={}:{}", cpu_seconds, cpu_seconds));
+            }
+            if let Some(max_files) = limits.max_files {
+                wrapped.push(format!("--ulimit=nofile={}:{}", max_files, max_files));
+            }
+        }
+        wrapped.extend(self.conf.run_args.iter().cloned());
+        wrapped.extend(["--entrypoint".to_owned(), command.to_owned()]);
+        wrapped.push(self.conf.image.clone());
+        wrapped.extend(args.iter().cloned());
+        (self.conf.runtime.clone(), wrapped)
+    }
+
+    /// Kill the container, which outlives the runtime client killed on cancelling the run.
+    pub(crate) async fn kill(&self) {
+        debug!("  remove container {}", self.name);
+        let res = tokio::process::Command::new(self.conf.runtime.as_str())
+            .args(["rm", "-f", self.name.as_str()])
+            .output()
+            .await;
+        match res {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => warn!(
+                "  failed to remove container {}: {}",
+                self.name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(err) => warn!("  failed to remove container {}: {}", self.name, err),
+        }
+    }
+
+    /// The limit the command finished by `status` was killed for exceeding, if any, as told
+    /// by the runtime exiting by 128 plus the signal killing the command.
+    pub(crate) fn exceeded(&self, status: &ExitStatus) -> Option<LimitExceeded> {
+        let limits = self.limits?;
+        match *status {
+            ExitStatus::Exited { code: 137 } if limits.memory_bytes.is_some() => {
+                Some(LimitExceeded::Memory)
+            }
+            ExitStatus::Exited { code: 152 } if limits.cpu_seconds.is_some() => {
+                Some(LimitExceeded::CpuTime)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let conf: ContainerConf = serde_yaml::from_str("image: alpine:3.18").unwrap();
+        assert_eq!(conf.runtime, "docker");
+        let limits = ResourceLimits {
+            memory_bytes: Some(1 << 30),
+            ..ResourceLimits::default()
+        };
+        let container = Container::new(conf, "42", Some(limits));
+        let env = HashMap::from([("TOKEN".to_owned(), "secret".to_owned())]);
+        let (program, args) = container.wrap(
+            "/bin/ls",
+            &["-l".to_owned()],
+            Path::new("/tmp/ws"),
+            None,
+            &env,
+            false,
+        );
+        assert_eq!(program, "docker");
+        assert_eq!(args[..4], ["run", "--rm", "--name", "cmdproxy-42"]);
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-v", "/tmp/ws:/tmp/ws"]));
+        assert!(args.windows(2).any(|pair| pair == ["-w", "/tmp/ws"]));
+        assert!(args.windows(2).any(|pair| pair == ["-e", "TOKEN"]));
+        assert!(!args.iter().any(|arg| arg.contains("secret")));
+        assert!(args.contains(&"--memory=1073741824".to_owned()));
+        assert_eq!(
+            args[args.len() - 4..],
+            ["--entrypoint", "/bin/ls", "alpine:3.18", "-l"]
+        );
+
+        let killed = ExitStatus::Exited { code: 137 };
+        assert_eq!(container.exceeded(&killed), Some(LimitExceeded::Memory));
+    }
+}
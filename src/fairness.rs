@@ -0,0 +1,231 @@
+//! Weighted-fair sharing of the run slots of a worker among the tenants of its queues.
+//!
+//! The worker runs at most as many commands at once as its slots, and the runs beyond wait.
+//! A freed slot goes to the waiting run of the tenant with the least running runs relative
+//! to its weight, or the earliest one among those of equal shares, so that a burst of one
+//! tenant only takes the slots no other tenant is waiting for. The runs of higher priorities
+//! go ahead of all the others, whatever their tenants.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// How the slots of the worker are shared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FairSharePolicy {
+    /// Max number of commands run at once.
+    pub slots: usize,
+    /// Label of the runs telling their tenants apart, such as `team`, or else the identity
+    /// of the client signing them, with the unsigned ones all of a single tenant.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Weights of the tenants, default to 1.
+    #[serde(default)]
+    pub weights: HashMap<String, u32>,
+}
+
+impl FairSharePolicy {
+    fn weight(&self, tenant: &str) -> u32 {
+        self.weights.get(tenant).copied().unwrap_or(1).max(1)
+    }
+}
+
+struct Waiter {
+    tenant: String,
+    priority: i32,
+    seq: u64,
+    granted: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    running: HashMap<String, usize>,
+    waiting: Vec<Waiter>,
+    next_seq: u64,
+}
+
+impl Scheduler {
+    fn total_running(&self) -> usize {
+        self.running.values().sum()
+    }
+
+    fn run(&mut self, tenant: &str) {
+        *self.running.entry(tenant.to_owned()).or_default() += 1;
+    }
+
+    /// Hand the free slots to the waiting runs in fair order.
+    fn dispatch(&mut self, policy: &FairSharePolicy) {
+        // the waiters having given up are dropped rather than granted
+        self.waiting.retain(|waiter| !waiter.granted.is_closed());
+        while self.total_running() < policy.slots && !self.waiting.is_empty() {
+            let running = |waiter: &Waiter| {
+                let running = self
+                    .running
+                    .get(&waiter.tenant)
+                    .copied()
+                    .unwrap_or_default();
+                running as u64
+            };
+            // compare the running runs over the weights without dividing them
+            let (i, _) = self
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| {
+                    let a_share = running(a) * policy.weight(&b.tenant) as u64;
+                    let b_share = running(b) * policy.weight(&a.tenant) as u64;
+                    b.priority
+                        .cmp(&a.priority)
+                        .then(a_share.cmp(&b_share))
+                        .then(a.seq.cmp(&b.seq))
+                })
+                .unwrap();
+            let waiter = self.waiting.swap_remove(i);
+            if waiter.granted.send(()).is_ok() {
+                self.run(&waiter.tenant);
+            }
+        }
+    }
+
+    fn release(&mut self, tenant: &str) {
+        if let Some(running) = self.running.get_mut(tenant) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(tenant);
+            }
+        }
+    }
+}
+
+static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(Mutex::default);
+
+/// A slot taken by a run, which is given back when dropped.
+pub(crate) struct Slot {
+    tenant: String,
+    policy: FairSharePolicy,
+}
+
+impl Slot {
+    /// Take a slot for a run of `tenant` at `priority`, waiting for its turn if all are taken.
+    pub(crate) async fn acquire(tenant: &str, priority: i32, policy: &FairSharePolicy) -> Slot {
+        let mut waiting = {
+            let mut scheduler = SCHEDULER.lock().unwrap();
+            let (sender, receiver) = oneshot::channel();
+            let seq = scheduler.next_seq;
+            scheduler.next_seq += 1;
+            scheduler.waiting.push(Waiter {
+                tenant: tenant.to_owned(),
+                priority,
+                seq,
+                granted: sender,
+            });
+            scheduler.dispatch(policy);
+            Waiting {
+                tenant: tenant.to_owned(),
+                policy: policy.clone(),
+                granted: receiver,
+            }
+        };
+        // the sender is only dropped once sent, as the waiter is kept while waited for
+        (&mut waiting.granted).await.ok();
+        Slot {
+            tenant: tenant.to_owned(),
+            policy: policy.clone(),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut scheduler = SCHEDULER.lock().unwrap();
+        scheduler.release(self.tenant.as_str());
+        scheduler.dispatch(&self.policy);
+    }
+}
+
+/// A run waiting for its slot, which gives the slot back if granted after giving up.
+struct Waiting {
+    tenant: String,
+    policy: FairSharePolicy,
+    granted: oneshot::Receiver<()>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        // a grant already awaited cannot be received again
+        if self.granted.try_recv().is_ok() {
+            let mut scheduler = SCHEDULER.lock().unwrap();
+            scheduler.release(self.tenant.as_str());
+            scheduler.dispatch(&self.policy);
+        }
+    }
+}
+
+/// Parse the weights of the tenants given as TENANT=WEIGHT.
+pub fn parse_weights(specs: &[String]) -> anyhow::Result<HashMap<String, u32>> {
+    let mut weights = HashMap::new();
+    for spec in specs {
+        let (tenant, weight) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expect TENANT=WEIGHT, got {}", spec))?;
+        let weight: u32 = weight.trim().parse()?;
+        anyhow::ensure!(weight > 0, "Weight of tenant {} must be positive", tenant);
+        weights.insert(tenant.trim().to_owned(), weight);
+    }
+    Ok(weights)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fair_share() {
+        let policy = FairSharePolicy {
+            slots: 2,
+            label: None,
+            weights: HashMap::from([("heavy".to_owned(), 2)]),
+        };
+        let burst_1 = Slot::acquire("burst", 0, &policy).await;
+        let burst_2 = Slot::acquire("burst", 0, &policy).await;
+
+        // all the slots are taken by the burst, which waits behind the other tenants
+        let mut burst_3 = Box::pin(Slot::acquire("burst", 0, &policy));
+        let mut other = Box::pin(Slot::acquire("other", 0, &policy));
+        assert!((&mut burst_3).now_or_never().is_none());
+        assert!((&mut other).now_or_never().is_none());
+
+        drop(burst_1);
+        let other = other.now_or_never().unwrap();
+        assert!((&mut burst_3).now_or_never().is_none());
+
+        // a tenant arriving later still goes ahead of the burst
+        let mut heavy = Box::pin(Slot::acquire("heavy", 0, &policy));
+        assert!((&mut heavy).now_or_never().is_none());
+        drop(other);
+        let heavy = heavy.now_or_never().unwrap();
+
+        // a given up waiter never takes a slot
+        drop(burst_3);
+        drop(heavy);
+        let burst_4 = Slot::acquire("burst", 0, &policy).now_or_never().unwrap();
+
+        // an urgent run goes ahead of the others, even of a tenant running more
+        let mut low = Box::pin(Slot::acquire("low", 0, &policy));
+        let mut urgent = Box::pin(Slot::acquire("burst", 5, &policy));
+        assert!((&mut low).now_or_never().is_none());
+        assert!((&mut urgent).now_or_never().is_none());
+        drop(burst_2);
+        let urgent = urgent.now_or_never().unwrap();
+        assert!((&mut low).now_or_never().is_none());
+
+        drop(burst_4);
+        let _low = low.now_or_never().unwrap();
+        drop(urgent);
+    }
+}
@@ -0,0 +1,141 @@
+//! Features of the protocol negotiated per request, so that the clients and the workers can
+//! evolve apart rather than in lockstep with [`crate::registry::PROTOCOL_VERSION`].
+//!
+//! A client lists in a request the features it can make use of, and among them those the run
+//! relies on, such as `archive-tar-zst` for the folders packed so. A worker takes the listed
+//! features it knows and ignores the others, but refuses the request with
+//! [`UnsupportedFeatures`] if it lacks any of the required ones, rather than running it in a
+//! way the client does not expect. The response lists the features the worker took.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::archive::ArchiveFormat;
+use crate::protocol::{RunRequest, Stdin};
+
+/// The outputs of the command are streamed live while it runs.
+pub const STREAMING_OUTPUT: &str = "streaming-output";
+/// The stdin of the command is given inline in the request.
+pub const INLINE_STDIN: &str = "inline-stdin";
+/// The folders are packed in tar.zst rather than zip.
+pub const ARCHIVE_TAR_ZST: &str = "archive-tar-zst";
+/// The preserved files keep their attributes across the transfers.
+pub const PRESERVE_ATTRS: &str = "preserve-attrs";
+/// The resources of the command are limited as requested.
+pub const RESOURCE_LIMITS: &str = "resource-limits";
+/// The outputs unchanged since the previous run are not transferred.
+pub const DELTA_OUTPUTS: &str = "delta-outputs";
+/// The files are compressed in transfer by gzip or zstd.
+pub const COMPRESSION: &str = "compression";
+/// The request is resolved but not run, reporting the recipe instead.
+pub const DRY_RUN: &str = "dry-run";
+
+/// The features this build supports.
+pub const SUPPORTED: [&str; 8] = [
+    STREAMING_OUTPUT,
+    INLINE_STDIN,
+    ARCHIVE_TAR_ZST,
+    PRESERVE_ATTRS,
+    RESOURCE_LIMITS,
+    DELTA_OUTPUTS,
+    COMPRESSION,
+    DRY_RUN,
+];
+
+/// Error of a request refused for requiring features the worker lacks.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedFeatures {
+    pub features: Vec<String>,
+}
+
+impl fmt::Display for UnsupportedFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Worker lacks the required features: {}",
+            self.features.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedFeatures {}
+
+/// List in the request the features of this build, requiring those the run relies on.
+pub(crate) fn advertise(request: &mut RunRequest) {
+    let mut required: BTreeSet<String> = request.required_features.drain(..).collect();
+    if matches!(request.stdin, Some(Stdin::Content(_))) {
+        required.insert(INLINE_STDIN.to_owned());
+    }
+    if request.archive == ArchiveFormat::TarZst {
+        required.insert(ARCHIVE_TAR_ZST.to_owned());
+    }
+    if !request.preserve.is_empty() {
+        required.insert(PRESERVE_ATTRS.to_owned());
+    }
+    if request.limits.is_some() {
+        required.insert(RESOURCE_LIMITS.to_owned());
+    }
+    if request.compression.is_some() {
+        required.insert(COMPRESSION.to_owned());
+    }
+    // a worker unaware of it would run the command for real
+    if request.dry_run {
+        required.insert(DRY_RUN.to_owned());
+    }
+    let mut features: BTreeSet<String> = request.features.drain(..).collect();
+    features.extend(SUPPORTED.iter().map(|feature| feature.to_string()));
+    features.extend(required.iter().cloned());
+    request.features = features.into_iter().collect();
+    request.required_features = required.into_iter().collect();
+}
+
+/// The features of the request taken by this build, or an error if it lacks any of the
+/// required ones.
+pub(crate) fn negotiate(
+    features: &[String],
+    required: &[String],
+) -> Result<Vec<String>, UnsupportedFeatures> {
+    let supported = |feature: &&String| SUPPORTED.contains(&feature.as_str());
+    let lacking: Vec<_> = required
+        .iter()
+        .filter(|feature| !supported(feature))
+        .cloned()
+        .collect();
+    if !lacking.is_empty() {
+        return Err(UnsupportedFeatures { features: lacking });
+    }
+    let taken: BTreeSet<_> = features.iter().chain(required).filter(supported).collect();
+    Ok(taken.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::Param;
+
+    #[test]
+    fn test_negotiate() {
+        let mut request = RunRequest::builder()
+            .command(Param::cmd_name("bc"))
+            .args(vec![])
+            .stdin(Stdin::Content("1 + 1".to_owned()))
+            .build();
+        advertise(&mut request);
+        assert_eq!(request.required_features, [INLINE_STDIN]);
+        assert!(request.features.contains(&STREAMING_OUTPUT.to_owned()));
+
+        let features = vec!["inline-capture".to_owned(), STREAMING_OUTPUT.to_owned()];
+        let required = vec![INLINE_STDIN.to_owned()];
+        assert_eq!(
+            negotiate(&features, &required).unwrap(),
+            [INLINE_STDIN, STREAMING_OUTPUT]
+        );
+        let required = vec!["inline-capture".to_owned()];
+        assert_eq!(
+            negotiate(&features, &required).unwrap_err().features,
+            ["inline-capture"]
+        );
+    }
+}
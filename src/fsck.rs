@@ -0,0 +1,607 @@
+//! Auditing the storage bucket against itself and against the task history.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use log::{debug, info, warn};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::Collection;
+use mongodb_gridfs::GridFSBucket;
+
+use crate::chunked;
+use crate::history::{TaskHistory, TaskState};
+use crate::params::{Param, BLOB_HOSTNAME};
+use crate::protocol::ExitStatus;
+
+/// A problem found in the storage.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageIssue {
+    /// A file document lacking the fields every upload has.
+    Malformed {
+        oid: ObjectId,
+        filename: Option<String>,
+    },
+    /// A file whose chunks are not all there, e.g. due to an interrupted upload.
+    Incomplete {
+        oid: ObjectId,
+        filename: String,
+        expected_chunks: u64,
+        found_chunks: u64,
+    },
+    /// Chunks of a file which does not exist.
+    OrphanChunks { files_id: Bson, count: u64 },
+    /// An output uploaded by a run but never committed, or set aside while committing and
+    /// never put back, e.g. due to a crashed worker.
+    ///
+    /// A staged one is committable if the run finished successfully and nothing has been
+    /// committed in its place, and one set aside is if nothing is in its place.
+    StaleStaged {
+        oid: ObjectId,
+        filename: String,
+        committable: bool,
+    },
+    /// An input of an in-flight task which is missing on the cloud.
+    DanglingReference { task_id: String, cloud_url: String },
+    /// A blob of the inputs uploaded longer ago than the retention and taken by no task in
+    /// flight.
+    ExpiredBlob { oid: ObjectId, filename: String },
+}
+
+impl fmt::Display for StorageIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageIssue::Malformed { oid, filename } => write!(
+                f,
+                "malformed    {} {}",
+                oid,
+                filename.as_deref().unwrap_or("-")
+            ),
+            StorageIssue::Incomplete {
+                oid,
+                filename,
+                expected_chunks,
+                found_chunks,
+            } => write!(
+                f,
+                "incomplete   {} {} ({} of {} chunks)",
+                oid, filename, found_chunks, expected_chunks
+            ),
+            StorageIssue::OrphanChunks { files_id, count } => {
+                write!(f, "orphan       {} ({} chunks)", files_id, count)
+            }
+            StorageIssue::StaleStaged {
+                oid,
+                filename,
+                committable,
+            } => {
+                write!(f, "stale-staged {} {}", oid, filename)?;
+                if *committable {
+                    write!(f, " (committable)")?;
+                }
+                Ok(())
+            }
+            StorageIssue::DanglingReference { task_id, cloud_url } => {
+                write!(f, "dangling     task {} -> {}", task_id, cloud_url)
+            }
+            StorageIssue::ExpiredBlob { oid, filename } => {
+                write!(f, "expired-blob {} {}", oid, filename)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FsckOptions {
+    /// Commit the committable staged outputs.
+    pub repair: bool,
+    /// Delete the junk: malformed, incomplete and stale staged files, orphan chunks, and
+    /// expired blobs.
+    pub purge: bool,
+    /// Staged outputs younger than this may still be committed by a running worker.
+    pub stale_after: Duration,
+    /// Blobs of the inputs uploaded longer ago than this expire once no task in flight takes
+    /// them, or are kept forever if not given.
+    pub blob_retention: Option<Duration>,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        FsckOptions {
+            repair: false,
+            purge: false,
+            stale_after: Duration::from_secs(24 * 60 * 60),
+            blob_retention: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub issues: Vec<StorageIssue>,
+    pub repaired: usize,
+    pub purged: usize,
+}
+
+pub(crate) struct Fsck {
+    pub(crate) bucket: GridFSBucket,
+    pub(crate) files: Collection<Document>,
+    pub(crate) chunks: Collection<Document>,
+    pub(crate) history: TaskHistory,
+}
+
+impl Fsck {
+    pub(crate) async fn run(&self, options: &FsckOptions) -> anyhow::Result<FsckReport> {
+        let mut report = FsckReport::default();
+
+        let mut chunk_counts = HashMap::new();
+        let mut cursor = self
+            .chunks
+            .aggregate(
+                [doc! { "$group": { "_id": "$files_id", "count": { "$sum": 1 } } }],
+                None,
+            )
+            .await?;
+        while let Some(group) = cursor.try_next().await? {
+            let count = group.get("count").and_then(as_u64).unwrap_or(0);
+            chunk_counts.insert(group.get("_id").cloned().unwrap_or(Bson::Null), count);
+        }
+
+        let mut filenames = HashSet::new();
+        let mut staged = vec![];
+        let mut blobs = vec![];
+        let blob_prefix = format!("@{}:", BLOB_HOSTNAME);
+        let mut cursor = self.files.find(None, None).await?;
+        while let Some(file) = cursor.try_next().await? {
+            let oid = match file.get_object_id("_id") {
+                Ok(oid) => oid,
+                Err(_) => continue,
+            };
+            let found_chunks = chunk_counts.remove(&Bson::ObjectId(oid)).unwrap_or(0);
+            let filename = file.get_str("filename").ok().map(str::to_owned);
+            let length = file.get("length").and_then(as_u64);
+            let chunk_size = file.get("chunkSize").and_then(as_u64);
+            let (filename, length, chunk_size) = match (filename, length, chunk_size) {
+                (Some(filename), Some(length), Some(chunk_size)) if chunk_size > 0 => {
+                    (filename, length, chunk_size)
+                }
+                (filename, ..) => {
+                    report
+                        .issues
+                        .push(StorageIssue::Malformed { oid, filename });
+                    continue;
+                }
+            };
+
+            let expected_chunks = (length + chunk_size - 1) / chunk_size;
+            if found_chunks != expected_chunks {
+                report.issues.push(StorageIssue::Incomplete {
+                    oid,
+                    filename,
+                    expected_chunks,
+                    found_chunks,
+                });
+                continue;
+            }
+
+            // the chunks of a staged file are left to go along with it
+            if committed_url(filename.as_str()).is_some() && !chunked::is_chunk(filename.as_str()) {
+                staged.push((oid, filename, file));
+            } else {
+                // the chunks of a blob expire along with it
+                if filename.starts_with(blob_prefix.as_str()) {
+                    blobs.push((oid, filename.clone(), uploaded_at(&file)));
+                }
+                filenames.insert(filename);
+            }
+        }
+
+        for (files_id, count) in chunk_counts {
+            report
+                .issues
+                .push(StorageIssue::OrphanChunks { files_id, count });
+        }
+
+        let stale_before =
+            DateTime::now().timestamp_millis() - options.stale_after.as_millis() as i64;
+        for (oid, filename, file) in staged {
+            if uploaded_at(&file) > stale_before {
+                continue;
+            }
+            let (committed, replaced) = committed_url(filename.as_str()).unwrap_or_default();
+            // a chunked file cannot be committed by renaming it alone
+            let committable = !filenames.contains(committed)
+                && !chunked::is_chunked(file.get_document("metadata").ok())
+                && (replaced || self.is_finished_successfully(&file).await?);
+            report.issues.push(StorageIssue::StaleStaged {
+                oid,
+                filename,
+                committable,
+            });
+        }
+
+        let mut referenced = HashSet::new();
+        for record in self.history.in_flight(None).await? {
+            let request = match record.run_request() {
+                Ok(Some(request)) => request,
+                _ => continue,
+            };
+            let mut inputs = vec![];
+            request.map_params(|param| collect_cloud_inputs(param, &mut inputs));
+            for cloud_url in inputs {
+                if !filenames.contains(&cloud_url) {
+                    report.issues.push(StorageIssue::DanglingReference {
+                        task_id: record.task_id.clone(),
+                        cloud_url: cloud_url.clone(),
+                    });
+                }
+                referenced.insert(cloud_url);
+            }
+        }
+
+        if let Some(retention) = options.blob_retention {
+            let expire_before = DateTime::now().timestamp_millis() - retention.as_millis() as i64;
+            for (oid, filename, uploaded_at) in blobs {
+                if uploaded_at > expire_before
+                    || referenced.contains(chunked::head_url(filename.as_str()))
+                {
+                    continue;
+                }
+                report
+                    .issues
+                    .push(StorageIssue::ExpiredBlob { oid, filename });
+            }
+        }
+
+        for issue in &report.issues {
+            match issue {
+                StorageIssue::StaleStaged {
+                    oid,
+                    filename,
+                    committable: true,
+                } if options.repair => {
+                    let (committed_url, _) = committed_url(filename).unwrap_or_default();
+                    debug!("Commit staged {} to {}...", filename, committed_url);
+                    self.bucket.rename(*oid, committed_url).await?;
+                    report.repaired += 1;
+                }
+                StorageIssue::Malformed { oid, .. }
+                | StorageIssue::Incomplete { oid, .. }
+                | StorageIssue::StaleStaged { oid, .. }
+                | StorageIssue::ExpiredBlob { oid, .. }
+                    if options.purge =>
+                {
+                    debug!("Delete file {}...", oid);
+                    self.files.delete_one(doc! { "_id": oid }, None).await?;
+                    self.chunks
+                        .delete_many(doc! { "files_id": oid }, None)
+                        .await?;
+                    report.purged += 1;
+                }
+                StorageIssue::OrphanChunks { files_id, .. } if options.purge => {
+                    debug!("Delete chunks of missing file {}...", files_id);
+                    self.chunks
+                        .delete_many(doc! { "files_id": files_id.clone() }, None)
+                        .await?;
+                    report.purged += 1;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Delete the staged outputs, and those set aside while committing, left longer than
+    /// `stale_after` ago by the runs which crashed before committing or rolling back, along
+    /// with their chunks, and return how many are deleted.
+    ///
+    /// The committable ones are left to `cmdproxy storage fsck --repair`, as is any other junk.
+    pub(crate) async fn sweep_staged(&self, stale_after: Duration) -> anyhow::Result<usize> {
+        let stale_before = DateTime::now().timestamp_millis() - stale_after.as_millis() as i64;
+        let filter = doc! {
+            "filename": { "$regex": STAGED_PATTERN },
+            "uploadDate": { "$lt": DateTime::from_millis(stale_before) },
+        };
+        let mut stale = vec![];
+        let mut cursor = self.files.find(filter, None).await?;
+        while let Some(file) = cursor.try_next().await? {
+            if let (Ok(oid), Ok(filename)) = (file.get_object_id("_id"), file.get_str("filename")) {
+                let filename = filename.to_owned();
+                stale.push((oid, filename, file));
+            }
+        }
+
+        let mut doomed = HashSet::new();
+        for (_, filename, file) in stale.iter().filter(|(_, name, _)| !chunked::is_chunk(name)) {
+            let (committed, replaced) = committed_url(filename.as_str()).unwrap_or_default();
+            let committable = !self.exists(committed).await?
+                && !chunked::is_chunked(file.get_document("metadata").ok())
+                && (replaced || self.is_finished_successfully(file).await?);
+            if !committable {
+                doomed.insert(filename.clone());
+            }
+        }
+
+        let mut swept = 0;
+        for (oid, filename, _) in &stale {
+            let head = chunked::head_url(filename.as_str());
+            // the chunks go along with their heads, unless left without any
+            let is_doomed = doomed.contains(head)
+                || (chunked::is_chunk(filename.as_str()) && !self.exists(head).await?);
+            if !is_doomed {
+                continue;
+            }
+            debug!("Sweep stale staged {}...", filename);
+            self.files.delete_one(doc! { "_id": oid }, None).await?;
+            self.chunks
+                .delete_many(doc! { "files_id": oid }, None)
+                .await?;
+            swept += 1;
+        }
+        Ok(swept)
+    }
+
+    async fn exists(&self, filename: &str) -> anyhow::Result<bool> {
+        let count = self
+            .files
+            .count_documents(doc! { "filename": filename }, None)
+            .await?;
+        Ok(count > 0)
+    }
+
+    /// Whether the run which staged the file, as stamped in its provenance, has finished
+    /// successfully.
+    async fn is_finished_successfully(&self, file: &Document) -> anyhow::Result<bool> {
+        let task_id = file
+            .get_document("metadata")
+            .and_then(|metadata| metadata.get_document("provenance"))
+            .and_then(|provenance| provenance.get_str("task_id"));
+        let task_id = match task_id {
+            Ok(task_id) => task_id,
+            Err(_) => return Ok(false),
+        };
+        Ok(matches!(
+            self.history.get(task_id).await?,
+            Some(record) if record.state == TaskState::Finished
+                && record.status.as_ref().map(ExitStatus::success).unwrap_or(false)
+        ))
+    }
+}
+
+/// Sweep the stale staged outputs by `fsck` from time to time, until the future is dropped.
+pub(crate) async fn sweep_staged_periodically(fsck: Fsck, stale_after: Duration) {
+    let interval = (stale_after / 4).clamp(Duration::from_secs(60), Duration::from_secs(3600));
+    loop {
+        match fsck.sweep_staged(stale_after).await {
+            Ok(0) => {}
+            Ok(swept) => info!("Swept {} stale staged outputs", swept),
+            Err(err) => warn!("Failed to sweep the stale staged outputs: {}", err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Pattern of the names of the files staged, or set aside, while committing the outputs.
+const STAGED_PATTERN: &str = r"\.(staged|replaced)-";
+
+/// The url the staged file of `filename` is committed to, and whether it is one set aside while
+/// committing rather than staged, or none if it is neither.
+fn committed_url(filename: &str) -> Option<(&str, bool)> {
+    let head = chunked::head_url(filename);
+    if let Some((committed, _)) = head.rsplit_once(".staged-") {
+        return Some((committed, false));
+    }
+    head.rsplit_once(".replaced-")
+        .map(|(committed, _)| (committed, true))
+}
+
+/// Milliseconds since the epoch the file was uploaded at, or 0 if unknown.
+fn uploaded_at(file: &Document) -> i64 {
+    file.get_datetime("uploadDate")
+        .map(DateTime::timestamp_millis)
+        .unwrap_or(0)
+}
+
+fn as_u64(value: &Bson) -> Option<u64> {
+    match value {
+        Bson::Int32(value) => u64::try_from(*value).ok(),
+        Bson::Int64(value) => u64::try_from(*value).ok(),
+        Bson::Double(value) if *value >= 0.0 => Some(*value as u64),
+        _ => None,
+    }
+}
+
+fn collect_cloud_inputs(param: Param, inputs: &mut Vec<String>) {
+    match param {
+        Param::FormatParam { args, .. } => {
+            for arg in args.into_values() {
+                collect_cloud_inputs(arg, inputs);
+            }
+        }
+        Param::InCloudGlobParam { files, .. } => {
+            for file in files.into_values() {
+                collect_cloud_inputs(file, inputs);
+            }
+        }
+        param @ (Param::InCloudFileParam { .. } | Param::InCloudDirParam { .. }) => {
+            inputs.push(param.cloud_url())
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chain_ext::mongodb_gridfs::DatabaseExt;
+    use test_utilities::docker;
+
+    use crate::protocol::RunRequest;
+
+    use super::*;
+
+    /// Put the document of a file into `files`, with `chunks` chunks of it into `chunks`.
+    async fn put_file(fsck: &Fsck, file: Document, chunks: u32) -> ObjectId {
+        let oid = file.get_object_id("_id").unwrap();
+        fsck.files.insert_one(file, None).await.unwrap();
+        for n in 0..chunks {
+            fsck.chunks
+                .insert_one(doc! { "files_id": oid, "n": n }, None)
+                .await
+                .unwrap();
+        }
+        oid
+    }
+
+    fn file(filename: &str, length: i64) -> Document {
+        doc! {
+            "_id": ObjectId::new(),
+            "filename": filename,
+            "length": length,
+            "chunkSize": 255,
+            "uploadDate": DateTime::from_millis(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_and_fix_issues() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-fsck")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+        let db = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db");
+        let fsck = Fsck {
+            bucket: db.bucket(None),
+            files: db.collection("fs.files"),
+            chunks: db.collection("fs.chunks"),
+            history: TaskHistory::new(db.collection("tasks")),
+        };
+
+        put_file(&fsck, file("@host:/in/a.txt", 10), 1).await;
+        let malformed = put_file(&fsck, doc! { "_id": ObjectId::new(), "filename": "x" }, 0).await;
+        let incomplete = put_file(&fsck, file("@host:/in/b.txt", 600), 1).await;
+        let orphan = ObjectId::new();
+        for n in 0..2 {
+            fsck.chunks
+                .insert_one(doc! { "files_id": orphan, "n": n }, None)
+                .await
+                .unwrap();
+        }
+        // set aside while committing, with nothing put in its place
+        let replaced = put_file(&fsck, file("@host:/out/c.txt.replaced-1", 10), 1).await;
+        // staged by a run never finished
+        let staged = put_file(&fsck, file("@host:/out/d.txt.staged-1", 10), 1).await;
+
+        let request = RunRequest::builder()
+            .command(Param::cmd_name("cat"))
+            .args(vec![
+                Param::InCloudFileParam {
+                    filepath: "/in/a.txt".to_owned(),
+                    hostname: "host".to_owned(),
+                },
+                Param::InCloudFileParam {
+                    filepath: "/in/gone.txt".to_owned(),
+                    hostname: "host".to_owned(),
+                },
+            ])
+            .build();
+        let request = serde_json::to_string(&request).unwrap();
+        fsck.history
+            .submitted("task", "cat", request.as_str(), "client", "client", None)
+            .await
+            .unwrap();
+
+        let report = fsck.run(&FsckOptions::default()).await.unwrap();
+        let mut expected = vec![
+            StorageIssue::Malformed {
+                oid: malformed,
+                filename: Some("x".to_owned()),
+            },
+            StorageIssue::Incomplete {
+                oid: incomplete,
+                filename: "@host:/in/b.txt".to_owned(),
+                expected_chunks: 3,
+                found_chunks: 1,
+            },
+            StorageIssue::OrphanChunks {
+                files_id: Bson::ObjectId(orphan),
+                count: 2,
+            },
+            StorageIssue::StaleStaged {
+                oid: replaced,
+                filename: "@host:/out/c.txt.replaced-1".to_owned(),
+                committable: true,
+            },
+            StorageIssue::StaleStaged {
+                oid: staged,
+                filename: "@host:/out/d.txt.staged-1".to_owned(),
+                committable: false,
+            },
+            StorageIssue::DanglingReference {
+                task_id: "task".to_owned(),
+                cloud_url: "@host:/in/gone.txt".to_owned(),
+            },
+        ];
+        let sorted = |mut issues: Vec<StorageIssue>| {
+            issues.sort_by_key(|issue| issue.to_string());
+            issues
+        };
+        assert_eq!(sorted(report.issues), sorted(expected.clone()));
+        assert_eq!((report.repaired, report.purged), (0, 0));
+
+        // the one set aside is put back in place
+        let options = FsckOptions {
+            repair: true,
+            ..FsckOptions::default()
+        };
+        let report = fsck.run(&options).await.unwrap();
+        assert_eq!(report.repaired, 1);
+        assert!(fsck.exists("@host:/out/c.txt").await.unwrap());
+        expected.retain(
+            |issue| !matches!(issue, StorageIssue::StaleStaged { oid, .. } if *oid == replaced),
+        );
+
+        // and the junk is deleted, leaving only the dangling reference
+        let options = FsckOptions {
+            purge: true,
+            ..FsckOptions::default()
+        };
+        let report = fsck.run(&options).await.unwrap();
+        assert_eq!(sorted(report.issues), sorted(expected));
+        assert_eq!(report.purged, 4);
+        let report = fsck.run(&FsckOptions::default()).await.unwrap();
+        assert!(matches!(
+            report.issues.as_slice(),
+            [StorageIssue::DanglingReference { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_committed_url() {
+        assert_eq!(
+            committed_url("@host:/out/a.txt.staged-63f0"),
+            Some(("@host:/out/a.txt", false))
+        );
+        assert_eq!(
+            committed_url("@host:/out/a.txt.replaced-63f0"),
+            Some(("@host:/out/a.txt", true))
+        );
+        assert_eq!(
+            committed_url("@host:/out/a.txt.staged-63f0.chunk-2"),
+            Some(("@host:/out/a.txt", false))
+        );
+        assert_eq!(committed_url("@host:/out/a.txt"), None);
+        assert_eq!(committed_url("@host:/out/a.txt.chunk-2"), None);
+
+        let pattern = regex::Regex::new(STAGED_PATTERN).unwrap();
+        assert!(pattern.is_match("@host:/out/a.txt.staged-63f0"));
+        assert!(pattern.is_match("@host:/out/a.txt.replaced-63f0.chunk-0"));
+        assert!(!pattern.is_match("@host:/out/staged-a.txt"));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::params::Param;
+
+/// Rules guessing which of the raw arguments of a command are files to be transferred.
+///
+/// An existing local file is taken as an input, and the path following an output flag, either
+/// as the next argument or as `--flag=path`, is taken as an output. Everything else is passed
+/// as it is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParamHeuristics {
+    /// Flags followed by an output path.
+    pub output_flags: Vec<String>,
+    /// Whether to take the existing local files as inputs.
+    pub detect_inputs: bool,
+}
+
+impl Default for ParamHeuristics {
+    fn default() -> Self {
+        ParamHeuristics {
+            output_flags: vec!["-o".to_owned(), "--output".to_owned(), "--out".to_owned()],
+            detect_inputs: true,
+        }
+    }
+}
+
+impl ParamHeuristics {
+    pub fn to_params<I: IntoIterator<Item = String>>(&self, args: I) -> Vec<Param> {
+        let mut params = vec![];
+        let mut expect_output = false;
+        for arg in args {
+            let param = if expect_output {
+                Param::opath(&arg)
+            } else if let Some((flag, path)) = self.split_output_flag(arg.as_str()) {
+                let tmpl = format!("{}={{path}}", flag.replace('{', "{{").replace('}', "}}"));
+                Param::format(tmpl, HashMap::from([("path", Param::opath(path))]))
+            } else if self.detect_inputs && Path::new(&arg).is_file() {
+                Param::ipath(&arg)
+            } else {
+                Param::str(&arg)
+            };
+            expect_output = self.output_flags.contains(&arg);
+            params.push(param);
+        }
+        params
+    }
+
+    fn split_output_flag<'a>(&self, arg: &'a str) -> Option<(&'a str, &'a str)> {
+        arg.split_once('=')
+            .filter(|(flag, path)| !path.is_empty() && self.output_flags.iter().any(|f| f == flag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_params() {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        let input = input.path().to_str().unwrap().to_owned();
+        let heuristics = ParamHeuristics::default();
+
+        let params = heuristics.to_params(
+            [
+                &input,
+                "-v",
+                "-o",
+                "out.bin",
+                "--output=log.txt",
+                "missing.txt",
+            ]
+            .into_iter()
+            .map(str::to_owned),
+        );
+        assert_eq!(params[0], Param::ipath(&input));
+        assert_eq!(params[1], Param::str("-v"));
+        assert_eq!(params[2], Param::str("-o"));
+        assert_eq!(params[3], Param::opath("out.bin"));
+        assert_eq!(
+            params[4],
+            Param::format(
+                "--output={path}",
+                HashMap::from([("path", Param::opath("log.txt"))])
+            )
+        );
+        assert_eq!(params[5], Param::str("missing.txt"));
+
+        let heuristics = ParamHeuristics {
+            output_flags: vec![],
+            detect_inputs: false,
+        };
+        let params = heuristics.to_params([input.clone(), "-o".to_owned(), "out.bin".to_owned()]);
+        assert_eq!(params[0], Param::str(&input));
+        assert_eq!(params[2], Param::str("out.bin"));
+    }
+}
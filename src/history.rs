@@ -0,0 +1,749 @@
+use std::fmt;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson, Bson, DateTime, Document};
+use mongodb::options::{FindOptions, UpdateOptions};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Artifact, AuthEnvelope, ExitStatus, RunRequest};
+use crate::usage::ResourceUsage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaskState {
+    /// Sent to the queue, but not yet picked by any worker.
+    Pending,
+    /// Picked and being run by a worker.
+    Started,
+    /// Picked by a worker, but held until approved by an operator, see [`crate::approval`].
+    Parked,
+    /// Run to the end, no matter the exit status of the command.
+    Finished,
+    /// Failed on the worker before or after running the command.
+    Failed,
+    /// Cancelled on request, either before or while running.
+    Revoked,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A task sent by a client, tracked from its submission to its completion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRecord {
+    #[serde(rename = "_id")]
+    pub task_id: String,
+    pub state: TaskState,
+    #[serde(default)]
+    pub queue: Option<String>,
+    /// The request as sent to the worker, in which local files are already uploaded.
+    #[serde(default)]
+    pub request: Option<String>,
+    /// Identity of the client which submitted the task.
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Hostname tagging the local files in the cloud urls of the request, which is rendered by
+    /// the hostname template if any, see [`crate::naming`].
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub worker: Option<String>,
+    #[serde(default)]
+    pub submitted_at: Option<DateTime>,
+    /// Time the task is to start no earlier than, if scheduled.
+    #[serde(default)]
+    pub scheduled_at: Option<DateTime>,
+    #[serde(default)]
+    pub started_at: Option<DateTime>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime>,
+    #[serde(default)]
+    pub status: Option<ExitStatus>,
+    #[serde(default)]
+    pub exc: Option<String>,
+    /// Outputs collected by the worker, as reported in the response.
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+    /// Resources used by the command, if run.
+    #[serde(default)]
+    pub usage: Option<ResourceUsage>,
+    /// Identity of the operator who approved the task, if approved.
+    #[serde(default)]
+    pub approved_by: Option<String>,
+    #[serde(default)]
+    pub approved_at: Option<DateTime>,
+    /// Signature of the approval by the secret of the operators, see [`crate::approval`].
+    #[serde(default)]
+    pub approval: Option<String>,
+}
+
+impl TaskRecord {
+    /// The recorded request, without the credentials if it was sent with any.
+    pub fn run_request(&self) -> anyhow::Result<Option<RunRequest>> {
+        let serialized = match &self.request {
+            Some(serialized) => serialized,
+            None => return Ok(None),
+        };
+        let payload = match serde_json::from_str::<AuthEnvelope>(serialized.as_str()) {
+            Ok(envelope) => envelope.payload,
+            Err(_) => serialized.clone(),
+        };
+        Ok(Some(serde_json::from_str(payload.as_str())?))
+    }
+
+    /// Time spent on running the task by the worker, if finished.
+    pub fn duration(&self) -> Option<std::time::Duration> {
+        let started_at = self.started_at?.timestamp_millis();
+        let finished_at = self.finished_at?.timestamp_millis();
+        u64::try_from(finished_at - started_at)
+            .ok()
+            .map(std::time::Duration::from_millis)
+    }
+}
+
+/// Conditions for querying the task history.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Only tasks sent to this queue.
+    pub queue: Option<String>,
+    /// Only tasks submitted within this duration until now.
+    pub since: Option<std::time::Duration>,
+    /// Only tasks which are done but not finished successfully.
+    pub failed: bool,
+    pub limit: Option<i64>,
+}
+
+impl HistoryQuery {
+    fn to_filter(&self) -> anyhow::Result<Document> {
+        let mut filter = doc! {};
+        if let Some(queue) = &self.queue {
+            filter.insert("queue", queue);
+        }
+        if let Some(since) = self.since {
+            let since = DateTime::now().timestamp_millis() - since.as_millis() as i64;
+            filter.insert(
+                "submitted_at",
+                doc! { "$gte": DateTime::from_millis(since) },
+            );
+        }
+        if self.failed {
+            filter.insert(
+                "state",
+                doc! { "$in": [
+                    to_bson(&TaskState::Finished)?,
+                    to_bson(&TaskState::Failed)?,
+                    to_bson(&TaskState::Revoked)?,
+                ] },
+            );
+            filter.insert(
+                "$nor",
+                vec![doc! {
+                    "state": to_bson(&TaskState::Finished)?,
+                    "status.Exited.code": 0,
+                }],
+            );
+        }
+        Ok(filter)
+    }
+}
+
+/// Resources used by the runs of a command, as summarized from the history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageSummary {
+    pub command: String,
+    pub runs: u64,
+    pub avg_duration: Duration,
+    pub max_duration: Duration,
+    /// Mean cpu time of the runs, if sampled.
+    pub avg_cpu_time: Option<Duration>,
+    /// Peak resident memory of all the runs, if sampled.
+    pub max_rss_bytes: Option<u64>,
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+}
+
+impl UsageSummary {
+    fn from_group(group: &Document) -> UsageSummary {
+        // the numbers come back as whichever of the bson types fits them
+        let number = |key: &str| match group.get(key) {
+            Some(Bson::Double(value)) => Some(*value),
+            Some(Bson::Int32(value)) => Some(*value as f64),
+            Some(Bson::Int64(value)) => Some(*value as f64),
+            _ => None,
+        };
+        let millis = |key: &str| number(key).map(|ms| Duration::from_millis(ms as u64));
+        UsageSummary {
+            command: group.get_str("_id").unwrap_or_default().to_owned(),
+            runs: number("runs").unwrap_or_default() as u64,
+            avg_duration: millis("avg_duration_ms").unwrap_or_default(),
+            max_duration: millis("max_duration_ms").unwrap_or_default(),
+            avg_cpu_time: millis("avg_cpu_ms"),
+            max_rss_bytes: number("max_rss_bytes").map(|bytes| bytes as u64),
+            downloaded_bytes: number("downloaded_bytes").unwrap_or_default() as u64,
+            uploaded_bytes: number("uploaded_bytes").unwrap_or_default() as u64,
+        }
+    }
+}
+
+/// Format a recorded time for display, or `-` if not recorded.
+pub(crate) fn format_time(time: Option<DateTime>) -> String {
+    time.map(|time| {
+        chrono::DateTime::<chrono::Local>::from(time.to_system_time())
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    })
+    .unwrap_or_else(|| "-".to_owned())
+}
+
+/// The collection tracking the tasks, shared by the clients and the workers.
+///
+/// Both ends upsert only their own fields, since a worker may pick a task before the client
+/// gets to record its submission.
+#[derive(Clone)]
+pub struct TaskHistory {
+    coll: Collection<TaskRecord>,
+}
+
+impl TaskHistory {
+    pub fn new(coll: Collection<TaskRecord>) -> TaskHistory {
+        TaskHistory { coll }
+    }
+
+    pub(crate) async fn submitted(
+        &self,
+        task_id: &str,
+        queue: &str,
+        request: &str,
+        client: &str,
+        hostname: &str,
+        eta: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<()> {
+        let mut set = doc! {
+            "queue": queue,
+            "request": request,
+            "client": client,
+            "hostname": hostname,
+            "submitted_at": DateTime::now(),
+        };
+        if let Some(eta) = eta {
+            set.insert(
+                "scheduled_at",
+                DateTime::from_millis(eta.timestamp_millis()),
+            );
+        }
+        self.upsert(
+            task_id,
+            doc! {
+                "$set": set,
+                "$setOnInsert": { "state": to_bson(&TaskState::Pending)? },
+            },
+        )
+        .await
+    }
+
+    /// Mark the task as started by `worker`, unless it has been revoked.
+    ///
+    /// Return false if the task has been revoked and should not be run.
+    pub(crate) async fn started(&self, task_id: &str, worker: &str) -> anyhow::Result<bool> {
+        if self.is_revoked(task_id).await? {
+            return Ok(false);
+        }
+        self.upsert(
+            task_id,
+            doc! {
+                "$set": {
+                    "state": to_bson(&TaskState::Started)?,
+                    "worker": worker,
+                    "started_at": DateTime::now(),
+                },
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    pub(crate) async fn finished(
+        &self,
+        task_id: &str,
+        status: Option<&ExitStatus>,
+        exc: Option<&str>,
+        artifacts: &[Artifact],
+        usage: Option<&ResourceUsage>,
+    ) -> anyhow::Result<()> {
+        let state = if exc.is_some() {
+            TaskState::Failed
+        } else {
+            TaskState::Finished
+        };
+        // keep the state of a revoked task as is
+        self.coll
+            .update_one(
+                doc! {
+                    "_id": task_id,
+                    "state": { "$ne": to_bson(&TaskState::Revoked)? },
+                },
+                doc! { "$set": { "state": to_bson(&state)? } },
+                None,
+            )
+            .await?;
+        self.upsert(
+            task_id,
+            doc! {
+                "$set": {
+                    "finished_at": DateTime::now(),
+                    "status": to_bson(&status)?,
+                    "exc": exc,
+                    "artifacts": to_bson(artifacts)?,
+                    "usage": to_bson(&usage)?,
+                },
+            },
+        )
+        .await
+    }
+
+    /// Park the started task until approved, unless it has been revoked, dropping any
+    /// approval set ahead.
+    pub(crate) async fn parked(&self, task_id: &str) -> anyhow::Result<()> {
+        self.coll
+            .update_one(
+                doc! { "_id": task_id, "state": to_bson(&TaskState::Started)? },
+                doc! { "$set": {
+                    "state": to_bson(&TaskState::Parked)?,
+                    "approved_by": null,
+                    "approved_at": null,
+                    "approval": null,
+                } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Mark the parked task as started again once approved.
+    pub(crate) async fn resumed(&self, task_id: &str) -> anyhow::Result<()> {
+        self.coll
+            .update_one(
+                doc! { "_id": task_id, "state": to_bson(&TaskState::Parked)? },
+                doc! { "$set": { "state": to_bson(&TaskState::Started)? } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Approve the parked task on behalf of `operator` by the signed `approval`, unless
+    /// submitted by the operator, see [`crate::approval`].
+    ///
+    /// Return false if there is no such parked task submitted by another client.
+    pub async fn approve(
+        &self,
+        task_id: &str,
+        operator: &str,
+        approval: &str,
+    ) -> anyhow::Result<bool> {
+        let res = self
+            .coll
+            .update_one(
+                doc! {
+                    "_id": task_id,
+                    "state": to_bson(&TaskState::Parked)?,
+                    "client": { "$ne": operator },
+                },
+                doc! { "$set": {
+                    "approved_by": operator,
+                    "approved_at": DateTime::now(),
+                    "approval": approval,
+                } },
+                None,
+            )
+            .await?;
+        Ok(res.matched_count > 0)
+    }
+
+    /// Revoke the task if it has not been finished yet.
+    ///
+    /// Return false if there is no such unfinished task.
+    pub async fn revoke(&self, task_id: &str) -> anyhow::Result<bool> {
+        let res = self
+            .coll
+            .update_one(
+                doc! {
+                    "_id": task_id,
+                    "state": { "$in": [
+                        to_bson(&TaskState::Pending)?,
+                        to_bson(&TaskState::Started)?,
+                        to_bson(&TaskState::Parked)?,
+                    ] },
+                },
+                doc! { "$set": { "state": to_bson(&TaskState::Revoked)? } },
+                None,
+            )
+            .await?;
+        Ok(res.matched_count > 0)
+    }
+
+    pub async fn is_revoked(&self, task_id: &str) -> anyhow::Result<bool> {
+        Ok(matches!(
+            self.get(task_id).await?,
+            Some(TaskRecord {
+                state: TaskState::Revoked,
+                ..
+            })
+        ))
+    }
+
+    pub async fn get(&self, task_id: &str) -> anyhow::Result<Option<TaskRecord>> {
+        Ok(self.coll.find_one(doc! { "_id": task_id }, None).await?)
+    }
+
+    /// Tasks matching `filter`, latest submitted first.
+    pub async fn find(
+        &self,
+        filter: Document,
+        limit: Option<i64>,
+    ) -> anyhow::Result<Vec<TaskRecord>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "submitted_at": -1 })
+            .limit(limit)
+            .build();
+        Ok(self.coll.find(filter, options).await?.try_collect().await?)
+    }
+
+    pub async fn query(&self, query: &HistoryQuery) -> anyhow::Result<Vec<TaskRecord>> {
+        self.find(query.to_filter()?, query.limit).await
+    }
+
+    /// Tasks which are either pending, started or parked, optionally only those in `queue`.
+    pub async fn in_flight(&self, queue: Option<&str>) -> anyhow::Result<Vec<TaskRecord>> {
+        let mut filter = doc! {
+            "state": { "$in": [
+                to_bson(&TaskState::Pending)?,
+                to_bson(&TaskState::Started)?,
+                to_bson(&TaskState::Parked)?,
+            ] },
+        };
+        if let Some(queue) = queue {
+            filter.insert("queue", queue);
+        }
+        self.find(filter, None).await
+    }
+
+    /// Number of tasks in `queue` which have not been picked by any worker yet.
+    pub async fn depth(&self, queue: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .coll
+            .count_documents(
+                doc! { "queue": queue, "state": to_bson(&TaskState::Pending)? },
+                None,
+            )
+            .await?)
+    }
+
+    /// Hostnames tagging the local files of the tasks submitted by `client`, the latest used
+    /// first.
+    pub async fn hostnames(&self, client: &str) -> anyhow::Result<Vec<String>> {
+        let pipeline = [
+            doc! { "$match": { "client": client, "hostname": { "$type": "string" } } },
+            doc! { "$group": {
+                "_id": "$hostname",
+                "last_submitted_at": { "$max": "$submitted_at" },
+            } },
+            doc! { "$sort": { "last_submitted_at": -1 } },
+        ];
+        let groups: Vec<Document> = self
+            .coll
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(groups
+            .iter()
+            .filter_map(|group| group.get_str("_id").ok().map(str::to_owned))
+            .collect())
+    }
+
+    /// Resources used by the runs of each command finished within `since` until now, such as
+    /// for the mean duration of each command over the last week.
+    pub async fn usage_summary(&self, since: Duration) -> anyhow::Result<Vec<UsageSummary>> {
+        let since = DateTime::now().timestamp_millis() - since.as_millis() as i64;
+        let pipeline = [
+            doc! { "$match": {
+                "usage": { "$ne": null },
+                "finished_at": { "$gte": DateTime::from_millis(since) },
+            } },
+            doc! { "$group": {
+                "_id": "$usage.command",
+                "runs": { "$sum": 1 },
+                "avg_duration_ms": { "$avg": "$usage.duration_ms" },
+                "max_duration_ms": { "$max": "$usage.duration_ms" },
+                "avg_cpu_ms": { "$avg": "$usage.cpu_ms" },
+                "max_rss_bytes": { "$max": "$usage.max_rss_bytes" },
+                "downloaded_bytes": { "$sum": "$usage.downloaded_bytes" },
+                "uploaded_bytes": { "$sum": "$usage.uploaded_bytes" },
+            } },
+            doc! { "$sort": { "_id": 1 } },
+        ];
+        let groups: Vec<Document> = self
+            .coll
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(groups.iter().map(UsageSummary::from_group).collect())
+    }
+
+    async fn upsert(&self, task_id: &str, update: Document) -> anyhow::Result<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.coll
+            .update_one(doc! { "_id": task_id }, update, options)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_utilities::docker;
+
+    use crate::params::Param;
+
+    use super::*;
+
+    async fn state_of(history: &TaskHistory, task_id: &str) -> TaskState {
+        history.get(task_id).await.unwrap().unwrap().state
+    }
+
+    #[tokio::test]
+    async fn test_state_transitions() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-history")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+        let db = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db");
+        let history = TaskHistory::new(db.collection("tasks"));
+
+        // submitted, started and finished by its exit status
+        history
+            .submitted("a", "gcc", "{}", "client", "client", None)
+            .await
+            .unwrap();
+        assert_eq!(state_of(&history, "a").await, TaskState::Pending);
+        assert_eq!(history.depth("gcc").await.unwrap(), 1);
+        assert!(history.started("a", "worker").await.unwrap());
+        assert_eq!(state_of(&history, "a").await, TaskState::Started);
+        assert_eq!(history.depth("gcc").await.unwrap(), 0);
+        let status = ExitStatus::Exited { code: 0 };
+        history
+            .finished("a", Some(&status), None, &[], None)
+            .await
+            .unwrap();
+        let record = history.get("a").await.unwrap().unwrap();
+        assert_eq!(record.state, TaskState::Finished);
+        assert_eq!(record.status, Some(status));
+        assert_eq!(record.worker.as_deref(), Some("worker"));
+        assert!(record.duration().is_some());
+        // a finished task is revoked no more
+        assert!(!history.revoke("a").await.unwrap());
+
+        // failed on the worker
+        history
+            .submitted("b", "gcc", "{}", "client", "client", None)
+            .await
+            .unwrap();
+        history
+            .finished("b", None, Some("broken"), &[], None)
+            .await
+            .unwrap();
+        assert_eq!(state_of(&history, "b").await, TaskState::Failed);
+
+        // revoked before started, which is never run, and stays revoked once finished
+        history
+            .submitted("c", "gcc", "{}", "client", "client", None)
+            .await
+            .unwrap();
+        assert!(history.revoke("c").await.unwrap());
+        assert!(!history.started("c", "worker").await.unwrap());
+        history.finished("c", None, None, &[], None).await.unwrap();
+        assert_eq!(state_of(&history, "c").await, TaskState::Revoked);
+        assert!(history.is_revoked("c").await.unwrap());
+
+        // picked by a worker before its submission is recorded
+        assert!(history.started("d", "worker").await.unwrap());
+        history
+            .submitted("d", "gcc", "{}", "client", "client", None)
+            .await
+            .unwrap();
+        assert_eq!(state_of(&history, "d").await, TaskState::Started);
+
+        let in_flight = history.in_flight(Some("gcc")).await.unwrap();
+        assert_eq!(
+            in_flight
+                .iter()
+                .map(|record| record.task_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["d"]
+        );
+        let failed = HistoryQuery {
+            failed: true,
+            ..HistoryQuery::default()
+        };
+        let mut failed: Vec<_> = history
+            .query(&failed)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|record| record.task_id)
+            .collect();
+        failed.sort();
+        assert_eq!(failed, vec!["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_park_and_approve() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-history_approval")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+        let db = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db");
+        let history = TaskHistory::new(db.collection("tasks"));
+
+        history
+            .submitted("a", "gcc", "{}", "client", "client", None)
+            .await
+            .unwrap();
+        // only a started task is parked
+        history.parked("a").await.unwrap();
+        assert_eq!(state_of(&history, "a").await, TaskState::Pending);
+        assert!(!history.approve("a", "operator", "sig").await.unwrap());
+
+        history.started("a", "worker").await.unwrap();
+        history.parked("a").await.unwrap();
+        assert_eq!(state_of(&history, "a").await, TaskState::Parked);
+        // never by its own submitter
+        assert!(!history.approve("a", "client", "sig").await.unwrap());
+        assert!(history.approve("a", "operator", "sig").await.unwrap());
+        let record = history.get("a").await.unwrap().unwrap();
+        assert_eq!(record.approved_by.as_deref(), Some("operator"));
+        assert_eq!(record.approval.as_deref(), Some("sig"));
+
+        // parked again, the approval set ahead is dropped
+        history.resumed("a").await.unwrap();
+        assert_eq!(state_of(&history, "a").await, TaskState::Started);
+        history.parked("a").await.unwrap();
+        let record = history.get("a").await.unwrap().unwrap();
+        assert_eq!((record.approved_by, record.approval), (None, None));
+
+        // a parked task may be revoked
+        assert!(history.revoke("a").await.unwrap());
+        assert_eq!(state_of(&history, "a").await, TaskState::Revoked);
+    }
+
+    #[tokio::test]
+    async fn test_hostnames() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-history_hostnames")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+        let db = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db");
+        let history = TaskHistory::new(db.collection("tasks"));
+
+        let submitted = [
+            ("a", "client", "ci/gcc/2024-03-08/client"),
+            ("b", "client", "ci/gcc/2024-03-09/client"),
+            ("c", "client", "ci/gcc/2024-03-08/client"),
+            ("d", "other", "ci/gcc/2024-03-10/other"),
+        ];
+        for (task_id, client, hostname) in submitted {
+            history
+                .submitted(task_id, "gcc", "{}", client, hostname, None)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // picked by a worker, but never recorded as submitted
+        history.started("e", "worker").await.unwrap();
+
+        assert_eq!(
+            history.hostnames("client").await.unwrap(),
+            vec!["ci/gcc/2024-03-08/client", "ci/gcc/2024-03-09/client"]
+        );
+        assert!(history.hostnames("unknown").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_request_of_record() {
+        let request = RunRequest::builder()
+            .command(Param::cmd_name("gcc"))
+            .args(vec![Param::str("-c")])
+            .build();
+        let payload = serde_json::to_string(&request).unwrap();
+        let envelope = serde_json::to_string(&AuthEnvelope {
+            credentials: "credentials".to_owned(),
+            payload: payload.clone(),
+            signer: None,
+        })
+        .unwrap();
+
+        let record = |request: Option<String>| TaskRecord {
+            task_id: "a".to_owned(),
+            state: TaskState::Pending,
+            queue: None,
+            request,
+            client: None,
+            hostname: None,
+            worker: None,
+            submitted_at: None,
+            scheduled_at: None,
+            started_at: Some(DateTime::from_millis(1_000)),
+            finished_at: Some(DateTime::from_millis(3_500)),
+            status: None,
+            exc: None,
+            artifacts: vec![],
+            usage: None,
+            approved_by: None,
+            approved_at: None,
+            approval: None,
+        };
+        for serialized in [payload, envelope] {
+            let recorded = record(Some(serialized)).run_request().unwrap().unwrap();
+            assert_eq!(recorded.args, request.args);
+        }
+        assert!(record(None).run_request().unwrap().is_none());
+        assert_eq!(record(None).duration(), Some(Duration::from_millis(2_500)));
+    }
+
+    #[test]
+    fn test_usage_summary_from_group() {
+        let summary = UsageSummary::from_group(&doc! {
+            "_id": "gcc",
+            "runs": 2,
+            "avg_duration_ms": 1500.0,
+            "max_duration_ms": 2000_i64,
+            "avg_cpu_ms": null,
+            "downloaded_bytes": 10,
+            "uploaded_bytes": 20_i64,
+        });
+        assert_eq!(summary.command, "gcc");
+        assert_eq!(summary.runs, 2);
+        assert_eq!(summary.avg_duration, Duration::from_millis(1500));
+        assert_eq!(summary.max_duration, Duration::from_secs(2));
+        assert_eq!((summary.avg_cpu_time, summary.max_rss_bytes), (None, None));
+        assert_eq!((summary.downloaded_bytes, summary.uploaded_bytes), (10, 20));
+    }
+}
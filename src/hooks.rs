@@ -0,0 +1,248 @@
+//! Hooks letting a deployment customize how the worker runs the requests.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use celery::export::async_trait;
+use log::debug;
+
+use crate::protocol::{RunRecipe, RunResponse};
+
+/// Policy applied by the worker to a resolved request right before running it, which can
+/// adjust the recipe, e.g. inject mandatory flags, or veto the run by failing.
+///
+/// The pre-processors are registered by name, and enabled per queue in the server config.
+#[async_trait]
+pub trait PreProcessor: Send + Sync {
+    async fn process(&self, recipe: &mut RunRecipe) -> anyhow::Result<()>;
+}
+
+/// The pre-processors enabled for the queues of the resolved `command`, each once, in the order
+/// of the queues by name and then as `enabled` by the queues.
+///
+/// The celery messages do not tell the queue they are delivered from, and the queue in the
+/// task history is written by the client, hence the queues are told by the command instead,
+/// as those named by the commands of the `palette` resolved to `command`, prefixed by `queue`.
+/// A command out of the palette may have come from any queue of the worker, hence the policies
+/// of all the queues apply to it.
+///
+/// An enabled but unregistered one fails the run, since skipping a policy silently is worse
+/// than not running at all.
+pub(crate) fn enabled_pre_processors(
+    command: &str,
+    palette: &HashMap<String, String>,
+    queue: impl Fn(&str) -> String,
+    enabled: &HashMap<String, Vec<String>>,
+    registered: &HashMap<String, Arc<dyn PreProcessor>>,
+) -> anyhow::Result<Vec<(String, Arc<dyn PreProcessor>)>> {
+    let queues_of_command: Vec<_> = palette
+        .iter()
+        .filter(|(_, path)| path.as_str() == command)
+        .map(|(name, _)| queue(name.as_str()))
+        .collect();
+    let mut queues: Vec<_> = enabled
+        .iter()
+        .filter(|(enabled_queue, _)| {
+            queues_of_command.is_empty() || queues_of_command.contains(*enabled_queue)
+        })
+        .collect();
+    queues.sort();
+    let mut names = Vec::new();
+    for name in queues.into_iter().flat_map(|(_, names)| names) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+        .into_iter()
+        .map(|name| match registered.get(name) {
+            Some(processor) => Ok((name.clone(), processor.clone())),
+            None => Err(anyhow::anyhow!("Pre-processor {} is not registered", name)),
+        })
+        .collect()
+}
+
+/// Run the named processors in order, failing with the first veto.
+pub(crate) async fn pre_process(
+    processors: &[(String, Arc<dyn PreProcessor>)],
+    recipe: &mut RunRecipe,
+) -> anyhow::Result<()> {
+    for (name, processor) in processors {
+        processor
+            .process(recipe)
+            .await
+            .map_err(|err| anyhow::anyhow!("Rejected by pre-processor {}: {}", name, err))?;
+    }
+    Ok(())
+}
+
+/// Step run by the worker right after the command exits, but before its outputs are
+/// uploaded, such as virus-scanning the outputs or normalizing the encodings of the logs.
+///
+/// Every output of the run, as well as the redirected stdout and stderr, is somewhere in
+/// the `workspace` by the time the hook is called.
+#[async_trait]
+pub trait PostProcessor: Send + Sync {
+    async fn process(
+        &self,
+        workspace: &Path,
+        recipe: &RunRecipe,
+        response: &mut RunResponse,
+    ) -> anyhow::Result<()>;
+}
+
+/// Run the processors in order, stopping at the first failure, which is reported in the
+/// response instead of the outputs being silently unprocessed.
+pub(crate) async fn post_process(
+    processors: &[Arc<dyn PostProcessor>],
+    workspace: &Path,
+    recipe: &RunRecipe,
+    response: &mut RunResponse,
+) {
+    for processor in processors {
+        if let Err(err) = processor.process(workspace, recipe, response).await {
+            debug!("  post-processor failed: {}", err);
+            response.exc = Some(format!("Failed to post-process the run: {}", err));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::ExitStatus;
+
+    use super::*;
+
+    struct Append(&'static str);
+
+    #[async_trait]
+    impl PostProcessor for Append {
+        async fn process(
+            &self,
+            _: &Path,
+            _: &RunRecipe,
+            response: &mut RunResponse,
+        ) -> anyhow::Result<()> {
+            anyhow::ensure!(!self.0.is_empty(), "nothing to append");
+            let exc = response.exc.get_or_insert_with(String::new);
+            exc.push_str(self.0);
+            Ok(())
+        }
+    }
+
+    struct Deny(&'static str);
+
+    #[async_trait]
+    impl PreProcessor for Deny {
+        async fn process(&self, recipe: &mut RunRecipe) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                !recipe.args.iter().any(|arg| arg == self.0),
+                "{} is not allowed",
+                self.0
+            );
+            recipe.args.insert(0, "--safe".to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pre_process() {
+        let processors: Vec<(String, Arc<dyn PreProcessor>)> =
+            vec![("deny-force".to_owned(), Arc::new(Deny("--force")))];
+
+        let mut recipe = RunRecipe::builder()
+            .command("/bin/rm".to_owned())
+            .args(vec!["a.txt".to_owned()])
+            .build();
+        pre_process(&processors, &mut recipe).await.unwrap();
+        assert_eq!(recipe.args, vec!["--safe".to_owned(), "a.txt".to_owned()]);
+
+        let mut recipe = RunRecipe::builder()
+            .command("/bin/rm".to_owned())
+            .args(vec!["--force".to_owned()])
+            .build();
+        let err = pre_process(&processors, &mut recipe).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Rejected by pre-processor deny-force: --force is not allowed"
+        );
+    }
+
+    struct Allow;
+
+    #[async_trait]
+    impl PreProcessor for Allow {
+        async fn process(&self, _: &mut RunRecipe) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_enabled_pre_processors() {
+        let palette = HashMap::from([
+            ("rm".to_owned(), "/bin/rm".to_owned()),
+            ("cp".to_owned(), "/bin/cp".to_owned()),
+        ]);
+        let queue = |name: &str| format!("cmdproxy-{}", name);
+        let enabled = HashMap::from([
+            ("cmdproxy-rm".to_owned(), vec!["deny-force".to_owned()]),
+            (
+                "cmdproxy-cp".to_owned(),
+                vec!["allow".to_owned(), "deny-force".to_owned()],
+            ),
+        ]);
+        let registered: HashMap<String, Arc<dyn PreProcessor>> = HashMap::from([
+            (
+                "deny-force".to_owned(),
+                Arc::new(Deny("--force")) as Arc<dyn PreProcessor>,
+            ),
+            ("allow".to_owned(), Arc::new(Allow) as Arc<dyn PreProcessor>),
+        ]);
+        let names_of = |command: &str| {
+            enabled_pre_processors(command, &palette, queue, &enabled, &registered)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        // each queue has its own policy
+        assert_eq!(names_of("/bin/rm"), vec!["deny-force"]);
+        assert_eq!(names_of("/bin/cp"), vec!["allow", "deny-force"]);
+        // while a command out of the palette takes those of all
+        assert_eq!(names_of("/usr/bin/shred"), vec!["allow", "deny-force"]);
+
+        let registered = HashMap::new();
+        let err =
+            enabled_pre_processors("/bin/rm", &palette, queue, &enabled, &registered).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Pre-processor deny-force is not registered"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_process() {
+        let recipe = RunRecipe::builder()
+            .command("/bin/sh".to_owned())
+            .args(vec![])
+            .build();
+
+        let processors: Vec<Arc<dyn PostProcessor>> =
+            vec![Arc::new(Append("a")), Arc::new(Append("b"))];
+        let mut response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
+        post_process(&processors, Path::new("."), &recipe, &mut response).await;
+        assert_eq!(response.exc.as_deref(), Some("ab"));
+
+        let processors: Vec<Arc<dyn PostProcessor>> =
+            vec![Arc::new(Append("")), Arc::new(Append("b"))];
+        let mut response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
+        post_process(&processors, Path::new("."), &recipe, &mut response).await;
+        assert_eq!(
+            response.exc.as_deref(),
+            Some("Failed to post-process the run: nothing to append")
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! Job files, describing a run as data rather than code, so that it can be authored and
+//! version-controlled without writing any Rust.
+//!
+//! A job file is written in YAML, or in JSON if named `*.json`, such as
+//!
+//! ```yaml
+//! command: cmake
+//! args:
+//!   - -S
+//!   - in: src
+//!   - -B
+//!   - out_dir: build
+//!   - format:
+//!       tmpl: "-DCMAKE_INSTALL_PREFIX={prefix}"
+//!       args:
+//!         prefix:
+//!           remote_env: INSTALL_PREFIX
+//! env:
+//!   CC: gcc
+//! stdout: cmake.log
+//! preserve:
+//!   - build
+//! labels:
+//!   team: infra
+//! limits:
+//!   timeout: 10m
+//!   memory_bytes: 4G
+//!   retry:
+//!     transfer: 5
+//! ```
+//!
+//! An argument is either a plain string, a short form of a param as above, or a [`Param`] in
+//! full. Relative local paths are taken as relative to the folder of the job file. The sizes
+//! and the durations are given either in bytes and seconds or with their units, see
+//! [`crate::units`].
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::archive::ArchiveFormat;
+use crate::limits::ResourceLimits;
+use crate::params::Param;
+use crate::protocol::{RunRequest, RunSpecification, Stdin};
+use crate::retry::FailureClass;
+use crate::storage::compression::Compression;
+use crate::units;
+
+/// A run described in a job file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobFile {
+    /// Name of the command in the command palette of the server.
+    pub command: String,
+    /// Queue to send the run to, default to the name of the command.
+    #[serde(default)]
+    pub queue: Option<String>,
+    #[serde(default)]
+    pub args: Vec<JobParam>,
+    /// Working directory of the command on the server, where `${WORKSPACE}` is the temp
+    /// folder of the run.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub env: HashMap<String, JobParam>,
+    /// Local path of the file fed to the stdin of the command.
+    #[serde(default)]
+    pub stdin: Option<String>,
+    /// Local path receiving the stdout of the command.
+    #[serde(default)]
+    pub stdout: Option<String>,
+    /// Local path receiving the stderr of the command.
+    #[serde(default)]
+    pub stderr: Option<String>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub limits: JobLimits,
+    /// Local paths of the inputs and outputs keeping their mtimes, permissions and extended
+    /// attributes across the transfers.
+    #[serde(default)]
+    pub preserve: Vec<String>,
+    /// Transfer only the outputs changed since the previous run, keeping the others as is.
+    #[serde(default)]
+    pub delta: bool,
+    /// How the files are compressed in transfer, or never if not given.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Folder the relative local paths are relative to.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+/// What the run may take, and how hard the client tries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobLimits {
+    /// Seconds the run may take on the worker before being cancelled.
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub timeout: Option<u64>,
+    /// Refuse to send the request if larger than this many bytes once serialized.
+    #[serde(default, deserialize_with = "units::deserialize_opt_size")]
+    pub max_request_bytes: Option<usize>,
+    /// Hold the run back while the queue has more pending tasks than this.
+    #[serde(default)]
+    pub max_queue_depth: Option<u64>,
+    /// Max numbers of retries of the classes of failures, overriding their defaults.
+    #[serde(default)]
+    pub retry: HashMap<FailureClass, u32>,
+    /// Seconds of cpu time each process of the command may take on the worker.
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub cpu_seconds: Option<u64>,
+    /// Bytes of memory the command may take on the worker.
+    #[serde(default, deserialize_with = "units::deserialize_opt_size")]
+    pub memory_bytes: Option<u64>,
+    /// Number of the files each process of the command may open at once on the worker.
+    #[serde(default)]
+    pub max_files: Option<u64>,
+}
+
+impl JobLimits {
+    /// The limits of the resources of the command, if any is given.
+    fn resources(&self) -> Option<ResourceLimits> {
+        let limits = ResourceLimits {
+            cpu_seconds: self.cpu_seconds,
+            memory_bytes: self.memory_bytes,
+            max_files: self.max_files,
+        };
+        (limits != ResourceLimits::default()).then_some(limits)
+    }
+}
+
+/// An argument, or the value of an environment variable, in a job file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum JobParam {
+    Str(String),
+    Short(ShortParam),
+    Full(Param),
+}
+
+/// The short forms of the params, named after the constructors of [`Param`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ShortParam {
+    /// A local input file, see [`Param::ipath`].
+    In(String),
+    /// A local input folder, see [`Param::idir`].
+    InDir(String),
+    /// Local inputs matching a pattern, see [`Param::iglob`].
+    InGlob(String),
+    /// A local output file, see [`Param::opath`].
+    Out(String),
+    /// A local output folder, see [`Param::odir`].
+    OutDir(String),
+    /// Outputs matching a pattern, downloaded into `dir` or else the folder of the job file,
+    /// see [`Param::oglob_to`].
+    OutGlob {
+        pattern: String,
+        #[serde(default)]
+        dir: Option<String>,
+    },
+    /// An environment variable of the client, see [`Param::env`].
+    Env(String),
+    /// An environment variable of the server, see [`Param::remote_env`].
+    RemoteEnv(String),
+    /// A command in the palette of the server, see [`Param::cmd_name`].
+    Cmd(String),
+    /// A command by its path on the server, see [`Param::cmd_path`].
+    CmdPath(String),
+    /// A small file of the text given inline, see [`Param::content`].
+    Content { name: String, text: String },
+    /// A template formatted with other params, see [`Param::format`].
+    Format {
+        tmpl: String,
+        #[serde(default)]
+        args: HashMap<String, JobParam>,
+    },
+}
+
+impl JobParam {
+    fn to_param(&self, base_dir: &Path) -> Param {
+        let local = |filepath: &str| base_dir.join(filepath).to_string_lossy().into_owned();
+        match self {
+            JobParam::Str(value) => Param::str(value),
+            JobParam::Full(param) => param.clone(),
+            JobParam::Short(param) => match param {
+                ShortParam::In(filepath) => Param::ipath(local(filepath)),
+                ShortParam::InDir(dirpath) => Param::idir(local(dirpath)),
+                ShortParam::InGlob(pattern) => Param::iglob_in(pattern, base_dir.to_string_lossy()),
+                ShortParam::Out(filepath) => Param::opath(local(filepath)),
+                ShortParam::OutDir(dirpath) => Param::odir(local(dirpath)),
+                ShortParam::OutGlob { pattern, dir } => {
+                    Param::oglob_to(pattern, local(dir.as_deref().unwrap_or(".")))
+                }
+                ShortParam::Env(name) => Param::env(name),
+                ShortParam::RemoteEnv(name) => Param::remote_env(name),
+                ShortParam::Cmd(name) => Param::cmd_name(name),
+                ShortParam::CmdPath(path) => Param::cmd_path(path),
+                ShortParam::Content { name, text } => Param::content(name, text),
+                ShortParam::Format { tmpl, args } => Param::format(
+                    tmpl,
+                    args.iter()
+                        .map(|(key, arg)| (key.as_str(), arg.to_param(base_dir)))
+                        .collect(),
+                ),
+            },
+        }
+    }
+}
+
+impl JobFile {
+    /// Load the job file at `path`, in JSON if named `*.json`, or else in YAML.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<JobFile> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read job file {}", path.display()))?;
+        let mut job: JobFile = if path.extension().map_or(false, |ext| ext == "json") {
+            serde_json::from_str(content.as_str())?
+        } else {
+            serde_yaml::from_str(content.as_str())?
+        };
+        job.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(job)
+    }
+
+    /// The queue to send the run to.
+    pub fn queue(&self) -> String {
+        self.queue.clone().unwrap_or_else(|| self.command.clone())
+    }
+
+    /// The request of the run described by the job file.
+    pub fn to_request(&self) -> RunRequest {
+        let base_dir = self.base_dir.as_path();
+        let local = |filepath: &String| base_dir.join(filepath).to_string_lossy().into_owned();
+        RunRequest {
+            command: Param::cmd_name(self.command.as_str()),
+            args: self.args.iter().map(|arg| arg.to_param(base_dir)).collect(),
+            cwd: self.cwd.clone(),
+            env: (!self.env.is_empty()).then(|| {
+                self.env
+                    .iter()
+                    .map(|(key, val)| (key.clone(), val.to_param(base_dir)))
+                    .collect()
+            }),
+            stdin: self
+                .stdin
+                .as_ref()
+                .map(local)
+                .map(|filepath| Stdin::File(Param::ipath(filepath))),
+            stdout: self.stdout.as_ref().map(local).map(Param::opath),
+            stderr: self.stderr.as_ref().map(local).map(Param::opath),
+            priority: self.priority,
+            timeout: self.limits.timeout.map(Duration::from_secs),
+            precheck: None,
+            labels: self.labels.clone(),
+            input_bytes: None,
+            input_sizes: HashMap::new(),
+            archive: ArchiveFormat::default(),
+            preserve: self
+                .preserve
+                .iter()
+                .map(|filepath| Param::ipath(local(filepath)).cloud_url())
+                .collect(),
+            input_attrs: HashMap::new(),
+            enqueued_at: None,
+            limits: self.limits.resources(),
+            features: vec![],
+            required_features: vec![],
+            delta: self.delta,
+            output_digests: HashMap::new(),
+            compression: self.compression,
+            encryption_keys: HashMap::new(),
+            dry_run: false,
+        }
+    }
+
+    /// The local files and folders read by the run.
+    pub fn local_inputs(&self) -> Vec<PathBuf> {
+        self.to_request().local_paths(Param::is_input)
+    }
+
+    /// The local files and folders written by the run.
+    pub fn local_outputs(&self) -> Vec<PathBuf> {
+        self.to_request().local_paths(Param::is_output)
+    }
+}
+
+impl RunSpecification<Param> {
+    /// The request described by the job file at `path`, see [`JobFile`].
+    pub fn from_job_file<P: AsRef<Path>>(path: P) -> anyhow::Result<RunRequest> {
+        Ok(JobFile::load(path)?.to_request())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::local_hostname;
+
+    use super::*;
+
+    #[test]
+    fn test_job_file() {
+        let mut job: JobFile = serde_yaml::from_str(
+            r#"
+command: cmake
+args:
+  - -S
+  - in: src
+  - out: /tmp/out.txt
+  - format:
+      tmpl: "--prefix={prefix}"
+      args:
+        prefix:
+          remote_env: PREFIX
+  - StrParam:
+      value: raw
+  - content:
+      name: init.cfg
+      text: "verbose = 1"
+env:
+  CC: gcc
+labels:
+  team: infra
+limits:
+  timeout: 10m
+  retry:
+    out-of-memory: 2
+"#,
+        )
+        .unwrap();
+        job.base_dir = PathBuf::from("/jobs");
+
+        let request = job.to_request();
+        assert_eq!(request.command, Param::cmd_name("cmake"));
+        assert_eq!(
+            request.args,
+            vec![
+                Param::str("-S"),
+                Param::InLocalFileParam {
+                    filepath: "/jobs/src".to_owned(),
+                    hostname: local_hostname(),
+                },
+                Param::opath("/tmp/out.txt"),
+                Param::format(
+                    "--prefix={prefix}",
+                    HashMap::from([("prefix", Param::remote_env("PREFIX"))])
+                ),
+                Param::str("raw"),
+                Param::content("init.cfg", "verbose = 1"),
+            ]
+        );
+        assert_eq!(request.env.unwrap()["CC"], Param::str("gcc"));
+        assert_eq!(request.labels["team"], "infra");
+        assert_eq!(request.timeout, Some(Duration::from_secs(600)));
+        assert_eq!(job.limits.retry[&FailureClass::OutOfMemory], 2);
+        assert_eq!(job.queue(), "cmake");
+        assert_eq!(job.local_inputs(), vec![PathBuf::from("/jobs/src")]);
+        assert_eq!(job.local_outputs(), vec![PathBuf::from("/tmp/out.txt")]);
+
+        // typos are refused rather than silently ignored
+        assert!(serde_yaml::from_str::<JobFile>("command: cmake\ntimout: 1\n").is_err());
+    }
+}
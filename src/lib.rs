@@ -1,11 +1,57 @@
 #![allow(non_upper_case_globals)]
 
+pub mod admission;
 pub mod app;
+pub mod approval;
+pub mod archive;
+pub mod attrs;
+pub mod backpressure;
+pub mod batch;
+pub mod broker;
+pub mod canary;
+pub mod catalog;
+mod chunked;
 pub mod client;
 mod codegen;
+pub mod command_policy;
+mod commands;
+pub mod composite;
 pub mod configs;
+pub mod container;
+pub mod fairness;
+pub mod features;
+pub mod fsck;
+pub mod heuristics;
+pub mod history;
+pub mod hooks;
+pub mod job;
+pub mod limits;
+pub mod metrics;
 pub mod middles;
+mod naming;
+pub mod outcome;
+pub mod palette;
 pub mod params;
+pub mod paths;
+pub mod pipeline;
+pub mod postmortem;
+pub mod precheck;
+pub mod preemption;
+mod process_group;
 pub mod protocol;
+pub mod recording;
+pub mod registry;
+pub mod retry;
+pub mod schedule;
 mod server;
+pub mod sla;
+mod sparse;
+pub mod storage;
+pub mod streams;
 pub mod tasks;
+pub mod tls;
+pub mod transfer;
+pub mod units;
+pub mod usage;
+pub mod warm;
+mod workspace;
@@ -0,0 +1,219 @@
+//! Limits of the resources a command may take on the worker, as asked by the request.
+//!
+//! The memory of a command is capped by a cgroup of its own, if the worker is given a cgroup
+//! v2 delegated to it, under which the memory taken by all the processes of the command is
+//! counted, and a command exceeding it is told apart from one killed for anything else. Or
+//! else, and on the hosts without cgroups, the memory is capped by the address space of each
+//! process, as are the cpu time and the open files, by rlimits set by a shell right before
+//! the command is executed, which fails the run rather than running it unlimited.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::ExitStatus;
+
+/// Limits of the resources of a command, each of which is unlimited if not given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Seconds of cpu time each process of the command may take.
+    #[serde(default)]
+    pub cpu_seconds: Option<u64>,
+    /// Bytes of memory the command may take.
+    #[serde(default)]
+    pub memory_bytes: Option<u64>,
+    /// Number of the files each process of the command may open at once.
+    #[serde(default)]
+    pub max_files: Option<u64>,
+}
+
+/// A limit a command was killed for exceeding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitExceeded {
+    Memory,
+    CpuTime,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Memory => write!(f, "memory limit exceeded"),
+            LimitExceeded::CpuTime => write!(f, "cpu time limit exceeded"),
+        }
+    }
+}
+
+/// Signal sent to a process exceeding its soft limit of cpu time.
+const SIGXCPU: i32 = 24;
+
+const SIGKILL: i32 = 9;
+
+/// The confinement of a command within its limits, removing its cgroup once dropped.
+pub(crate) struct Confinement {
+    limits: ResourceLimits,
+    cgroup: Option<PathBuf>,
+}
+
+impl Confinement {
+    /// Prepare to confine a command of the run of `task_id` within `limits`, in a cgroup
+    /// under `cgroup_root` if given.
+    pub(crate) fn prepare(
+        limits: ResourceLimits,
+        cgroup_root: Option<&Path>,
+        task_id: &str,
+    ) -> anyhow::Result<Confinement> {
+        let cgroup = match (limits.memory_bytes, cgroup_root) {
+            (Some(memory_bytes), Some(root)) => {
+                let cgroup = root.join(format!("cmdproxy-{}", task_id));
+                std::fs::create_dir_all(&cgroup)?;
+                std::fs::write(cgroup.join("memory.max"), memory_bytes.to_string())?;
+                // swapping out would let the command take more than the limit
+                if let Err(err) = std::fs::write(cgroup.join("memory.swap.max"), "0") {
+                    debug!("  keep the swap of {}: {}", cgroup.display(), err);
+                }
+                Some(cgroup)
+            }
+            _ => None,
+        };
+        Ok(Confinement { limits, cgroup })
+    }
+
+    /// The program and the arguments running `command` with `args` within the limits.
+    pub(crate) fn wrap(&self, command: &str, args: &[String]) -> (String, Vec<String>) {
+        let script = self.script();
+        if script.is_empty() {
+            return (command.to_owned(), args.to_vec());
+        }
+        if cfg!(not(unix)) {
+            warn!("  resource limits are not supported on this platform, run unlimited");
+            return (command.to_owned(), args.to_vec());
+        }
+        // the command is executed in place of the shell, by the same pid
+        let mut wrapped = vec!["-c".to_owned(), format!("{}exec \"$0\" \"$@\"", script)];
+        wrapped.push(command.to_owned());
+        wrapped.extend(args.iter().cloned());
+        ("sh".to_owned(), wrapped)
+    }
+
+    /// The shell commands confining the shell itself, each followed by `&&`.
+    fn script(&self) -> String {
+        let mut script = String::new();
+        if let Some(cgroup) = &self.cgroup {
+            let procs = cgroup.join("cgroup.procs");
+            script += format!("echo $$ > {} && ", shell_quote(&procs.to_string_lossy())).as_str();
+        } else if let Some(memory_bytes) = self.limits.memory_bytes {
+            script += format!("ulimit -v {} && ", (memory_bytes + 1023) / 1024).as_str();
+        }
+        if let Some(cpu_seconds) = self.limits.cpu_seconds {
+            script += format!("ulimit -t {} && ", cpu_seconds).as_str();
+        }
+        if let Some(max_files) = self.limits.max_files {
+            script += format!("ulimit -n {} && ", max_files).as_str();
+        }
+        script
+    }
+
+    /// The limit the command finished by `status`, having taken `cpu_ms` of cpu time, was
+    /// killed for exceeding, if any.
+    pub(crate) fn exceeded(
+        &self,
+        status: &ExitStatus,
+        cpu_ms: Option<u64>,
+    ) -> Option<LimitExceeded> {
+        let signal = match *status {
+            ExitStatus::Signaled { signal, .. } => signal,
+            _ => return None,
+        };
+        if signal == SIGKILL {
+            let oom_kills = self
+                .cgroup
+                .as_ref()
+                .and_then(|cgroup| std::fs::read_to_string(cgroup.join("memory.events")).ok())
+                .and_then(|events| parse_oom_kills(events.as_str()));
+            if oom_kills.map_or(false, |kills| kills > 0) {
+                return Some(LimitExceeded::Memory);
+            }
+        }
+        // killed at the hard limit of cpu time, if not by the soft one
+        let cpu_limit_ms = self.limits.cpu_seconds.map(|seconds| seconds * 1000);
+        let over_cpu = matches!((cpu_ms, cpu_limit_ms), (Some(used), Some(limit)) if used >= limit);
+        if signal == SIGXCPU || (signal == SIGKILL && over_cpu) {
+            return Some(LimitExceeded::CpuTime);
+        }
+        None
+    }
+}
+
+impl Drop for Confinement {
+    fn drop(&mut self) {
+        // removable only once all the processes in it have exited
+        if let Some(cgroup) = &self.cgroup {
+            if let Err(err) = std::fs::remove_dir(cgroup) {
+                warn!("  failed to remove cgroup {}: {}", cgroup.display(), err);
+            }
+        }
+    }
+}
+
+/// Parse the count of the OOM kills in `memory.events`.
+fn parse_oom_kills(events: &str) -> Option<u64> {
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Quote `value` as a single word of the shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let limits = ResourceLimits {
+            cpu_seconds: Some(60),
+            memory_bytes: Some(1024 * 1024),
+            max_files: None,
+        };
+        let confinement = Confinement::prepare(limits, None, "task").unwrap();
+        let (program, args) = confinement.wrap("make", &["-j".to_owned(), "it's".to_owned()]);
+        assert_eq!(program, "sh");
+        assert_eq!(
+            args[1],
+            "ulimit -v 1024 && ulimit -t 60 && exec \"$0\" \"$@\""
+        );
+        assert_eq!(&args[2..], ["make", "-j", "it's"]);
+
+        let unlimited = Confinement::prepare(ResourceLimits::default(), None, "task").unwrap();
+        assert_eq!(unlimited.wrap("make", &[]), ("make".to_owned(), vec![]));
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let limits = ResourceLimits {
+            cpu_seconds: Some(2),
+            ..ResourceLimits::default()
+        };
+        let confinement = Confinement::prepare(limits, None, "task").unwrap();
+        let killed = |signal| ExitStatus::Signaled {
+            signal,
+            core_dumped: false,
+        };
+        assert_eq!(
+            confinement.exceeded(&killed(SIGXCPU), None),
+            Some(LimitExceeded::CpuTime)
+        );
+        assert_eq!(
+            confinement.exceeded(&killed(SIGKILL), Some(2500)),
+            Some(LimitExceeded::CpuTime)
+        );
+        assert_eq!(confinement.exceeded(&killed(SIGKILL), Some(100)), None);
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Metrics of a single run, published by the client once the run has finished.
+#[derive(Debug, Clone)]
+pub struct RunMetrics {
+    /// Queue the request was sent to.
+    pub queue: String,
+    /// Time spent on preparing the request, mostly uploading the inputs.
+    pub prepare: Duration,
+    /// Time from submitting the request to receiving the response.
+    pub remote: Duration,
+    /// Time spent on finalizing the response, mostly downloading the outputs.
+    pub finalize: Duration,
+    /// Part of `remote` the request waited in the queue before picked by a worker, if told
+    /// by the worker, as accurate as the clocks of the client and the worker are in sync.
+    pub queue_wait: Option<Duration>,
+    /// Part of `remote` the worker spent on the run, including its transfers, if told.
+    pub execution: Option<Duration>,
+    /// Time from calling [`crate::client::Client::run`] to its return.
+    pub total: Duration,
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    /// Size of the serialized request sent through the broker.
+    pub request_bytes: u64,
+    /// Size of the serialized response, including the one spilled to the cloud if any.
+    pub response_bytes: u64,
+    /// Times the run was retried, by the client and by the worker running the command again.
+    pub retries: u32,
+    /// Whether the run either errored or finished with a non-successful status.
+    pub failed: bool,
+}
+
+/// Receiver of the client metrics, e.g. an adapter feeding them into a prometheus registry.
+///
+/// Any `Fn(&RunMetrics)` works as a sink, for callers just wanting a callback.
+pub trait MetricsSink: Send + Sync {
+    fn record_run(&self, metrics: &RunMetrics);
+}
+
+impl<F> MetricsSink for F
+where
+    F: Fn(&RunMetrics) + Send + Sync,
+{
+    fn record_run(&self, metrics: &RunMetrics) {
+        self(metrics)
+    }
+}
+
+/// Upper bounds of the buckets of the durations, in seconds.
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+/// Upper bounds of the buckets of the sizes, in bytes.
+pub const SIZE_BUCKETS: &[f64] = &[
+    1024.0,
+    16.0 * 1024.0,
+    256.0 * 1024.0,
+    1024.0 * 1024.0,
+    16.0 * 1024.0 * 1024.0,
+    256.0 * 1024.0 * 1024.0,
+    1024.0 * 1024.0 * 1024.0,
+    16.0 * 1024.0 * 1024.0 * 1024.0,
+];
+
+/// Observations counted by the buckets they fall in, as a histogram of prometheus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// Count of each bucket, followed by the one of the values above all the bounds.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    /// An empty histogram of the buckets up to the ascending `bounds`.
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// The upper bounds of the buckets with the counts of the values up to them, cumulative
+    /// as prometheus exposes them, the last one of all the values.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let bounds = self.bounds.iter().copied().chain([f64::INFINITY]);
+        let counts = self.counts.iter().scan(0, |total, count| {
+            *total += count;
+            Some(*total)
+        });
+        bounds.zip(counts).collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+}
+
+/// Histograms of the runs sent to a queue, see [`RunHistograms`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueHistograms {
+    /// Seconds from calling the client to its return.
+    pub total: Histogram,
+    /// Seconds from submitting the request to receiving the response.
+    pub remote: Histogram,
+    /// Seconds the requests waited in the queue, of the runs told so by their workers.
+    pub queue_wait: Histogram,
+    /// Bytes uploaded and downloaded by each run.
+    pub transferred_bytes: Histogram,
+    /// Bytes of the requests and the responses of each run through the broker.
+    pub message_bytes: Histogram,
+    pub runs: u64,
+    pub failures: u64,
+    pub retries: u64,
+}
+
+impl Default for QueueHistograms {
+    fn default() -> Self {
+        QueueHistograms {
+            total: Histogram::new(DURATION_BUCKETS),
+            remote: Histogram::new(DURATION_BUCKETS),
+            queue_wait: Histogram::new(DURATION_BUCKETS),
+            transferred_bytes: Histogram::new(SIZE_BUCKETS),
+            message_bytes: Histogram::new(SIZE_BUCKETS),
+            runs: 0,
+            failures: 0,
+            retries: 0,
+        }
+    }
+}
+
+impl QueueHistograms {
+    fn record(&mut self, metrics: &RunMetrics) {
+        self.total.observe(metrics.total.as_secs_f64());
+        self.remote.observe(metrics.remote.as_secs_f64());
+        if let Some(queue_wait) = metrics.queue_wait {
+            self.queue_wait.observe(queue_wait.as_secs_f64());
+        }
+        let transferred = metrics.uploaded_bytes + metrics.downloaded_bytes;
+        self.transferred_bytes.observe(transferred as f64);
+        let message = metrics.request_bytes + metrics.response_bytes;
+        self.message_bytes.observe(message as f64);
+        self.runs += 1;
+        self.failures += metrics.failed as u64;
+        self.retries += metrics.retries as u64;
+    }
+}
+
+/// Sink aggregating the metrics of the runs into histograms by their queues, for the
+/// applications scraping them into their monitoring on their own.
+#[derive(Debug, Default)]
+pub struct RunHistograms {
+    queues: Mutex<HashMap<String, QueueHistograms>>,
+}
+
+impl RunHistograms {
+    /// The histograms of the runs sent to each queue so far.
+    pub fn snapshot(&self) -> HashMap<String, QueueHistograms> {
+        self.queues.lock().unwrap().clone()
+    }
+}
+
+impl MetricsSink for RunHistograms {
+    fn record_run(&self, metrics: &RunMetrics) {
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(metrics.queue.clone())
+            .or_default()
+            .record(metrics);
+    }
+}
+
+/// Bytes transferred between the local host and the cloud or the broker during a run.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded_bytes: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.uploaded_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn request_bytes(&self) -> u64 {
+        self.request_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn response_bytes(&self) -> u64 {
+        self.response_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_uploaded(&self, bytes: u64) {
+        self.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_request(&self, bytes: u64) {
+        self.request_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_response(&self, bytes: u64) {
+        self.response_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Workspaces on this worker removed by force for outliving their max lifetime.
+static LEAKED_WORKSPACES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of the workspaces on this worker which have leaked since it started, which is also
+/// advertised in the registry of the workers.
+pub fn leaked_workspaces() -> u64 {
+    LEAKED_WORKSPACES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn add_leaked_workspaces(count: u64) {
+    LEAKED_WORKSPACES.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Alerts raised on this worker for exceeding the thresholds of its SLA.
+static SLA_ALERTS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of the alerts raised on this worker since it started, see [`crate::sla`], which is
+/// also advertised in the registry of the workers.
+pub fn sla_alerts() -> u64 {
+    SLA_ALERTS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn add_sla_alert() {
+    SLA_ALERTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Outcomes of the canary runs of a worker, see [`crate::canary`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanaryStats {
+    pub runs: u64,
+    pub failures: u64,
+    /// Number of the latest canary runs which failed in a row.
+    pub failures_in_row: u32,
+    /// Milliseconds the latest canary run took, command and storage round trip together.
+    pub last_latency_ms: u64,
+    /// Whether the worker takes itself as fit to serve, which it does not once too many
+    /// canary runs failed in a row, until one succeeds again.
+    pub healthy: bool,
+}
+
+impl Default for CanaryStats {
+    fn default() -> Self {
+        CanaryStats {
+            runs: 0,
+            failures: 0,
+            failures_in_row: 0,
+            last_latency_ms: 0,
+            healthy: true,
+        }
+    }
+}
+
+impl CanaryStats {
+    /// Count a canary run taking `latency`, turning unhealthy after `max_failures` failures
+    /// in a row, and return whether the health has changed.
+    pub(crate) fn record(&mut self, ok: bool, latency: Duration, max_failures: u32) -> bool {
+        self.runs += 1;
+        self.last_latency_ms = latency.as_millis() as u64;
+        if ok {
+            self.failures_in_row = 0;
+        } else {
+            self.failures += 1;
+            self.failures_in_row += 1;
+        }
+        let healthy = self.failures_in_row < max_failures.max(1);
+        std::mem::replace(&mut self.healthy, healthy) != healthy
+    }
+}
+
+/// Canary runs of this worker, if it runs any.
+static CANARY: Lazy<Mutex<Option<CanaryStats>>> = Lazy::new(Mutex::default);
+
+/// The outcomes of the canary runs of this worker so far, if it runs any, which are also
+/// advertised in the registry of the workers.
+pub fn canary_stats() -> Option<CanaryStats> {
+    *CANARY.lock().unwrap()
+}
+
+/// Count a canary run of this worker, see [`CanaryStats::record`].
+pub(crate) fn record_canary(ok: bool, latency: Duration, max_failures: u32) -> (CanaryStats, bool) {
+    let mut canary = CANARY.lock().unwrap();
+    let stats = canary.get_or_insert_with(CanaryStats::default);
+    let changed = stats.record(ok, latency, max_failures);
+    (*stats, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_metrics(queue: &str, total_ms: u64, bytes: u64, retries: u32) -> RunMetrics {
+        RunMetrics {
+            queue: queue.to_owned(),
+            prepare: Duration::ZERO,
+            remote: Duration::from_millis(total_ms),
+            finalize: Duration::ZERO,
+            queue_wait: None,
+            execution: None,
+            total: Duration::from_millis(total_ms),
+            uploaded_bytes: bytes,
+            downloaded_bytes: 0,
+            request_bytes: 512,
+            response_bytes: 512,
+            retries,
+            failed: retries > 0,
+        }
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        for value in [0.5, 1.0, 3.0, 20.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(
+            histogram.buckets(),
+            vec![(1.0, 2), (10.0, 3), (f64::INFINITY, 4)]
+        );
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), 24.5);
+    }
+
+    #[test]
+    fn test_run_histograms() {
+        let histograms = RunHistograms::default();
+        histograms.record_run(&run_metrics("gcc", 20, 2048, 0));
+        histograms.record_run(&run_metrics("gcc", 2_000, 4 * 1024 * 1024, 2));
+        histograms.record_run(&run_metrics("ld", 20, 0, 0));
+
+        let snapshot = histograms.snapshot();
+        let gcc = &snapshot["gcc"];
+        assert_eq!((gcc.runs, gcc.failures, gcc.retries), (2, 1, 2));
+        assert_eq!(gcc.total.count(), 2);
+        // 20ms and 2s
+        assert_eq!(gcc.total.buckets()[1], (0.05, 1));
+        assert_eq!(gcc.total.buckets()[5], (5.0, 2));
+        // 2KiB and 4MiB
+        assert_eq!(gcc.transferred_bytes.buckets()[1], (16.0 * 1024.0, 1));
+        assert_eq!(
+            gcc.transferred_bytes.buckets()[4],
+            (16.0 * 1024.0 * 1024.0, 2)
+        );
+        assert_eq!(gcc.message_bytes.sum(), 2048.0);
+        // the waits untold by the workers are not observed
+        assert_eq!(gcc.queue_wait.count(), 0);
+
+        let ld = &snapshot["ld"];
+        assert_eq!((ld.runs, ld.retries), (1, 0));
+    }
+}
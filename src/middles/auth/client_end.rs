@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use celery::export::async_trait;
+
+use crate::middles::auth::{signed_payload, AuthMiddle};
+use crate::middles::Middle;
+use crate::protocol::AuthEnvelope;
+
+pub(crate) struct MiddleImpl {
+    auth: Arc<dyn AuthMiddle>,
+    stored: bool,
+    signer: Option<String>,
+}
+
+impl MiddleImpl {
+    pub(crate) fn new(auth: Arc<dyn AuthMiddle>) -> MiddleImpl {
+        MiddleImpl {
+            auth,
+            stored: false,
+            signer: None,
+        }
+    }
+
+    /// Sign the requests stored to be sent later, see [`AuthMiddle::credentials_stored`].
+    pub(crate) fn stored(auth: Arc<dyn AuthMiddle>) -> MiddleImpl {
+        MiddleImpl {
+            auth,
+            stored: true,
+            signer: None,
+        }
+    }
+
+    /// Sign the requests on behalf of the client of `client_id`, which the worker verifies
+    /// along with the requests.
+    pub(crate) fn signed_by(mut self, client_id: String) -> MiddleImpl {
+        self.signer = Some(client_id);
+        self
+    }
+
+    async fn credentials(&self, payload: &str) -> anyhow::Result<Option<String>> {
+        if self.stored {
+            self.auth.credentials_stored(payload).await
+        } else {
+            self.auth.credentials(payload).await
+        }
+    }
+}
+
+#[async_trait]
+impl Middle<String, String, String, String> for MiddleImpl {
+    async fn transform_request(&self, request: String) -> anyhow::Result<String> {
+        let signer = self.signer.as_deref();
+        let signed = signed_payload(signer, request.as_str());
+        match self.credentials(signed.as_ref()).await? {
+            Some(credentials) => Ok(serde_json::to_string(&AuthEnvelope {
+                credentials,
+                payload: request,
+                signer: self.signer.clone(),
+            })?),
+            None => Ok(request),
+        }
+    }
+
+    async fn transform_response(&self, response: anyhow::Result<String>) -> anyhow::Result<String> {
+        response
+    }
+}
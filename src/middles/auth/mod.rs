@@ -0,0 +1,165 @@
+use std::borrow::Cow;
+
+use celery::export::async_trait;
+
+pub(crate) mod client_end;
+pub(crate) mod server_end;
+mod signing;
+
+pub use signing::HmacAuth;
+
+/// Authentication scheme shared by the client and the server.
+///
+/// The client attaches the credentials computed from the serialized request, and the
+/// server verifies them against the same payload before deserializing it.
+#[async_trait]
+pub trait AuthMiddle: Send + Sync {
+    /// Compute the credentials to be attached to `payload`, or `None` to send it as is.
+    async fn credentials(&self, payload: &str) -> anyhow::Result<Option<String>>;
+
+    /// Verify the `credentials` attached to `payload`, which is `None` if nothing attached.
+    async fn verify(&self, payload: &str, credentials: Option<&str>) -> anyhow::Result<()>;
+
+    /// Compute the credentials to be attached to `payload` stored to be sent later, such as of
+    /// a recurring run, which must never pass [`AuthMiddle::verify`] if they hold for longer.
+    async fn credentials_stored(&self, payload: &str) -> anyhow::Result<Option<String>> {
+        self.credentials(payload).await
+    }
+
+    /// Whether [`AuthMiddle::verify`] refuses anything, without which nothing attached to a
+    /// request, such as the id of its signer, can be taken for verified.
+    fn authenticates(&self) -> bool {
+        true
+    }
+
+    /// Verify the `credentials` attached to `payload` stored to be sent later, which hold
+    /// however long ago they were attached, see [`AuthMiddle::credentials_stored`].
+    async fn verify_stored(&self, payload: &str, credentials: Option<&str>) -> anyhow::Result<()> {
+        self.verify(payload, credentials).await
+    }
+}
+
+/// What the credentials of `payload` are computed over, binding the id of its `signer` if
+/// any, prefixed by its length so that no other pair of a signer and a payload is the same.
+pub(crate) fn signed_payload<'a>(signer: Option<&str>, payload: &'a str) -> Cow<'a, str> {
+    match signer {
+        Some(signer) => Cow::Owned(format!("signer {}:{}\n{}", signer.len(), signer, payload)),
+        None => Cow::Borrowed(payload),
+    }
+}
+
+/// Accept everything and attach nothing, which is the default.
+pub struct NoAuth;
+
+#[async_trait]
+impl AuthMiddle for NoAuth {
+    async fn credentials(&self, _: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn verify(&self, _: &str, _: Option<&str>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn authenticates(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use once_cell::sync::OnceCell;
+
+    use crate::middles::Middle;
+    use crate::protocol::AuthEnvelope;
+
+    use super::*;
+
+    const REQUEST: &str = r#"{"command":"gcc"}"#;
+
+    #[tokio::test]
+    async fn test_signed_request_passes() {
+        let auth: Arc<dyn AuthMiddle> = Arc::new(HmacAuth::new("shared secret"));
+        let client = client_end::MiddleImpl::new(auth.clone()).signed_by("client-a".to_owned());
+        let signer = Arc::new(OnceCell::new());
+        let server = server_end::MiddleImpl::new(auth).with_signer(signer.clone());
+
+        let sent = client.transform_request(REQUEST.to_owned()).await.unwrap();
+        assert_ne!(sent, REQUEST);
+        let received = server.transform_request(sent).await.unwrap();
+        assert_eq!(received, REQUEST);
+        assert_eq!(signer.get().map(String::as_str), Some("client-a"));
+    }
+
+    #[tokio::test]
+    async fn test_tampered_request_refused() {
+        let auth: Arc<dyn AuthMiddle> = Arc::new(HmacAuth::new("shared secret"));
+        let client = client_end::MiddleImpl::new(auth.clone()).signed_by("client-a".to_owned());
+        let sent = client.transform_request(REQUEST.to_owned()).await.unwrap();
+        let envelope: AuthEnvelope = serde_json::from_str(sent.as_str()).unwrap();
+
+        let tampered = [
+            AuthEnvelope {
+                payload: r#"{"command":"rm"}"#.to_owned(),
+                ..envelope.clone()
+            },
+            AuthEnvelope {
+                signer: Some("client-b".to_owned()),
+                ..envelope.clone()
+            },
+            AuthEnvelope {
+                signer: None,
+                ..envelope
+            },
+        ];
+        for envelope in tampered {
+            let signer = Arc::new(OnceCell::new());
+            let server = server_end::MiddleImpl::new(auth.clone()).with_signer(signer.clone());
+            let sent = serde_json::to_string(&envelope).unwrap();
+            assert!(server.transform_request(sent).await.is_err());
+            assert!(signer.get().is_none());
+        }
+
+        // so are the unsigned ones, and the ones signed by another secret
+        let server = server_end::MiddleImpl::new(auth.clone());
+        assert!(server.transform_request(REQUEST.to_owned()).await.is_err());
+        let other = client_end::MiddleImpl::new(Arc::new(HmacAuth::new("another secret")));
+        let sent = other.transform_request(REQUEST.to_owned()).await.unwrap();
+        assert!(server.transform_request(sent).await.is_err());
+
+        // and the stored ones sent as live
+        let stored = client_end::MiddleImpl::stored(auth);
+        let sent = stored.transform_request(REQUEST.to_owned()).await.unwrap();
+        assert!(server.transform_request(sent).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_auth_passes_through() {
+        let auth: Arc<dyn AuthMiddle> = Arc::new(NoAuth);
+        let client = client_end::MiddleImpl::new(auth.clone()).signed_by("client-a".to_owned());
+        let signer = Arc::new(OnceCell::new());
+        let server = server_end::MiddleImpl::new(auth).with_signer(signer.clone());
+
+        let sent = client.transform_request(REQUEST.to_owned()).await.unwrap();
+        assert_eq!(sent, REQUEST);
+        let received = server.transform_request(sent).await.unwrap();
+        assert_eq!(received, REQUEST);
+        assert!(signer.get().is_none());
+
+        // a signer claimed by an envelope is never taken for verified
+        let forged = serde_json::to_string(&AuthEnvelope {
+            credentials: "forged".to_owned(),
+            payload: REQUEST.to_owned(),
+            signer: Some("client-b".to_owned()),
+        })
+        .unwrap();
+        let received = server.transform_request(forged).await.unwrap();
+        assert_eq!(received, REQUEST);
+        assert!(signer.get().is_none());
+
+        let response = client.transform_response(Ok("done".to_owned())).await;
+        assert_eq!(response.unwrap(), "done");
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use celery::export::async_trait;
+use once_cell::sync::OnceCell;
+
+use crate::middles::auth::{signed_payload, AuthMiddle};
+use crate::middles::Middle;
+use crate::protocol::AuthEnvelope;
+
+pub(crate) struct MiddleImpl {
+    auth: Arc<dyn AuthMiddle>,
+    stored: bool,
+    signer: Arc<OnceCell<String>>,
+}
+
+impl MiddleImpl {
+    pub(crate) fn new(auth: Arc<dyn AuthMiddle>) -> MiddleImpl {
+        MiddleImpl {
+            auth,
+            stored: false,
+            signer: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Verify the requests stored to be sent later, see [`AuthMiddle::verify_stored`].
+    pub(crate) fn stored(auth: Arc<dyn AuthMiddle>) -> MiddleImpl {
+        MiddleImpl {
+            auth,
+            stored: true,
+            signer: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Set the id of the client which signed the request into `signer` once verified, which
+    /// is left unset if the request is signed by no one, or if the auth verifies nothing.
+    pub(crate) fn with_signer(mut self, signer: Arc<OnceCell<String>>) -> MiddleImpl {
+        self.signer = signer;
+        self
+    }
+
+    async fn verify(&self, payload: &str, credentials: Option<&str>) -> anyhow::Result<()> {
+        if self.stored {
+            self.auth.verify_stored(payload, credentials).await
+        } else {
+            self.auth.verify(payload, credentials).await
+        }
+    }
+}
+
+#[async_trait]
+impl Middle<String, String, String, String> for MiddleImpl {
+    async fn transform_request(&self, request: String) -> anyhow::Result<String> {
+        match serde_json::from_str::<AuthEnvelope>(request.as_str()) {
+            Ok(envelope) => {
+                let signer = envelope.signer.as_deref();
+                let signed = signed_payload(signer, envelope.payload.as_str());
+                self.verify(signed.as_ref(), Some(envelope.credentials.as_str()))
+                    .await?;
+                if let Some(signer) = envelope.signer.filter(|_| self.auth.authenticates()) {
+                    let _ = self.signer.set(signer);
+                }
+                Ok(envelope.payload)
+            }
+            Err(_) => {
+                self.verify(request.as_str(), None).await?;
+                Ok(request)
+            }
+        }
+    }
+
+    async fn transform_response(&self, response: anyhow::Result<String>) -> anyhow::Result<String> {
+        response
+    }
+}
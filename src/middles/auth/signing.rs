@@ -0,0 +1,260 @@
+//! Requests signed by HMAC-SHA256 under a secret shared by the clients and the workers, so
+//! that a worker on a broker shared with others refuses the requests of unauthorized clients.
+//!
+//! The client attaches the signature of the serialized request together with when it is
+//! issued, as `hmac-sha256:<unix secs>:<base64>`, and the worker verifies it before
+//! deserializing anything, refusing the unsigned requests as well. A request issued longer
+//! ago, or later, than the max skew is refused, so that a captured request cannot be
+//! replayed for long. The secret is taken from `$CMDPROXY_HMAC_SECRET` or a secret file,
+//! which is also what a config names, keeping the secret itself out of the configs.
+//!
+//! The requests stored to be sent later, such as of the recurring runs, are signed under a
+//! scheme of their own, as `hmac-sha256-stored:<base64>`, which holds however long ago it was
+//! attached. Each scheme is refused where the other is expected, so that a live request
+//! captured off the broker cannot be stored to be replayed forever.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use celery::export::async_trait;
+use hmac::{Hmac, Mac};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
+
+use crate::middles::auth::AuthMiddle;
+
+/// Prefix of the credentials telling the scheme of the signature.
+const SCHEME: &str = "hmac-sha256:";
+/// Prefix of the credentials of the requests stored to be sent later.
+const STORED_SCHEME: &str = "hmac-sha256-stored:";
+/// What the signatures of the stored requests are computed over in place of the issue time,
+/// which is never a number as that of the live requests is.
+const STORED_DOMAIN: &str = "stored";
+
+/// How far the clock of the signer may be off from that of the verifier by default, which
+/// bounds how long a captured request can be replayed.
+const MAX_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Sign the requests by, and verify them against, a shared secret.
+#[derive(Clone)]
+pub struct HmacAuth {
+    secret: Vec<u8>,
+    max_skew: Duration,
+}
+
+impl FromStr for HmacAuth {
+    type Err = anyhow::Error;
+
+    /// Take the secret as is, except for the surrounding whitespaces.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secret = s.trim();
+        anyhow::ensure!(!secret.is_empty(), "HMAC secret must not be empty");
+        Ok(HmacAuth::new(secret))
+    }
+}
+
+impl<'de> Deserialize<'de> for HmacAuth {
+    /// Load the secret from the file at the path given, see [`HmacAuth::load`].
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = PathBuf::deserialize(deserializer)?;
+        HmacAuth::load(path.as_path()).map_err(|err| {
+            D::Error::custom(format!(
+                "Malformed HMAC secret in {}: {}",
+                path.display(),
+                err
+            ))
+        })
+    }
+}
+
+impl fmt::Debug for HmacAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HmacAuth(..)")
+    }
+}
+
+impl HmacAuth {
+    pub fn new(secret: impl Into<Vec<u8>>) -> HmacAuth {
+        HmacAuth {
+            secret: secret.into(),
+            max_skew: MAX_SKEW,
+        }
+    }
+
+    /// Refuse the requests issued further than `max_skew` from now.
+    pub fn with_max_skew(mut self, max_skew: Duration) -> HmacAuth {
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// Load the secret from the file at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<HmacAuth> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    fn mac(&self, issued_at: impl fmt::Display, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_slice())
+            .expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n", issued_at).as_bytes());
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn sign(&self, issued_at: u64, payload: &str) -> String {
+        let signature = self.mac(issued_at, payload).finalize().into_bytes();
+        format!("{}{}:{}", SCHEME, issued_at, BASE64.encode(signature))
+    }
+
+    fn sign_stored(&self, payload: &str) -> String {
+        let signature = self.mac(STORED_DOMAIN, payload).finalize().into_bytes();
+        format!("{}{}", STORED_SCHEME, BASE64.encode(signature))
+    }
+
+    /// Verify the signature of the stored `payload`, refusing those of the live requests.
+    fn verify_stored_signature(
+        &self,
+        payload: &str,
+        credentials: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let credentials = credentials.ok_or_else(|| anyhow!("Refused unsigned stored request"))?;
+        let signature = credentials
+            .strip_prefix(STORED_SCHEME)
+            .ok_or_else(|| anyhow!("Refused stored request not signed to be stored"))?;
+        self.mac(STORED_DOMAIN, payload)
+            .verify_slice(BASE64.decode(signature)?.as_slice())
+            .map_err(|_| anyhow!("Refused stored request of a mismatched signature"))
+    }
+
+    /// Verify the signature of `payload`, returning when it was issued.
+    fn verify_signature(&self, payload: &str, credentials: Option<&str>) -> anyhow::Result<u64> {
+        let credentials = credentials.ok_or_else(|| anyhow!("Refused unsigned request"))?;
+        let (issued_at, signature) = credentials
+            .strip_prefix(SCHEME)
+            .and_then(|signed| signed.split_once(':'))
+            .ok_or_else(|| anyhow!("Refused request signed by an unknown scheme"))?;
+        let issued_at: u64 = issued_at
+            .parse()
+            .map_err(|_| anyhow!("Refused request of a malformed issue time"))?;
+        self.mac(issued_at, payload)
+            .verify_slice(BASE64.decode(signature)?.as_slice())
+            .map_err(|_| anyhow!("Refused request of a mismatched signature"))?;
+        Ok(issued_at)
+    }
+
+    fn verify_at(&self, now: u64, payload: &str, credentials: Option<&str>) -> anyhow::Result<()> {
+        let issued_at = self.verify_signature(payload, credentials)?;
+        anyhow::ensure!(
+            now.abs_diff(issued_at) <= self.max_skew.as_secs(),
+            "Refused request issued {}s away from now, over {}s",
+            now.abs_diff(issued_at),
+            self.max_skew.as_secs()
+        );
+        Ok(())
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[async_trait]
+impl AuthMiddle for HmacAuth {
+    async fn credentials(&self, payload: &str) -> anyhow::Result<Option<String>> {
+        Ok(Some(self.sign(unix_secs(), payload)))
+    }
+
+    async fn verify(&self, payload: &str, credentials: Option<&str>) -> anyhow::Result<()> {
+        self.verify_at(unix_secs(), payload, credentials)
+    }
+
+    async fn credentials_stored(&self, payload: &str) -> anyhow::Result<Option<String>> {
+        Ok(Some(self.sign_stored(payload)))
+    }
+
+    async fn verify_stored(&self, payload: &str, credentials: Option<&str>) -> anyhow::Result<()> {
+        self.verify_stored_signature(payload, credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sign_and_verify() {
+        let auth: HmacAuth = "shared secret\n".parse().unwrap();
+        let payload = r#"{"command":"gcc"}"#;
+        let credentials = auth.credentials(payload).await.unwrap().unwrap();
+        assert!(credentials.starts_with(SCHEME));
+        assert!(auth
+            .verify(payload, Some(credentials.as_str()))
+            .await
+            .is_ok());
+
+        assert!(auth.verify(payload, None).await.is_err());
+        let tampered = r#"{"command":"rm"}"#;
+        assert!(auth
+            .verify(tampered, Some(credentials.as_str()))
+            .await
+            .is_err());
+        let other = HmacAuth::new("another secret");
+        assert!(other
+            .verify(payload, Some(credentials.as_str()))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_issued_at() {
+        let auth = HmacAuth::new("shared secret").with_max_skew(Duration::from_secs(60));
+        let payload = r#"{"command":"gcc"}"#;
+        let credentials = auth.sign(1_000, payload);
+        assert!(auth.verify_at(1_030, payload, Some(&credentials)).is_ok());
+        assert!(auth.verify_at(970, payload, Some(&credentials)).is_ok());
+        // a replay long after is refused
+        assert!(auth.verify_at(1_061, payload, Some(&credentials)).is_err());
+
+        // so is one of a forged issue time
+        let forged = credentials.replacen("1000", "1050", 1);
+        assert!(auth.verify_at(1_061, payload, Some(&forged)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sign_and_verify_stored() {
+        let auth = HmacAuth::new("shared secret");
+        let payload = r#"{"command":"gcc"}"#;
+        let stored = auth.credentials_stored(payload).await.unwrap().unwrap();
+        assert!(stored.starts_with(STORED_SCHEME));
+        assert!(auth.verify_stored(payload, Some(&stored)).await.is_ok());
+        assert!(auth.verify_stored(payload, None).await.is_err());
+        let tampered = r#"{"command":"rm"}"#;
+        assert!(auth.verify_stored(tampered, Some(&stored)).await.is_err());
+
+        // a live request captured cannot be stored to be replayed forever
+        let live = auth.credentials(payload).await.unwrap().unwrap();
+        assert!(auth.verify_stored(payload, Some(&live)).await.is_err());
+        // nor is a stored one taken as live
+        assert!(auth.verify(payload, Some(&stored)).await.is_err());
+        let relabeled = stored.replacen(STORED_SCHEME, SCHEME, 1);
+        assert!(auth.verify(payload, Some(&relabeled)).await.is_err());
+    }
+
+    #[test]
+    fn test_load_from_config() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"shared secret\n").unwrap();
+        let path = serde_json::to_string(file.path()).unwrap();
+        let auth: HmacAuth = serde_json::from_str(path.as_str()).unwrap();
+        let credentials = auth.sign(1_000, "payload");
+        let expected = HmacAuth::new("shared secret").sign(1_000, "payload");
+        assert_eq!(credentials, expected);
+    }
+}
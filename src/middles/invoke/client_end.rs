@@ -1,21 +1,77 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use celery::export::async_trait;
-use log::debug;
-use mongodb_gridfs::GridFSBucket;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
 use tokio::sync::Mutex;
 
+use crate::archive::ArchiveFormat;
+use crate::attrs::FileAttrs;
+use crate::features;
+use crate::metrics::TransferStats;
 use crate::middles::invoke::{
     guard_hashmap_args, push_guard, ArcMtxRefCell, ArgGuard, GuardStack, GuardStackData,
     InvokeMiddle,
 };
-use crate::params::Param;
+use crate::outcome::format_bytes;
+use crate::params::{content_digest, local_size, Param, MAX_CONTENT_SIZE};
+use crate::paths::{normalize_separators, to_native_relpath};
+use crate::protocol::{now_millis, Artifact, ArtifactStatus, RunRequest, RunResponse};
+use crate::storage::encryption::key_name_for;
+use crate::storage::{CompressingStorage, Storage};
+
+/// Numbers of the runs in flight in this process using each uploaded input, by its cloud
+/// url, so that concurrent runs sharing an input, such as those served by one agent, upload
+/// it only once and never remove it from under each other.
+static SHARED_INPUTS: Lazy<std::sync::Mutex<HashMap<String, Arc<Mutex<usize>>>>> =
+    Lazy::new(Default::default);
+
+fn shared_input(cloud_url: String) -> Arc<Mutex<usize>> {
+    SHARED_INPUTS
+        .lock()
+        .unwrap()
+        .entry(cloud_url)
+        .or_default()
+        .clone()
+}
+
+/// Forget the input once no run is using or waiting for it.
+fn release_shared_input(cloud_url: &str, shared: &Arc<Mutex<usize>>) {
+    let mut inputs = SHARED_INPUTS.lock().unwrap();
+    // one held by the registry, and the other by the caller
+    if Arc::strong_count(shared) <= 2 {
+        inputs.remove(cloud_url);
+    }
+}
 
 struct Data {
-    bucket: GridFSBucket,
+    storage: Storage,
     guards: Vec<Box<dyn ArgGuard<Param, Data>>>,
+    artifacts: Vec<Artifact>,
+    stats: Arc<TransferStats>,
+    /// Bytes of the uploaded inputs by their cloud urls, declared to the server.
+    input_sizes: HashMap<String, u64>,
+    /// Archive format of the input folders, as requested.
+    archive: ArchiveFormat,
+    /// Cloud urls of the params whose attributes are preserved, as requested.
+    preserve: HashSet<String>,
+    /// Attributes of the preserved inputs by the cloud urls they are uploaded to.
+    input_attrs: HashMap<String, FileAttrs>,
+    /// Attributes of the preserved outputs by their cloud urls, as responded.
+    output_attrs: HashMap<String, FileAttrs>,
+    /// Whether only the outputs changed are transferred, as requested.
+    delta: bool,
+    /// Digests of the local outputs as of the previous run, by their cloud urls.
+    output_digests: HashMap<String, String>,
+    /// Names of the keys encrypting the inputs by their paths, as requested.
+    encryption_keys: HashMap<String, String>,
+    /// Whether the command is not run, hence nothing is to be downloaded, as requested.
+    dry_run: bool,
 }
 
 impl GuardStackData<Param, Param> for Data {
@@ -28,11 +84,21 @@ impl GuardStackData<Param, Param> for Data {
             Param::RemoteEnvParam { name } => Box::new(RemoteEnvGuard { name }),
             Param::CmdNameParam { name } => Box::new(CmdNameGuard { name }),
             Param::CmdPathParam { path } => Box::new(CmdPathGuard { path }),
+            Param::ContentParam { name, content } => Box::new(ContentGuard { name, content }),
             Param::FormatParam { tmpl, args } => Box::new(FormatGuard { tmpl, args }),
-            param @ Param::InLocalFileParam { .. } => Box::new(InLocalFileGuard { param }),
+            param @ (Param::InLocalFileParam { .. } | Param::InLocalDirParam { .. }) => {
+                Box::new(InLocalFileGuard { param })
+            }
             param @ Param::OutLocalFileParam { .. } => Box::new(OutLocalFileGuard { param }),
-            param @ Param::InCloudFileParam { .. } => Box::new(InCloudFileGuard { param }),
+            param @ (Param::InCloudFileParam { .. }
+            | Param::InCloudDirParam { .. }
+            | Param::InCloudGlobParam { .. }) => Box::new(InCloudFileGuard { param }),
+            param @ Param::InLocalGlobParam { .. } => Box::new(InLocalGlobGuard { param }),
             param @ Param::OutCloudFileParam { .. } => Box::new(OutCloudFileGuard { param }),
+            param @ Param::OutLocalDirParam { .. } => Box::new(OutLocalDirGuard { param }),
+            param @ Param::OutCloudDirParam { .. } => Box::new(OutCloudFileGuard { param }),
+            param @ Param::OutLocalGlobParam { .. } => Box::new(OutLocalGlobGuard { param }),
+            param @ Param::OutCloudGlobParam { .. } => Box::new(OutCloudFileGuard { param }),
         }
     }
 
@@ -65,6 +131,11 @@ struct CmdPathGuard {
     path: String,
 }
 
+struct ContentGuard {
+    name: String,
+    content: String,
+}
+
 struct InCloudFileGuard {
     param: Param,
 }
@@ -77,10 +148,22 @@ struct InLocalFileGuard {
     param: Param,
 }
 
+struct InLocalGlobGuard {
+    param: Param,
+}
+
 struct OutLocalFileGuard {
     param: Param,
 }
 
+struct OutLocalDirGuard {
+    param: Param,
+}
+
+struct OutLocalGlobGuard {
+    param: Param,
+}
+
 struct FormatGuard {
     tmpl: String,
     args: HashMap<String, Param>,
@@ -131,6 +214,24 @@ impl ArgGuard<Param, Data> for CmdPathGuard {
     }
 }
 
+#[async_trait]
+impl ArgGuard<Param, Data> for ContentGuard {
+    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
+        let size = BASE64.decode(self.content.as_str())?.len();
+        anyhow::ensure!(
+            size <= MAX_CONTENT_SIZE,
+            "Content of {} too large to send inline: {} > {}",
+            self.name,
+            format_bytes(size as u64),
+            format_bytes(MAX_CONTENT_SIZE as u64)
+        );
+        Ok(Param::ContentParam {
+            name: self.name.clone(),
+            content: self.content.clone(),
+        })
+    }
+}
+
 #[async_trait]
 impl ArgGuard<Param, Data> for InCloudFileGuard {
     async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
@@ -141,32 +242,113 @@ impl ArgGuard<Param, Data> for InCloudFileGuard {
 #[async_trait]
 impl ArgGuard<Param, Data> for InLocalFileGuard {
     async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
+        if let Some(relpath) = shared_relpath(data, &self.param).await {
+            debug!("Pass local input {} in place...", self.param.filepath());
+            return Ok(self.param.as_shared(relpath));
+        }
+        let (storage, keyed) = storage_for(data, &self.param).await?;
+        let size = local_size(Path::new(self.param.filepath()));
+        if !self.param.is_dir() {
+            let blob = upload_blob(data, &self.param, storage, keyed, size).await?;
+            record_attrs(data, &self.param, blob.cloud_url()).await?;
+            return Ok(blob);
+        }
         debug!(
             "Upload local input {} to {}...",
             self.param.filepath(),
             self.param.cloud_url(),
         );
-
-        let bucket = {
+        let shared = shared_input(self.param.cloud_url());
+        let mut users = shared.lock().await;
+        if *users == 0 {
+            let archive = {
+                let data = data.lock().await;
+                let data = data.borrow();
+                data.archive
+            };
+            self.param
+                .upload_archived(storage, self.param.filepath(), archive)
+                .await?;
+            stats(data).await.add_uploaded(size);
+        } else {
+            debug!("  reuse the upload by another run in flight");
+        }
+        *users += 1;
+        {
             let data = data.lock().await;
-            let data = data.borrow();
-            data.bucket.clone()
-        };
-        self.param.upload_inplace(bucket).await?;
+            let mut data = data.borrow_mut();
+            data.input_sizes.insert(self.param.cloud_url(), size);
+        }
+        record_attrs(data, &self.param, self.param.cloud_url()).await?;
         Ok(self.param.as_cloud())
     }
 
     //noinspection DuplicatedCode
     async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
-        let bucket = {
+        // the blobs are kept for the later runs over the same content
+        if !self.param.is_dir() || shared_relpath(data, &self.param).await.is_some() {
+            return Ok(());
+        }
+        let storage = {
             let data = data.lock().await;
             let data = data.borrow();
-            data.bucket.clone()
+            data.storage.clone()
         };
-        self.param
-            .remove_from_cloud(bucket)
-            .await
-            .map_err(Into::into)
+        let cloud_url = self.param.cloud_url();
+        let shared = shared_input(cloud_url.clone());
+        let mut users = shared.lock().await;
+        *users = users.saturating_sub(1);
+        if *users > 0 {
+            return Ok(());
+        }
+        // removed while still locked, so that a run coming meanwhile uploads it again
+        let res = self.param.remove_from_cloud(storage).await;
+        release_shared_input(cloud_url.as_str(), &shared);
+        res
+    }
+
+    async fn abandon(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        self.exit(data).await
+    }
+}
+
+#[async_trait]
+impl ArgGuard<Param, Data> for InLocalGlobGuard {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
+        debug!("Upload local inputs matching {}...", self.param.pattern());
+        let base = Path::new(self.param.filepath());
+        let mut files = HashMap::new();
+        for path in glob::glob(self.param.pattern())? {
+            let path = path?;
+            if !path.is_file() {
+                continue;
+            }
+            let relpath = path
+                .strip_prefix(base)
+                .unwrap_or_else(|_| Path::new(path.file_name().unwrap()))
+                .to_str()
+                .unwrap();
+            // separated by `/`, so that the server can place it on whatever platform
+            let relpath = normalize_separators(relpath);
+            let child = Param::InLocalFileParam {
+                filepath: path.to_str().unwrap().to_owned(),
+                hostname: self.param.hostname().to_owned(),
+            };
+            // uploaded and removed as a standalone input, on the guard stack of its own
+            files.insert(relpath, push_guard(data, child, None).await?);
+        }
+        anyhow::ensure!(
+            !files.is_empty(),
+            "No local input matches {}",
+            self.param.pattern()
+        );
+
+        Ok(Param::InCloudGlobParam {
+            pattern: self.param.pattern().to_owned(),
+            filepath: self.param.filepath().to_owned(),
+            hostname: self.param.hostname().to_owned(),
+            files,
+        })
     }
 }
 
@@ -179,31 +361,148 @@ impl ArgGuard<Param, Data> for OutCloudFileGuard {
 
 #[async_trait]
 impl ArgGuard<Param, Data> for OutLocalFileGuard {
-    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
-        Ok(self.param.as_cloud())
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
+        Ok(match shared_relpath(data, &self.param).await {
+            Some(relpath) => self.param.as_shared(relpath),
+            None => {
+                record_output_digest(data, &self.param).await;
+                self.param.as_cloud()
+            }
+        })
     }
 
     async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        // written in place by the server, or not at all
+        if shared_relpath(data, &self.param).await.is_some() || is_dry_run(data).await {
+            return Ok(());
+        }
+        if is_unchanged(data, &self.param).await {
+            debug!("Keep unchanged output {}", self.param.filepath());
+            return Ok(());
+        }
         debug!(
             "Download cloud output {} to {}...",
             self.param.cloud_url(),
             self.param.filepath()
         );
 
-        let bucket = {
+        let storage = {
             let data = data.lock().await;
             let data = data.borrow();
-            data.bucket.clone()
+            data.storage.clone()
         };
-        self.param.download_inplace(bucket.clone()).await?;
+        self.param.download_inplace(storage.clone()).await?;
+        restore_attrs(data, &self.param).await?;
+        stats(data)
+            .await
+            .add_downloaded(local_size(Path::new(self.param.filepath())));
         self.param
-            .remove_from_cloud(bucket)
+            .remove_from_cloud(storage)
             .await
             .unwrap_or_default();
         Ok(())
     }
 }
 
+#[async_trait]
+impl ArgGuard<Param, Data> for OutLocalDirGuard {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
+        Ok(match shared_relpath(data, &self.param).await {
+            Some(relpath) => self.param.as_shared(relpath),
+            None => self.param.as_cloud(),
+        })
+    }
+
+    async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        // written in place by the server, or not at all
+        if shared_relpath(data, &self.param).await.is_some() || is_dry_run(data).await {
+            return Ok(());
+        }
+        debug!(
+            "Export cloud output folder {} to {}...",
+            self.param.cloud_url(),
+            self.param.filepath()
+        );
+
+        let storage = {
+            let data = data.lock().await;
+            let data = data.borrow();
+            data.storage.clone()
+        };
+        tokio::fs::create_dir_all(self.param.filepath()).await?;
+        self.param.download_inplace(storage.clone()).await?;
+        restore_attrs(data, &self.param).await?;
+        stats(data)
+            .await
+            .add_downloaded(local_size(Path::new(self.param.filepath())));
+        self.param
+            .remove_from_cloud(storage)
+            .await
+            .unwrap_or_default();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArgGuard<Param, Data> for OutLocalGlobGuard {
+    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
+        Ok(self.param.as_cloud())
+    }
+
+    async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        let (storage, artifacts) = {
+            let data = data.lock().await;
+            let data = data.borrow();
+            (data.storage.clone(), data.artifacts.clone())
+        };
+
+        let cloud = self.param.as_cloud();
+        let mut failures = vec![];
+        for artifact in artifacts {
+            let child = cloud.child(artifact.relpath.as_str());
+            if child.cloud_url() != artifact.cloud_url || !artifact.is_ok() {
+                continue;
+            }
+
+            let filepath =
+                Path::new(self.param.filepath()).join(to_native_relpath(artifact.relpath.as_str()));
+            debug!(
+                "Download cloud output {} to {}...",
+                artifact.cloud_url,
+                filepath.display()
+            );
+            // the failed one is kept on the cloud, to be fetched again later
+            if let Err(err) = download_artifact(storage.clone(), &child, filepath.as_path()).await {
+                warn!("  failed to download {}: {}", artifact.cloud_url, err);
+                failures.push((artifact.cloud_url, err.to_string()));
+                continue;
+            }
+            stats(data)
+                .await
+                .add_downloaded(local_size(filepath.as_path()));
+            child
+                .remove_from_cloud(storage.clone())
+                .await
+                .unwrap_or_default();
+        }
+
+        let data = data.lock().await;
+        let mut data = data.borrow_mut();
+        for (cloud_url, cause) in failures {
+            if let Some(artifact) = data
+                .artifacts
+                .iter_mut()
+                .find(|artifact| artifact.cloud_url == cloud_url)
+            {
+                artifact.status = ArtifactStatus::Failed {
+                    cause: format!("failed to download: {}", cause),
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl ArgGuard<Param, Data> for FormatGuard {
     async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<Param> {
@@ -215,6 +514,163 @@ impl ArgGuard<Param, Data> for FormatGuard {
     }
 }
 
+/// The storage encrypting the upload of the param by the key picked for it, if any, and
+/// whether one is picked.
+async fn storage_for(data: &ArcMtxRefCell<Data>, param: &Param) -> anyhow::Result<(Storage, bool)> {
+    let data = data.lock().await;
+    let data = data.borrow();
+    match key_name_for(&data.encryption_keys, param.filepath()) {
+        Some(name) => Ok((data.storage.encrypting_by(name)?, true)),
+        None => Ok((data.storage.clone(), false)),
+    }
+}
+
+/// Upload the local input file as the blob of its content, unless the blob is on the cloud
+/// already, such as uploaded by an earlier run over the same content.
+///
+/// The blob of a file encrypted by a picked key is uploaded anyway, under a name keyed by
+/// the key, so that it is never taken from a blob of the same content by another key.
+async fn upload_blob(
+    data: &ArcMtxRefCell<Data>,
+    param: &Param,
+    storage: Storage,
+    keyed: bool,
+    size: u64,
+) -> anyhow::Result<Param> {
+    let path = Path::new(param.filepath());
+    let digest = content_digest(path).await?;
+    let blob = param.as_blob(storage.blob_name(digest.as_str()).as_str());
+    let cloud_url = blob.cloud_url();
+    debug!(
+        "Upload local input {} to {}...",
+        param.filepath(),
+        cloud_url
+    );
+
+    // uploaded by one run at a time, so that the others never see it half replaced
+    let shared = shared_input(cloud_url.clone());
+    let res = async {
+        let _uploading = shared.lock().await;
+        let digest = (!keyed).then_some(digest.as_str());
+        blob.upload_blob(storage, path, digest).await
+    }
+    .await;
+    release_shared_input(cloud_url.as_str(), &shared);
+    if res? {
+        stats(data).await.add_uploaded(size);
+    } else {
+        debug!("  reuse the blob uploaded already");
+    }
+
+    let data = data.lock().await;
+    let mut data = data.borrow_mut();
+    data.input_sizes.insert(cloud_url, size);
+    Ok(blob)
+}
+
+/// Record the attributes of the local input, if asked to preserve, to be sent along in the
+/// request under the cloud url it is uploaded to.
+async fn record_attrs(
+    data: &ArcMtxRefCell<Data>,
+    param: &Param,
+    cloud_url: String,
+) -> anyhow::Result<()> {
+    let data = data.lock().await;
+    let mut data = data.borrow_mut();
+    if data.preserve.contains(param.cloud_url().as_str()) {
+        let attrs = FileAttrs::read(Path::new(param.filepath()))?;
+        data.input_attrs.insert(cloud_url, attrs);
+    }
+    Ok(())
+}
+
+/// Apply the attributes of the downloaded output, if responded by the server.
+async fn restore_attrs(data: &ArcMtxRefCell<Data>, param: &Param) -> anyhow::Result<()> {
+    let attrs = {
+        let data = data.lock().await;
+        let data = data.borrow();
+        data.output_attrs.get(param.cloud_url().as_str()).cloned()
+    };
+    if let Some(attrs) = attrs {
+        attrs.apply(Path::new(param.filepath()), false)?;
+    }
+    Ok(())
+}
+
+async fn download_artifact(storage: Storage, param: &Param, filepath: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = filepath.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    param.download(storage, filepath).await?;
+    Ok(())
+}
+
+/// Where the file of the local param is on the filesystem shared with the server, if it is,
+/// in which case it is passed by its path instead of being transferred.
+async fn shared_relpath(data: &ArcMtxRefCell<Data>, param: &Param) -> Option<String> {
+    let data = data.lock().await;
+    let data = data.borrow();
+    data.storage.share(Path::new(param.filepath()))
+}
+
+async fn stats(data: &ArcMtxRefCell<Data>) -> Arc<TransferStats> {
+    let data = data.lock().await;
+    let data = data.borrow();
+    data.stats.clone()
+}
+
+/// Record the digest of the local output as left by the previous run, if only the changed
+/// outputs are to be transferred.
+async fn record_output_digest(data: &ArcMtxRefCell<Data>, param: &Param) {
+    let delta = {
+        let data = data.lock().await;
+        let data = data.borrow();
+        data.delta
+    };
+    let path = Path::new(param.filepath());
+    if !delta || !path.is_file() {
+        return;
+    }
+    match content_digest(path).await {
+        Ok(digest) => {
+            let data = data.lock().await;
+            let mut data = data.borrow_mut();
+            data.output_digests.insert(param.cloud_url(), digest);
+        }
+        Err(err) => debug!("Failed to hash output {}: {}", param.filepath(), err),
+    }
+}
+
+/// Whether the output has been reported unchanged since the previous run.
+async fn is_unchanged(data: &ArcMtxRefCell<Data>, param: &Param) -> bool {
+    let cloud_url = param.cloud_url();
+    let data = data.lock().await;
+    let data = data.borrow();
+    data.artifacts.iter().any(|artifact| {
+        artifact.cloud_url == cloud_url && artifact.status == ArtifactStatus::Unchanged
+    })
+}
+
+/// Whether the run is a dry run, which leaves no outputs to download.
+async fn is_dry_run(data: &ArcMtxRefCell<Data>) -> bool {
+    let data = data.lock().await;
+    let data = data.borrow();
+    data.dry_run
+}
+
+/// Undo the guards left on the stack by a run abandoned before its response.
+async fn abandon_guards(data: &ArcMtxRefCell<Data>) {
+    let guards: Vec<_> = {
+        let data = data.lock().await;
+        let mut data = data.borrow_mut();
+        data.guards.drain(..).rev().collect()
+    };
+    let results = futures::future::join_all(guards.iter().map(|guard| guard.abandon(data))).await;
+    for err in results.into_iter().filter_map(Result::err) {
+        warn!("Failed to clean up after the abandoned run: {}", err);
+    }
+}
+
 struct ContextStack {
     data: ArcMtxRefCell<Data>,
 }
@@ -231,21 +687,75 @@ pub(crate) struct MiddleImpl {
 }
 
 impl MiddleImpl {
+    pub fn new(storage: Storage) -> MiddleImpl {
+        MiddleImpl::with_stats(storage, Arc::new(TransferStats::default()))
+    }
+
     //noinspection DuplicatedCode
-    pub fn new(bucket: GridFSBucket) -> MiddleImpl {
+    pub fn with_stats(storage: Storage, stats: Arc<TransferStats>) -> MiddleImpl {
         MiddleImpl {
             ctx: ContextStack {
                 data: Arc::new(Mutex::new(RefCell::new(Data {
-                    bucket,
+                    storage,
                     guards: Vec::new(),
+                    artifacts: Vec::new(),
+                    stats,
+                    input_sizes: HashMap::new(),
+                    archive: ArchiveFormat::default(),
+                    preserve: HashSet::new(),
+                    input_attrs: HashMap::new(),
+                    output_attrs: HashMap::new(),
+                    delta: false,
+                    output_digests: HashMap::new(),
+                    encryption_keys: HashMap::new(),
+                    dry_run: false,
                 }))),
             },
         }
     }
 }
 
+impl Drop for MiddleImpl {
+    /// Clean up after the run dropped by its caller, such as on a timeout of its own, while
+    /// the guards are still on the stack, which is only possible before its response.
+    fn drop(&mut self) {
+        let abandoned = match self.ctx.data.try_lock() {
+            Ok(data) => !data.borrow().guards.is_empty(),
+            Err(_) => true,
+        };
+        if !abandoned {
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                debug!("Clean up after the abandoned run...");
+                let data = self.ctx.data.clone();
+                handle.spawn(async move { abandon_guards(&data).await });
+            }
+            Err(_) => warn!("Failed to clean up after the abandoned run: no runtime"),
+        }
+    }
+}
+
 #[async_trait]
 impl InvokeMiddle<Param, Param> for MiddleImpl {
+    async fn admit(&self, request: &RunRequest) -> anyhow::Result<()> {
+        let data = self.ctx.data.lock().await;
+        let mut data = data.borrow_mut();
+        data.archive = request.archive;
+        data.preserve = request.preserve.clone();
+        data.delta = request.delta;
+        data.encryption_keys = request.encryption_keys.clone();
+        data.dry_run = request.dry_run;
+        if let Some(compression) = request.compression {
+            data.storage = Arc::new(CompressingStorage::new(
+                data.storage.clone(),
+                Some(compression),
+            ));
+        }
+        Ok(())
+    }
+
     async fn push_guard(&self, param: Param, key: Option<String>) -> anyhow::Result<Param> {
         self.ctx.push_guard(param, key).await
     }
@@ -253,6 +763,41 @@ impl InvokeMiddle<Param, Param> for MiddleImpl {
     async fn pop_all_guards(&self) -> anyhow::Result<Vec<()>> {
         self.ctx.pop_all_guards().await
     }
+
+    async fn abandon(&self) {
+        abandon_guards(&self.ctx.data).await
+    }
+
+    async fn fill_request(&self, request: &mut RunRequest) {
+        let data = self.ctx.data.lock().await;
+        let mut data = data.borrow_mut();
+        // the sizes declared by the caller are kept, to be checked against by the server
+        for (cloud_url, size) in std::mem::take(&mut data.input_sizes) {
+            request.input_sizes.entry(cloud_url).or_insert(size);
+        }
+        request
+            .input_attrs
+            .extend(std::mem::take(&mut data.input_attrs));
+        request
+            .output_digests
+            .extend(std::mem::take(&mut data.output_digests));
+        features::advertise(request);
+        // the inputs are uploaded by now, hence the request is as good as sent
+        request.enqueued_at = Some(now_millis());
+    }
+
+    async fn peek_response(&self, response: &RunResponse) {
+        let data = self.ctx.data.lock().await;
+        let mut data = data.borrow_mut();
+        data.artifacts = response.artifacts.clone();
+        data.output_attrs = response.output_attrs.clone();
+    }
+
+    async fn fill_response(&self, response: &mut RunResponse) {
+        let data = self.ctx.data.lock().await;
+        let data = data.borrow();
+        response.artifacts = data.artifacts.clone();
+    }
 }
 
 #[cfg(test)]
@@ -265,7 +810,8 @@ mod tests {
     use test_utilities::docker;
 
     use crate::middles::Middle;
-    use crate::protocol::{RunRequest, RunResponse};
+    use crate::protocol::{ExitStatus, RunRequest, RunResponse};
+    use crate::storage::GridFsStorage;
 
     use super::*;
 
@@ -283,6 +829,7 @@ mod tests {
             .unwrap()
             .database("cmdproxy-test-client-db")
             .bucket(None);
+        let storage = GridFsStorage::shared(bucket);
 
         let fake_workspace = tempdir().unwrap();
 
@@ -345,7 +892,7 @@ mod tests {
             .build();
 
         {
-            let invoke_middle = MiddleImpl::new(bucket.clone());
+            let invoke_middle = MiddleImpl::new(storage.clone());
             let wrapped_req = invoke_middle.transform_request(req).await.unwrap();
 
             assert!(matches!(wrapped_req.command,
@@ -370,11 +917,11 @@ mod tests {
 
             // assert file params have been transformed as cloud param
             for (wrapped_in_param, (_, content)) in wrapped_in_params.iter().zip(in_params.iter()) {
-                assert!(wrapped_in_param.is_input() && wrapped_in_param.is_cloud());
+                assert!(wrapped_in_param.is_input() && wrapped_in_param.is_blob());
 
                 // assert input files have been uploaded
                 let uploaded_content = wrapped_in_param
-                    .download_to_string(bucket.clone())
+                    .download_to_string(storage.clone())
                     .await
                     .unwrap();
                 assert_eq!(content, &uploaded_content);
@@ -388,15 +935,12 @@ mod tests {
             // mimic server to upload output files after running
             for (out_param, content) in &out_params {
                 out_param
-                    .upload_from_string(bucket.clone(), content.as_str())
+                    .upload_from_string(storage.clone(), content.as_str())
                     .await
                     .unwrap();
             }
 
-            let run_response = RunResponse {
-                return_code: 0,
-                exc: None,
-            };
+            let run_response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
             invoke_middle
                 .transform_response(Ok(run_response))
                 .await
@@ -405,16 +949,64 @@ mod tests {
 
         // assert all the inputs have been removed from the cloud
         for (in_param, _content) in in_params {
-            assert!(!in_param.exists_on_cloud(bucket.clone()).await.unwrap());
+            assert!(!in_param.exists_on_cloud(storage.clone()).await.unwrap());
         }
 
         // assert all the outputs have been downloaded, and been removed from the cloud
         for (out_param, content) in out_params {
-            assert!(!out_param.exists_on_cloud(bucket.clone()).await.unwrap());
+            assert!(!out_param.exists_on_cloud(storage.clone()).await.unwrap());
             assert_eq!(
                 content,
                 std::fs::read_to_string(out_param.filepath()).unwrap()
             );
         }
     }
+
+    #[tokio::test]
+    async fn test_blob_kept_for_later_runs() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-client-blob")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+
+        let bucket = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-client-blob-db")
+            .bucket(None);
+        let storage = GridFsStorage::shared(bucket);
+
+        let fake_workspace = tempdir().unwrap();
+        let mut fake_input = NamedTempFile::new_in(fake_workspace.path()).unwrap();
+        fake_input
+            .write_all((30..50).fake::<String>().as_bytes())
+            .unwrap();
+
+        let mut blobs = vec![];
+        for _ in 0..2 {
+            let req = RunRequest::builder()
+                .command(Param::str("/bin/cat"))
+                .args(vec![Param::ipath(fake_input.path().to_str().unwrap())])
+                .build();
+
+            let invoke_middle = MiddleImpl::new(storage.clone());
+            let wrapped_req = invoke_middle.transform_request(req).await.unwrap();
+            let blob = wrapped_req.args.first().unwrap().clone();
+            assert!(blob.is_blob());
+
+            let run_response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
+            invoke_middle
+                .transform_response(Ok(run_response))
+                .await
+                .unwrap();
+
+            // assert the blob has been kept on the cloud for the later runs
+            assert!(blob.exists_on_cloud(storage.clone()).await.unwrap());
+            blobs.push(blob.cloud_url());
+        }
+
+        // assert the later run has reused the blob of the same content
+        assert_eq!(blobs[0], blobs[1]);
+    }
 }
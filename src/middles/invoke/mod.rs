@@ -8,7 +8,7 @@ use celery::export::async_trait;
 use tokio::sync::Mutex;
 
 use crate::middles::Middle;
-use crate::protocol::{RunResponse, RunSpecification};
+use crate::protocol::{RunResponse, RunSpecification, Stdin};
 
 pub mod client_end;
 pub mod server_end;
@@ -22,6 +22,12 @@ where
     async fn exit(&self, _: &ArcMtxRefCell<D>) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// Undo what `enter` did, such as removing the uploaded input, when the run is abandoned
+    /// before any response to `exit` on.
+    async fn abandon(&self, _: &ArcMtxRefCell<D>) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -30,29 +36,75 @@ where
     PA: Send + Sync,
     PB: Send + Sync,
 {
+    /// Refuse the request before guarding any of its params, e.g. for lack of resources, or
+    /// take what the guards need from it.
+    async fn admit(&self, _: &RunSpecification<PA>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Refuse the request by its command as resolved by its guard, which is pushed ahead of
+    /// the others, e.g. for a command the worker does not run.
+    async fn admit_command(&self, _: PB) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     async fn push_guard(&self, param: PA, key: Option<String>) -> anyhow::Result<PB>;
     async fn pop_all_guards(&self) -> anyhow::Result<Vec<()>>;
+
+    /// Undo the guards pushed so far, for a request failing before it could be sent.
+    async fn abandon(&self) {}
+
+    /// Complete the request with what has been collected while pushing the guards.
+    async fn fill_request(&self, _: &mut RunSpecification<PB>) {}
+
+    /// Peek the response before popping the guards, so that they can act on it when exiting.
+    async fn peek_response(&self, _: &RunResponse) {}
+
+    /// Complete the response with what has been collected while popping the guards.
+    async fn fill_response(&self, _: &mut RunResponse) {}
 }
 
 #[async_trait]
 impl<PA, PB, M> Middle<RunSpecification<PA>, RunResponse, RunSpecification<PB>, RunResponse> for M
 where
     PA: Send + Sync + 'static,
-    PB: Send + Sync,
+    PB: Clone + Send + Sync,
     M: InvokeMiddle<PA, PB>,
 {
     async fn transform_request(
         &self,
         request: RunSpecification<PA>,
     ) -> anyhow::Result<RunSpecification<PB>> {
-        guard_run_args(request, |param, key| self.push_guard(param, key)).await
+        self.admit(&request).await?;
+        let res = guard_run_args(
+            request,
+            |param, key| self.push_guard(param, key),
+            |command| self.admit_command(command),
+        )
+        .await;
+        let mut request = match res {
+            Ok(request) => request,
+            Err(err) => {
+                // the guards entered already would never be popped without a response
+                self.abandon().await;
+                return Err(err);
+            }
+        };
+        self.fill_request(&mut request).await;
+        Ok(request)
     }
 
     async fn transform_response(
         &self,
-        response: anyhow::Result<RunResponse>,
+        mut response: anyhow::Result<RunResponse>,
     ) -> anyhow::Result<RunResponse> {
+        if let Ok(response) = response.as_ref() {
+            self.peek_response(response).await;
+        }
         self.pop_all_guards().await?;
+        if let Ok(response) = response.as_mut() {
+            self.fill_response(response).await;
+        }
         response
     }
 }
@@ -158,15 +210,41 @@ where
     Ok(args)
 }
 
-async fn guard_run_args<PA, PB, F, Fut>(
+async fn guard_run_args<PA, PB, F, Fut, C, CFut>(
     run_request: RunSpecification<PA>,
     mut fn_guard: F,
+    check_command: C,
 ) -> anyhow::Result<RunSpecification<PB>>
 where
+    PB: Clone,
     F: FnMut(PA, Option<String>) -> Fut,
     Fut: Future<Output = anyhow::Result<PB>>,
+    C: FnOnce(PB) -> CFut,
+    CFut: Future<Output = anyhow::Result<()>>,
 {
+    // resolved ahead of the others, so that nothing else is guarded for a refused command
+    let command = fn_guard(run_request.command, None).await?;
+    check_command(command.clone()).await?;
+
     let cwd = run_request.cwd;
+    let priority = run_request.priority;
+    let timeout = run_request.timeout;
+    let precheck = run_request.precheck;
+    let labels = run_request.labels;
+    let input_bytes = run_request.input_bytes;
+    let input_sizes = run_request.input_sizes;
+    let archive = run_request.archive;
+    let preserve = run_request.preserve;
+    let input_attrs = run_request.input_attrs;
+    let enqueued_at = run_request.enqueued_at;
+    let limits = run_request.limits;
+    let features = run_request.features;
+    let required_features = run_request.required_features;
+    let delta = run_request.delta;
+    let output_digests = run_request.output_digests;
+    let compression = run_request.compression;
+    let encryption_keys = run_request.encryption_keys;
+    let dry_run = run_request.dry_run;
     let env = if let Some(env) = run_request.env {
         let mut wrapped_env = HashMap::new();
         for (key, arg) in env.into_iter() {
@@ -178,12 +256,17 @@ where
         None
     };
 
+    let stdin = match run_request.stdin {
+        Some(Stdin::File(param)) => Some(Stdin::File(fn_guard(param, None).await?)),
+        Some(Stdin::Content(content)) => Some(Stdin::Content(content)),
+        None => None,
+    };
+
     let has_stdout = run_request.stdout.as_ref().map(|_| ());
     let has_stderr = run_request.stderr.as_ref().map(|_| ());
 
     let mut wrapped_args = futures::future::join_all(
         iter::empty()
-            .chain(iter::once(run_request.command))
             .chain(run_request.stdout.into_iter())
             .chain(run_request.stderr.into_iter())
             .chain(run_request.args.into_iter())
@@ -193,7 +276,6 @@ where
     .into_iter()
     .collect::<anyhow::Result<LinkedList<_>>>()?;
 
-    let command = wrapped_args.pop_front().unwrap();
     let stdout = has_stdout.and_then(|_| wrapped_args.pop_front());
     let stderr = has_stderr.and_then(|_| wrapped_args.pop_front());
     let args = wrapped_args.into_iter().collect();
@@ -203,8 +285,27 @@ where
         args,
         cwd,
         env,
+        stdin,
         stdout,
         stderr,
+        priority,
+        timeout,
+        precheck,
+        labels,
+        input_bytes,
+        input_sizes,
+        archive,
+        preserve,
+        input_attrs,
+        enqueued_at,
+        limits,
+        features,
+        required_features,
+        delta,
+        output_digests,
+        compression,
+        encryption_keys,
+        dry_run,
     })
 }
 
@@ -212,13 +313,17 @@ pub type ArcMtxRefCell<T> = Arc<Mutex<RefCell<T>>>;
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::AtomicBool;
+
     use chain_ext::mongodb_gridfs::DatabaseExt;
     use tempfile::tempdir;
     use test_utilities::docker;
 
     use crate::middles::invoke::server_end::Config;
     use crate::params::Param;
-    use crate::protocol::RunRequest;
+    use crate::protocol::{ExitStatus, RunRequest};
+    use crate::retry::RetryPolicy;
+    use crate::storage::GridFsStorage;
 
     use super::*;
 
@@ -235,10 +340,18 @@ mod tests {
             .unwrap()
             .database("cmdproxy-test-db")
             .bucket(None);
+        let storage = GridFsStorage::shared(bucket);
 
         let fake_password = "fake password";
         let conf = Config {
             command_palette: HashMap::<String, String>::new(),
+            transfer: None,
+            retry: RetryPolicy::NEVER,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task_id: String::new(),
+            admission: None,
+            command_policy: None,
+            preserve_ownership: false,
         };
 
         let req = RunRequest::builder()
@@ -256,7 +369,7 @@ mod tests {
             .build();
 
         let server_tempdir = tempdir().unwrap();
-        let middle = server_end::MiddleImpl::new(bucket.clone(), server_tempdir, conf);
+        let middle = server_end::MiddleImpl::new(storage, server_tempdir, conf);
         let spec = middle.transform_request(req).await;
 
         assert!(spec.is_ok());
@@ -265,4 +378,108 @@ mod tests {
         assert_eq!(spec.stdout, Some(fake_password.to_owned()));
         assert_eq!(spec.stderr, Some(fake_password.to_owned()));
     }
+
+    #[tokio::test]
+    async fn test_guard_stdin_resolve_passed_env() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-guard_stdin")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+
+        let bucket = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db")
+            .bucket(None);
+        let storage = GridFsStorage::shared(bucket);
+
+        let fake_password = "fake password";
+        let conf = Config {
+            command_palette: HashMap::<String, String>::new(),
+            transfer: None,
+            retry: RetryPolicy::NEVER,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task_id: String::new(),
+            admission: None,
+            command_policy: None,
+            preserve_ownership: false,
+        };
+
+        let req = RunRequest::builder()
+            .command(Param::str("/bin/sh"))
+            .args(vec![])
+            .stdin(Stdin::File(Param::env("PASSWORD")))
+            .env(HashMap::from([(
+                "PASSWORD".to_owned(),
+                Param::str(fake_password),
+            )]))
+            .build();
+
+        let server_tempdir = tempdir().unwrap();
+        let middle = server_end::MiddleImpl::new(storage, server_tempdir, conf);
+        let spec = middle.transform_request(req).await.unwrap();
+        assert_eq!(spec.stdin, Some(Stdin::File(fake_password.to_owned())));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_through_invoke_middles() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-dry_run")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+
+        let bucket = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db")
+            .bucket(None);
+        let storage = GridFsStorage::shared(bucket);
+
+        let conf = Config {
+            command_palette: HashMap::<String, String>::new(),
+            transfer: None,
+            retry: RetryPolicy::NEVER,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task_id: String::new(),
+            admission: None,
+            command_policy: None,
+            preserve_ownership: false,
+        };
+
+        let client_workspace = tempdir().unwrap();
+        let output = client_workspace.path().join("output");
+        let req = RunRequest::builder()
+            .command(Param::str("/bin/sh"))
+            .args(vec![
+                Param::str("-c"),
+                Param::format(
+                    "echo ran > {output}",
+                    HashMap::from([("output", Param::opath(output.to_str().unwrap()))]),
+                ),
+            ])
+            .dry_run(true)
+            .build();
+
+        let client = client_end::MiddleImpl::new(storage.clone());
+        let server = server_end::MiddleImpl::new(storage, tempdir().unwrap(), conf);
+        let wrapped_req = client.transform_request(req).await.unwrap();
+        assert!(wrapped_req.dry_run);
+        let spec = server.transform_request(wrapped_req).await.unwrap();
+        assert!(spec.dry_run);
+
+        // as the worker does, report the recipe instead of running it
+        let response = RunResponse {
+            recipe: Some(spec.clone()),
+            ..RunResponse::from_status(ExitStatus::Unknown)
+        };
+        let response = server.transform_response(Ok(response)).await.unwrap();
+        let response = client.transform_response(Ok(response)).await.unwrap();
+
+        let recipe = response.recipe.unwrap();
+        assert_eq!(recipe.command, "/bin/sh");
+        assert_eq!(recipe.args, spec.args);
+        assert!(!output.exists());
+    }
 }
@@ -1,29 +1,80 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use celery::export::async_trait;
 use chain_ext::path::file_ext::FileExt;
-use log::debug;
-use mongodb_gridfs::GridFSBucket;
+use log::{debug, warn};
+use mongodb::bson::oid::ObjectId;
 use strfmt::strfmt;
 use tempfile::{TempDir, TempPath};
 use tokio::sync::Mutex;
 
+use crate::admission::{AdmissionPolicy, Reservation};
+use crate::archive::ArchiveFormat;
+use crate::attrs::FileAttrs;
+use crate::command_policy::CommandPolicy;
 use crate::middles::invoke::{
     guard_hashmap_args, push_guard, ArcMtxRefCell, ArgGuard, GuardStack, GuardStackData,
     InvokeMiddle,
 };
-use crate::params::Param;
+use crate::outcome::format_bytes;
+use crate::params::{content_digest, local_hostname, local_size, Param};
+use crate::paths::{normalize_separators, to_native_relpath, HostPath};
+use crate::protocol::{
+    now_millis, Artifact, ArtifactStatus, Provenance, RunRequest, RunResponse, RunSpecification,
+    RunTiming, WORKSPACE_VAR,
+};
+use crate::retry::{retrying, RetryPolicy, TransferFailed};
+use crate::storage::encryption::key_name_for;
+use crate::storage::{CompressingStorage, Storage};
+use crate::transfer::Transfer;
+use crate::workspace;
 
 struct Data {
-    bucket: GridFSBucket,
+    storage: Storage,
     conf: Config,
     tempdir: TempDir,
     guards: Vec<Box<dyn ArgGuard<String, Data>>>,
     passed_env: HashMap<String, String>,
+    artifacts: Vec<Artifact>,
+    stage: String,
+    staged: Vec<(String, Param)>,
+    /// Where the inputs have been downloaded to, to be released after the run.
+    inputs: Vec<PathBuf>,
+    /// Stamped on the uploaded outputs, completed with the command once resolved.
+    provenance: Provenance,
+    /// Bytes of the inputs declared by the client, by their cloud urls.
+    input_sizes: HashMap<String, u64>,
+    /// Bytes of the inputs downloaded so far.
+    downloaded: u64,
+    /// Bytes of the outputs uploaded so far.
+    uploaded: u64,
+    /// Disk reserved for the inputs yet to be downloaded, if admitted by a policy.
+    reservation: Option<Reservation>,
+    warnings: Vec<String>,
+    /// Archive format of the output folders, as asked by the client.
+    archive: ArchiveFormat,
+    /// Cloud urls of the params whose attributes are preserved, as asked by the client.
+    preserve: HashSet<String>,
+    /// Attributes of the preserved inputs by their cloud urls, as sent by the client.
+    input_attrs: HashMap<String, FileAttrs>,
+    /// Attributes of the uploaded outputs preserved, by their cloud urls.
+    output_attrs: HashMap<String, FileAttrs>,
+    /// Digests of the outputs of the previous run by their cloud urls, as sent by the client.
+    output_digests: HashMap<String, String>,
+    /// When the client sent the request, if told, and when the worker picked it.
+    enqueued_at: Option<u64>,
+    started_at: u64,
+    /// Names of the keys encrypting the outputs by their paths, as asked by the client.
+    encryption_keys: HashMap<String, String>,
+    /// Whether the command is not run, hence nothing is to be uploaded, as asked by the client.
+    dry_run: bool,
 }
 
 impl GuardStackData<Param, String> for Data {
@@ -32,11 +83,10 @@ impl GuardStackData<Param, String> for Data {
     }
 
     fn guard_param(&self, param: Param) -> Box<dyn ArgGuard<String, Self>> {
+        // the path comes from the client, which may not be parsable as a native path
         let new_temppath = |filepath: String| {
-            let filename = Path::new(filepath.as_str())
-                .file_name()
-                .and_then(std::ffi::OsStr::to_str)
-                .unwrap_or("");
+            let filepath = HostPath::parse(filepath.as_str());
+            let filename = filepath.file_name().unwrap_or("");
             let temppath = tempfile::Builder::new()
                 .suffix(filename)
                 .tempfile_in(self.tempdir.path())
@@ -45,6 +95,15 @@ impl GuardStackData<Param, String> for Data {
             temppath.remove().unwrap();
             temppath
         };
+        let new_tempdir = |dirpath: String| {
+            let dirpath = HostPath::parse(dirpath.as_str());
+            let dirname = dirpath.file_name().unwrap_or("");
+            tempfile::Builder::new()
+                .suffix(dirname)
+                .tempdir_in(self.tempdir.path())
+                .unwrap()
+                .into_path()
+        };
 
         match param {
             Param::StrParam { value } => Box::new(StrGuard { value }),
@@ -52,14 +111,40 @@ impl GuardStackData<Param, String> for Data {
             Param::CmdNameParam { name } => Box::new(CmdNameGuard { name }),
             Param::CmdPathParam { path } => Box::new(CmdPathGuard { path }),
             Param::FormatParam { tmpl, args } => Box::new(FormatGuard { tmpl, args }),
+            Param::ContentParam { name, content } => Box::new(ContentGuard {
+                temppath: new_temppath(name),
+                content,
+            }),
             param @ Param::InCloudFileParam { .. } => Box::new(InCloudFileGuard {
                 temppath: new_temppath(param.filepath().to_string()),
                 param,
             }),
+            param @ Param::InCloudDirParam { .. } => Box::new(InCloudDirGuard {
+                dirpath: new_tempdir(param.filepath().to_string()),
+                param,
+            }),
+            Param::InCloudGlobParam {
+                pattern,
+                filepath,
+                files,
+                ..
+            } => Box::new(InCloudGlobGuard {
+                dirpath: new_tempdir(filepath),
+                pattern,
+                files,
+            }),
             param @ Param::OutCloudFileParam { .. } => Box::new(OutCloudFileGuard {
                 temppath: new_temppath(param.filepath().to_string()),
                 param,
             }),
+            param @ Param::OutCloudDirParam { .. } => Box::new(OutCloudDirGuard {
+                dirpath: new_tempdir(param.filepath().to_string()),
+                param,
+            }),
+            param @ Param::OutCloudGlobParam { .. } => Box::new(OutCloudGlobGuard {
+                dirpath: new_tempdir(param.filepath().to_string()),
+                param,
+            }),
             param => unreachable!("Unaccepted Param {:#?} for server", param),
         }
     }
@@ -89,16 +174,42 @@ struct CmdPathGuard {
     path: String,
 }
 
+struct ContentGuard {
+    temppath: TempPath,
+    content: String,
+}
+
 struct InCloudFileGuard {
     temppath: TempPath,
     param: Param,
 }
 
+struct InCloudDirGuard {
+    dirpath: PathBuf,
+    param: Param,
+}
+
+struct InCloudGlobGuard {
+    dirpath: PathBuf,
+    pattern: String,
+    files: HashMap<String, Param>,
+}
+
 struct OutCloudFileGuard {
     temppath: TempPath,
     param: Param,
 }
 
+struct OutCloudDirGuard {
+    dirpath: PathBuf,
+    param: Param,
+}
+
+struct OutCloudGlobGuard {
+    dirpath: PathBuf,
+    param: Param,
+}
+
 struct FormatGuard {
     tmpl: String,
     args: HashMap<String, Param>,
@@ -106,8 +217,10 @@ struct FormatGuard {
 
 #[async_trait]
 impl ArgGuard<String, Data> for StrGuard {
-    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
-        Ok(self.value.clone())
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        let data = data.lock().await;
+        let data = data.borrow();
+        Ok(workspace::expand(self.value.as_str(), data.tempdir.path()))
     }
 }
 
@@ -129,7 +242,8 @@ impl ArgGuard<String, Data> for EnvGuard {
 impl ArgGuard<String, Data> for CmdNameGuard {
     async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
         let data = data.lock().await;
-        let data = data.borrow_mut();
+        let mut data = data.borrow_mut();
+        data.provenance.command = self.name.clone();
         let command_palette = &data.conf.command_palette;
         if let Some(command) = command_palette.get(self.name.as_str()) {
             Ok(command.clone())
@@ -145,56 +259,219 @@ impl ArgGuard<String, Data> for CmdNameGuard {
 
 #[async_trait]
 impl ArgGuard<String, Data> for CmdPathGuard {
-    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        let data = data.lock().await;
+        let mut data = data.borrow_mut();
+        data.provenance.command = self.path.clone();
         Ok(self.path.clone())
     }
 }
 
+#[async_trait]
+impl ArgGuard<String, Data> for ContentGuard {
+    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        debug!("Write inline content to {}...", self.temppath.display());
+        tokio::fs::write(&self.temppath, BASE64.decode(self.content.as_str())?).await?;
+        Ok(self.temppath.to_str().unwrap().to_string())
+    }
+}
+
 #[async_trait]
 impl ArgGuard<String, Data> for InCloudFileGuard {
     async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        if self.param.is_shared() {
+            let path = shared_path(data, self.param.filepath()).await?;
+            debug!("Use shared input {} in place...", path.display());
+            return Ok(path.to_str().unwrap().to_string());
+        }
         debug!(
             "Download cloud input {} to {}...",
             self.param.cloud_url(),
             self.temppath.to_str().unwrap(),
         );
+        download_input(data, &self.param, &self.temppath).await?;
+        Ok(self.temppath.to_str().unwrap().to_string())
+    }
+}
 
-        let bucket = {
-            let data = data.lock().await;
-            let data = data.borrow();
-            data.bucket.clone()
-        };
-        self.param
-            .download(bucket, self.temppath.to_path_buf())
-            .await?;
+#[async_trait]
+impl ArgGuard<String, Data> for InCloudDirGuard {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        if self.param.is_shared() {
+            let path = shared_path(data, self.param.filepath()).await?;
+            anyhow::ensure!(
+                path.is_dir(),
+                "Expected a directory at {}, but found a file",
+                path.display()
+            );
+            debug!("Use shared input folder {} in place...", path.display());
+            return Ok(path.to_str().unwrap().to_string());
+        }
+        debug!(
+            "Download cloud input folder {} to {}...",
+            self.param.cloud_url(),
+            self.dirpath.to_str().unwrap(),
+        );
+        download_input(data, &self.param, &self.dirpath).await?;
+        Ok(self.dirpath.to_str().unwrap().to_string())
+    }
 
-        Ok(self.temppath.to_str().unwrap().to_string())
+    async fn exit(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        // unlike a temp file, the folder is not removed by itself
+        if self.dirpath.exists() {
+            tokio::fs::remove_dir_all(&self.dirpath).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArgGuard<String, Data> for InCloudGlobGuard {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        debug!(
+            "Download {} cloud inputs matching {} to {}...",
+            self.files.len(),
+            self.pattern,
+            self.dirpath.to_str().unwrap(),
+        );
+        for (relpath, param) in &self.files {
+            let relpath = PathBuf::from(to_native_relpath(relpath));
+            // never placed out of the folder, whatever the client sends
+            anyhow::ensure!(
+                relpath
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_))),
+                "Malformed relative path {} of input {}",
+                relpath.display(),
+                param.cloud_url()
+            );
+            let path = self.dirpath.join(relpath);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            if param.is_shared() {
+                let shared = shared_path(data, param.filepath()).await?;
+                tokio::fs::copy(shared, path).await?;
+            } else {
+                download_input(data, param, path.as_path()).await?;
+            }
+        }
+        Ok(self.dirpath.to_str().unwrap().to_string())
+    }
+
+    async fn exit(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        if self.dirpath.exists() {
+            tokio::fs::remove_dir_all(&self.dirpath).await?;
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
 impl ArgGuard<String, Data> for OutCloudFileGuard {
-    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        if self.param.is_shared() {
+            let path = staged_in_place(data, &self.param).await?;
+            tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+            return Ok(path.to_str().unwrap().to_string());
+        }
         Ok(self.temppath.to_str().unwrap().to_string())
     }
 
     async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        if self.param.is_shared() {
+            return stage_in_place(data, &self.param).await;
+        }
         if self.temppath.exists() {
-            let bucket = {
-                let data = data.lock().await;
-                let data = data.borrow();
-                data.bucket.clone()
-            };
+            if skip_unchanged(data, &self.param, &self.temppath).await {
+                debug!("Skip unchanged output {}", self.param.cloud_url());
+                return Ok(());
+            }
+            debug!(
+                "Upload local output {} to {}...",
+                self.temppath.display(),
+                self.param.cloud_url(),
+            );
+            upload_staged(data, &self.param, &self.temppath).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArgGuard<String, Data> for OutCloudDirGuard {
+    async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        if self.param.is_shared() {
+            let path = staged_in_place(data, &self.param).await?;
+            tokio::fs::create_dir_all(path.as_path()).await?;
+            return Ok(path.to_str().unwrap().to_string());
+        }
+        Ok(self.dirpath.to_str().unwrap().to_string())
+    }
 
-            self.param
-                .upload(bucket, self.temppath.to_path_buf())
-                .await?;
+    async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        if self.param.is_shared() {
+            return stage_in_place(data, &self.param).await;
         }
         debug!(
-            "Upload local output {} to {}...",
-            self.temppath.to_str().unwrap(),
+            "Upload local output folder {} to {}...",
+            self.dirpath.display(),
             self.param.cloud_url(),
         );
+        upload_staged(data, &self.param, self.dirpath.as_path()).await
+    }
+}
+
+#[async_trait]
+impl ArgGuard<String, Data> for OutCloudGlobGuard {
+    async fn enter(&self, _: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
+        Ok(self.dirpath.to_str().unwrap().to_string())
+    }
+
+    async fn exit(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<()> {
+        let pattern = self
+            .param
+            .pattern()
+            .replace("{workspace}", self.dirpath.to_str().unwrap());
+        debug!("Collect local outputs matching {}...", pattern);
+
+        let mut artifacts = vec![];
+        for path in glob::glob(pattern.as_str())? {
+            let path = path?;
+            if !path.is_file() {
+                continue;
+            }
+
+            let relpath = path
+                .strip_prefix(self.dirpath.as_path())
+                .unwrap_or_else(|_| Path::new(path.file_name().unwrap()))
+                .to_str()
+                .unwrap();
+            // separated by `/`, so that the client can place it on whatever platform
+            let relpath = normalize_separators(relpath);
+            let child = self.param.child(relpath.as_str());
+            debug!("  upload {} to {}...", path.display(), child.cloud_url());
+            // one failed match should not cost the others
+            let status = match upload_staged(data, &child, path.as_path()).await {
+                Ok(()) => ArtifactStatus::Ok,
+                Err(err) => {
+                    warn!("  failed to upload {}: {}", path.display(), err);
+                    ArtifactStatus::Failed {
+                        cause: err.to_string(),
+                    }
+                }
+            };
+
+            artifacts.push(Artifact {
+                cloud_url: child.cloud_url(),
+                relpath,
+                status,
+            });
+        }
+
+        let data = data.lock().await;
+        let mut data = data.borrow_mut();
+        data.artifacts.extend(artifacts);
         Ok(())
     }
 }
@@ -203,8 +480,240 @@ impl ArgGuard<String, Data> for OutCloudFileGuard {
 impl ArgGuard<String, Data> for FormatGuard {
     async fn enter(&self, data: &ArcMtxRefCell<Data>) -> anyhow::Result<String> {
         let args = guard_hashmap_args(&self.args, |param| push_guard(data, param, None)).await?;
-        Ok(strfmt(self.tmpl.as_str(), &args)?)
+        // expanded first, as the braces of the placeholder would be taken as of an arg
+        let tmpl = {
+            let data = data.lock().await;
+            let data = data.borrow();
+            workspace::expand(self.tmpl.as_str(), data.tempdir.path())
+        };
+        Ok(strfmt(tmpl.as_str(), &args)?)
+    }
+}
+
+/// Download the cloud input `param` to `path`, retrying as configured.
+async fn download_input(
+    data: &ArcMtxRefCell<Data>,
+    param: &Param,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let (storage, transfer, retry) = {
+        let data = data.lock().await;
+        let mut data = data.borrow_mut();
+        data.inputs.push(path.to_path_buf());
+        (
+            data.storage.clone(),
+            data.conf.transfer.clone(),
+            data.conf.retry,
+        )
+    };
+    let what = format!("download {}", param.cloud_url());
+    retrying(retry, what.as_str(), || async {
+        match &transfer {
+            Some(transfer) => transfer.download(param, path).await,
+            None => {
+                param.download(storage.clone(), path).await?;
+                Ok(())
+            }
+        }
+    })
+    .await
+    .map_err(|err| TransferFailed::new(what.as_str(), err))?;
+
+    let (attrs, chown) = {
+        let data = data.lock().await;
+        let data = data.borrow();
+        let attrs = data.input_attrs.get(param.cloud_url().as_str()).cloned();
+        (attrs, data.conf.preserve_ownership)
+    };
+    if let Some(attrs) = attrs {
+        attrs.apply(path, chown)?;
+    }
+    downloaded(data, param, local_size(path)).await;
+    Ok(())
+}
+
+/// Account `bytes` just downloaded for the input `param`, warning if far from its declared
+/// size, which hints at an input changed or truncated since declared.
+async fn downloaded(data: &ArcMtxRefCell<Data>, param: &Param, bytes: u64) {
+    let data = data.lock().await;
+    let mut data = data.borrow_mut();
+    data.downloaded += bytes;
+    if let Some(reservation) = data.reservation.as_mut() {
+        reservation.consume(bytes);
+    }
+
+    let declared_total: u64 = data.input_sizes.values().sum();
+    if declared_total > 0 {
+        debug!(
+            "  downloaded {} of {} declared bytes of the inputs ({}%)",
+            data.downloaded,
+            declared_total,
+            (data.downloaded * 100 / declared_total).min(100),
+        );
+    }
+    let declared = data.input_sizes.get(param.cloud_url().as_str()).copied();
+    if let Some(declared) = declared {
+        if far_from(bytes, declared) {
+            let warning = format!(
+                "Input {} is {}, far from the {} declared",
+                param.filepath(),
+                format_bytes(bytes),
+                format_bytes(declared),
+            );
+            warn!("  {}", warning);
+            data.warnings.push(warning);
+        }
+    }
+}
+
+/// Whether `actual` bytes are far from the `declared`, by more than a tenth of the larger
+/// and more than a few blocks, so that small files changing a little are not flagged.
+fn far_from(actual: u64, declared: u64) -> bool {
+    let diff = actual.abs_diff(declared);
+    diff > 16 * 1024 && diff * 10 > actual.max(declared)
+}
+
+/// The local path of the file at `relpath` on the filesystem shared with the client.
+async fn shared_path(data: &ArcMtxRefCell<Data>, relpath: &str) -> anyhow::Result<PathBuf> {
+    let storage = {
+        let data = data.lock().await;
+        let data = data.borrow();
+        data.storage.clone()
+    };
+    storage.shared_path(relpath).ok_or_else(|| {
+        anyhow!(
+            "Path {} is not on the shared filesystem of the server",
+            relpath
+        )
+    })
+}
+
+/// Where the command writes a shared output in place, which is its staging url in this run,
+/// as if uploaded there.
+async fn staged_in_place(data: &ArcMtxRefCell<Data>, param: &Param) -> anyhow::Result<PathBuf> {
+    let stage = {
+        let data = data.lock().await;
+        let data = data.borrow();
+        data.stage.clone()
+    };
+    let filepath = HostPath::parse(param.filepath());
+    let relpath = format!("{}.staged-{}", filepath.normalized(), stage);
+    shared_path(data, relpath.as_str()).await
+}
+
+/// Stage a shared output written in place, if any, to be committed with the response.
+async fn stage_in_place(data: &ArcMtxRefCell<Data>, param: &Param) -> anyhow::Result<()> {
+    if !staged_in_place(data, param).await?.exists() {
+        return Ok(());
     }
+    let data = data.lock().await;
+    let mut data = data.borrow_mut();
+    if data.dry_run {
+        return Ok(());
+    }
+    let staged_url = param.staged_url(data.stage.as_str());
+    data.staged.push((staged_url, param.clone()));
+    Ok(())
+}
+
+/// Upload an output under the staging url of this run, to be committed with the response.
+async fn upload_staged(
+    data: &ArcMtxRefCell<Data>,
+    param: &Param,
+    filepath: &Path,
+) -> anyhow::Result<()> {
+    let (storage, key, stage, transfer, retry, provenance, archive) = {
+        let data = data.lock().await;
+        let mut data = data.borrow_mut();
+        // nothing has been produced by a dry run but the empty placeholders
+        if data.dry_run {
+            return Ok(());
+        }
+        // read before uploaded, which may pack the file away
+        if data.preserve.contains(param.cloud_url().as_str()) {
+            let attrs = FileAttrs::read(filepath)?;
+            data.output_attrs.insert(param.cloud_url(), attrs);
+        }
+        let key = key_name_for(&data.encryption_keys, param.filepath()).map(str::to_owned);
+        let storage = match &key {
+            Some(name) => data.storage.encrypting_by(name.as_str())?,
+            None => data.storage.clone(),
+        };
+        (
+            storage,
+            key,
+            data.stage.clone(),
+            data.conf.transfer.clone(),
+            data.conf.retry,
+            data.provenance.clone(),
+            data.archive,
+        )
+    };
+
+    let what = format!("upload {}", param.cloud_url());
+    let staged_url = retrying(retry, what.as_str(), || async {
+        match &transfer {
+            Some(transfer) => {
+                transfer
+                    .upload_staged(
+                        param,
+                        filepath,
+                        stage.as_str(),
+                        &provenance,
+                        archive,
+                        key.as_deref(),
+                    )
+                    .await
+            }
+            None => Ok(param
+                .upload_staged(
+                    storage.clone(),
+                    filepath,
+                    stage.as_str(),
+                    Some(&provenance),
+                    archive,
+                )
+                .await?),
+        }
+    })
+    .await
+    .map_err(|err| TransferFailed::new(what.as_str(), err))?;
+
+    let data = data.lock().await;
+    let mut data = data.borrow_mut();
+    data.uploaded += local_size(filepath);
+    data.staged.push((staged_url, param.clone()));
+    Ok(())
+}
+
+/// Whether the output at `path` is the same as of the previous run, by the digest sent by
+/// the client, in which case it is reported unchanged instead of being uploaded.
+async fn skip_unchanged(data: &ArcMtxRefCell<Data>, param: &Param, path: &Path) -> bool {
+    let cloud_url = param.cloud_url();
+    let previous = {
+        let data = data.lock().await;
+        let data = data.borrow();
+        data.output_digests.get(cloud_url.as_str()).cloned()
+    };
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return false,
+    };
+    if !matches!(content_digest(path).await, Ok(digest) if digest == previous) {
+        return false;
+    }
+    let relpath = HostPath::parse(param.filepath())
+        .file_name()
+        .unwrap_or("")
+        .to_owned();
+    let data = data.lock().await;
+    let mut data = data.borrow_mut();
+    data.artifacts.push(Artifact {
+        cloud_url,
+        relpath,
+        status: ArtifactStatus::Unchanged,
+    });
+    true
 }
 
 struct ContextStack {
@@ -221,6 +730,20 @@ impl GuardStack<Param, String, Data> for ContextStack {
 #[derive(Clone)]
 pub(crate) struct Config {
     pub(crate) command_palette: HashMap<String, String>,
+    /// Transfer workers staging the files, or none if transferring on this worker.
+    pub(crate) transfer: Option<Arc<dyn Transfer>>,
+    /// How the failed downloads and uploads are retried.
+    pub(crate) retry: RetryPolicy,
+    /// Set once the run has been cancelled, in which case its outputs are discarded.
+    pub(crate) cancelled: Arc<AtomicBool>,
+    /// Id of the task being run, stamped on the uploaded outputs.
+    pub(crate) task_id: String,
+    /// What must be left on the host for the run to be admitted, or anything goes if none.
+    pub(crate) admission: Option<AdmissionPolicy>,
+    /// Which commands out of the palette may be run, or any if none.
+    pub(crate) command_policy: Option<CommandPolicy>,
+    /// Whether the ownership of the inputs is preserved as sent by the client.
+    pub(crate) preserve_ownership: bool,
 }
 
 pub(crate) struct MiddleImpl {
@@ -228,15 +751,39 @@ pub(crate) struct MiddleImpl {
 }
 
 impl MiddleImpl {
-    pub(crate) fn new(bucket: GridFSBucket, tempdir: TempDir, conf: Config) -> MiddleImpl {
+    pub(crate) fn new(storage: Storage, tempdir: TempDir, conf: Config) -> MiddleImpl {
+        let provenance = Provenance {
+            task_id: conf.task_id.clone(),
+            command: String::new(),
+            worker: local_hostname(),
+        };
         MiddleImpl {
             ctx: ContextStack {
                 data: Arc::new(Mutex::new(RefCell::new(Data {
-                    bucket,
+                    storage,
                     conf,
                     tempdir,
                     guards: Vec::new(),
                     passed_env: HashMap::new(),
+                    artifacts: Vec::new(),
+                    stage: ObjectId::new().to_hex(),
+                    staged: Vec::new(),
+                    inputs: Vec::new(),
+                    provenance,
+                    input_sizes: HashMap::new(),
+                    downloaded: 0,
+                    uploaded: 0,
+                    reservation: None,
+                    warnings: Vec::new(),
+                    archive: ArchiveFormat::default(),
+                    preserve: HashSet::new(),
+                    input_attrs: HashMap::new(),
+                    output_attrs: HashMap::new(),
+                    output_digests: HashMap::new(),
+                    enqueued_at: None,
+                    started_at: now_millis(),
+                    encryption_keys: HashMap::new(),
+                    dry_run: false,
                 }))),
             },
         }
@@ -245,12 +792,223 @@ impl MiddleImpl {
 
 #[async_trait]
 impl InvokeMiddle<Param, String> for MiddleImpl {
+    async fn admit(&self, request: &RunRequest) -> anyhow::Result<()> {
+        let data = self.ctx.data.lock().await;
+        let mut data = data.borrow_mut();
+        data.input_sizes = request.input_sizes.clone();
+        data.archive = request.archive;
+        data.preserve = request.preserve.clone();
+        data.input_attrs = request.input_attrs.clone();
+        data.output_digests = request.output_digests.clone();
+        data.enqueued_at = request.enqueued_at;
+        data.encryption_keys = request.encryption_keys.clone();
+        data.dry_run = request.dry_run;
+        // whichever the command is, even one of the palette, the loader may be told to run
+        // anything else along with it
+        if let Some(policy) = &data.conf.command_policy {
+            for key in request.env.iter().flat_map(HashMap::keys) {
+                policy.check_env(key)?;
+            }
+        }
+        if let Some(compression) = request.compression {
+            data.storage = Arc::new(CompressingStorage::new(
+                data.storage.clone(),
+                Some(compression),
+            ));
+        }
+        if let Some(policy) = data.conf.admission {
+            let reservation = policy.admit(data.tempdir.path(), request.declared_input_bytes())?;
+            data.reservation = Some(reservation);
+        }
+        Ok(())
+    }
+
+    async fn admit_command(&self, command: String) -> anyhow::Result<()> {
+        let data = self.ctx.data.lock().await;
+        let data = data.borrow();
+        let conf = &data.conf;
+        // the commands of the palette are up to the operator already
+        match &conf.command_policy {
+            Some(policy) if !conf.command_palette.values().any(|known| *known == command) => {
+                Ok(policy.check(command.as_str())?)
+            }
+            _ => Ok(()),
+        }
+    }
+
     async fn push_guard(&self, param: Param, key: Option<String>) -> anyhow::Result<String> {
         self.ctx.push_guard(param, key).await
     }
 
+    async fn fill_request(&self, request: &mut RunSpecification<String>) {
+        let data = self.ctx.data.lock().await;
+        let data = data.borrow();
+        let workspace = data.tempdir.path();
+        if let Some(cwd) = request.cwd.as_mut() {
+            if cwd.contains(WORKSPACE_VAR) {
+                *cwd = workspace::expand(cwd.as_str(), workspace);
+                // for the commands writing into their working directories
+                std::fs::create_dir_all(cwd.as_str())
+                    .unwrap_or_else(|err| warn!("Failed to make the cwd {}: {}", cwd, err));
+            }
+        }
+    }
+
     async fn pop_all_guards(&self) -> anyhow::Result<Vec<()>> {
-        self.ctx.pop_all_guards().await
+        let res = self.ctx.pop_all_guards().await;
+        if res.is_err() {
+            // never leave a partial result set behind
+            let (storage, staged) = {
+                let data = self.ctx.data.lock().await;
+                let mut data = data.borrow_mut();
+                (data.storage.clone(), std::mem::take(&mut data.staged))
+            };
+            for (staged_url, _) in staged {
+                storage
+                    .delete(staged_url.as_str())
+                    .await
+                    .unwrap_or_default();
+            }
+        }
+        res
+    }
+
+    async fn fill_response(&self, response: &mut RunResponse) {
+        let (storage, stage, staged, mut artifacts, inputs) = {
+            let data = self.ctx.data.lock().await;
+            let mut data = data.borrow_mut();
+            response.warnings.append(&mut data.warnings);
+            response.output_attrs = std::mem::take(&mut data.output_attrs);
+            response.timing = Some(RunTiming {
+                enqueued_at: data.enqueued_at,
+                started_at: data.started_at,
+                finished_at: now_millis(),
+            });
+            if let Some(usage) = response.usage.as_mut() {
+                usage.command = data.provenance.command.clone();
+                usage.downloaded_bytes = data.downloaded;
+                usage.uploaded_bytes = data.uploaded;
+            }
+            (
+                data.storage.clone(),
+                data.stage.clone(),
+                std::mem::take(&mut data.staged),
+                std::mem::take(&mut data.artifacts),
+                std::mem::take(&mut data.inputs),
+            )
+        };
+
+        // a cancelled run may be a loser of speculative runs, whose outputs must not
+        // overwrite the winner's
+        let cancelled = {
+            let data = self.ctx.data.lock().await;
+            let data = data.borrow();
+            data.conf.cancelled.load(Ordering::SeqCst)
+        };
+        if cancelled {
+            debug!(
+                "Discard {} staged outputs of cancelled run...",
+                staged.len()
+            );
+            let mut outputs_discarded = true;
+            for (staged_url, _) in staged {
+                if let Err(err) = storage.delete(staged_url.as_str()).await {
+                    warn!("Failed to discard staged output {}: {}", staged_url, err);
+                    outputs_discarded = false;
+                }
+            }
+            for artifact in artifacts.iter_mut().filter(|artifact| artifact.is_ok()) {
+                artifact.status = ArtifactStatus::Skipped {
+                    reason: "run cancelled".to_owned(),
+                };
+            }
+            response.artifacts.extend(artifacts);
+
+            // the downloaded inputs are removed as their guards are popped
+            let inputs_released = inputs.iter().all(|input| !input.exists());
+            if let Some(cancellation) = response.cancellation.as_mut() {
+                cancellation.outputs_discarded = outputs_discarded;
+                cancellation.inputs_released = inputs_released;
+            }
+            return;
+        }
+
+        debug!("Commit {} staged outputs...", staged.len());
+        let mut committed = Vec::new();
+        let mut failure = None;
+        let mut staged = staged.into_iter();
+        for (staged_url, param) in staged.by_ref() {
+            let err = match param
+                .commit_staged(storage.clone(), staged_url.as_str(), stage.as_str())
+                .await
+            {
+                Ok(replaced) => {
+                    committed.push((param, replaced));
+                    continue;
+                }
+                Err(err) => err,
+            };
+            let cloud_url = param.cloud_url();
+            // a collected output is reported on its own, leaving the others usable
+            match artifacts
+                .iter_mut()
+                .find(|artifact| artifact.cloud_url == cloud_url)
+            {
+                Some(artifact) => {
+                    artifact.status = ArtifactStatus::Failed {
+                        cause: format!("failed to commit: {}", err),
+                    }
+                }
+                None => {
+                    storage
+                        .delete(staged_url.as_str())
+                        .await
+                        .unwrap_or_default();
+                    failure = Some(format!("Failed to commit output {}: {}", cloud_url, err));
+                    break;
+                }
+            }
+        }
+
+        match failure {
+            None => {
+                for (param, _) in committed.iter().filter(|(_, replaced)| *replaced) {
+                    if let Err(err) = param.drop_replaced(storage.clone(), stage.as_str()).await {
+                        warn!("Failed to drop replaced {}: {}", param.cloud_url(), err);
+                    }
+                }
+            }
+            Some(exc) => {
+                // never leave a partial result set behind
+                debug!("Roll back {} committed outputs...", committed.len());
+                for (staged_url, _) in staged {
+                    storage
+                        .delete(staged_url.as_str())
+                        .await
+                        .unwrap_or_default();
+                }
+                for (param, replaced) in committed.into_iter().rev() {
+                    if let Err(err) = param
+                        .rollback_staged(storage.clone(), stage.as_str(), replaced)
+                        .await
+                    {
+                        warn!("Failed to roll back output {}: {}", param.cloud_url(), err);
+                    }
+                }
+                for artifact in artifacts.iter_mut().filter(|artifact| artifact.is_ok()) {
+                    artifact.status = ArtifactStatus::Skipped {
+                        reason: "outputs rolled back".to_owned(),
+                    };
+                }
+                response.exc = Some(exc);
+            }
+        }
+        response.artifacts.extend(artifacts);
+
+        // the partial outputs of a run timed out are committed as well
+        if let Some(cancellation) = response.cancellation.as_mut() {
+            cancellation.inputs_released = inputs.iter().all(|input| !input.exists());
+        }
     }
 }
 
@@ -264,8 +1022,10 @@ mod tests {
     use tempfile::{tempdir, NamedTempFile};
     use test_utilities::docker;
 
+    use crate::command_policy::{CommandNotAllowed, EnvNotAllowed};
     use crate::middles::Middle;
-    use crate::protocol::{RunRequest, RunResponse};
+    use crate::protocol::{ExitStatus, RunRequest, RunResponse};
+    use crate::storage::{GridFsStorage, SharedFsStorage};
 
     use super::*;
 
@@ -283,6 +1043,7 @@ mod tests {
             .unwrap()
             .database("cmdproxy-test-server-db")
             .bucket(None);
+        let storage = GridFsStorage::shared(bucket);
 
         let fake_workspace = tempdir().unwrap();
 
@@ -296,6 +1057,13 @@ mod tests {
         let fake_stdout_content = (30..50).fake::<String>();
         let conf = Config {
             command_palette: HashMap::<String, String>::new(),
+            transfer: None,
+            retry: RetryPolicy::NEVER,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task_id: String::new(),
+            admission: None,
+            command_policy: None,
+            preserve_ownership: false,
         };
 
         fake_input.write_all(fake_input_content.as_bytes()).unwrap();
@@ -350,14 +1118,14 @@ mod tests {
         // mimic client upload input files
         for (param, content) in &in_params {
             param
-                .upload_from_string(bucket.clone(), content)
+                .upload_from_string(storage.clone(), content)
                 .await
                 .unwrap();
         }
 
         {
             let server_tempdir = tempdir().unwrap();
-            let invoke_middle = MiddleImpl::new(bucket.clone(), server_tempdir, conf);
+            let invoke_middle = MiddleImpl::new(storage.clone(), server_tempdir, conf);
             let run_spec = invoke_middle.transform_request(req).await.unwrap();
 
             assert_eq!(run_spec.command, "/bin/sh");
@@ -398,10 +1166,7 @@ mod tests {
             .unwrap();
             std::fs::write(run_spec.stderr.unwrap().as_str(), "").unwrap();
 
-            let run_response = RunResponse {
-                return_code: 0,
-                exc: None,
-            };
+            let run_response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
             invoke_middle
                 .transform_response(Ok(run_response))
                 .await
@@ -410,11 +1175,130 @@ mod tests {
 
         // assert all the outputs have been uploaded
         for (out_param, content) in out_params {
-            assert!(out_param.exists_on_cloud(bucket.clone()).await.unwrap());
+            assert!(out_param.exists_on_cloud(storage.clone()).await.unwrap());
             assert_eq!(
                 content,
-                out_param.download_to_string(bucket.clone()).await.unwrap()
+                out_param.download_to_string(storage.clone()).await.unwrap()
             );
         }
     }
+
+    #[tokio::test]
+    async fn test_command_not_allowed() {
+        let root = tempdir().unwrap();
+        let storage: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let conf = Config {
+            command_palette: HashMap::from([("cc".to_owned(), "/opt/cc/bin/cc".to_owned())]),
+            transfer: None,
+            retry: RetryPolicy::NEVER,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task_id: String::new(),
+            admission: None,
+            command_policy: Some(CommandPolicy {
+                allow: vec!["/usr/bin".to_owned()],
+                deny: vec![],
+            }),
+            preserve_ownership: false,
+        };
+
+        let allowed = [
+            Param::cmd_name("cc"),
+            Param::cmd_path("/usr/bin/env"),
+            Param::str("/usr/bin/env"),
+        ];
+        for command in allowed {
+            let invoke_middle = MiddleImpl::new(storage.clone(), tempdir().unwrap(), conf.clone());
+            let req = RunRequest::builder().command(command).args(vec![]).build();
+            assert!(invoke_middle.transform_request(req).await.is_ok());
+        }
+
+        // whichever param gives a command out of the allowlist, it is refused
+        let refused = [
+            Param::cmd_path("/bin/sh"),
+            Param::str("/bin/sh"),
+            Param::str("sh"),
+            Param::format("/bin/{name}", HashMap::from([("name", Param::str("sh"))])),
+        ];
+        for command in refused {
+            let invoke_middle = MiddleImpl::new(storage.clone(), tempdir().unwrap(), conf.clone());
+            let req = RunRequest::builder().command(command).args(vec![]).build();
+            let err = invoke_middle.transform_request(req).await.unwrap_err();
+            assert!(err.downcast_ref::<CommandNotAllowed>().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hijacking_env_not_allowed() {
+        let root = tempdir().unwrap();
+        let storage: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let conf = Config {
+            command_palette: HashMap::from([("cc".to_owned(), "/opt/cc/bin/cc".to_owned())]),
+            transfer: None,
+            retry: RetryPolicy::NEVER,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task_id: String::new(),
+            admission: None,
+            command_policy: Some(CommandPolicy {
+                allow: vec!["/usr/bin".to_owned()],
+                deny: vec![],
+            }),
+            preserve_ownership: false,
+        };
+
+        let invoke_middle = MiddleImpl::new(storage.clone(), tempdir().unwrap(), conf.clone());
+        let req = RunRequest::builder()
+            .command(Param::cmd_path("/usr/bin/env"))
+            .args(vec![])
+            .env(HashMap::from([("LANG".to_owned(), Param::str("C"))]))
+            .build();
+        assert!(invoke_middle.transform_request(req).await.is_ok());
+
+        // an allowed command, or one of the palette, must not load a library of the client
+        let hijacking = [
+            ("LD_PRELOAD", Param::ipath("/tmp/evil.so")),
+            ("LD_LIBRARY_PATH", Param::str("/tmp")),
+            ("PATH", Param::str("/tmp/bin")),
+        ];
+        for command in [Param::cmd_path("/usr/bin/env"), Param::cmd_name("cc")] {
+            for (key, val) in hijacking.iter().cloned() {
+                let invoke_middle =
+                    MiddleImpl::new(storage.clone(), tempdir().unwrap(), conf.clone());
+                let req = RunRequest::builder()
+                    .command(command.clone())
+                    .args(vec![])
+                    .env(HashMap::from([(key.to_owned(), val)]))
+                    .build();
+                let err = invoke_middle.transform_request(req).await.unwrap_err();
+                assert_eq!(
+                    err.downcast_ref::<EnvNotAllowed>(),
+                    Some(&EnvNotAllowed {
+                        key: key.to_owned()
+                    })
+                );
+            }
+        }
+
+        // while the workers without a policy trust their clients
+        let conf = Config {
+            command_policy: None,
+            ..conf
+        };
+        let invoke_middle = MiddleImpl::new(storage, tempdir().unwrap(), conf);
+        let req = RunRequest::builder()
+            .command(Param::cmd_path("/usr/bin/env"))
+            .args(vec![])
+            .env(HashMap::from([("PATH".to_owned(), Param::str("/tmp/bin"))]))
+            .build();
+        assert!(invoke_middle.transform_request(req).await.is_ok());
+    }
+
+    #[test]
+    fn test_far_from() {
+        assert!(!far_from(1024, 1024));
+        // small files changing a little are fine
+        assert!(!far_from(0, 1024));
+        assert!(!far_from(10 * 1024 * 1024, 10 * 1024 * 1024 + 100 * 1024));
+        assert!(far_from(0, 1024 * 1024));
+        assert!(far_from(10 * 1024 * 1024, 5 * 1024 * 1024));
+    }
 }
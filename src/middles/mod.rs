@@ -1,5 +1,6 @@
 use celery::export::async_trait;
 
+pub mod auth;
 pub(crate) mod invoke;
 pub(crate) mod serde;
 
@@ -1,35 +1,79 @@
+use std::sync::Arc;
+
 use celery::export::async_trait;
+use log::debug;
 
+use crate::metrics::TransferStats;
+use crate::middles::serde::PayloadLimits;
 use crate::middles::Middle;
-use crate::protocol::{RunRequest, RunResponse};
+use crate::protocol::{ResponseEnvelope, RunRequest, RunResponse, ServerError};
+use crate::storage::Storage;
 
-pub(crate) struct MiddleImpl {}
+pub(crate) struct MiddleImpl {
+    storage: Storage,
+    limits: PayloadLimits,
+    stats: Arc<TransferStats>,
+}
 
 impl MiddleImpl {
-    pub(crate) fn new() -> MiddleImpl {
-        MiddleImpl {}
+    /// Enforce the `limits`, and count the sizes of the payloads into `stats`.
+    pub(crate) fn new(
+        storage: Storage,
+        limits: PayloadLimits,
+        stats: Arc<TransferStats>,
+    ) -> MiddleImpl {
+        MiddleImpl {
+            storage,
+            limits,
+            stats,
+        }
     }
 }
 
 #[async_trait]
 impl Middle<RunRequest, RunResponse, String, String> for MiddleImpl {
     async fn transform_request(&self, request: RunRequest) -> anyhow::Result<String> {
-        Ok(serde_json::to_string(&request)?)
+        let serialized = serde_json::to_string(&request)?;
+        debug!("Serialized request of {} bytes", serialized.len());
+        self.stats.add_request(serialized.len() as u64);
+        self.limits.check_request(serialized.len())?;
+        Ok(serialized)
     }
 
     async fn transform_response(
         &self,
         response: anyhow::Result<String>,
     ) -> anyhow::Result<RunResponse> {
-        let response: RunResponse = serde_json::from_str(response?.as_str())?;
-        if response.exc.is_some() {
-            anyhow::bail!(
-                "Server Error: return code {}, {}",
-                response.return_code,
-                response.exc.unwrap()
-            )
-        } else {
-            Ok(response)
+        let response = response?;
+        self.stats.add_response(response.len() as u64);
+        let response = match serde_json::from_str(response.as_str())? {
+            ResponseEnvelope::Inline(response) => response,
+            ResponseEnvelope::Spilled { spilled_to } => {
+                let serialized = spilled_to.download_to_string(self.storage.clone()).await?;
+                spilled_to
+                    .remove_from_cloud(self.storage.clone())
+                    .await
+                    .unwrap_or_default();
+                debug!("Downloaded spilled response of {} bytes", serialized.len());
+                self.stats.add_response(serialized.len() as u64);
+                self.limits.check_response(serialized.len())?;
+                match serde_json::from_str(serialized.as_str())? {
+                    ResponseEnvelope::Inline(response) => response,
+                    ResponseEnvelope::Spilled { .. } => {
+                        anyhow::bail!("Server Error: response spilled more than once")
+                    }
+                }
+            }
+        };
+
+        match response.exc {
+            Some(exc) => Err(ServerError {
+                return_code: response.return_code,
+                exc,
+                failure: response.failure,
+            }
+            .into()),
+            None => Ok(response),
         }
     }
 }
@@ -1,2 +1,93 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
 pub mod client_end;
 pub mod server_end;
+
+/// Hard caps on the serialized requests and responses, beyond which they are refused rather
+/// than sent through the broker or the result backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadLimits {
+    pub max_request_bytes: usize,
+    /// Responses are spilled to the cloud long before, hence this only guards against
+    /// the absurd ones.
+    pub max_response_bytes: usize,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        PayloadLimits {
+            max_request_bytes: 16 * 1024 * 1024,
+            max_response_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl PayloadLimits {
+    pub(crate) fn check_request(&self, size: usize) -> Result<(), PayloadTooLarge> {
+        PayloadTooLarge::check("Request", size, self.max_request_bytes)
+    }
+
+    pub(crate) fn check_response(&self, size: usize) -> Result<(), PayloadTooLarge> {
+        PayloadTooLarge::check("Response", size, self.max_response_bytes)
+    }
+}
+
+/// Error of a request or response refused for exceeding its cap in [`PayloadLimits`].
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+    /// Either `Request` or `Response`.
+    pub payload: &'static str,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl PayloadTooLarge {
+    fn check(payload: &'static str, size: usize, limit: usize) -> Result<(), PayloadTooLarge> {
+        if size <= limit {
+            return Ok(());
+        }
+        Err(PayloadTooLarge {
+            payload,
+            size,
+            limit,
+        })
+    }
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes exceeds the cap of {} bytes, consider passing the large values \
+            as files instead of inline strings",
+            self.payload, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_limits() {
+        let limits = PayloadLimits {
+            max_request_bytes: 10,
+            max_response_bytes: 20,
+        };
+        assert!(limits.check_request(10).is_ok());
+        assert!(limits.check_response(15).is_ok());
+
+        let err = limits.check_request(11).unwrap_err();
+        assert_eq!(err.size, 11);
+        assert!(err
+            .to_string()
+            .starts_with("Request of 11 bytes exceeds the cap of 10 bytes"));
+    }
+}
@@ -1,33 +1,84 @@
+use std::sync::Mutex;
+
 use celery::export::async_trait;
+use log::debug;
+use mongodb::bson::oid::ObjectId;
 
+use crate::features;
+use crate::middles::serde::PayloadLimits;
 use crate::middles::Middle;
-use crate::protocol::{RunRequest, RunResponse};
+use crate::params::Param;
+use crate::protocol::{ResponseEnvelope, RunRequest, RunResponse};
+use crate::storage::Storage;
+
+/// Responses larger than this are spilled to the cloud instead of the result backend.
+pub(crate) const MAX_INLINE_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
 
-pub(crate) struct MiddleImpl {}
+pub(crate) struct MiddleImpl {
+    storage: Storage,
+    max_inline_size: usize,
+    limits: PayloadLimits,
+    /// Features of the request taken, reported back in the response.
+    features: Mutex<Vec<String>>,
+}
 
 impl MiddleImpl {
-    pub(crate) fn new() -> MiddleImpl {
-        MiddleImpl {}
+    pub(crate) fn new(storage: Storage, limits: PayloadLimits) -> MiddleImpl {
+        MiddleImpl {
+            storage,
+            max_inline_size: MAX_INLINE_RESPONSE_SIZE,
+            limits,
+            features: Mutex::default(),
+        }
     }
 }
 
 #[async_trait]
 impl Middle<String, String, RunRequest, RunResponse> for MiddleImpl {
     async fn transform_request(&self, request: String) -> anyhow::Result<RunRequest> {
-        Ok(serde_json::from_str(request.as_str())?)
+        debug!("Received request of {} bytes", request.len());
+        self.limits.check_request(request.len())?;
+        let request: RunRequest = serde_json::from_str(request.as_str())?;
+        let features = features::negotiate(&request.features, &request.required_features)?;
+        *self.features.lock().unwrap() = features;
+        Ok(request)
     }
 
     async fn transform_response(
         &self,
         response: anyhow::Result<RunResponse>,
     ) -> anyhow::Result<String> {
-        let response = match response {
+        let mut response = match response {
             Ok(response) => response,
-            Err(err) => RunResponse {
-                return_code: -1,
-                exc: Some(err.to_string()),
-            },
+            Err(err) => RunResponse::from_error(&err),
+        };
+        response.features = std::mem::take(&mut *self.features.lock().unwrap());
+
+        let mut serialized = serde_json::to_string(&ResponseEnvelope::Inline(response))?;
+        debug!("Serialized response of {} bytes", serialized.len());
+        if let Err(err) = self.limits.check_response(serialized.len()) {
+            // report the oversize instead, which the client can still receive
+            let response = RunResponse::from_error(&err.into());
+            serialized = serde_json::to_string(&ResponseEnvelope::Inline(response))?;
+        }
+        if serialized.len() <= self.max_inline_size {
+            return Ok(serialized);
+        }
+
+        let spilled_to = Param::OutCloudFileParam {
+            filepath: format!("responses/{}", ObjectId::new().to_hex()),
+            hostname: "cmdproxy".to_owned(),
         };
-        Ok(serde_json::to_string(&response)?)
+        debug!(
+            "Spill response of {} bytes to {}...",
+            serialized.len(),
+            spilled_to.cloud_url()
+        );
+        spilled_to
+            .upload_from_string(self.storage.clone(), serialized)
+            .await?;
+        Ok(serde_json::to_string(&ResponseEnvelope::Spilled {
+            spilled_to,
+        })?)
     }
 }
@@ -0,0 +1,90 @@
+//! Naming conventions of the queues and of the files on the cloud, configured centrally by
+//! templates rather than baked into the code, such as
+//!
+//! ```text
+//! --queue-template '{namespace}-{command}'
+//! --hostname-template '{namespace}/{command}/{date}/{client}'
+//! ```
+//!
+//! A queue template is rendered by the clients and the workers alike, hence must be the same
+//! on both sides, of the variables `namespace`, `prefix` and `command`, the name the queue is
+//! asked for. A hostname template takes the place of the client id in the cloud urls of the
+//! local files, rendered by the client once per run, of the variables `namespace`, `client`,
+//! `command` and `date`, the day the run is submitted in UTC.
+
+use chrono::{DateTime, Utc};
+
+/// Render `tmpl` by replacing each `{name}` with the value of the variable of the name,
+/// leaving those of unknown names as is.
+pub(crate) fn render(tmpl: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter()
+        .fold(tmpl.to_owned(), |rendered, (name, value)| {
+            rendered.replace(format!("{{{}}}", name).as_str(), value)
+        })
+}
+
+/// The queue of `command` by the template, see the module docs.
+pub(crate) fn queue(tmpl: &str, namespace: &str, prefix: &str, command: &str) -> String {
+    render(
+        tmpl,
+        &[
+            ("namespace", namespace),
+            ("prefix", prefix),
+            ("command", command),
+        ],
+    )
+}
+
+/// The hostname of the local files of a run of `command` submitted at `now` by `client`, by
+/// the template, see the module docs.
+pub(crate) fn hostname(
+    tmpl: &str,
+    namespace: &str,
+    client: &str,
+    command: &str,
+    now: DateTime<Utc>,
+) -> String {
+    let date = now.format("%Y-%m-%d").to_string();
+    let rendered = render(
+        tmpl,
+        &[
+            ("namespace", namespace),
+            ("client", client),
+            ("command", command),
+            ("date", date.as_str()),
+        ],
+    );
+    as_hostname(rendered.as_str())
+}
+
+/// `name` as the hostname of the cloud urls, which ends at the first colon of a url, hence
+/// with the colons replaced by `-`.
+pub(crate) fn as_hostname(name: &str) -> String {
+    name.replace(':', "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(queue("{namespace}-{command}", "ci", "", "gcc"), "ci-gcc");
+        assert_eq!(render("{unknown}/{command}", &[]), "{unknown}/{command}");
+
+        let now = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        assert_eq!(
+            hostname(
+                "{namespace}/{command}/{date}/{client}",
+                "ci",
+                "a:b",
+                "gcc",
+                now
+            ),
+            "ci/gcc/2024-03-09/a-b"
+        );
+        assert_eq!(as_hostname("host:8080"), "host-8080");
+    }
+}
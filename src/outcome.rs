@@ -0,0 +1,238 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::limits::LimitExceeded;
+use crate::metrics::RunMetrics;
+use crate::postmortem::PostMortem;
+use crate::protocol::{Artifact, CancelReason, Cancellation, ExitStatus, RunRecipe, TimedOut};
+
+/// Everything the client knows about a finished run.
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    pub status: ExitStatus,
+    pub artifacts: Vec<Artifact>,
+    /// Set if the run was cancelled on the worker rather than finished by itself.
+    pub cancellation: Option<Cancellation>,
+    /// What went wrong on the worker without failing the run.
+    pub warnings: Vec<String>,
+    /// Why the command died, if killed by the kernel, such as for running out of memory.
+    pub postmortem: Option<PostMortem>,
+    /// The limit of the resources the command was killed for exceeding, if any.
+    pub limit_exceeded: Option<LimitExceeded>,
+    /// Url of the recording of the run, to be replayed by `cmdproxy replay`, if recorded.
+    pub recording_url: Option<String>,
+    /// The recipe resolved by the worker, if a dry run.
+    pub recipe: Option<RunRecipe>,
+    /// Timeout the command was run with, if any.
+    pub timeout: Option<Duration>,
+    pub metrics: RunMetrics,
+    /// Local folder where all the outputs of the run were put, if the client was told so.
+    pub run_dir: Option<PathBuf>,
+}
+
+impl RunOutcome {
+    /// Fail with [`TimedOut`] if the run was killed for taking longer than its timeout.
+    pub fn check_timed_out(&self) -> Result<(), TimedOut> {
+        match &self.cancellation {
+            Some(cancellation) if cancellation.reason == CancelReason::Deadline => Err(TimedOut {
+                timeout: self.timeout.unwrap_or_default(),
+                status: self.status.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// A compact human-readable report of the run.
+    pub fn summary(&self) -> String {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+        writeln!(out, "status    : {}", self.status).unwrap();
+        if let Some(cancellation) = &self.cancellation {
+            writeln!(out, "cancelled : {}", cancellation).unwrap();
+        }
+        if let Some(limit) = self.limit_exceeded {
+            writeln!(out, "killed    : {}", limit).unwrap();
+        }
+        for warning in &self.warnings {
+            writeln!(out, "warning   : {}", warning).unwrap();
+        }
+        if let Some(postmortem) = &self.postmortem {
+            writeln!(out, "postmortem: {}", postmortem.note).unwrap();
+            if let Some(core_url) = &postmortem.core_url {
+                writeln!(out, "core      : {}", core_url).unwrap();
+            }
+        }
+        if let Some(recording_url) = &self.recording_url {
+            writeln!(out, "recording : {}", recording_url).unwrap();
+        }
+        writeln!(out, "queue     : {}", metrics.queue).unwrap();
+        writeln!(
+            out,
+            "duration  : {} (prepare {}, remote {}, finalize {})",
+            format_duration(metrics.total),
+            format_duration(metrics.prepare),
+            format_duration(metrics.remote),
+            format_duration(metrics.finalize),
+        )
+        .unwrap();
+        if let (Some(queue_wait), Some(execution)) = (metrics.queue_wait, metrics.execution) {
+            writeln!(
+                out,
+                "remote    : {} queued, {} executing",
+                format_duration(queue_wait),
+                format_duration(execution),
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "transfer  : {} up, {} down",
+            format_bytes(metrics.uploaded_bytes),
+            format_bytes(metrics.downloaded_bytes),
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "payload   : {} request, {} response",
+            format_bytes(metrics.request_bytes),
+            format_bytes(metrics.response_bytes),
+        )
+        .unwrap();
+        if let Some(run_dir) = &self.run_dir {
+            writeln!(out, "run dir   : {}", run_dir.display()).unwrap();
+        }
+        writeln!(out, "artifacts : {}", self.artifacts.len()).unwrap();
+        for artifact in &self.artifacts {
+            write!(out, "  - {} ({})", artifact.relpath, artifact.cloud_url).unwrap();
+            if !artifact.is_ok() {
+                write!(out, " [{}]", artifact.status).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+        out
+    }
+
+    /// The same report as [`RunOutcome::summary`], but in json.
+    pub fn summary_json(&self) -> String {
+        let metrics = &self.metrics;
+        json!({
+            "status": self.status,
+            "return_code": self.status.return_code(),
+            "signal": self.status.signal(),
+            "queue": metrics.queue,
+            "duration_ms": {
+                "total": metrics.total.as_millis() as u64,
+                "prepare": metrics.prepare.as_millis() as u64,
+                "remote": metrics.remote.as_millis() as u64,
+                "finalize": metrics.finalize.as_millis() as u64,
+                "queue_wait": metrics.queue_wait.map(|wait| wait.as_millis() as u64),
+                "execution": metrics.execution.map(|execution| execution.as_millis() as u64),
+            },
+            "uploaded_bytes": metrics.uploaded_bytes,
+            "downloaded_bytes": metrics.downloaded_bytes,
+            "request_bytes": metrics.request_bytes,
+            "response_bytes": metrics.response_bytes,
+            "artifacts": self.artifacts,
+            "cancellation": self.cancellation,
+            "warnings": self.warnings,
+            "postmortem": self.postmortem,
+            "limit_exceeded": self.limit_exceeded,
+            "recording_url": self.recording_url,
+            "recipe": self.recipe,
+            "run_dir": self.run_dir,
+        })
+        .to_string()
+    }
+}
+
+pub(crate) fn format_duration(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::ArtifactStatus;
+
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let outcome = RunOutcome {
+            status: ExitStatus::Exited { code: 0 },
+            artifacts: vec![
+                Artifact {
+                    cloud_url: "@host:/tmp/out/a.log".to_owned(),
+                    relpath: "a.log".to_owned(),
+                    status: ArtifactStatus::Ok,
+                },
+                Artifact {
+                    cloud_url: "@host:/tmp/out/b.log".to_owned(),
+                    relpath: "b.log".to_owned(),
+                    status: ArtifactStatus::Failed {
+                        cause: "failed to download: timeout".to_owned(),
+                    },
+                },
+            ],
+            cancellation: None,
+            warnings: vec!["Input /tmp/in.txt is 0 B, far from the 1.0 MiB declared".to_owned()],
+            postmortem: None,
+            limit_exceeded: None,
+            recording_url: None,
+            recipe: None,
+            timeout: None,
+            metrics: RunMetrics {
+                queue: "sh".to_owned(),
+                prepare: Duration::from_millis(100),
+                remote: Duration::from_millis(1000),
+                finalize: Duration::from_millis(200),
+                queue_wait: Some(Duration::from_millis(700)),
+                execution: Some(Duration::from_millis(250)),
+                total: Duration::from_millis(1300),
+                uploaded_bytes: 512,
+                downloaded_bytes: 3 * 1024 * 1024,
+                request_bytes: 2048,
+                response_bytes: 300,
+                retries: 0,
+                failed: false,
+            },
+            run_dir: None,
+        };
+
+        let summary = outcome.summary();
+        assert!(summary.contains("exited with code 0"));
+        assert!(summary.contains("warning   : Input /tmp/in.txt is 0 B"));
+        assert!(summary.contains("1.300s (prepare 0.100s, remote 1.000s, finalize 0.200s)"));
+        assert!(summary.contains("remote    : 0.700s queued, 0.250s executing"));
+        assert!(summary.contains("512 B up, 3.0 MiB down"));
+        assert!(summary.contains("2.0 KiB request, 300 B response"));
+        assert!(summary.contains("  - a.log (@host:/tmp/out/a.log)\n"));
+        assert!(summary
+            .contains("  - b.log (@host:/tmp/out/b.log) [failed: failed to download: timeout]"));
+
+        let summary: serde_json::Value = serde_json::from_str(&outcome.summary_json()).unwrap();
+        assert_eq!(summary["return_code"], 0);
+        assert_eq!(summary["duration_ms"]["remote"], 1000);
+        assert_eq!(summary["duration_ms"]["queue_wait"], 700);
+        assert_eq!(summary["artifacts"][0]["status"], "Ok");
+        assert!(summary["artifacts"][1]["status"]["Failed"]["cause"].is_string());
+        assert!(summary["cancellation"].is_null());
+    }
+}
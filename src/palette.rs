@@ -0,0 +1,238 @@
+//! Where the workers get their command palette from, and how they trust it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chain_ext::io::DeExt;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use log::debug;
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+
+use crate::configs::{CloudFSConf, PaletteEntry};
+
+/// Where a command palette is fetched from.
+///
+/// Parsed from a url of `http://` or `https://`, a name of a palette on the cloud prefixed by
+/// `cloud:`, or otherwise a path to a local file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PaletteSource {
+    File(PathBuf),
+    Url(String),
+    /// A palette stored in the `palettes` collection of the cloud, keyed by its name.
+    Cloud(String),
+}
+
+impl FromStr for PaletteSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(PaletteSource::Url(s.to_owned()))
+        } else if let Some(name) = s.strip_prefix("cloud:") {
+            if name.is_empty() {
+                anyhow::bail!("Name of the palette on the cloud is missing: {}", s);
+            }
+            Ok(PaletteSource::Cloud(name.to_owned()))
+        } else {
+            Ok(PaletteSource::File(PathBuf::from(s)))
+        }
+    }
+}
+
+impl TryFrom<String> for PaletteSource {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PaletteSource> for String {
+    fn from(source: PaletteSource) -> Self {
+        source.to_string()
+    }
+}
+
+impl fmt::Display for PaletteSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteSource::File(path) => write!(f, "{}", path.display()),
+            PaletteSource::Url(url) => write!(f, "{}", url),
+            PaletteSource::Cloud(name) => write!(f, "cloud:{}", name),
+        }
+    }
+}
+
+/// Raw content of a palette together with its detached signature, if any.
+struct Fetched {
+    content: Vec<u8>,
+    signature: Option<String>,
+}
+
+impl PaletteSource {
+    /// Fetch and parse the palette, verifying its signature if a key is given.
+    ///
+    /// A missing local file is taken as an empty palette, while a missing remote one is an
+    /// error, as it is most likely a misconfiguration.
+    pub async fn load(
+        &self,
+        cloud: &CloudFSConf,
+        key: Option<&PaletteKey>,
+    ) -> anyhow::Result<HashMap<String, PaletteEntry>> {
+        debug!("Load command palette from {}...", self);
+        let fetched = match self.fetch(cloud).await? {
+            Some(fetched) => fetched,
+            None => return Ok(HashMap::new()),
+        };
+
+        if let Some(key) = key {
+            let signature = fetched
+                .signature
+                .ok_or_else(|| anyhow!("Command palette {} is not signed", self))?;
+            key.verify(fetched.content.as_slice(), signature.as_str())
+                .map_err(|err| anyhow!("Command palette {} is not trusted: {}", self, err))?;
+        }
+        Ok(fetched.content.as_slice().de_yaml()?)
+    }
+
+    /// Fetch the palette, where the signature of a file or an url is expected at the same
+    /// location suffixed by `.sig`.
+    async fn fetch(&self, cloud: &CloudFSConf) -> anyhow::Result<Option<Fetched>> {
+        match self {
+            PaletteSource::File(path) => {
+                if !path.exists() {
+                    return Ok(None);
+                }
+                let mut sig_path = path.clone().into_os_string();
+                sig_path.push(".sig");
+                let signature = match tokio::fs::read_to_string(&sig_path).await {
+                    Ok(signature) => Some(signature),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(err) => return Err(err.into()),
+                };
+                Ok(Some(Fetched {
+                    content: tokio::fs::read(path).await?,
+                    signature,
+                }))
+            }
+            PaletteSource::Url(url) => {
+                let content = reqwest::get(url.as_str())
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?
+                    .to_vec();
+                let response = reqwest::get(format!("{}.sig", url)).await?;
+                let signature = if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    None
+                } else {
+                    Some(response.error_for_status()?.text().await?)
+                };
+                Ok(Some(Fetched { content, signature }))
+            }
+            PaletteSource::Cloud(name) => {
+                let document = cloud
+                    .palettes()
+                    .await?
+                    .find_one(doc! { "_id": name.as_str() }, None)
+                    .await?
+                    .ok_or_else(|| anyhow!("No command palette named {} on the cloud", name))?;
+                Ok(Some(Fetched {
+                    content: document.get_str("content")?.as_bytes().to_vec(),
+                    signature: document.get_str("signature").ok().map(str::to_owned),
+                }))
+            }
+        }
+    }
+}
+
+/// Public key trusted to sign the command palettes, in ed25519.
+#[derive(Clone, Debug)]
+pub struct PaletteKey(VerifyingKey);
+
+impl FromStr for PaletteKey {
+    type Err = anyhow::Error;
+
+    /// Parse the key from its raw bytes encoded in base64.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 32] = BASE64
+            .decode(s.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("Public key of palette must be 32 bytes"))?;
+        Ok(PaletteKey(VerifyingKey::from_bytes(&bytes)?))
+    }
+}
+
+impl PaletteKey {
+    /// Verify the signature, encoded in base64, of the content.
+    pub fn verify(&self, content: &[u8], signature: &str) -> anyhow::Result<()> {
+        let signature = Signature::from_slice(BASE64.decode(signature.trim())?.as_slice())?;
+        Ok(self.0.verify(content, &signature)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    #[test]
+    fn test_palette_source() {
+        assert_eq!(
+            "https://example.com/palette.yaml"
+                .parse::<PaletteSource>()
+                .unwrap(),
+            PaletteSource::Url("https://example.com/palette.yaml".to_owned())
+        );
+        assert_eq!(
+            "cloud:default".parse::<PaletteSource>().unwrap(),
+            PaletteSource::Cloud("default".to_owned())
+        );
+        assert_eq!(
+            "/etc/cmdproxy/palette.yaml"
+                .parse::<PaletteSource>()
+                .unwrap(),
+            PaletteSource::File(PathBuf::from("/etc/cmdproxy/palette.yaml"))
+        );
+        assert!("cloud:".parse::<PaletteSource>().is_err());
+    }
+
+    #[test]
+    fn test_palette_requires() {
+        let palette: HashMap<String, PaletteEntry> = "sh: /bin/sh\n\
+            train:\n  path: /opt/train\n  requires: [gpu, os=linux]\n"
+            .as_bytes()
+            .de_yaml()
+            .unwrap();
+
+        let tags = HashSet::from(["gpu".to_owned(), "os=linux".to_owned()]);
+        assert!(palette["sh"].is_served_by(&HashSet::new()));
+        assert!(palette["train"].is_served_by(&tags));
+        assert!(!palette["train"].is_served_by(&HashSet::from(["gpu".to_owned()])));
+    }
+
+    #[test]
+    fn test_palette_key() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let key = BASE64
+            .encode(signing_key.verifying_key().as_bytes())
+            .parse::<PaletteKey>()
+            .unwrap();
+
+        let content = b"sh: /bin/sh\n";
+        let signature = BASE64.encode(signing_key.sign(content).to_bytes());
+        assert!(key.verify(content, signature.as_str()).is_ok());
+        assert!(key.verify(b"sh: /tmp/sh\n", signature.as_str()).is_err());
+        assert!(key.verify(content, "not a signature").is_err());
+    }
+}
@@ -1,21 +1,32 @@
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use std::{collections::HashMap, io::Write};
 
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use chrono::{Datelike, Timelike};
 use log::debug;
-use mongodb::bson::doc;
-use mongodb::bson::oid::ObjectId;
-use mongodb_gridfs::options::GridFSUploadOptions;
-use mongodb_gridfs::GridFSBucket;
-use mongodb_gridfs_ext::bucket::common::GridFSBucketExt;
-use mongodb_gridfs_ext::bucket::file_sync::FileSync;
-use mongodb_gridfs_ext::error::Result as GridFSExtResult;
+use mongodb::bson::{doc, Document};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::Instrument;
 use walkdir::WalkDir;
 use zip::{self, write::FileOptions};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::archive::{tar_zst_dir, untar_zst_all, ArchiveFormat};
+use crate::chunked;
+use crate::paths::HostPath;
+use crate::protocol::Provenance;
+use crate::sparse;
+use crate::storage::{FileStorage, Storage};
+
+/// Max size of the content of a [`Param::ContentParam`], beyond which the file should go
+/// through the cloud instead.
+pub const MAX_CONTENT_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Param {
     StrParam {
         value: String,
@@ -32,6 +43,11 @@ pub enum Param {
     CmdPathParam {
         path: String,
     },
+    /// A small file sent inline in the request, by its name and its content in base64.
+    ContentParam {
+        name: String,
+        content: String,
+    },
     InLocalFileParam {
         filepath: String,
         hostname: String,
@@ -44,10 +60,48 @@ pub enum Param {
         filepath: String,
         hostname: String,
     },
+    InLocalDirParam {
+        filepath: String,
+        hostname: String,
+    },
+    InCloudDirParam {
+        filepath: String,
+        hostname: String,
+    },
     OutCloudFileParam {
         filepath: String,
         hostname: String,
     },
+    OutLocalDirParam {
+        filepath: String,
+        hostname: String,
+    },
+    OutCloudDirParam {
+        filepath: String,
+        hostname: String,
+    },
+    InLocalGlobParam {
+        pattern: String,
+        filepath: String,
+        hostname: String,
+    },
+    /// The files matching the pattern, by their paths relative to the folder `filepath`.
+    InCloudGlobParam {
+        pattern: String,
+        filepath: String,
+        hostname: String,
+        files: HashMap<String, Param>,
+    },
+    OutLocalGlobParam {
+        pattern: String,
+        filepath: String,
+        hostname: String,
+    },
+    OutCloudGlobParam {
+        pattern: String,
+        filepath: String,
+        hostname: String,
+    },
     FormatParam {
         tmpl: String,
         args: HashMap<String, Param>,
@@ -63,16 +117,92 @@ impl Param {
 
     pub fn ipath<S: AsRef<str>>(filepath: S) -> Param {
         let filepath = filepath.as_ref().to_string();
-        let hostname = hostname::get().unwrap().into_string().unwrap();
+        let hostname = local_hostname();
         Param::InLocalFileParam { filepath, hostname }
     }
 
     pub fn opath<S: AsRef<str>>(filepath: S) -> Param {
         let filepath = filepath.as_ref().to_string();
-        let hostname = hostname::get().unwrap().into_string().unwrap();
+        let hostname = local_hostname();
         Param::OutLocalFileParam { filepath, hostname }
     }
 
+    /// An input folder whose whole content is uploaded, and downloaded into a fresh folder on
+    /// the server, which fails rather than passing a file if not a folder.
+    pub fn idir<S: AsRef<str>>(dirpath: S) -> Param {
+        let filepath = dirpath.as_ref().to_string();
+        let hostname = local_hostname();
+        Param::InLocalDirParam { filepath, hostname }
+    }
+
+    /// Inputs matching `pattern`, which are uploaded one by one, and downloaded into a fresh
+    /// folder on the server keeping their paths relative to the leading folders of the
+    /// pattern free of wildcards, such as `data` of `data/*.csv`.
+    ///
+    /// The param resolves to the fresh folder when passed to the command.
+    ///
+    /// A relative pattern is taken in the current directory, failing if there is none, or if
+    /// it is not valid unicode.
+    pub fn iglob<S: AsRef<str>>(pattern: S) -> anyhow::Result<Param> {
+        let cwd = std::env::current_dir().context("No current directory to glob in")?;
+        let cwd = cwd
+            .to_str()
+            .with_context(|| format!("Current directory {} is not valid unicode", cwd.display()))?;
+        Ok(Param::iglob_in(pattern, cwd))
+    }
+
+    /// Same as [`Param::iglob`], but take a relative pattern in `dirpath`.
+    pub fn iglob_in<S: AsRef<str>, T: AsRef<str>>(pattern: S, dirpath: T) -> Param {
+        let pattern = Path::new(dirpath.as_ref()).join(pattern.as_ref());
+        let filepath: PathBuf = pattern
+            .components()
+            .take_while(|component| {
+                let component = component.as_os_str().to_string_lossy();
+                !component.contains(['*', '?', '['])
+            })
+            .collect();
+        // joined of valid unicode, hence converted without a loss
+        Param::InLocalGlobParam {
+            pattern: pattern.to_string_lossy().into_owned(),
+            filepath: filepath.to_string_lossy().into_owned(),
+            hostname: local_hostname(),
+        }
+    }
+
+    /// An output folder whose whole content, generated by the command, will be exported to
+    /// the local `dirpath` after the run.
+    pub fn odir<S: AsRef<str>>(dirpath: S) -> Param {
+        let filepath = dirpath.as_ref().to_string();
+        let hostname = local_hostname();
+        Param::OutLocalDirParam { filepath, hostname }
+    }
+
+    /// Outputs matching `pattern` after the run, downloaded into the current directory.
+    ///
+    /// The placeholder `{workspace}` in the pattern stands for a fresh folder on the server,
+    /// which is also the value the param resolves to when passed to the command.
+    ///
+    /// Fail if there is no current directory, or if it is not valid unicode.
+    pub fn oglob<S: AsRef<str>>(pattern: S) -> anyhow::Result<Param> {
+        let cwd = std::env::current_dir().context("No current directory to download into")?;
+        let cwd = cwd
+            .to_str()
+            .with_context(|| format!("Current directory {} is not valid unicode", cwd.display()))?;
+        Ok(Param::oglob_to(pattern, cwd))
+    }
+
+    /// Same as [`Param::oglob`], but download the matched outputs into `dirpath`.
+    pub fn oglob_to<S: AsRef<str>, T: AsRef<str>>(pattern: S, dirpath: T) -> Param {
+        let pattern = pattern.as_ref().to_string();
+        let filepath = dirpath.as_ref().to_string();
+        let hostname = local_hostname();
+        Param::OutLocalGlobParam {
+            pattern,
+            filepath,
+            hostname,
+        }
+    }
+
     pub fn env<S: AsRef<str>>(name: S) -> Param {
         Param::EnvParam {
             name: name.as_ref().to_string(),
@@ -97,6 +227,15 @@ impl Param {
         }
     }
 
+    /// A file named `name` of `content`, carried in the request itself rather than through
+    /// the cloud, for the small files such as configs of at most [`MAX_CONTENT_SIZE`] bytes.
+    pub fn content<S: AsRef<str>, B: AsRef<[u8]>>(name: S, content: B) -> Param {
+        Param::ContentParam {
+            name: name.as_ref().to_string(),
+            content: BASE64.encode(content),
+        }
+    }
+
     pub fn format<S: AsRef<str>>(tmpl: S, args: HashMap<&str, Param>) -> Param {
         Param::FormatParam {
             tmpl: tmpl.as_ref().to_string(),
@@ -107,12 +246,132 @@ impl Param {
         }
     }
 
+    /// Replace the hostname of the local file params, including those nested in a format
+    /// param, if it is `from`.
+    pub fn with_hostname(self, from: &str, to: &str) -> Param {
+        match self {
+            Param::FormatParam { tmpl, args } => Param::FormatParam {
+                tmpl,
+                args: args
+                    .into_iter()
+                    .map(|(key, arg)| (key, arg.with_hostname(from, to)))
+                    .collect(),
+            },
+            Param::InLocalFileParam { filepath, hostname } if hostname == from => {
+                Param::InLocalFileParam {
+                    filepath,
+                    hostname: to.to_owned(),
+                }
+            }
+            Param::OutLocalFileParam { filepath, hostname } if hostname == from => {
+                Param::OutLocalFileParam {
+                    filepath,
+                    hostname: to.to_owned(),
+                }
+            }
+            Param::InLocalDirParam { filepath, hostname } if hostname == from => {
+                Param::InLocalDirParam {
+                    filepath,
+                    hostname: to.to_owned(),
+                }
+            }
+            Param::OutLocalDirParam { filepath, hostname } if hostname == from => {
+                Param::OutLocalDirParam {
+                    filepath,
+                    hostname: to.to_owned(),
+                }
+            }
+            Param::InLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            } if hostname == from => Param::InLocalGlobParam {
+                pattern,
+                filepath,
+                hostname: to.to_owned(),
+            },
+            Param::OutLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            } if hostname == from => Param::OutLocalGlobParam {
+                pattern,
+                filepath,
+                hostname: to.to_owned(),
+            },
+            param => param,
+        }
+    }
+
+    /// Relocate the local outputs, including those nested in a format param, to `f(filepath)`.
+    pub fn relocate_outputs<F: Fn(&str) -> String>(self, f: &F) -> Param {
+        match self {
+            Param::FormatParam { tmpl, args } => Param::FormatParam {
+                tmpl,
+                args: args
+                    .into_iter()
+                    .map(|(key, arg)| (key, arg.relocate_outputs(f)))
+                    .collect(),
+            },
+            Param::OutLocalFileParam { filepath, hostname } => Param::OutLocalFileParam {
+                filepath: f(filepath.as_str()),
+                hostname,
+            },
+            Param::OutLocalDirParam { filepath, hostname } => Param::OutLocalDirParam {
+                filepath: f(filepath.as_str()),
+                hostname,
+            },
+            Param::OutLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            } => Param::OutLocalGlobParam {
+                pattern,
+                filepath: f(filepath.as_str()),
+                hostname,
+            },
+            param => param,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Param::StrParam { .. } => "StrParam",
+            Param::EnvParam { .. } => "EnvParam",
+            Param::RemoteEnvParam { .. } => "RemoteEnvParam",
+            Param::CmdNameParam { .. } => "CmdNameParam",
+            Param::CmdPathParam { .. } => "CmdPathParam",
+            Param::ContentParam { .. } => "ContentParam",
+            Param::InLocalFileParam { .. } => "InLocalFileParam",
+            Param::OutLocalFileParam { .. } => "OutLocalFileParam",
+            Param::InCloudFileParam { .. } => "InCloudFileParam",
+            Param::OutCloudFileParam { .. } => "OutCloudFileParam",
+            Param::InLocalDirParam { .. } => "InLocalDirParam",
+            Param::InCloudDirParam { .. } => "InCloudDirParam",
+            Param::OutLocalDirParam { .. } => "OutLocalDirParam",
+            Param::OutCloudDirParam { .. } => "OutCloudDirParam",
+            Param::OutLocalGlobParam { .. } => "OutLocalGlobParam",
+            Param::OutCloudGlobParam { .. } => "OutCloudGlobParam",
+            Param::InLocalGlobParam { .. } => "InLocalGlobParam",
+            Param::InCloudGlobParam { .. } => "InCloudGlobParam",
+            Param::FormatParam { .. } => "FormatParam",
+        }
+    }
+
     pub fn hostname(&self) -> &str {
         match self {
             Param::InLocalFileParam { hostname, .. } => hostname,
             Param::OutLocalFileParam { hostname, .. } => hostname,
             Param::InCloudFileParam { hostname, .. } => hostname,
             Param::OutCloudFileParam { hostname, .. } => hostname,
+            Param::InLocalDirParam { hostname, .. } => hostname,
+            Param::InCloudDirParam { hostname, .. } => hostname,
+            Param::OutLocalDirParam { hostname, .. } => hostname,
+            Param::OutCloudDirParam { hostname, .. } => hostname,
+            Param::OutLocalGlobParam { hostname, .. } => hostname,
+            Param::OutCloudGlobParam { hostname, .. } => hostname,
+            Param::InLocalGlobParam { hostname, .. } => hostname,
+            Param::InCloudGlobParam { hostname, .. } => hostname,
             _ => unreachable!(),
         }
     }
@@ -123,6 +382,24 @@ impl Param {
             Param::OutLocalFileParam { filepath, .. } => filepath,
             Param::InCloudFileParam { filepath, .. } => filepath,
             Param::OutCloudFileParam { filepath, .. } => filepath,
+            Param::InLocalDirParam { filepath, .. } => filepath,
+            Param::InCloudDirParam { filepath, .. } => filepath,
+            Param::OutLocalDirParam { filepath, .. } => filepath,
+            Param::OutCloudDirParam { filepath, .. } => filepath,
+            Param::OutLocalGlobParam { filepath, .. } => filepath,
+            Param::OutCloudGlobParam { filepath, .. } => filepath,
+            Param::InLocalGlobParam { filepath, .. } => filepath,
+            Param::InCloudGlobParam { filepath, .. } => filepath,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        match self {
+            Param::OutLocalGlobParam { pattern, .. } => pattern,
+            Param::OutCloudGlobParam { pattern, .. } => pattern,
+            Param::InLocalGlobParam { pattern, .. } => pattern,
+            Param::InCloudGlobParam { pattern, .. } => pattern,
             _ => unreachable!(),
         }
     }
@@ -130,28 +407,58 @@ impl Param {
     pub fn is_input(&self) -> bool {
         matches!(
             self,
-            Param::InLocalFileParam { .. } | Param::InCloudFileParam { .. }
+            Param::InLocalFileParam { .. }
+                | Param::InCloudFileParam { .. }
+                | Param::InLocalDirParam { .. }
+                | Param::InCloudDirParam { .. }
+                | Param::InLocalGlobParam { .. }
+                | Param::InCloudGlobParam { .. }
         )
     }
 
     pub fn is_output(&self) -> bool {
         matches!(
             self,
-            Param::OutLocalFileParam { .. } | Param::OutCloudFileParam { .. }
+            Param::OutLocalFileParam { .. }
+                | Param::OutCloudFileParam { .. }
+                | Param::OutLocalDirParam { .. }
+                | Param::OutCloudDirParam { .. }
+                | Param::OutLocalGlobParam { .. }
+                | Param::OutCloudGlobParam { .. }
+        )
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(
+            self,
+            Param::InLocalDirParam { .. }
+                | Param::InCloudDirParam { .. }
+                | Param::OutLocalDirParam { .. }
+                | Param::OutCloudDirParam { .. }
         )
     }
 
     pub fn is_local(&self) -> bool {
         matches!(
             self,
-            Param::InLocalFileParam { .. } | Param::OutLocalFileParam { .. }
+            Param::InLocalFileParam { .. }
+                | Param::OutLocalFileParam { .. }
+                | Param::InLocalDirParam { .. }
+                | Param::OutLocalDirParam { .. }
+                | Param::InLocalGlobParam { .. }
+                | Param::OutLocalGlobParam { .. }
         )
     }
 
     pub fn is_cloud(&self) -> bool {
         matches!(
             self,
-            Param::InCloudFileParam { .. } | Param::OutCloudFileParam { .. }
+            Param::InCloudFileParam { .. }
+                | Param::OutCloudFileParam { .. }
+                | Param::InCloudDirParam { .. }
+                | Param::OutCloudDirParam { .. }
+                | Param::InCloudGlobParam { .. }
+                | Param::OutCloudGlobParam { .. }
         )
     }
 
@@ -163,111 +470,643 @@ impl Param {
             Param::OutLocalFileParam { filepath, hostname } => {
                 Param::OutCloudFileParam { filepath, hostname }
             }
+            Param::InLocalDirParam { filepath, hostname } => {
+                Param::InCloudDirParam { filepath, hostname }
+            }
+            Param::OutLocalDirParam { filepath, hostname } => {
+                Param::OutCloudDirParam { filepath, hostname }
+            }
+            Param::OutLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            } => Param::OutCloudGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            },
+            // the matched files are only known once uploaded
+            Param::InLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            } => Param::InCloudGlobParam {
+                pattern,
+                filepath,
+                hostname,
+                files: HashMap::new(),
+            },
             cloud @ Param::InCloudFileParam { .. } => cloud,
             cloud @ Param::OutCloudFileParam { .. } => cloud,
+            cloud @ Param::InCloudDirParam { .. } => cloud,
+            cloud @ Param::OutCloudDirParam { .. } => cloud,
+            cloud @ Param::OutCloudGlobParam { .. } => cloud,
+            cloud @ Param::InCloudGlobParam { .. } => cloud,
             _ => unreachable!(),
         }
     }
 
+    /// Inverse of [`Param::as_cloud`].
+    pub fn as_local(&self) -> Param {
+        match self.clone() {
+            Param::InCloudFileParam { filepath, hostname } => {
+                Param::InLocalFileParam { filepath, hostname }
+            }
+            Param::OutCloudFileParam { filepath, hostname } => {
+                Param::OutLocalFileParam { filepath, hostname }
+            }
+            Param::InCloudDirParam { filepath, hostname } => {
+                Param::InLocalDirParam { filepath, hostname }
+            }
+            Param::OutCloudDirParam { filepath, hostname } => {
+                Param::OutLocalDirParam { filepath, hostname }
+            }
+            Param::OutCloudGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            } => Param::OutLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            },
+            Param::InCloudGlobParam {
+                pattern,
+                filepath,
+                hostname,
+                ..
+            } => Param::InLocalGlobParam {
+                pattern,
+                filepath,
+                hostname,
+            },
+            local @ Param::InLocalFileParam { .. } => local,
+            local @ Param::OutLocalFileParam { .. } => local,
+            local @ Param::InLocalDirParam { .. } => local,
+            local @ Param::OutLocalDirParam { .. } => local,
+            local @ Param::OutLocalGlobParam { .. } => local,
+            local @ Param::InLocalGlobParam { .. } => local,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The cloud output file standing for `relpath` under this folder-like output.
+    ///
+    /// The child path is in the style of the host of this param, no matter where it is made.
+    pub fn child<S: AsRef<str>>(&self, relpath: S) -> Param {
+        let filepath = HostPath::parse(self.filepath()).join(relpath.as_ref());
+        Param::OutCloudFileParam {
+            filepath: filepath.to_host_string(),
+            hostname: self.hostname().to_string(),
+        }
+    }
+
+    /// Url of the file on the cloud, in which the path is normalized so that the url is the
+    /// same no matter how the path is written on its host.
     pub fn cloud_url(&self) -> String {
         format!(
             "@{hostname}:{filepath}",
             hostname = self.hostname(),
-            filepath = self.filepath()
+            filepath = HostPath::parse(self.filepath()).normalized()
         )
     }
 
-    pub async fn id_on_cloud(&self, bucket: GridFSBucket) -> GridFSExtResult<ObjectId> {
-        bucket.id(self.cloud_url().as_str()).await
+    /// The cloud counterpart of this local file param, standing for the file at `relpath` on
+    /// the filesystem shared with the server, which is used in place rather than transferred.
+    pub fn as_shared<S: AsRef<str>>(&self, relpath: S) -> Param {
+        let filepath = relpath.as_ref().to_string();
+        let hostname = SHARED_HOSTNAME.to_owned();
+        match self.as_cloud() {
+            Param::InCloudFileParam { .. } => Param::InCloudFileParam { filepath, hostname },
+            Param::OutCloudFileParam { .. } => Param::OutCloudFileParam { filepath, hostname },
+            Param::InCloudDirParam { .. } => Param::InCloudDirParam { filepath, hostname },
+            Param::OutCloudDirParam { .. } => Param::OutCloudDirParam { filepath, hostname },
+            _ => unreachable!(),
+        }
     }
 
-    pub async fn exists_on_cloud(&self, bucket: GridFSBucket) -> GridFSExtResult<bool> {
-        bucket.exists(self.cloud_url().as_str()).await
+    /// Whether this param stands for a file on the shared filesystem, see [`Param::as_shared`].
+    pub fn is_shared(&self) -> bool {
+        (self.is_local() || self.is_cloud()) && self.hostname() == SHARED_HOSTNAME
     }
 
-    pub async fn remove_from_cloud(&self, bucket: GridFSBucket) -> GridFSExtResult<()> {
-        bucket
-            .delete(self.id_on_cloud(bucket.clone()).await?)
-            .await
-            .map_err(Into::into)
+    /// The cloud input standing for the content-addressed copy of this local input file,
+    /// named `name` after its content, such as by its sha256 digest, and which is shared by
+    /// the runs over the same content instead of being uploaded for each of them.
+    pub fn as_blob(&self, name: &str) -> Param {
+        let filepath = HostPath::parse(self.filepath());
+        // named after the file, so that the command sees the same extension on the server
+        let filename = filepath.file_name().unwrap_or("blob");
+        Param::InCloudFileParam {
+            filepath: format!("blobs/{}/{}", name, filename),
+            hostname: BLOB_HOSTNAME.to_owned(),
+        }
+    }
+
+    /// Whether this param stands for a content-addressed input, see [`Param::as_blob`].
+    pub fn is_blob(&self) -> bool {
+        self.is_cloud() && self.hostname() == BLOB_HOSTNAME
+    }
+
+    /// Url the file of this param is uploaded to before published, in the stage of a run.
+    pub fn staged_url(&self, stage: &str) -> String {
+        format!("{}.staged-{}", self.cloud_url(), stage)
+    }
+
+    /// The output a cloud url points to, as the inverse of [`Param::cloud_url`].
+    pub fn from_cloud_url(cloud_url: &str) -> Option<Param> {
+        let (hostname, filepath) = cloud_url.strip_prefix('@')?.split_once(':')?;
+        Some(Param::OutCloudFileParam {
+            filepath: filepath.to_owned(),
+            hostname: hostname.to_owned(),
+        })
     }
 
+    pub async fn exists_on_cloud(&self, storage: Storage) -> anyhow::Result<bool> {
+        storage.exists(self.cloud_url().as_str()).await
+    }
+
+    pub async fn remove_from_cloud(&self, storage: Storage) -> anyhow::Result<()> {
+        let cloud_url = self.cloud_url();
+        let op = StorageOp::start("delete", self, cloud_url.as_str(), storage.as_ref());
+        let res = remove_file(storage.as_ref(), cloud_url.as_str())
+            .instrument(op.span.clone())
+            .await;
+        op.finish(&res, None);
+        res
+    }
+
+    /// Download the file to `filepath`, replacing the file there, if any, only once complete,
+    /// which is left as is if the download fails.
     pub async fn download(
         &self,
-        bucket: GridFSBucket,
+        storage: Storage,
         filepath: impl AsRef<Path> + Send + Sync,
-    ) -> GridFSExtResult<ObjectId> {
+    ) -> anyhow::Result<()> {
         let path = filepath.as_ref();
+        let op = StorageOp::start(
+            "download",
+            self,
+            self.cloud_url().as_str(),
+            storage.as_ref(),
+        );
+        let res = self
+            .download_untraced(storage.as_ref(), path)
+            .instrument(op.span.clone())
+            .await;
+        op.finish(&res, file_size(path));
+        res
+    }
+
+    async fn download_untraced(
+        &self,
+        storage: &dyn FileStorage,
+        path: &Path,
+    ) -> anyhow::Result<()> {
         // download to cache path
         let tmp_file = tempfile::Builder::new()
             .prefix(path.file_name().unwrap())
             .suffix(".download.parts")
             .tempfile_in(path.parent().unwrap())?;
-        let oid = bucket
-            .download_to(self.cloud_url().as_str(), tmp_file.path())
+        let cloud_url = self.cloud_url();
+        let metadata = storage
+            .download(cloud_url.as_str(), tmp_file.path())
             .await?;
+        chunked::download(
+            storage,
+            cloud_url.as_str(),
+            metadata.as_ref(),
+            tmp_file.path(),
+        )
+        .await?;
+        let sparse = metadata
+            .as_ref()
+            .map_or(false, |metadata| metadata.get_bool("sparse") == Ok(true));
+        if sparse && !self.is_dir() {
+            debug!("Unpack the downloaded sparse file to {:#?}...", path);
+            let unpacked = tempfile::Builder::new()
+                .prefix(path.file_name().unwrap())
+                .suffix(".unpack.parts")
+                .tempfile_in(path.parent().unwrap())?;
+            sparse::unpack(tmp_file.path(), unpacked.path())?;
+            return replace_with(unpacked, path);
+        }
 
-        // unzip if the cloud file is a compressed directory
-        if let Some(metadata) = bucket.metadata(oid).await? {
-            if let Ok("application/directory+zip") = metadata.get_str("content_type") {
+        // unpack if the cloud file is an archived directory
+        let format = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get_str("content_type").ok())
+            .and_then(ArchiveFormat::from_content_type);
+        match format {
+            Some(ArchiveFormat::Zip) => {
                 debug!("Unzip the downloaded zip file to {:#?}...", path);
-                unzip_all(tmp_file, path).unwrap();
-                return Ok(oid);
+                unzip_all(tmp_file, path)?;
+                return Ok(());
+            }
+            Some(ArchiveFormat::TarZst) => {
+                debug!("Untar the downloaded tar.zst file to {:#?}...", path);
+                untar_zst_all(tmp_file.path(), path)?;
+                return Ok(());
             }
+            None => {}
         }
+        anyhow::ensure!(
+            !self.is_dir(),
+            "Expected a directory at {}, but found a file",
+            self.cloud_url()
+        );
 
         // otherwise, just move the downloaded file to the target path
-        let (_, tmp_path) = tmp_file.keep().unwrap();
-        tokio::fs::rename(tmp_path, path).await?;
-        Ok(oid)
+        replace_with(tmp_file, path)
+    }
+
+    /// Fail unless `path` is a folder, if this param stands for one.
+    fn check_dir(&self, path: &Path) -> anyhow::Result<()> {
+        if self.is_dir() && !path.is_dir() {
+            let found = if path.exists() { "a file" } else { "nothing" };
+            anyhow::bail!(
+                "Expected a directory at {}, but found {}",
+                path.display(),
+                found
+            );
+        }
+        Ok(())
     }
 
     pub async fn upload(
         &self,
-        mut bucket: GridFSBucket,
+        storage: Storage,
         filepath: impl AsRef<Path> + Send,
-    ) -> GridFSExtResult<ObjectId> {
-        let filepath = filepath.as_ref();
-        if filepath.is_dir() {
-            let options = GridFSUploadOptions::builder()
-                .metadata(Some(doc! {"content_type": "application/directory+zip"}))
-                .build();
-            let zip_file = tempfile::NamedTempFile::new()?;
-            zip_dir(filepath, zip_file.path()).unwrap();
-
-            return bucket
-                .upload_from(self.cloud_url().as_str(), zip_file.path(), Some(options))
-                .await;
+    ) -> anyhow::Result<()> {
+        self.upload_archived(storage, filepath, ArchiveFormat::default())
+            .await
+    }
+
+    /// Upload, packing the folder in the archive format `archive` if this is a directory.
+    pub async fn upload_archived(
+        &self,
+        storage: Storage,
+        filepath: impl AsRef<Path> + Send,
+        archive: ArchiveFormat,
+    ) -> anyhow::Result<()> {
+        let path = filepath.as_ref();
+        self.check_dir(path)?;
+        let cloud_url = self.cloud_url();
+        let op = StorageOp::start("upload", self, cloud_url.as_str(), storage.as_ref());
+        let res = upload_to(storage.as_ref(), path, cloud_url.as_str(), None, archive)
+            .instrument(op.span.clone())
+            .await;
+        op.finish(&res, file_size(path));
+        res
+    }
+
+    /// Upload the file at `filepath` as the blob of its content of `digest`, unless it is on
+    /// the cloud already, and return whether it is uploaded. Without the digest, such as for
+    /// a blob encrypted by a picked key, it is uploaded anyway.
+    ///
+    /// The digest is stamped in the metadata of the blob, so that a blob is taken as there
+    /// only once uploaded completely.
+    pub async fn upload_blob(
+        &self,
+        storage: Storage,
+        filepath: impl AsRef<Path> + Send,
+        digest: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let path = filepath.as_ref();
+        let cloud_url = self.cloud_url();
+        if let Some(digest) = digest {
+            let uploaded = storage.metadata(cloud_url.as_str()).await.ok().flatten();
+            if uploaded.map_or(false, |metadata| metadata.get_str("sha256") == Ok(digest)) {
+                return Ok(false);
+            }
         }
 
-        bucket
-            .upload_from(self.cloud_url().as_str(), filepath, None)
-            .await
+        let op = StorageOp::start("upload", self, cloud_url.as_str(), storage.as_ref());
+        let metadata = digest.map(|digest| doc! { "sha256": digest });
+        let archive = ArchiveFormat::default();
+        let res = upload_to(
+            storage.as_ref(),
+            path,
+            cloud_url.as_str(),
+            metadata,
+            archive,
+        )
+        .instrument(op.span.clone())
+        .await;
+        op.finish(&res, file_size(path));
+        res.map(|_| true)
+    }
+
+    /// Upload under a staging url which is invisible to the readers of this param, until
+    /// it gets published by [`Param::commit_staged`], and return the staging url.
+    ///
+    /// The provenance, if given, is stamped in the metadata of the uploaded file, and a folder
+    /// is packed in the archive format `archive`.
+    pub async fn upload_staged(
+        &self,
+        storage: Storage,
+        filepath: impl AsRef<Path> + Send,
+        stage: &str,
+        provenance: Option<&Provenance>,
+        archive: ArchiveFormat,
+    ) -> anyhow::Result<String> {
+        let path = filepath.as_ref();
+        self.check_dir(path)?;
+        let staged_url = self.staged_url(stage);
+        let metadata = provenance.map(|provenance| {
+            doc! {
+                "provenance": {
+                    "task_id": provenance.task_id.as_str(),
+                    "command": provenance.command.as_str(),
+                    "worker": provenance.worker.as_str(),
+                }
+            }
+        });
+        let op = StorageOp::start("upload_staged", self, staged_url.as_str(), storage.as_ref());
+        let res = upload_to(
+            storage.as_ref(),
+            path,
+            staged_url.as_str(),
+            metadata,
+            archive,
+        )
+        .instrument(op.span.clone())
+        .await;
+        op.finish(&res, file_size(path));
+        res.map(|_| staged_url)
+    }
+
+    /// Url the file published under the url of this param is set aside under while the
+    /// outputs of the stage of a run are committed, to be put back if any of them fails.
+    pub fn replaced_url(&self, stage: &str) -> String {
+        format!("{}.replaced-{}", self.cloud_url(), stage)
     }
 
-    pub async fn download_inplace(&self, bucket: GridFSBucket) -> GridFSExtResult<ObjectId> {
+    /// Publish a file uploaded by [`Param::upload_staged`] under the url of this param.
+    ///
+    /// The file published there before, if any, is set aside under [`Param::replaced_url`]
+    /// rather than removed, so that the commit can be undone by [`Param::rollback_staged`]
+    /// until [`Param::drop_replaced`]. Return whether there was such a file.
+    pub async fn commit_staged(
+        &self,
+        storage: Storage,
+        staged_url: &str,
+        stage: &str,
+    ) -> anyhow::Result<bool> {
+        let cloud_url = self.cloud_url();
+        let replaced_url = self.replaced_url(stage);
+        let op = StorageOp::start("commit", self, cloud_url.as_str(), storage.as_ref());
+        let res = async {
+            let replaced = storage.exists(cloud_url.as_str()).await?;
+            if replaced {
+                move_file(storage.as_ref(), cloud_url.as_str(), replaced_url.as_str()).await?;
+            }
+            if let Err(err) = move_file(storage.as_ref(), staged_url, cloud_url.as_str()).await {
+                if replaced {
+                    move_file(storage.as_ref(), replaced_url.as_str(), cloud_url.as_str())
+                        .await
+                        .unwrap_or_else(|err| {
+                            debug!("Failed to put back replaced {}: {}", cloud_url, err)
+                        });
+                }
+                return Err(err);
+            }
+            Ok(replaced)
+        }
+        .instrument(op.span.clone())
+        .await;
+        op.finish(&res, None);
+        res
+    }
+
+    /// Undo [`Param::commit_staged`], removing the file committed, and putting back the file
+    /// it `replaced` if any.
+    pub async fn rollback_staged(
+        &self,
+        storage: Storage,
+        stage: &str,
+        replaced: bool,
+    ) -> anyhow::Result<()> {
+        let cloud_url = self.cloud_url();
+        remove_file(storage.as_ref(), cloud_url.as_str()).await?;
+        if replaced {
+            let replaced_url = self.replaced_url(stage);
+            move_file(storage.as_ref(), replaced_url.as_str(), cloud_url.as_str()).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the file set aside by [`Param::commit_staged`], once the outputs of the stage
+    /// are all committed.
+    pub async fn drop_replaced(&self, storage: Storage, stage: &str) -> anyhow::Result<()> {
+        remove_file(storage.as_ref(), self.replaced_url(stage).as_str()).await
+    }
+
+    /// Where the file of this param on the cloud comes from, if stamped when uploaded.
+    pub async fn provenance(&self, storage: Storage) -> anyhow::Result<Option<Provenance>> {
+        Ok(storage
+            .metadata(self.cloud_url().as_str())
+            .await?
+            .and_then(|metadata| metadata.get_document("provenance").ok().cloned())
+            .and_then(|provenance| mongodb::bson::from_document(provenance).ok()))
+    }
+
+    pub async fn download_inplace(&self, storage: Storage) -> anyhow::Result<()> {
         assert!(self.is_local());
-        self.download(bucket, self.filepath()).await
+        self.download(storage, self.filepath()).await
     }
 
-    pub async fn upload_inplace(&self, bucket: GridFSBucket) -> GridFSExtResult<ObjectId> {
+    pub async fn upload_inplace(&self, storage: Storage) -> anyhow::Result<()> {
         assert!(self.is_local());
-        self.upload(bucket, self.filepath()).await
+        self.upload(storage, self.filepath()).await
     }
 
-    pub async fn download_to_string(&self, bucket: GridFSBucket) -> GridFSExtResult<String> {
-        bucket.read_string(self.cloud_url().as_str()).await
+    pub async fn download_to_string(&self, storage: Storage) -> anyhow::Result<String> {
+        let cloud_url = self.cloud_url();
+        let op = StorageOp::start("read", self, cloud_url.as_str(), storage.as_ref());
+        let res = storage
+            .read_string(cloud_url.as_str())
+            .instrument(op.span.clone())
+            .await;
+        let size = res.as_ref().ok().map(|content| content.len() as u64);
+        op.finish(&res, size);
+        res
     }
 
     pub async fn upload_from_string<S: AsRef<str>>(
         &self,
-        mut bucket: GridFSBucket,
+        storage: Storage,
         content: S,
-    ) -> GridFSExtResult<()> {
-        bucket
-            .write_string(self.cloud_url().as_str(), content.as_ref())
-            .await
+    ) -> anyhow::Result<()> {
+        let cloud_url = self.cloud_url();
+        let op = StorageOp::start("write", self, cloud_url.as_str(), storage.as_ref());
+        let res = storage
+            .write_string(cloud_url.as_str(), content.as_ref())
+            .instrument(op.span.clone())
+            .await;
+        op.finish(&res, Some(content.as_ref().len() as u64));
+        res
+    }
+}
+
+/// A traced operation against the cloud storage.
+struct StorageOp {
+    span: tracing::Span,
+    start: Instant,
+}
+
+impl StorageOp {
+    fn start(
+        op: &'static str,
+        param: &Param,
+        cloud_url: &str,
+        storage: &dyn FileStorage,
+    ) -> StorageOp {
+        let span = tracing::debug_span!(
+            "storage",
+            op,
+            kind = param.kind(),
+            url = cloud_url,
+            backend = storage.backend(),
+            size = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        );
+        StorageOp {
+            span,
+            start: Instant::now(),
+        }
+    }
+
+    fn finish<T, E: std::fmt::Display>(self, res: &Result<T, E>, size: Option<u64>) {
+        self.span
+            .record("elapsed_ms", self.start.elapsed().as_millis() as u64);
+        if let Some(size) = size {
+            self.span.record("size", size);
+        }
+        match res {
+            Ok(_) => tracing::debug!(parent: &self.span, "storage operation done"),
+            Err(err) => {
+                tracing::warn!(parent: &self.span, error = %err, "storage operation failed")
+            }
+        }
+    }
+}
+
+/// Hostname of the params standing for the files on the filesystem shared by the clients and
+/// the servers, whose paths are relative to its mount points.
+pub const SHARED_HOSTNAME: &str = "(shared)";
+
+/// Hostname of the params standing for the content-addressed inputs, see [`Param::as_blob`].
+pub const BLOB_HOSTNAME: &str = "(blobs)";
+
+/// Hostname of the current machine, which local file params are tagged with by default.
+pub fn local_hostname() -> String {
+    hostname::get().unwrap().to_string_lossy().into_owned()
+}
+
+/// Total size of the files under `path`, which can be either a file or a directory.
+pub(crate) fn local_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Hex of the sha256 of the content of the file at `path`, hashed off the async runtime.
+pub(crate) async fn content_digest(path: &Path) -> anyhow::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+/// Move the file at `from` to `to`, the chunks first, so that the head is never there without
+/// them.
+async fn move_file(storage: &dyn FileStorage, from: &str, to: &str) -> anyhow::Result<()> {
+    let metadata = storage.metadata(from).await?;
+    chunked::rename(storage, from, to, metadata.as_ref()).await?;
+    storage.rename(from, to).await
+}
+
+/// Remove the file at `url` along with its chunks.
+async fn remove_file(storage: &dyn FileStorage, url: &str) -> anyhow::Result<()> {
+    let metadata = storage.metadata(url).await?;
+    chunked::delete(storage, url, metadata.as_ref()).await?;
+    storage.delete(url).await
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    path.metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
+
+async fn upload_to(
+    storage: &dyn FileStorage,
+    filepath: &Path,
+    cloud_url: &str,
+    metadata: Option<Document>,
+    archive: ArchiveFormat,
+) -> anyhow::Result<()> {
+    if filepath.is_dir() {
+        let mut metadata = metadata.unwrap_or_default();
+        metadata.insert("content_type", archive.content_type());
+        let archive_file = tempfile::NamedTempFile::new()?;
+        match archive {
+            ArchiveFormat::Zip => zip_dir(filepath, archive_file.path())?,
+            ArchiveFormat::TarZst => tar_zst_dir(filepath, archive_file.path())?,
+        }
+
+        return upload_file(storage, archive_file.path(), cloud_url, Some(metadata)).await;
     }
+
+    upload_file(storage, filepath, cloud_url, metadata).await
+}
+
+/// Upload a file in one go, or in chunks if large, and packed without its holes if sparse,
+/// unless into the shared filesystem.
+async fn upload_file(
+    storage: &dyn FileStorage,
+    filepath: &Path,
+    cloud_url: &str,
+    metadata: Option<Document>,
+) -> anyhow::Result<()> {
+    let shared = Param::from_cloud_url(cloud_url).map_or(false, |param| param.is_shared());
+    let packed;
+    let (filepath, metadata) = if !shared && sparse::is_sparse(filepath) {
+        debug!("  pack sparse file {:#?} without its holes...", filepath);
+        packed = tempfile::NamedTempFile::new()?;
+        sparse::pack(filepath, packed.path())?;
+        let mut metadata = metadata.unwrap_or_default();
+        metadata.insert("sparse", true);
+        (packed.path(), Some(metadata))
+    } else {
+        (filepath, metadata)
+    };
+
+    if !shared && file_size(filepath).map_or(false, |size| size > chunked::CHUNK_SIZE) {
+        return chunked::upload(storage, cloud_url, filepath, metadata, chunked::CHUNK_SIZE).await;
+    }
+    storage.upload(cloud_url, filepath, metadata).await
+}
+
+/// Replace the file at `path` with the complete `file` at once, keeping its permissions, so
+/// that it is never seen half written, nor lost if the replacing fails.
+///
+/// The file is removed instead if it fails to replace.
+fn replace_with(file: tempfile::NamedTempFile, path: &Path) -> anyhow::Result<()> {
+    if let Ok(metadata) = path.metadata() {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    file.persist(path)?;
+    Ok(())
 }
 
 fn unzip_all<R, P>(src: R, dst: P) -> zip::result::ZipResult<()>
@@ -353,6 +1192,8 @@ mod tests {
         use fake::Fake;
         use test_utilities::docker;
 
+        use crate::storage::{GridFsStorage, SharedFsStorage};
+
         use super::*;
 
         #[test]
@@ -370,6 +1211,51 @@ mod tests {
 
             let param = param.as_cloud();
             assert!(matches!(param, Param::OutCloudFileParam { .. }));
+
+            let param = Param::odir(fake_file.path().to_str().unwrap());
+            assert!(matches!(param, Param::OutLocalDirParam { .. }));
+            assert!(param.is_dir() && param.is_output());
+
+            let param = param.as_cloud();
+            assert!(matches!(param, Param::OutCloudDirParam { .. }));
+
+            let param = Param::from_cloud_url(param.cloud_url().as_str()).unwrap();
+            assert!(matches!(param, Param::OutCloudFileParam { .. }));
+            assert_eq!(param.filepath(), fake_file.path().to_str().unwrap());
+            assert!(Param::from_cloud_url("no-host").is_none());
+
+            let param = Param::idir(fake_file.path().parent().unwrap().to_str().unwrap());
+            assert!(matches!(param, Param::InLocalDirParam { .. }));
+            assert!(param.is_dir() && param.is_input());
+            assert!(param.check_dir(Path::new(param.filepath())).is_ok());
+            assert_eq!(param.as_cloud().as_local(), param);
+
+            let param = param.as_cloud();
+            assert!(matches!(param, Param::InCloudDirParam { .. }));
+            let err = param.check_dir(fake_file.path()).unwrap_err();
+            assert!(err.to_string().ends_with("but found a file"));
+
+            let param = Param::iglob("data/*/[ab].csv").unwrap();
+            let cwd = std::env::current_dir().unwrap();
+            assert_eq!(param.filepath(), cwd.join("data").to_str().unwrap());
+            assert_eq!(
+                param.pattern(),
+                cwd.join("data/*/[ab].csv").to_str().unwrap()
+            );
+            assert!(param.is_input() && param.is_local());
+            assert_eq!(param.as_cloud().as_local(), param);
+
+            let param = Param::iglob_in("data/*.csv", "/jobs");
+            assert_eq!(param.filepath(), "/jobs/data");
+            assert_eq!(param.pattern(), "/jobs/data/*.csv");
+            // an absolute pattern is taken as is
+            let param = Param::iglob_in("/data/*.csv", "/jobs");
+            assert_eq!(param.filepath(), "/data");
+
+            let param = Param::oglob("{workspace}/*.o").unwrap();
+            assert_eq!(param.filepath(), cwd.to_str().unwrap());
+            assert_eq!(param.pattern(), "{workspace}/*.o");
+            assert!(param.is_output() && param.is_local());
         }
 
         #[tokio::test]
@@ -387,6 +1273,7 @@ mod tests {
                 .unwrap()
                 .database("cmdproxy-test-params-db")
                 .bucket(None);
+            let storage = GridFsStorage::shared(bucket);
 
             let mut fake_file = tempfile::NamedTempFile::new_in(workspace.path()).unwrap();
             let fake_filepath = fake_file.path().to_str().unwrap().to_owned();
@@ -394,13 +1281,12 @@ mod tests {
             fake_file.write_all(fake_content.as_bytes()).unwrap();
 
             let param = Param::ipath(fake_filepath.as_str());
-            let uploaded_id = param
-                .upload(bucket.clone(), fake_filepath.as_str())
+            param
+                .upload(storage.clone(), fake_filepath.as_str())
                 .await
                 .unwrap();
 
-            let content_on_cloud = bucket
-                .clone()
+            let content_on_cloud = storage
                 .read_string(param.cloud_url().as_str())
                 .await
                 .unwrap();
@@ -413,19 +1299,113 @@ mod tests {
 
             let downloaded_file = tempfile::NamedTempFile::new_in(workspace.path()).unwrap();
             let downloaded_filepath = downloaded_file.path();
-            let downloaded_id = param
-                .download(bucket.clone(), downloaded_filepath)
+            param
+                .download(storage.clone(), downloaded_filepath)
                 .await
                 .unwrap();
 
             // assert download
-            assert_eq!(uploaded_id, downloaded_id);
             assert_eq!(
                 std::fs::read_to_string(downloaded_filepath).unwrap(),
                 std::fs::read_to_string(fake_filepath.as_str()).unwrap()
             );
         }
 
+        #[tokio::test]
+        async fn test_download_failed_keeps_target() {
+            let root = tempfile::tempdir().unwrap();
+            let storage: Storage =
+                std::sync::Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+            let workspace = tempfile::tempdir().unwrap();
+            let target = workspace.path().join("out.txt");
+            std::fs::write(&target, "kept").unwrap();
+
+            let param = Param::opath(target.to_str().unwrap());
+            assert!(param.download_inplace(storage).await.is_err());
+            assert_eq!(std::fs::read_to_string(&target).unwrap(), "kept");
+            // nor any part left behind
+            assert_eq!(std::fs::read_dir(workspace.path()).unwrap().count(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_commit_and_rollback_staged() {
+            let root = tempfile::tempdir().unwrap();
+            let storage: Storage =
+                std::sync::Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+            let workspace = tempfile::tempdir().unwrap();
+            let output = workspace.path().join("out.txt");
+            let param = Param::opath(output.to_str().unwrap()).as_cloud();
+            let read = |storage: Storage| {
+                let param = param.clone();
+                async move { storage.read_string(param.cloud_url().as_str()).await }
+            };
+
+            std::fs::write(&output, "first").unwrap();
+            let staged_url = param
+                .upload_staged(
+                    storage.clone(),
+                    &output,
+                    "a",
+                    None,
+                    ArchiveFormat::default(),
+                )
+                .await
+                .unwrap();
+            assert!(!param.exists_on_cloud(storage.clone()).await.unwrap());
+            let replaced = param
+                .commit_staged(storage.clone(), staged_url.as_str(), "a")
+                .await
+                .unwrap();
+            assert!(!replaced);
+            assert_eq!(read(storage.clone()).await.unwrap(), "first");
+
+            // the first output is put back when the commit of the second is rolled back
+            std::fs::write(&output, "second").unwrap();
+            let staged_url = param
+                .upload_staged(
+                    storage.clone(),
+                    &output,
+                    "b",
+                    None,
+                    ArchiveFormat::default(),
+                )
+                .await
+                .unwrap();
+            let replaced = param
+                .commit_staged(storage.clone(), staged_url.as_str(), "b")
+                .await
+                .unwrap();
+            assert!(replaced);
+            assert_eq!(read(storage.clone()).await.unwrap(), "second");
+            param
+                .rollback_staged(storage.clone(), "b", replaced)
+                .await
+                .unwrap();
+            assert_eq!(read(storage.clone()).await.unwrap(), "first");
+            let replaced_url = param.replaced_url("b");
+            assert!(!storage.exists(replaced_url.as_str()).await.unwrap());
+
+            // and dropped once the commit is kept
+            let staged_url = param
+                .upload_staged(
+                    storage.clone(),
+                    &output,
+                    "c",
+                    None,
+                    ArchiveFormat::default(),
+                )
+                .await
+                .unwrap();
+            assert!(param
+                .commit_staged(storage.clone(), staged_url.as_str(), "c")
+                .await
+                .unwrap());
+            param.drop_replaced(storage.clone(), "c").await.unwrap();
+            assert_eq!(read(storage.clone()).await.unwrap(), "second");
+            let replaced_url = param.replaced_url("c");
+            assert!(!storage.exists(replaced_url.as_str()).await.unwrap());
+        }
+
         #[test]
         fn test_zip_unzip() {
             let workspace = tempfile::tempdir().unwrap();
@@ -490,23 +1470,23 @@ mod tests {
                 .unwrap()
                 .database("cmdproxy-test-params-db")
                 .bucket(None);
+            let storage = GridFsStorage::shared(bucket);
 
             let project_root = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
             let resources_dir = project_root.join("resources/test");
             let fake_folder_path = resources_dir.join("fake_folder");
 
             let param = Param::ipath(fake_folder_path.to_str().unwrap());
-            let uploaded_id = param
-                .upload(bucket.clone(), fake_folder_path.to_str().unwrap())
+            param
+                .upload(storage.clone(), fake_folder_path.to_str().unwrap())
                 .await
                 .unwrap();
 
             // download the uploaded directory as a zip file, and unzip it for checking identity
             let uploaded_zip_path = tempfile::NamedTempFile::new_in(workspace.path()).unwrap();
             let download_unzip_path = tempfile::tempdir_in(workspace.path()).unwrap();
-            bucket
-                .clone()
-                .download_to(param.cloud_url().as_str(), uploaded_zip_path.path())
+            storage
+                .download(param.cloud_url().as_str(), uploaded_zip_path.path())
                 .await
                 .unwrap();
 
@@ -524,14 +1504,12 @@ mod tests {
             assert!(res.new_files.is_empty());
 
             let downloaded_path = tempfile::tempdir_in(workspace.path()).unwrap();
-            let downloaded_id = param
-                .download(bucket.clone(), downloaded_path.path())
+            param
+                .download(storage.clone(), downloaded_path.path())
                 .await
                 .unwrap();
 
             // assert download
-            assert_eq!(uploaded_id, downloaded_id);
-
             let res = folder_compare::FolderCompare::new(
                 downloaded_path.path(),
                 fake_folder_path.as_path(),
@@ -0,0 +1,188 @@
+//! Paths of hosts which may run on a different platform from the current one.
+//!
+//! A param carries the raw path of the client, which the server may not be able to parse
+//! with [`std::path::Path`] when the platforms differ. [`HostPath`] parses it by its own
+//! style instead, and normalizes it for building cloud urls.
+
+/// Flavor of a path, hinted by its own look since the host platform is not recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStyle {
+    Posix,
+    Windows,
+}
+
+impl PathStyle {
+    pub fn native() -> PathStyle {
+        if cfg!(windows) {
+            PathStyle::Windows
+        } else {
+            PathStyle::Posix
+        }
+    }
+
+    /// Guess the style of a raw path: a drive letter, a UNC prefix, or a backslash implies
+    /// Windows.
+    pub fn detect(path: &str) -> PathStyle {
+        let bytes = path.as_bytes();
+        let has_drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+        if has_drive || path.contains('\\') {
+            PathStyle::Windows
+        } else {
+            PathStyle::Posix
+        }
+    }
+
+    fn separator(&self) -> char {
+        match self {
+            PathStyle::Posix => '/',
+            PathStyle::Windows => '\\',
+        }
+    }
+}
+
+/// A path of some host, kept in a normalized form: separated by `/`, without redundant
+/// separators, and with the drive letter upper-cased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPath {
+    normalized: String,
+    style: PathStyle,
+}
+
+impl HostPath {
+    pub fn parse(path: &str) -> HostPath {
+        let style = PathStyle::detect(path);
+        let mut normalized = normalize_separators(path);
+        if style == PathStyle::Windows && normalized.as_bytes().get(1) == Some(&b':') {
+            normalized[..1].make_ascii_uppercase();
+        }
+        HostPath { normalized, style }
+    }
+
+    pub fn style(&self) -> PathStyle {
+        self.style
+    }
+
+    /// The path separated by `/`, which is the same no matter the platform of the host.
+    pub fn normalized(&self) -> &str {
+        self.normalized.as_str()
+    }
+
+    /// The last component of the path, if any.
+    pub fn file_name(&self) -> Option<&str> {
+        self.normalized
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty() && !name.ends_with(':') && *name != "..")
+    }
+
+    /// Append a relative path, which may be separated in either style.
+    pub fn join(&self, relpath: &str) -> HostPath {
+        let relpath = normalize_separators(relpath);
+        let normalized = if self.normalized.is_empty() {
+            relpath
+        } else if self.normalized.ends_with('/') {
+            format!("{}{}", self.normalized, relpath)
+        } else {
+            format!("{}/{}", self.normalized, relpath)
+        };
+        HostPath {
+            normalized,
+            style: self.style,
+        }
+    }
+
+    /// The path in the style of its own host.
+    pub fn to_host_string(&self) -> String {
+        match self.style {
+            PathStyle::Posix => self.normalized.clone(),
+            PathStyle::Windows => self.normalized.replace('/', "\\"),
+        }
+    }
+}
+
+/// Separate a path by `/`, collapsing redundant separators except a leading UNC `//`.
+pub fn normalize_separators(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let (prefix, rest) = match path.strip_prefix("//") {
+        Some(rest) => ("//", rest),
+        None => ("", path.as_str()),
+    };
+    let mut normalized = prefix.to_owned();
+    let mut last_was_sep = false;
+    for ch in rest.chars() {
+        if ch == '/' && last_was_sep {
+            continue;
+        }
+        last_was_sep = ch == '/';
+        normalized.push(ch);
+    }
+    normalized
+}
+
+/// Relative path mirroring the layout of `path` under some folder, e.g. `C:\out\a.txt` to
+/// `C/out/a.txt`, with the parent components resolved lexically, failing if one goes above
+/// the start of the path, which would escape the folder.
+pub fn to_mirrored_relpath(path: &str) -> anyhow::Result<String> {
+    let mut components = vec![];
+    for component in normalize_separators(path).split('/') {
+        match component.strip_suffix(':').unwrap_or(component) {
+            "" | "." => {}
+            ".." => {
+                components
+                    .pop()
+                    .ok_or_else(|| anyhow::anyhow!("Path {} goes above its start", path))?;
+            }
+            component => components.push(component),
+        }
+    }
+    Ok(components.join("/"))
+}
+
+/// Convert a relative path, e.g. of an artifact, into the style of the current platform.
+pub fn to_native_relpath(relpath: &str) -> String {
+    normalize_separators(relpath).replace('/', &PathStyle::native().separator().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_path() {
+        let path = HostPath::parse(r"c:\Users\me\\data\in.txt");
+        assert_eq!(path.style(), PathStyle::Windows);
+        assert_eq!(path.normalized(), "C:/Users/me/data/in.txt");
+        assert_eq!(path.file_name(), Some("in.txt"));
+        assert_eq!(
+            path.join("out/x.bin").to_host_string(),
+            r"C:\Users\me\data\in.txt\out\x.bin"
+        );
+
+        let path = HostPath::parse("/home/me//out/");
+        assert_eq!(path.style(), PathStyle::Posix);
+        assert_eq!(path.normalized(), "/home/me/out/");
+        assert_eq!(path.file_name(), None);
+        assert_eq!(
+            path.join(r"sub\x.bin").to_host_string(),
+            "/home/me/out/sub/x.bin"
+        );
+
+        let path = HostPath::parse(r"\\server\share\f.txt");
+        assert_eq!(path.normalized(), "//server/share/f.txt");
+        assert_eq!(path.file_name(), Some("f.txt"));
+
+        assert_eq!(HostPath::parse("C:").file_name(), None);
+    }
+
+    #[test]
+    fn test_to_mirrored_relpath() {
+        let mirrored = |path: &str| to_mirrored_relpath(path).unwrap();
+        assert_eq!(mirrored(r"C:\out\a.txt"), "C/out/a.txt");
+        assert_eq!(mirrored("/home/me/../a.txt"), "home/a.txt");
+        assert_ne!(mirrored("/home/me/../a.txt"), mirrored("/home/me/a.txt"));
+        assert_eq!(mirrored("./out/a.txt"), "out/a.txt");
+        assert_eq!(mirrored(r"\\server\share\a"), "server/share/a");
+        assert!(to_mirrored_relpath("../a.txt").is_err());
+        assert!(to_mirrored_relpath("/home/../../a.txt").is_err());
+    }
+}
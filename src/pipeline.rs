@@ -0,0 +1,367 @@
+//! Pipelines of runs, in which the outputs of some steps are the inputs of the others.
+//!
+//! A step of a pipeline writes an intermediate file as a cloud output, see
+//! [`Intermediate::output`], and the steps reading it take it as the cloud input of the same
+//! url, see [`Intermediate::input`]. Hence the intermediate file stays on the storage between
+//! the steps instead of being downloaded by the client and uploaded again. A step reading an
+//! intermediate file runs after the step writing it, and after the steps it is told to run
+//! after, while the steps independent of each other run at once.
+//!
+//! A failing step skips the steps depending on it, but not the others. The intermediate files
+//! are removed from the storage once the pipeline finishes, unless told to keep them.
+//!
+//! ```no_run
+//! # async fn example(client: cmdproxy::client::Client) -> anyhow::Result<()> {
+//! use cmdproxy::params::Param;
+//! use cmdproxy::pipeline::{Pipeline, Step};
+//! use cmdproxy::protocol::RunRequest;
+//!
+//! let pipeline = Pipeline::new();
+//! let object = pipeline.intermediate("main.o");
+//! let compile = RunRequest::builder()
+//!     .command(Param::cmd_name("cc"))
+//!     .args(vec![Param::str("-c"), Param::ipath("main.c"), Param::str("-o"), object.output()])
+//!     .build();
+//! let link = RunRequest::builder()
+//!     .command(Param::cmd_name("cc"))
+//!     .args(vec![object.input(), Param::str("-o"), Param::opath("main")])
+//!     .build();
+//! let pipeline = pipeline
+//!     .step(Step::new("compile", compile))
+//!     .step(Step::new("link", link));
+//! let outcome = client.run_pipeline(pipeline).await?;
+//! assert!(outcome.success());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::anyhow;
+use futures::future::join_all;
+use log::{debug, warn};
+use mongodb::bson::oid::ObjectId;
+
+use crate::client::Client;
+use crate::outcome::RunOutcome;
+use crate::params::Param;
+use crate::protocol::RunRequest;
+use crate::storage::Storage;
+
+/// Hostname of the intermediate files of the pipelines on the storage.
+pub const PIPELINE_HOSTNAME: &str = "(pipeline)";
+
+/// A file or a folder passed from a step of a pipeline to the others through the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Intermediate {
+    filepath: String,
+}
+
+impl Intermediate {
+    /// The param of the step writing the file.
+    pub fn output(&self) -> Param {
+        Param::OutCloudFileParam {
+            filepath: self.filepath.clone(),
+            hostname: PIPELINE_HOSTNAME.to_owned(),
+        }
+    }
+
+    /// The param of a step reading the file.
+    pub fn input(&self) -> Param {
+        Param::InCloudFileParam {
+            filepath: self.filepath.clone(),
+            hostname: PIPELINE_HOSTNAME.to_owned(),
+        }
+    }
+
+    /// The param of the step writing the folder.
+    pub fn output_dir(&self) -> Param {
+        Param::OutCloudDirParam {
+            filepath: self.filepath.clone(),
+            hostname: PIPELINE_HOSTNAME.to_owned(),
+        }
+    }
+
+    /// The param of a step reading the folder.
+    pub fn input_dir(&self) -> Param {
+        Param::InCloudDirParam {
+            filepath: self.filepath.clone(),
+            hostname: PIPELINE_HOSTNAME.to_owned(),
+        }
+    }
+}
+
+/// A named run of a pipeline.
+#[derive(Debug, Clone)]
+pub struct Step {
+    name: String,
+    request: RunRequest,
+    queue: Option<String>,
+    after: Vec<String>,
+}
+
+impl Step {
+    pub fn new<S: AsRef<str>>(name: S, request: RunRequest) -> Step {
+        Step {
+            name: name.as_ref().to_owned(),
+            request,
+            queue: None,
+            after: vec![],
+        }
+    }
+
+    /// Send the run to `queue` rather than to the queue of its command.
+    pub fn with_queue<S: AsRef<str>>(mut self, queue: S) -> Step {
+        self.queue = Some(queue.as_ref().to_owned());
+        self
+    }
+
+    /// Run the step only after the step of `name` succeeds, though it reads none of its
+    /// outputs, such as a test after a migration of the database.
+    pub fn after<S: AsRef<str>>(mut self, name: S) -> Step {
+        self.after.push(name.as_ref().to_owned());
+        self
+    }
+}
+
+/// How a step of a pipeline ended.
+#[derive(Debug)]
+pub enum StepOutcome {
+    /// The run of the step finished, successfully or not.
+    Finished(RunOutcome),
+    /// The run of the step could not be done, such as for a lost worker.
+    Failed(anyhow::Error),
+    /// The step was not run, as a step it depends on did not succeed.
+    Skipped,
+}
+
+impl StepOutcome {
+    pub fn success(&self) -> bool {
+        matches!(self, StepOutcome::Finished(outcome) if outcome.status.success())
+    }
+}
+
+/// How the steps of a pipeline ended, by their names.
+#[derive(Debug)]
+pub struct PipelineOutcome {
+    pub steps: HashMap<String, StepOutcome>,
+}
+
+impl PipelineOutcome {
+    /// Whether every step succeeded.
+    pub fn success(&self) -> bool {
+        self.steps.values().all(StepOutcome::success)
+    }
+}
+
+/// Runs with dependencies, see the module doc.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    id: String,
+    steps: Vec<Step>,
+    keep_intermediates: bool,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline {
+            id: ObjectId::new().to_hex(),
+            steps: vec![],
+            keep_intermediates: false,
+        }
+    }
+
+    /// The intermediate file or folder of `name`, unique to this pipeline.
+    pub fn intermediate<S: AsRef<str>>(&self, name: S) -> Intermediate {
+        Intermediate {
+            filepath: format!("pipelines/{}/{}", self.id, name.as_ref()),
+        }
+    }
+
+    pub fn step(mut self, step: Step) -> Pipeline {
+        self.steps.push(step);
+        self
+    }
+
+    /// Leave the intermediate files on the storage once finished, such as for debugging.
+    pub fn with_intermediates_kept(mut self) -> Pipeline {
+        self.keep_intermediates = true;
+        self
+    }
+
+    /// The steps each step depends on, by their indices, or an error if the pipeline is
+    /// malformed, such as by reading an intermediate file no step writes.
+    fn dependencies(&self) -> anyhow::Result<Vec<HashSet<usize>>> {
+        let mut indices = HashMap::new();
+        let mut writers = HashMap::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            anyhow::ensure!(
+                indices.insert(step.name.as_str(), i).is_none(),
+                "Duplicate step {} in pipeline",
+                step.name
+            );
+            for url in intermediates(&step.request, Param::is_output) {
+                if let Some(writer) = writers.insert(url.clone(), i) {
+                    return Err(anyhow!(
+                        "Intermediate {} is written by both steps {} and {}",
+                        url,
+                        self.steps[writer].name,
+                        step.name
+                    ));
+                }
+            }
+        }
+
+        self.steps
+            .iter()
+            .map(|step| {
+                let mut deps = HashSet::new();
+                for url in intermediates(&step.request, Param::is_input) {
+                    let writer = writers.get(&url).ok_or_else(|| {
+                        anyhow!(
+                            "Intermediate {} of step {} is written by no step",
+                            url,
+                            step.name
+                        )
+                    })?;
+                    deps.insert(*writer);
+                }
+                for name in &step.after {
+                    let dep = indices.get(name.as_str()).ok_or_else(|| {
+                        anyhow!("Step {} is to run after no such step {}", step.name, name)
+                    })?;
+                    deps.insert(*dep);
+                }
+                Ok(deps)
+            })
+            .collect()
+    }
+
+    /// The steps by the waves they run in, each wave depending only on those before it, or
+    /// an error if the steps depend on each other in a cycle.
+    fn waves(&self) -> anyhow::Result<Vec<Vec<usize>>> {
+        let deps = self.dependencies()?;
+        let mut done = HashSet::new();
+        let mut waves = vec![];
+        while done.len() < self.steps.len() {
+            let wave: Vec<usize> = (0..self.steps.len())
+                .filter(|i| !done.contains(i) && deps[*i].is_subset(&done))
+                .collect();
+            if wave.is_empty() {
+                let stuck: Vec<_> = (0..self.steps.len())
+                    .filter(|i| !done.contains(i))
+                    .map(|i| self.steps[i].name.as_str())
+                    .collect();
+                return Err(anyhow!("Steps {} depend on each other", stuck.join(", ")));
+            }
+            done.extend(wave.iter().copied());
+            waves.push(wave);
+        }
+        Ok(waves)
+    }
+
+    /// Run the steps by the `client` in their order, see the module doc.
+    pub(crate) async fn run(
+        self,
+        client: &Client,
+        storage: Storage,
+    ) -> anyhow::Result<PipelineOutcome> {
+        let deps = self.dependencies()?;
+        let waves = self.waves()?;
+
+        let mut outcomes: Vec<Option<StepOutcome>> = self.steps.iter().map(|_| None).collect();
+        for wave in waves {
+            let mut runs = vec![];
+            for i in wave {
+                if deps[i]
+                    .iter()
+                    .all(|dep| outcomes[*dep].as_ref().unwrap().success())
+                {
+                    let step = &self.steps[i];
+                    debug!("Run step {} of pipeline {}", step.name, self.id);
+                    let run = client.run_outcome(step.request.clone(), step.queue.clone());
+                    runs.push(async move { (i, run.await) });
+                } else {
+                    outcomes[i] = Some(StepOutcome::Skipped);
+                }
+            }
+            for (i, res) in join_all(runs).await {
+                outcomes[i] = Some(match res {
+                    Ok(outcome) => StepOutcome::Finished(outcome),
+                    Err(err) => StepOutcome::Failed(err),
+                });
+            }
+        }
+
+        if !self.keep_intermediates {
+            let urls: HashSet<_> = self
+                .steps
+                .iter()
+                .flat_map(|step| intermediates(&step.request, Param::is_output))
+                .collect();
+            for url in urls {
+                let param = Param::from_cloud_url(url.as_str()).unwrap();
+                if let Err(err) = param.remove_from_cloud(storage.clone()).await {
+                    warn!("Failed to remove intermediate {}: {}", url, err);
+                }
+            }
+        }
+
+        let steps = self
+            .steps
+            .into_iter()
+            .zip(outcomes)
+            .map(|(step, outcome)| (step.name, outcome.unwrap()))
+            .collect();
+        Ok(PipelineOutcome { steps })
+    }
+}
+
+/// Urls of the intermediate files among the params of `request` passing `filter`.
+fn intermediates(request: &RunRequest, filter: fn(&Param) -> bool) -> Vec<String> {
+    let mut urls = request.cloud_urls(filter);
+    urls.retain(|url| url.starts_with(&format!("@{}:", PIPELINE_HOSTNAME)));
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(args: Vec<Param>) -> RunRequest {
+        RunRequest::builder()
+            .command(Param::cmd_name("cat"))
+            .args(args)
+            .build()
+    }
+
+    #[test]
+    fn test_waves() {
+        let pipeline = Pipeline::new();
+        let (a, b) = (pipeline.intermediate("a"), pipeline.intermediate("b"));
+        let pipeline = pipeline
+            .step(Step::new("merge", request(vec![a.input(), b.input()])))
+            .step(Step::new("make-a", request(vec![a.output()])))
+            .step(Step::new("make-b", request(vec![b.output()])).after("make-a"))
+            .step(Step::new("lint", request(vec![Param::ipath("c")])));
+        assert_eq!(
+            pipeline.waves().unwrap(),
+            vec![vec![1, 3], vec![2], vec![0]]
+        );
+
+        let cyclic = pipeline
+            .clone()
+            .step(Step::new("loop", request(vec![])).after("loop"));
+        assert!(cyclic.waves().is_err());
+        let orphan = Pipeline::new();
+        let x = orphan.intermediate("x");
+        let orphan = orphan.step(Step::new("read", request(vec![x.input()])));
+        assert!(orphan.waves().is_err());
+    }
+}
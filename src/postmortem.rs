@@ -0,0 +1,247 @@
+//! Post-mortems of the commands killed by the kernel for running out of memory or dumping core.
+//!
+//! A command killed by the OOM killer exits by SIGKILL, or 137 if run by a shell, which tells
+//! nothing of who killed it. Hence the worker counts the OOM kills in its cgroup, as told by
+//! `memory.events` of cgroup v2, before and after the run, and falls back to scraping `dmesg`
+//! for the pid of the command where the cgroup does not tell, as on cgroup v1. The core dumped
+//! by a command is looked for as `/proc/sys/kernel/core_pattern` says, either in the working
+//! directory of the command, or handed to the program the cores are piped to.
+//!
+//! The findings are attached to the response as a short note, together with the core itself,
+//! truncated, if the worker is configured to upload it.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::params::Param;
+use crate::protocol::ExitStatus;
+use crate::storage::Storage;
+
+/// Hostname of the cores uploaded by the workers on the storage.
+pub const POSTMORTEM_HOSTNAME: &str = "(postmortem)";
+
+/// What is known of why a command died, attached to the response of its run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PostMortem {
+    /// Whether the command, or one of its processes, was killed for running out of memory.
+    #[serde(default)]
+    pub oom_killed: bool,
+    /// Where the core dumped by the command went, such as its path on the worker.
+    #[serde(default)]
+    pub core: Option<String>,
+    /// Url of the core on the cloud, truncated, if uploaded.
+    #[serde(default)]
+    pub core_url: Option<String>,
+    /// A short human-readable account of the death.
+    pub note: String,
+}
+
+/// Signals of which the default action is to dump core.
+const CORE_SIGNALS: [i32; 7] = [3, 4, 5, 6, 7, 8, 11];
+
+const SIGKILL: i32 = 9;
+
+/// The observations of a command taken around its run, for a post-mortem if it dies.
+pub(crate) struct Watch {
+    pid: Option<u32>,
+    started: SystemTime,
+    oom_kills: Option<u64>,
+}
+
+impl Watch {
+    /// Start watching the command of `pid`, just spawned.
+    pub(crate) fn start(pid: Option<u32>) -> Watch {
+        Watch {
+            pid,
+            started: SystemTime::now(),
+            oom_kills: read_oom_kills(),
+        }
+    }
+
+    /// The post-mortem of the command finished by `status` in the folder `cwd`, if it died
+    /// by the kernel rather than exited by itself.
+    pub(crate) fn finish(self, status: &ExitStatus, cwd: &Path) -> Option<PostMortem> {
+        let signal = match *status {
+            ExitStatus::Signaled { signal, .. } => signal,
+            // as reported by a shell whose child was killed by the signal
+            ExitStatus::Exited { code } if code > 128 && code < 128 + 32 => code - 128,
+            _ => return None,
+        };
+        let core_dumped = matches!(
+            status,
+            ExitStatus::Signaled {
+                core_dumped: true,
+                ..
+            }
+        );
+
+        let mut postmortem = PostMortem::default();
+        if signal == SIGKILL {
+            postmortem.oom_killed = match (self.oom_kills, read_oom_kills()) {
+                (Some(before), Some(after)) => after > before,
+                _ => self.pid.map_or(false, dmesg_tells_oom_kill),
+            };
+            if !postmortem.oom_killed {
+                return None;
+            }
+            postmortem.note = "Killed by the kernel for running out of memory".to_owned();
+        } else if core_dumped || CORE_SIGNALS.contains(&signal) {
+            postmortem.core = find_core(cwd, self.pid, self.started);
+            postmortem.note = match &postmortem.core {
+                Some(core) => format!("Crashed by signal {}, dumping core to {}", signal, core),
+                None => format!("Crashed by signal {}, dumping no core found", signal),
+            };
+        } else {
+            return None;
+        }
+        debug!("  post-mortem: {}", postmortem.note);
+        Some(postmortem)
+    }
+}
+
+impl PostMortem {
+    /// Upload the first `max_bytes` of the core to the cloud, if found on this host.
+    pub(crate) async fn attach_core(
+        &mut self,
+        storage: Storage,
+        task_id: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<()> {
+        let core = match self.core.as_deref().map(PathBuf::from) {
+            Some(core) if core.is_file() => core,
+            _ => return Ok(()),
+        };
+        let truncated = tempfile::NamedTempFile::new()?;
+        let mut reader = std::io::Read::take(std::fs::File::open(&core)?, max_bytes);
+        std::io::copy(&mut reader, &mut truncated.as_file())?;
+
+        let param = Param::OutCloudFileParam {
+            filepath: format!("{}/core", task_id),
+            hostname: POSTMORTEM_HOSTNAME.to_owned(),
+        };
+        param.upload(storage, truncated.path()).await?;
+        self.core_url = Some(param.cloud_url());
+        Ok(())
+    }
+}
+
+/// The count of the OOM kills in the cgroup of this process, if on cgroup v2.
+fn read_oom_kills() -> Option<u64> {
+    let cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let events = Path::new("/sys/fs/cgroup")
+        .join(parse_cgroup_path(cgroup.as_str())?.trim_start_matches('/'))
+        .join("memory.events");
+    parse_oom_kills(std::fs::read_to_string(events).ok()?.as_str())
+}
+
+/// Parse the path of the cgroup v2 in `/proc/<pid>/cgroup`.
+fn parse_cgroup_path(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+/// Parse the count of the OOM kills in `memory.events`.
+fn parse_oom_kills(events: &str) -> Option<u64> {
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Whether the kernel log tells that the process of `pid` was killed for running out of
+/// memory, which is false where the log cannot be read, such as without the privilege.
+fn dmesg_tells_oom_kill(pid: u32) -> bool {
+    let output = match std::process::Command::new("dmesg").output() {
+        Ok(output) if output.status.success() => output,
+        _ => return false,
+    };
+    let needle = format!("Killed process {} ", pid);
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.contains(needle.as_str()))
+}
+
+/// Where the core of the process of `pid` went, as the core pattern of the kernel says.
+fn find_core(cwd: &Path, pid: Option<u32>, since: SystemTime) -> Option<String> {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern")
+        .unwrap_or_else(|_| "core".to_owned());
+    if let Some(program) = pattern.trim().strip_prefix('|') {
+        let program = program.split_whitespace().next().unwrap_or(program);
+        return Some(format!("piped to {}", program));
+    }
+    find_core_file(cwd, pattern.trim(), pid, since).map(|path| path.display().to_string())
+}
+
+/// The core file dumped since `since` by the process of `pid` by the core `pattern`, which
+/// is matched by the name up to its first specifier if any, or else by the name itself
+/// followed by the pid or not, as the kernel appends the pid if told to.
+fn find_core_file(
+    cwd: &Path,
+    pattern: &str,
+    pid: Option<u32>,
+    since: SystemTime,
+) -> Option<PathBuf> {
+    let pattern = Path::new(pattern);
+    let dir = match pattern.parent() {
+        Some(parent) if parent.is_absolute() => parent.to_owned(),
+        Some(parent) => cwd.join(parent),
+        None => cwd.to_owned(),
+    };
+    let pattern = pattern.file_name()?.to_str()?;
+    let prefix = pattern.split('%').next().unwrap_or(pattern);
+    let matches = |name: &str| match pid {
+        _ if prefix != pattern => name.starts_with(prefix) && name.contains("core"),
+        Some(pid) => name == pattern || name == format!("{}.{}", pattern, pid),
+        None => name == pattern,
+    };
+
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            matches(entry.file_name().to_string_lossy().as_ref())
+                && entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map_or(false, |modified| modified >= since)
+        })
+        .map(|entry| entry.path())
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cgroup = "12:pids:/user.slice\n0::/system.slice/cmdproxy.service\n";
+        assert_eq!(
+            parse_cgroup_path(cgroup),
+            Some("/system.slice/cmdproxy.service")
+        );
+        let events = "low 0\nhigh 0\nmax 12\noom 3\noom_kill 2\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kills(events), Some(2));
+    }
+
+    #[test]
+    fn test_find_core_file() {
+        let cwd = tempfile::tempdir().unwrap();
+        let since = SystemTime::now() - std::time::Duration::from_secs(1);
+        assert_eq!(find_core_file(cwd.path(), "core", Some(42), since), None);
+
+        std::fs::write(cwd.path().join("core.42"), "core").unwrap();
+        std::fs::write(cwd.path().join("score.txt"), "99").unwrap();
+        assert_eq!(
+            find_core_file(cwd.path(), "core", Some(42), since),
+            Some(cwd.path().join("core.42"))
+        );
+        assert_eq!(find_core_file(cwd.path(), "core", Some(7), since), None);
+        assert_eq!(
+            find_core_file(cwd.path(), "core.%p", Some(7), since),
+            Some(cwd.path().join("core.42"))
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Cheap validations run on the client before a request is sent at all.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use serde::{Deserialize, Serialize};
+
+use crate::admission::free_disk;
+use crate::protocol::ExitStatus;
+
+/// A local command validating a request, such as `tool --dry-run`, which must exit with 0
+/// for the request to be sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Precheck {
+    pub command: String,
+    pub args: Vec<String>,
+    pub cwd: Option<String>,
+}
+
+impl Precheck {
+    pub fn new<S: Into<String>>(command: S, args: Vec<String>) -> Precheck {
+        Precheck {
+            command: command.into(),
+            args,
+            cwd: None,
+        }
+    }
+
+    /// Run the validation, failing with [`PrecheckFailed`] if it does not pass.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut command = tokio::process::Command::new(self.command.as_str());
+        command
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+
+        let output = match command.output().await {
+            Ok(output) => output,
+            Err(err) => {
+                return Err(PrecheckFailed {
+                    status: ExitStatus::SpawnFailed {
+                        reason: err.to_string(),
+                    },
+                    stderr: String::new(),
+                }
+                .into())
+            }
+        };
+        let status = ExitStatus::from(output.status);
+        if !status.success() {
+            return Err(PrecheckFailed {
+                status,
+                stderr: String::from_utf8_lossy(output.stderr.as_slice()).into_owned(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Error of a request not sent because it failed its precheck.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrecheckFailed {
+    pub status: ExitStatus,
+    /// What the validation complained about.
+    pub stderr: String,
+}
+
+impl fmt::Display for PrecheckFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Precheck {}", self.status)?;
+        let stderr = self.stderr.trim_end();
+        if !stderr.is_empty() {
+            write!(f, ":\n{}", stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PrecheckFailed {}
+
+/// A check that the local outputs of a request can be written where requested, so that a
+/// request bound to fail to deliver its outputs is never run remotely at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputCheck {
+    /// Bytes left free on the filesystems of the outputs, or unchecked if none.
+    pub min_free_disk: Option<u64>,
+}
+
+impl OutputCheck {
+    /// Check the outputs at `paths`, failing with [`OutputUnwritable`] on the first which
+    /// cannot be written.
+    ///
+    /// A folder is checked by itself if there, or else by its parent, where it would be
+    /// created, and a file by its folder, which must be there.
+    pub fn run(&self, paths: &[PathBuf]) -> Result<(), OutputUnwritable> {
+        for path in paths {
+            let dir = match path.parent() {
+                Some(_) if path.is_dir() => path.as_path(),
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            self.check_dir(path, dir)?;
+        }
+        Ok(())
+    }
+
+    fn check_dir(&self, path: &Path, dir: &Path) -> Result<(), OutputUnwritable> {
+        let unwritable = |cause: String| OutputUnwritable {
+            path: path.to_path_buf(),
+            cause,
+        };
+        if !dir.is_dir() {
+            return Err(unwritable(format!("no folder {}", dir.display())));
+        }
+        // by writing a file indeed, as the permission bits tell nothing of the acls or a
+        // read-only mount
+        tempfile::tempfile_in(dir).map_err(|err| unwritable(err.to_string()))?;
+        if let (Some(min_free_disk), Some(available)) = (self.min_free_disk, free_disk(dir)) {
+            if available < min_free_disk {
+                return Err(unwritable(format!(
+                    "only {} bytes free, less than {} required",
+                    available, min_free_disk
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error of a request not sent because one of its local outputs cannot be written.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputUnwritable {
+    pub path: PathBuf,
+    pub cause: String,
+}
+
+impl fmt::Display for OutputUnwritable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Output {} cannot be written: {}",
+            self.path.display(),
+            self.cause
+        )
+    }
+}
+
+impl std::error::Error for OutputUnwritable {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_precheck() {
+        assert!(Precheck::new("true", vec![]).run().await.is_ok());
+
+        let err = Precheck::new(
+            "/bin/sh",
+            vec!["-c".to_owned(), "echo bad input >&2; exit 2".to_owned()],
+        )
+        .run()
+        .await
+        .unwrap_err();
+        let err = err.downcast::<PrecheckFailed>().unwrap();
+        assert_eq!(err.status, ExitStatus::Exited { code: 2 });
+        assert_eq!(err.to_string(), "Precheck exited with code 2:\nbad input");
+    }
+
+    #[test]
+    fn test_output_check() {
+        let workspace = tempfile::tempdir().unwrap();
+        let check = OutputCheck::default();
+        let file = workspace.path().join("out.txt");
+        let dir = workspace.path().join("out_dir");
+        assert!(check.run(&[file, dir]).is_ok());
+
+        let missing = workspace.path().join("missing").join("out.txt");
+        let err = check.run(&[missing.clone()]).unwrap_err();
+        assert_eq!(err.path, missing);
+
+        let check = OutputCheck {
+            min_free_disk: Some(u64::MAX),
+        };
+        let file = workspace.path().join("out.txt");
+        #[cfg(unix)]
+        assert!(check.run(&[file]).is_err());
+    }
+}
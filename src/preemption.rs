@@ -0,0 +1,173 @@
+//! Preemption of low-priority commands by high-priority ones on the same worker.
+//!
+//! Every running command registers itself with its priority. A command is held while some
+//! other command of the same kind runs with a priority higher by at least the gap of the
+//! policy, and released once all such commands have finished.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// What happens to a running command when it is held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum PreemptionMode {
+    /// Stop the command with `SIGSTOP`, and continue it with `SIGCONT` once released.
+    Suspend,
+    /// Kill the command, and run it again from scratch once released.
+    Requeue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreemptionPolicy {
+    pub mode: PreemptionMode,
+    /// Min difference of priorities for a command to preempt another.
+    pub min_gap: i32,
+}
+
+struct Entry {
+    task_id: String,
+    /// Commands preempt only those of the same kind, i.e. served by the same queue.
+    kind: String,
+    priority: i32,
+    /// Tasks holding this one.
+    holders: HashSet<String>,
+    held: watch::Sender<bool>,
+}
+
+static RUNNING: Lazy<Mutex<Vec<Entry>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Registration of a running command, which is withdrawn when dropped.
+pub(crate) struct Registration {
+    task_id: String,
+    held: watch::Receiver<bool>,
+}
+
+impl Registration {
+    pub(crate) fn register(
+        task_id: &str,
+        kind: &str,
+        priority: i32,
+        policy: PreemptionPolicy,
+    ) -> Registration {
+        let mut running = RUNNING.lock().unwrap();
+        let mut holders = HashSet::new();
+        for entry in running.iter_mut().filter(|entry| entry.kind == kind) {
+            if entry.priority + policy.min_gap <= priority {
+                entry.holders.insert(task_id.to_owned());
+                entry.held.send_replace(true);
+            } else if priority + policy.min_gap <= entry.priority {
+                holders.insert(entry.task_id.clone());
+            }
+        }
+
+        let (held, receiver) = watch::channel(!holders.is_empty());
+        running.push(Entry {
+            task_id: task_id.to_owned(),
+            kind: kind.to_owned(),
+            priority,
+            holders,
+            held,
+        });
+        Registration {
+            task_id: task_id.to_owned(),
+            held: receiver,
+        }
+    }
+
+    pub(crate) fn is_held(&self) -> bool {
+        *self.held.borrow()
+    }
+
+    /// Wait until held or released, returning whether held then.
+    pub(crate) async fn changed(&mut self) -> bool {
+        if self.held.changed().await.is_err() {
+            // never happens while registered, since the entry owns the sender
+            std::future::pending::<()>().await;
+        }
+        self.is_held()
+    }
+
+    pub(crate) async fn released(&mut self) {
+        while self.is_held() {
+            self.changed().await;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap();
+        running.retain(|entry| entry.task_id != self.task_id);
+        for entry in running.iter_mut() {
+            if entry.holders.remove(self.task_id.as_str()) && entry.holders.is_empty() {
+                entry.held.send_replace(false);
+            }
+        }
+    }
+}
+
+/// Send a signal such as `STOP` or `CONT` to the process.
+#[cfg(unix)]
+pub(crate) fn signal(pid: u32, signal: &str) -> std::io::Result<()> {
+    let status = std::process::Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("kill -{} {} {}", signal, pid, status),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn signal(_: u32, signal: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("signal {} is not supported on this platform", signal),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration() {
+        let policy = PreemptionPolicy {
+            mode: PreemptionMode::Suspend,
+            min_gap: 2,
+        };
+        let low = Registration::register("low", "test-kind", 0, policy);
+        assert!(!low.is_held());
+
+        // not urgent enough to preempt
+        let mid = Registration::register("mid", "test-kind", 1, policy);
+        assert!(!low.is_held());
+        assert!(!mid.is_held());
+
+        // of another kind
+        let other = Registration::register("other", "other-kind", 10, policy);
+        assert!(!low.is_held());
+        drop(other);
+
+        let high = Registration::register("high", "test-kind", 3, policy);
+        assert!(low.is_held());
+        assert!(mid.is_held());
+        assert!(!high.is_held());
+
+        // arriving while the high one is running
+        let late = Registration::register("late", "test-kind", 0, policy);
+        assert!(late.is_held());
+
+        drop(high);
+        assert!(!low.is_held());
+        assert!(!mid.is_held());
+        assert!(!late.is_held());
+    }
+}
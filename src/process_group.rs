@@ -0,0 +1,104 @@
+//! Supervision of the process group of a command, so that the processes it forks and leaves
+//! behind, such as daemons still writing the outputs, are waited for or killed before the
+//! outputs are collected.
+//!
+//! The command leads a group of its own, which its children join unless they leave it on
+//! purpose, e.g. by `setsid`.
+
+use std::time::{Duration, Instant};
+
+/// Interval of checking if any process of the group is still alive.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the killed processes may take to be gone, as zombies never reaped by an init
+/// which does not reap, such as in some containers, are never gone.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(unix)]
+fn kill(args: &[&str]) -> std::io::Result<bool> {
+    let status = std::process::Command::new("kill")
+        .args(args)
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// Whether any process of the group of `pgid` is still alive.
+#[cfg(unix)]
+pub(crate) fn is_alive(pgid: u32) -> bool {
+    kill(&["-0", "--", format!("-{}", pgid).as_str()]).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_alive(_: u32) -> bool {
+    false
+}
+
+/// Send a signal such as `STOP` or `CONT` to every process of the group of `pgid`.
+#[cfg(unix)]
+pub(crate) fn signal_group(pgid: u32, signal: &str) -> std::io::Result<()> {
+    let group = format!("-{}", pgid);
+    if !kill(&[format!("-{}", signal).as_str(), "--", group.as_str()])? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("kill -{} -- {} failed", signal, group),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn signal_group(_: u32, signal: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("signal {} is not supported on this platform", signal),
+    ))
+}
+
+/// Wait up to `grace` for the processes left in the group of `pgid` to exit, and kill those
+/// still alive then, returning whether any has been killed.
+pub(crate) async fn reap(pgid: u32, grace: Duration) -> bool {
+    let deadline = Instant::now() + grace;
+    while is_alive(pgid) {
+        if Instant::now() >= deadline {
+            signal_group(pgid, "KILL").unwrap_or_default();
+            // the killed ones are gone once reaped by their parents, or else by init
+            let deadline = Instant::now() + KILL_TIMEOUT;
+            while is_alive(pgid) && Instant::now() < deadline {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            return true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reap() {
+        // the shell exits right away, leaving a child sleeping in its group
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "sleep 30 & exit 0"]).process_group(0);
+        let mut child = command.spawn().unwrap();
+        let pgid = child.id().unwrap();
+        child.wait().await.unwrap();
+        assert!(is_alive(pgid));
+
+        let started = Instant::now();
+        assert!(reap(pgid, Duration::from_millis(200)).await);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // nothing left to kill
+        let mut child = tokio::process::Command::new("true")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = child.id().unwrap();
+        child.wait().await.unwrap();
+        assert!(!reap(pgid, Duration::from_secs(1)).await);
+    }
+}
@@ -1,29 +1,772 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use typed_builder::TypedBuilder;
 
+use crate::archive::ArchiveFormat;
+use crate::attrs::FileAttrs;
+use crate::limits::{LimitExceeded, ResourceLimits};
 use crate::params::Param;
+use crate::postmortem::PostMortem;
+use crate::precheck::Precheck;
+use crate::retry::{classify_error, FailureClass};
+use crate::storage::compression::Compression;
+use crate::usage::ResourceUsage;
+
+/// Placeholder of the workspace of the run on the worker, the temp folder the inputs are
+/// downloaded into, expanded by the worker in the cwd and in the string and format params,
+/// such as in `--out-dir=${WORKSPACE}/out`.
+pub const WORKSPACE_VAR: &str = "${WORKSPACE}";
 
 #[derive(Debug, Clone, Serialize, Deserialize, TypedBuilder)]
 pub struct RunSpecification<P> {
     pub command: P,
     pub args: Vec<P>,
+    /// Working directory of the command, made by the worker if under [`WORKSPACE_VAR`].
     #[builder(default, setter(strip_option))]
     pub cwd: Option<String>,
     #[builder(default, setter(strip_option))]
     pub env: Option<HashMap<String, P>>,
+    /// What is fed to the stdin of the command, which inherits that of the worker if not given.
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub stdin: Option<Stdin<P>>,
     #[builder(default, setter(strip_option))]
     pub stdout: Option<P>,
     #[builder(default, setter(strip_option))]
     pub stderr: Option<P>,
+    /// Priority of the run, the higher the more urgent, which the worker may preempt other
+    /// runs of the same command for. The runs of positive priorities are sent ahead of the
+    /// backlog of the queue, and take the free slots of the worker first.
+    #[builder(default)]
+    #[serde(default)]
+    pub priority: i32,
+    /// How long the worker lets the run take, including any time held by preemption, before
+    /// killing it, in which case the client fails with [`TimedOut`].
+    #[builder(default, setter(strip_option))]
+    #[serde(default, with = "opt_secs")]
+    pub timeout: Option<Duration>,
+    /// Validation run locally by the client before sending anything, never sent itself.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip)]
+    pub precheck: Option<Precheck>,
+    /// Free-form labels of the run, such as `team: infra`, recorded with it in the history.
+    #[builder(default)]
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Bytes of the inputs the worker downloads, which it refuses the run for if short of
+    /// disk, or else the sum of `input_sizes`.
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub input_bytes: Option<u64>,
+    /// Expected bytes of the inputs by their cloud urls, which the client fills in for the
+    /// local inputs it uploads unless declared, and the worker checks the downloads against.
+    #[builder(default)]
+    #[serde(default)]
+    pub input_sizes: HashMap<String, u64>,
+    /// Archive format the client packs the input folders in, and asks the worker to pack the
+    /// output folders in, see [`ArchiveFormat`].
+    #[builder(default)]
+    #[serde(default)]
+    pub archive: ArchiveFormat,
+    /// Cloud urls of the params whose files keep their attributes across the transfers, see
+    /// [`FileAttrs`].
+    #[builder(default)]
+    #[serde(default)]
+    pub preserve: HashSet<String>,
+    /// Attributes of the preserved inputs by the cloud urls they are uploaded to, which the
+    /// client fills in.
+    #[builder(default)]
+    #[serde(default)]
+    pub input_attrs: HashMap<String, FileAttrs>,
+    /// Milliseconds since the unix epoch when the client sent the request, by its clock,
+    /// which the client fills in.
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub enqueued_at: Option<u64>,
+    /// Limits of the resources the command may take on the worker, see [`ResourceLimits`].
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub limits: Option<ResourceLimits>,
+    /// Features of the protocol the client makes use of, see [`crate::features`], which the
+    /// client fills in.
+    #[builder(default)]
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Those of the features the run relies on, refused by the workers lacking any of them.
+    #[builder(default)]
+    #[serde(default)]
+    pub required_features: Vec<String>,
+    /// Report only the outputs changed since the previous run writing to them, leaving the
+    /// others untransferred, see `output_digests`.
+    #[builder(default)]
+    #[serde(default)]
+    pub delta: bool,
+    /// Sha256 of the outputs of the previous run by their cloud urls, such as those of the
+    /// local outputs existing already, which the client fills in if `delta`. The worker skips
+    /// uploading the outputs of the same digests, marked [`ArtifactStatus::Unchanged`].
+    #[builder(default)]
+    #[serde(default)]
+    pub output_digests: HashMap<String, String>,
+    /// How the inputs and the outputs are compressed in transfer, or never if not given.
+    #[builder(default)]
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Names of the keys of the keyring encrypting the files of the params at rest by the
+    /// paths of the params, such as the key of a tenant for its confidential inputs, instead
+    /// of the default key. The key of a folder holds for the files under it.
+    #[builder(default)]
+    #[serde(default)]
+    pub encryption_keys: HashMap<String, String>,
+    /// Resolve the request on the worker, downloading the inputs, but report the recipe
+    /// instead of running the command, such as for debugging the templates of the params.
+    #[builder(default)]
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl<P> RunSpecification<P> {
+    /// Convert every param of the specification with `f`.
+    pub fn map_params<Q, F: FnMut(P) -> Q>(self, mut f: F) -> RunSpecification<Q> {
+        RunSpecification {
+            command: f(self.command),
+            args: self.args.into_iter().map(&mut f).collect(),
+            cwd: self.cwd,
+            env: self
+                .env
+                .map(|env| env.into_iter().map(|(key, val)| (key, f(val))).collect()),
+            stdin: self.stdin.map(|stdin| stdin.map(&mut f)),
+            stdout: self.stdout.map(&mut f),
+            stderr: self.stderr.map(&mut f),
+            priority: self.priority,
+            timeout: self.timeout,
+            precheck: self.precheck,
+            labels: self.labels,
+            input_bytes: self.input_bytes,
+            input_sizes: self.input_sizes,
+            archive: self.archive,
+            preserve: self.preserve,
+            input_attrs: self.input_attrs,
+            enqueued_at: self.enqueued_at,
+            limits: self.limits,
+            features: self.features,
+            required_features: self.required_features,
+            delta: self.delta,
+            output_digests: self.output_digests,
+            compression: self.compression,
+            encryption_keys: self.encryption_keys,
+            dry_run: self.dry_run,
+        }
+    }
+
+    /// Bytes of the inputs as declared, in total.
+    pub fn declared_input_bytes(&self) -> u64 {
+        self.input_bytes
+            .unwrap_or_else(|| self.input_sizes.values().sum())
+    }
+}
+
+impl RunSpecification<Param> {
+    /// Paths of the local files and folders among the params, including those nested in a
+    /// format param, which pass `filter`, such as [`Param::is_input`].
+    pub fn local_paths(&self, filter: fn(&Param) -> bool) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = self
+            .file_params()
+            .into_iter()
+            .filter(|param| param.is_local() && filter(param))
+            .map(|param| PathBuf::from(param.filepath()))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    /// Urls of the cloud files and folders among the params, including those nested in a
+    /// format param, which pass `filter`, such as [`Param::is_output`].
+    pub fn cloud_urls(&self, filter: fn(&Param) -> bool) -> Vec<String> {
+        let mut urls: Vec<_> = self
+            .file_params()
+            .into_iter()
+            .filter(|param| param.is_cloud() && filter(param))
+            .map(Param::cloud_url)
+            .collect();
+        urls.sort();
+        urls
+    }
+
+    /// The params of the arguments, environment and redirections, with those nested in the
+    /// format params in place of them.
+    fn file_params(&self) -> Vec<&Param> {
+        let mut params: Vec<&Param> = self.args.iter().collect();
+        params.extend(self.env.iter().flat_map(HashMap::values));
+        params.extend(self.stdin.iter().filter_map(Stdin::file));
+        params.extend(self.stdout.iter().chain(self.stderr.iter()));
+
+        let mut flattened = vec![];
+        while let Some(param) = params.pop() {
+            match param {
+                Param::FormatParam { args, .. } => params.extend(args.values()),
+                param => flattened.push(param),
+            }
+        }
+        flattened
+    }
+}
+
+/// Source of the stdin of a command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stdin<P> {
+    /// Content given inline, such as the expression fed to `bc`.
+    Content(String),
+    /// A file read through, such as a local input file uploaded by the client.
+    File(P),
+}
+
+impl<P> Stdin<P> {
+    pub fn map<Q, F: FnOnce(P) -> Q>(self, f: F) -> Stdin<Q> {
+        match self {
+            Stdin::Content(content) => Stdin::Content(content),
+            Stdin::File(param) => Stdin::File(f(param)),
+        }
+    }
+
+    pub fn file(&self) -> Option<&P> {
+        match self {
+            Stdin::Content(_) => None,
+            Stdin::File(param) => Some(param),
+        }
+    }
+}
+
+/// (De)serialize an optional duration as whole seconds, rounded up, which keeps the wire
+/// format of the timeout given in seconds.
+mod opt_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        duration
+            .map(|duration| duration.as_secs() + u64::from(duration.subsec_nanos() > 0))
+            .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(d)?.map(Duration::from_secs))
+    }
 }
 
 pub type RunRequest = RunSpecification<Param>;
-pub(crate) type RunRecipe = RunSpecification<String>;
+/// A request resolved by the worker, ready to be run.
+pub type RunRecipe = RunSpecification<String>;
+
+/// How the proxied command finished on the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitStatus {
+    /// The command exited normally with the given code.
+    Exited { code: i32 },
+    /// The command was terminated by a signal.
+    Signaled { signal: i32, core_dumped: bool },
+    /// The command was killed for running longer than its timeout.
+    TimedOut,
+    /// The command could not be spawned at all.
+    SpawnFailed { reason: String },
+    /// The server did not get to run the command, or the status is not available.
+    #[default]
+    Unknown,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        matches!(self, ExitStatus::Exited { code: 0 })
+    }
+
+    /// Flatten the status into a shell-like return code.
+    ///
+    /// A signaled command is reported as the negative signal number, and everything which
+    /// did not produce a real exit code is reported as `-1`.
+    pub fn return_code(&self) -> i32 {
+        match self {
+            ExitStatus::Exited { code } => *code,
+            ExitStatus::Signaled { signal, .. } => -signal,
+            ExitStatus::TimedOut | ExitStatus::SpawnFailed { .. } | ExitStatus::Unknown => -1,
+        }
+    }
+
+    /// The signal the command was terminated by, if any.
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            ExitStatus::Signaled { signal, .. } => Some(*signal),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited { code } => write!(f, "exited with code {}", code),
+            ExitStatus::Signaled {
+                signal,
+                core_dumped,
+            } => {
+                write!(f, "killed by signal {}", signal)?;
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                Ok(())
+            }
+            ExitStatus::TimedOut => write!(f, "timed out"),
+            ExitStatus::SpawnFailed { reason } => write!(f, "failed to spawn: {}", reason),
+            ExitStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        if let Some(code) = status.code() {
+            return ExitStatus::Exited { code };
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return ExitStatus::Signaled {
+                    signal,
+                    core_dumped: status.core_dumped(),
+                };
+            }
+        }
+
+        ExitStatus::Unknown
+    }
+}
+
+/// An output file collected by the server after the run, e.g. matched by a glob param.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    /// Url of the output on the cloud.
+    pub cloud_url: String,
+    /// Path of the output relative to the folder it was collected from.
+    pub relpath: String,
+    /// Whether the output made it to the cloud, and then to the client.
+    #[serde(default)]
+    pub status: ArtifactStatus,
+}
+
+impl Artifact {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, ArtifactStatus::Ok | ArtifactStatus::Unchanged)
+    }
+}
+
+/// How far an output got on its way from the server to the client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArtifactStatus {
+    /// Transferred as expected.
+    #[default]
+    Ok,
+    /// Failed to be uploaded, committed or downloaded.
+    ///
+    /// An output failed to be downloaded is left on the cloud, so that it can be fetched
+    /// again later.
+    Failed { cause: String },
+    /// Not transferred on purpose, such as the outputs of a cancelled run.
+    Skipped { reason: String },
+    /// Not transferred as the same as the output of the previous run, which the client keeps.
+    Unchanged,
+}
+
+impl fmt::Display for ArtifactStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArtifactStatus::Ok => write!(f, "ok"),
+            ArtifactStatus::Failed { cause } => write!(f, "failed: {}", cause),
+            ArtifactStatus::Skipped { reason } => write!(f, "skipped: {}", reason),
+            ArtifactStatus::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+/// Where an output on the cloud comes from, stamped in the metadata of the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Id of the task whose run produced the output.
+    pub task_id: String,
+    /// Name or path of the command, as requested.
+    pub command: String,
+    /// Hostname of the worker which ran the command.
+    pub worker: String,
+}
+
+/// Why a run was given up before it finished by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CancelReason {
+    /// Cancelled by a client, such as the losers of speculative runs.
+    ClientCancel,
+    /// Ran out of the time the request allowed.
+    Deadline,
+    /// Killed to make room for more urgent runs, and not run again before the worker stopped.
+    Preemption,
+    /// The worker was stopping.
+    Shutdown,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CancelReason::ClientCancel => write!(f, "cancelled by client"),
+            CancelReason::Deadline => write!(f, "deadline exceeded"),
+            CancelReason::Preemption => write!(f, "preempted"),
+            CancelReason::Shutdown => write!(f, "worker shutting down"),
+        }
+    }
+}
+
+/// How a run was cancelled, and how far the worker got in cleaning up after it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cancellation {
+    pub reason: CancelReason,
+    /// The command is no longer running, either killed or never started.
+    pub child_killed: bool,
+    /// The outputs of the run have been removed from the cloud instead of being committed.
+    pub outputs_discarded: bool,
+    /// The inputs downloaded for the run have been removed from the worker.
+    pub inputs_released: bool,
+}
+
+impl Cancellation {
+    pub fn new(reason: CancelReason, child_killed: bool) -> Cancellation {
+        Cancellation {
+            reason,
+            child_killed,
+            outputs_discarded: false,
+            inputs_released: false,
+        }
+    }
+
+    /// Whether nothing of the run is left behind, so that it is safe to be retried.
+    pub fn is_clean(&self) -> bool {
+        self.child_killed && self.outputs_discarded && self.inputs_released
+    }
+}
+
+impl fmt::Display for Cancellation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)?;
+        let leftovers: Vec<_> = [
+            (self.child_killed, "command still running"),
+            (self.outputs_discarded, "outputs not discarded"),
+            (self.inputs_released, "inputs not released"),
+        ]
+        .into_iter()
+        .filter(|(done, _)| !done)
+        .map(|(_, leftover)| leftover)
+        .collect();
+        if !leftovers.is_empty() {
+            write!(f, " ({})", leftovers.join(", "))?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RunResponse {
     pub return_code: i32,
+    #[serde(default)]
+    pub status: ExitStatus,
+    /// The signal the command was terminated by, if any, so that a command killed is never
+    /// mistaken for one exiting by itself by the clients reading only the codes.
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
     pub exc: Option<String>,
+    /// Class of the failure reported in `exc`, if known.
+    #[serde(default)]
+    pub failure: Option<FailureClass>,
+    /// Set if the run was cancelled rather than finished by itself.
+    #[serde(default)]
+    pub cancellation: Option<Cancellation>,
+    /// What went wrong without failing the run, such as an input far from its declared size.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// Attributes of the preserved outputs by their cloud urls.
+    #[serde(default)]
+    pub output_attrs: HashMap<String, FileAttrs>,
+    /// Resources used by the command, if run.
+    #[serde(default)]
+    pub usage: Option<ResourceUsage>,
+    /// Why the command died, if killed by the kernel.
+    #[serde(default)]
+    pub postmortem: Option<PostMortem>,
+    /// When the run was queued and run, if known.
+    #[serde(default)]
+    pub timing: Option<RunTiming>,
+    /// The limit of the resources the command was killed for exceeding, if any.
+    #[serde(default)]
+    pub limit_exceeded: Option<LimitExceeded>,
+    /// Features of the request taken by the worker.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Url of the recording of the run on the cloud, if recorded, see [`crate::recording`].
+    #[serde(default)]
+    pub recording_url: Option<String>,
+    /// The recipe resolved by the worker, if a dry run.
+    #[serde(default)]
+    pub recipe: Option<RunRecipe>,
+    /// Times the worker ran the command again after it failed, by its retry policies.
+    #[serde(default)]
+    pub retries: u32,
+}
+
+impl RunResponse {
+    pub fn from_status(status: ExitStatus) -> RunResponse {
+        RunResponse {
+            return_code: status.return_code(),
+            signal: status.signal(),
+            status,
+            artifacts: Vec::new(),
+            exc: None,
+            failure: None,
+            cancellation: None,
+            warnings: Vec::new(),
+            output_attrs: HashMap::new(),
+            usage: None,
+            postmortem: None,
+            timing: None,
+            limit_exceeded: None,
+            features: Vec::new(),
+            recording_url: None,
+            recipe: None,
+            retries: 0,
+        }
+    }
+
+    pub fn from_exc(exc: String) -> RunResponse {
+        RunResponse {
+            return_code: -1,
+            status: ExitStatus::Unknown,
+            signal: None,
+            artifacts: Vec::new(),
+            exc: Some(exc),
+            failure: None,
+            cancellation: None,
+            warnings: Vec::new(),
+            output_attrs: HashMap::new(),
+            usage: None,
+            postmortem: None,
+            timing: None,
+            limit_exceeded: None,
+            features: Vec::new(),
+            recording_url: None,
+            recipe: None,
+            retries: 0,
+        }
+    }
+
+    /// The response reporting `err`, classified for the client to decide on retrying.
+    pub fn from_error(err: &anyhow::Error) -> RunResponse {
+        RunResponse {
+            failure: Some(classify_error(err)),
+            ..RunResponse::from_exc(err.to_string())
+        }
+    }
+}
+
+/// When a run was sent by the client, picked by the worker and done with, in milliseconds
+/// since the unix epoch, which tells an overloaded cluster from a slow command.
+///
+/// The time of sending is by the clock of the client, while the others are by that of the
+/// worker, hence the wait in the queue is as accurate as the clocks are in sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunTiming {
+    /// Sent by the client, if told by the request.
+    #[serde(default)]
+    pub enqueued_at: Option<u64>,
+    /// Picked by the worker.
+    pub started_at: u64,
+    /// Done with by the worker, the outputs uploaded.
+    pub finished_at: u64,
+}
+
+impl RunTiming {
+    /// Time the request waited in the queue before picked, if known.
+    pub fn queue_wait(&self) -> Option<Duration> {
+        let enqueued_at = self.enqueued_at?;
+        Some(Duration::from_millis(
+            self.started_at.saturating_sub(enqueued_at),
+        ))
+    }
+
+    /// Time the worker spent on the run, including the transfers.
+    pub fn execution(&self) -> Duration {
+        Duration::from_millis(self.finished_at.saturating_sub(self.started_at))
+    }
+}
+
+/// Milliseconds since the unix epoch by the clock of this host.
+pub(crate) fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Error of a run failed on the worker, as reported in the response.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub return_code: i32,
+    pub exc: String,
+    pub failure: Option<FailureClass>,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Server Error: return code {}, {}",
+            self.return_code, self.exc
+        )
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Error of a run killed by the worker for taking longer than its timeout.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedOut {
+    pub timeout: Duration,
+    /// How the killed command finished.
+    pub status: ExitStatus,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Remote command timed out after {:?}, {}",
+            self.timeout, self.status
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// A serialized request together with the credentials attached by the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AuthEnvelope {
+    pub(crate) credentials: String,
+    pub(crate) payload: String,
+    /// Id of the client signing the request, which the credentials are computed over as
+    /// well, see [`crate::middles::auth::signed_payload`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) signer: Option<String>,
+}
+
+/// What actually travels through the result backend in place of a [`RunResponse`].
+///
+/// A response too large for the backend is spilled to the cloud, and only its url is sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ResponseEnvelope {
+    Spilled { spilled_to: Param },
+    Inline(RunResponse),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellation() {
+        let mut cancellation = Cancellation::new(CancelReason::Deadline, true);
+        assert!(!cancellation.is_clean());
+        assert_eq!(
+            cancellation.to_string(),
+            "deadline exceeded (outputs not discarded, inputs not released)"
+        );
+
+        cancellation.outputs_discarded = true;
+        cancellation.inputs_released = true;
+        assert!(cancellation.is_clean());
+        assert_eq!(cancellation.to_string(), "deadline exceeded");
+    }
+
+    #[test]
+    fn test_timeout_in_secs() {
+        let request = RunRequest::builder()
+            .command(Param::cmd_name("sleep"))
+            .args(vec![])
+            .timeout(Duration::from_millis(1500))
+            .build();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["timeout"], 2);
+
+        let request: RunRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(request.timeout, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_stdin() {
+        let request = RunRequest::builder()
+            .command(Param::cmd_name("sort"))
+            .args(vec![])
+            .stdin(Stdin::File(Param::ipath("/tmp/lines.txt")))
+            .build();
+        assert_eq!(
+            request.local_paths(Param::is_input),
+            vec![PathBuf::from("/tmp/lines.txt")]
+        );
+
+        let recipe = request.map_params(|_| "/workspace/lines.txt".to_owned());
+        assert_eq!(
+            recipe.stdin,
+            Some(Stdin::File("/workspace/lines.txt".to_owned()))
+        );
+
+        // a request of an older client feeds nothing
+        let mut json = serde_json::to_value(&recipe).unwrap();
+        json.as_object_mut().unwrap().remove("stdin");
+        let recipe: RunRecipe = serde_json::from_value(json).unwrap();
+        assert_eq!(recipe.stdin, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_status_from_std() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let status = ExitStatus::from(std::process::ExitStatus::from_raw(3 << 8));
+        assert_eq!(status, ExitStatus::Exited { code: 3 });
+        assert_eq!(status.return_code(), 3);
+        assert!(!status.success());
+
+        // terminated by SIGKILL
+        let status = ExitStatus::from(std::process::ExitStatus::from_raw(9));
+        assert_eq!(
+            status,
+            ExitStatus::Signaled {
+                signal: 9,
+                core_dumped: false
+            }
+        );
+        assert_eq!(status.return_code(), -9);
+        assert!(!status.success());
+        let response = RunResponse::from_status(status);
+        assert_eq!((response.return_code, response.signal), (-9, Some(9)));
+    }
+
+    #[test]
+    fn test_exit_status_timed_out() {
+        let response = RunResponse::from_status(ExitStatus::TimedOut);
+        assert_eq!((response.return_code, response.signal), (-1, None));
+        assert!(!response.status.success());
+        assert_eq!(response.status.to_string(), "timed out");
+    }
 }
@@ -0,0 +1,341 @@
+//! Recordings of the runs, for reproducing on a developer machine the failures seen only on
+//! the workers.
+//!
+//! A request labeled `record: always`, or `record: failure` for only the runs not succeeding,
+//! is recorded by the worker into a bundle: the recipe as resolved by the worker, the
+//! workspace as it was before the command ran, with the inputs downloaded, and as it was after,
+//! with the outputs. The bundle is uploaded next to the task, at [`recording_param`], and told
+//! by the response. `cmdproxy replay` fetches it, runs the recipe again on a copy of the inputs
+//! in a fresh workspace, and compares the status and the outputs with the recorded ones.
+//!
+//! Only the workspace is recorded, hence neither the shared inputs used in place nor the
+//! command itself, which is expected to be installed alike where replayed. The run is replayed
+//! as a plain command, even if the worker ran it in a container, by a warm daemon, or batched.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use walkdir::WalkDir;
+
+use crate::archive::ArchiveFormat;
+use crate::params::{content_digest, local_hostname, Param};
+use crate::protocol::{ExitStatus, RunRecipe, RunSpecification, Stdin};
+use crate::storage::Storage;
+
+/// Label of the runs to be recorded, by a [`RecordMode`].
+pub const RECORD_LABEL: &str = "record";
+/// Hostname of the recordings uploaded by the workers on the storage.
+pub const RECORDING_HOSTNAME: &str = "(recording)";
+
+/// File of the bundle describing the recorded run.
+const RECORDING_FILE: &str = "recording.json";
+/// Folder of the bundle holding the workspace before the run.
+const INPUTS_DIR: &str = "inputs";
+/// Folder of the bundle holding the workspace after the run.
+const OUTPUTS_DIR: &str = "outputs";
+
+/// Which runs are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RecordMode {
+    /// Record the run however it finishes.
+    Always,
+    /// Record the run only if it does not succeed.
+    Failure,
+}
+
+impl fmt::Display for RecordMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordMode::Always => write!(f, "always"),
+            RecordMode::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+impl RecordMode {
+    /// How the run of the `labels` is recorded, if at all.
+    pub fn of(labels: &HashMap<String, String>) -> Option<RecordMode> {
+        match labels.get(RECORD_LABEL).map(String::as_str) {
+            Some("always") => Some(RecordMode::Always),
+            Some("failure") => Some(RecordMode::Failure),
+            _ => None,
+        }
+    }
+}
+
+/// Where the recording of the run of `task_id` is uploaded.
+pub fn recording_param(task_id: &str) -> Param {
+    Param::OutCloudDirParam {
+        filepath: format!("{}/recording", task_id),
+        hostname: RECORDING_HOSTNAME.to_owned(),
+    }
+}
+
+/// What is known of a recorded run, besides its inputs and outputs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub task_id: String,
+    /// Hostname of the worker which ran it.
+    pub worker: String,
+    /// Path of the workspace on the worker, which the paths in the recipe are under.
+    pub workspace: String,
+    pub recipe: RunRecipe,
+    pub status: ExitStatus,
+}
+
+impl Recording {
+    /// Load the recording of the bundle at `bundle`.
+    pub fn load(bundle: &Path) -> anyhow::Result<Recording> {
+        let recording = std::fs::read_to_string(bundle.join(RECORDING_FILE))?;
+        Ok(serde_json::from_str(recording.as_str())?)
+    }
+
+    /// The recipe with the workspace of the worker replaced by the folder `sandbox`.
+    pub fn relocate(&self, sandbox: &Path) -> RunRecipe {
+        let sandbox = sandbox.to_string_lossy();
+        let relocate = |value: String| value.replace(self.workspace.as_str(), sandbox.as_ref());
+        let recipe = self.recipe.clone();
+        RunSpecification {
+            command: relocate(recipe.command),
+            args: recipe.args.into_iter().map(relocate).collect(),
+            cwd: recipe.cwd.map(relocate),
+            env: recipe.env.map(|env| {
+                env.into_iter()
+                    .map(|(name, value)| (name, relocate(value)))
+                    .collect()
+            }),
+            stdin: recipe.stdin.map(|stdin| stdin.map(relocate)),
+            stdout: recipe.stdout.map(relocate),
+            stderr: recipe.stderr.map(relocate),
+            ..recipe
+        }
+    }
+}
+
+/// Records a run on the worker, from before its command is run.
+pub(crate) struct Recorder {
+    mode: RecordMode,
+    workspace: PathBuf,
+    bundle: TempDir,
+}
+
+impl Recorder {
+    /// Start recording the run in `workspace`, taking its inputs as they are now.
+    pub(crate) fn start(mode: RecordMode, workspace: &Path) -> anyhow::Result<Recorder> {
+        // beside the workspace, so as not to record the recording
+        let bundle = tempfile::Builder::new()
+            .prefix("cmdproxy-recording-")
+            .tempdir_in(workspace.parent().unwrap_or(workspace))?;
+        copy_dir(workspace, &bundle.path().join(INPUTS_DIR))?;
+        Ok(Recorder {
+            mode,
+            workspace: workspace.to_owned(),
+            bundle,
+        })
+    }
+
+    /// Finish recording the run of `recipe` finished by `status`, and upload the recording,
+    /// returning its url, unless the run is not to be recorded after all.
+    pub(crate) async fn finish(
+        self,
+        storage: Storage,
+        task_id: &str,
+        recipe: &RunRecipe,
+        status: &ExitStatus,
+    ) -> anyhow::Result<Option<String>> {
+        if self.mode == RecordMode::Failure && status.success() {
+            return Ok(None);
+        }
+        copy_dir(&self.workspace, &self.bundle.path().join(OUTPUTS_DIR))?;
+        let recording = Recording {
+            task_id: task_id.to_owned(),
+            worker: local_hostname(),
+            workspace: self.workspace.to_string_lossy().into_owned(),
+            recipe: recipe.clone(),
+            status: status.clone(),
+        };
+        std::fs::write(
+            self.bundle.path().join(RECORDING_FILE),
+            serde_json::to_string_pretty(&recording)?,
+        )?;
+
+        let param = recording_param(task_id);
+        param
+            .upload_archived(storage, self.bundle.path(), ArchiveFormat::TarZst)
+            .await?;
+        Ok(Some(param.cloud_url()))
+    }
+}
+
+/// How a replay of a recording went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    /// How the command finished when replayed.
+    pub status: ExitStatus,
+    /// How the command finished on the worker.
+    pub recorded: ExitStatus,
+    /// Paths, relative to the workspace, of the files left different from those recorded,
+    /// including those left by only one of the runs.
+    pub differing: Vec<String>,
+}
+
+impl Replay {
+    /// Whether the replay reproduced the recorded run exactly.
+    pub fn reproduced(&self) -> bool {
+        self.status == self.recorded && self.differing.is_empty()
+    }
+}
+
+/// Replay the run recorded in the bundle at `bundle` in the empty folder `sandbox`, which is
+/// the workspace of the replay as well as the working directory of the command by default.
+pub async fn replay(bundle: &Path, sandbox: &Path) -> anyhow::Result<Replay> {
+    let recording = Recording::load(bundle)?;
+    copy_dir(&bundle.join(INPUTS_DIR), sandbox)?;
+    let recipe = recording.relocate(sandbox);
+    let status = run_locally(&recipe, sandbox).await?;
+    Ok(Replay {
+        status,
+        recorded: recording.status,
+        differing: diff_dirs(&bundle.join(OUTPUTS_DIR), sandbox).await?,
+    })
+}
+
+/// Run the command of `recipe` on this host, in `cwd` unless the recipe says otherwise.
+async fn run_locally(recipe: &RunRecipe, cwd: &Path) -> anyhow::Result<ExitStatus> {
+    let mut command = tokio::process::Command::new(recipe.command.as_str());
+    command
+        .args(&recipe.args)
+        .envs(recipe.env.clone().unwrap_or_default())
+        .current_dir(recipe.cwd.as_deref().map_or(cwd, Path::new));
+    if let Some(cwd) = recipe.cwd.as_deref() {
+        std::fs::create_dir_all(cwd)?;
+    }
+    command.stdin(match &recipe.stdin {
+        Some(Stdin::Content(_)) => Stdio::piped(),
+        Some(Stdin::File(path)) => Stdio::from(std::fs::File::open(path)?),
+        None => Stdio::null(),
+    });
+    if let Some(path) = recipe.stdout.as_deref() {
+        command.stdout(std::fs::File::create(path)?);
+    }
+    if let Some(path) = recipe.stderr.as_deref() {
+        command.stderr(std::fs::File::create(path)?);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            return Ok(ExitStatus::SpawnFailed {
+                reason: err.to_string(),
+            })
+        }
+    };
+    if let (Some(Stdin::Content(content)), Some(mut stdin)) = (&recipe.stdin, child.stdin.take()) {
+        // the command may well stop reading early, which is up to it
+        stdin
+            .write_all(content.as_bytes())
+            .await
+            .unwrap_or_default();
+    }
+    Ok(ExitStatus::from(child.wait().await?))
+}
+
+/// Copy the folder `src` into the folder `dst`, made if missing, keeping the symlinks as is.
+fn copy_dir(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in WalkDir::new(src).min_depth(1) {
+        let entry = entry?;
+        let target = dst.join(entry.path().strip_prefix(src)?);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths of the files differing between the folders `a` and `b`, relative to them.
+async fn diff_dirs(a: &Path, b: &Path) -> anyhow::Result<Vec<String>> {
+    let files = |root: &Path| -> BTreeSet<PathBuf> {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| Some(entry.path().strip_prefix(root).ok()?.to_owned()))
+            .collect()
+    };
+    let (files_a, files_b) = (files(a), files(b));
+    let mut differing = Vec::new();
+    for relpath in files_a.union(&files_b) {
+        let same = files_a.contains(relpath)
+            && files_b.contains(relpath)
+            && content_digest(&a.join(relpath)).await? == content_digest(&b.join(relpath)).await?;
+        if !same {
+            differing.push(relpath.to_string_lossy().into_owned());
+        }
+    }
+    Ok(differing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replay() {
+        let workspace = tempfile::tempdir().unwrap();
+        let workdir = workspace.path().to_string_lossy().into_owned();
+        std::fs::write(workspace.path().join("in.txt"), "hello\n").unwrap();
+        let recipe = RunRecipe::builder()
+            .command("/bin/sh".to_owned())
+            .args(vec![
+                "-c".to_owned(),
+                format!("tr a-z A-Z < {0}/in.txt > {0}/out.txt", workdir),
+            ])
+            .build();
+
+        let recorder = Recorder::start(RecordMode::Always, workspace.path()).unwrap();
+        std::fs::write(workspace.path().join("out.txt"), "HELLO\n").unwrap();
+        copy_dir(workspace.path(), &recorder.bundle.path().join(OUTPUTS_DIR)).unwrap();
+        let recording = Recording {
+            task_id: "task".to_owned(),
+            worker: "worker".to_owned(),
+            workspace: workdir,
+            recipe,
+            status: ExitStatus::Exited { code: 0 },
+        };
+        std::fs::write(
+            recorder.bundle.path().join(RECORDING_FILE),
+            serde_json::to_string(&recording).unwrap(),
+        )
+        .unwrap();
+
+        let sandbox = tempfile::tempdir().unwrap();
+        let outcome = replay(recorder.bundle.path(), sandbox.path())
+            .await
+            .unwrap();
+        assert!(outcome.reproduced(), "{:?}", outcome);
+
+        // outputs depending on the host are told apart
+        std::fs::write(
+            recorder.bundle.path().join(OUTPUTS_DIR).join("out.txt"),
+            "HI\n",
+        )
+        .unwrap();
+        let sandbox = tempfile::tempdir().unwrap();
+        let outcome = replay(recorder.bundle.path(), sandbox.path())
+            .await
+            .unwrap();
+        assert_eq!(outcome.differing, vec!["out.txt".to_owned()]);
+    }
+}
@@ -0,0 +1,278 @@
+//! Registry of the live workers, each advertising what it serves and which versions it runs.
+
+use std::fmt;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use log::warn;
+use mongodb::bson::{doc, to_bson, DateTime};
+use mongodb::options::UpdateOptions;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+
+/// Version of the wire format of the requests and responses, bumped on every change which
+/// an older peer cannot understand.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Version of this crate.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Interval at which a worker refreshes its entry in the registry.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A worker is taken as gone if it has missed this many heartbeats.
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// What a worker advertises about itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerInfo {
+    #[serde(rename = "_id")]
+    pub worker_id: String,
+    pub hostname: String,
+    /// The queues consumed by the worker, prefixed already.
+    pub queues: Vec<String>,
+    /// Version of the crate of the worker, empty if advertised by none.
+    #[serde(default)]
+    pub version: String,
+    /// Version of the protocol of the worker, 0 if advertised by none, as by the builds
+    /// registering before the versions were, which no client is compatible with.
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub heartbeat_at: Option<DateTime>,
+    /// Number of the workspaces of the worker removed by force, as of the last heartbeat.
+    #[serde(default)]
+    pub leaked_workspaces: u64,
+    /// Number of the SLA alerts raised on the worker, as of the last heartbeat.
+    #[serde(default)]
+    pub sla_alerts: u64,
+    /// Outcomes of the canary runs of the worker, as of the last heartbeat, if it runs any.
+    #[serde(default)]
+    pub canary: Option<metrics::CanaryStats>,
+}
+
+impl WorkerInfo {
+    /// The info of this process serving `queues`.
+    pub fn this_worker(hostname: &str, queues: Vec<String>) -> WorkerInfo {
+        WorkerInfo {
+            worker_id: format!("{}:{}", hostname, std::process::id()),
+            hostname: hostname.to_owned(),
+            queues,
+            version: CRATE_VERSION.to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            heartbeat_at: None,
+            leaked_workspaces: 0,
+            sla_alerts: 0,
+            canary: None,
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    /// Whether the worker takes itself as fit to serve, as told by its canary runs if any.
+    pub fn is_healthy(&self) -> bool {
+        self.canary.map_or(true, |canary| canary.healthy)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkerRegistry {
+    coll: Collection<WorkerInfo>,
+}
+
+impl WorkerRegistry {
+    pub fn new(coll: Collection<WorkerInfo>) -> WorkerRegistry {
+        WorkerRegistry { coll }
+    }
+
+    /// Register the worker, or refresh it if registered already.
+    pub(crate) async fn heartbeat(&self, worker: &WorkerInfo) -> anyhow::Result<()> {
+        self.coll
+            .update_one(
+                doc! { "_id": worker.worker_id.as_str() },
+                doc! {
+                    "$set": {
+                        "hostname": worker.hostname.as_str(),
+                        "queues": to_bson(&worker.queues)?,
+                        "version": worker.version.as_str(),
+                        "protocol_version": worker.protocol_version,
+                        "heartbeat_at": DateTime::now(),
+                        "leaked_workspaces": metrics::leaked_workspaces() as i64,
+                        "sla_alerts": metrics::sla_alerts() as i64,
+                        "canary": to_bson(&metrics::canary_stats())?,
+                    },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Keep the worker registered by sending heartbeats, until the future is dropped.
+    pub(crate) async fn keep_alive(&self, worker: WorkerInfo) {
+        loop {
+            if let Err(err) = self.heartbeat(&worker).await {
+                warn!("Failed to send the heartbeat: {}", err);
+            }
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        }
+    }
+
+    /// The live workers consuming `queue`.
+    pub async fn serving(&self, queue: &str) -> anyhow::Result<Vec<WorkerInfo>> {
+        Ok(self
+            .coll
+            .find(
+                doc! {
+                    "queues": queue,
+                    "heartbeat_at": { "$gte": alive_since() },
+                },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// The live workers which have marked themselves unhealthy by their canary runs, to be
+    /// taken out of rotation.
+    pub async fn unhealthy(&self) -> anyhow::Result<Vec<WorkerInfo>> {
+        Ok(self
+            .coll
+            .find(
+                doc! {
+                    "canary.healthy": false,
+                    "heartbeat_at": { "$gte": alive_since() },
+                },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
+
+/// The time since which a worker sending heartbeats is taken as alive.
+fn alive_since() -> DateTime {
+    DateTime::from_millis(
+        DateTime::now().timestamp_millis()
+            - (HEARTBEAT_INTERVAL * MISSED_HEARTBEATS).as_millis() as i64,
+    )
+}
+
+/// What the client does when a queue is served by workers of another protocol version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum VersionCheck {
+    /// Submit without checking.
+    Ignore,
+    /// Submit anyway, but warn about the incompatible workers.
+    #[default]
+    Warn,
+    /// Fail with [`Incompatible`] instead of submitting.
+    Refuse,
+}
+
+/// Error of a request not submitted because its queue is served by incompatible workers.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incompatible {
+    pub queue: String,
+    /// Ids of the incompatible workers, with the protocol versions they run.
+    pub workers: Vec<(String, u32)>,
+}
+
+impl fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Queue `{}' is served by workers of protocol versions other than {}:",
+            self.queue, PROTOCOL_VERSION
+        )?;
+        for (worker_id, protocol_version) in &self.workers {
+            write!(f, " {} ({})", worker_id, protocol_version)?;
+        }
+        Ok(())
+    }
+}
+
+impl Incompatible {
+    /// The incompatible ones of the `workers` serving `queue`, if any.
+    pub fn among(queue: &str, workers: Vec<WorkerInfo>) -> Option<Incompatible> {
+        let workers: Vec<_> = workers
+            .into_iter()
+            .filter(|worker| !worker.is_compatible())
+            .map(|worker| (worker.worker_id, worker.protocol_version))
+            .collect();
+        (!workers.is_empty()).then(|| Incompatible {
+            queue: queue.to_owned(),
+            workers,
+        })
+    }
+}
+
+impl std::error::Error for Incompatible {}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::from_document;
+
+    use super::*;
+
+    fn worker(worker_id: &str, protocol_version: u32) -> WorkerInfo {
+        WorkerInfo {
+            worker_id: worker_id.to_owned(),
+            protocol_version,
+            ..WorkerInfo::this_worker("host", vec!["gcc".to_owned()])
+        }
+    }
+
+    #[test]
+    fn test_compatible_workers() {
+        let workers = vec![worker("a", PROTOCOL_VERSION), worker("b", PROTOCOL_VERSION)];
+        assert!(workers.iter().all(WorkerInfo::is_compatible));
+        assert_eq!(Incompatible::among("gcc", workers), None);
+        assert_eq!(Incompatible::among("gcc", vec![]), None);
+    }
+
+    #[test]
+    fn test_incompatible_workers() {
+        let workers = vec![
+            worker("a", PROTOCOL_VERSION),
+            worker("b", PROTOCOL_VERSION + 1),
+            worker("c", PROTOCOL_VERSION - 1),
+        ];
+        let incompatible = Incompatible::among("gcc", workers).unwrap();
+        assert_eq!(incompatible.queue, "gcc");
+        assert_eq!(
+            incompatible.workers,
+            vec![
+                ("b".to_owned(), PROTOCOL_VERSION + 1),
+                ("c".to_owned(), PROTOCOL_VERSION - 1)
+            ]
+        );
+        assert!(incompatible.to_string().contains(" b (3) c (1)"));
+    }
+
+    #[test]
+    fn test_workers_advertising_no_versions() {
+        // as registered by a build before the versions were advertised
+        let worker: WorkerInfo = from_document(doc! {
+            "_id": "old:1",
+            "hostname": "old",
+            "queues": ["gcc"],
+        })
+        .unwrap();
+        assert_eq!((worker.version.as_str(), worker.protocol_version), ("", 0));
+        assert!(!worker.is_compatible());
+        assert!(worker.is_healthy());
+
+        let workers = vec![worker, self::worker("new:1", PROTOCOL_VERSION)];
+        let incompatible = Incompatible::among("gcc", workers).unwrap();
+        assert_eq!(incompatible.workers, vec![("old:1".to_owned(), 0)]);
+    }
+}
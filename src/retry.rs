@@ -0,0 +1,480 @@
+//! Classification of the failed runs, deciding which of them are worth retrying.
+//!
+//! A run failed by the infrastructure, such as a lost worker or a broken transfer, may well
+//! succeed if tried again, while a command failing by itself will fail the same way every
+//! time. Each class of failures has its own [`RetryPolicy`], which can be overridden.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::admission::ResourcesUnavailable;
+use crate::backpressure::Backpressure;
+use crate::command_policy::{CommandNotAllowed, EnvNotAllowed};
+use crate::features::UnsupportedFeatures;
+use crate::outcome::RunOutcome;
+use crate::precheck::{OutputUnwritable, PrecheckFailed};
+use crate::protocol::{CancelReason, Cancellation, ExitStatus, ServerError, TimedOut};
+use crate::registry::Incompatible;
+
+/// Why a run failed, as far as retrying is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureClass {
+    /// Moving the files to or from the cloud failed.
+    Transfer,
+    /// The worker never answered, or stopped before the run finished.
+    WorkerLost,
+    /// The command was killed by the system, most likely for running out of memory.
+    OutOfMemory,
+    /// The command failed by itself, including running out of its time.
+    Command,
+    /// The request was refused before running, e.g. by its precheck or a pre-processor.
+    Rejected,
+    /// The worker lacked the disk or memory to take the run.
+    ResourcesUnavailable,
+    /// The run was cancelled on request.
+    Cancelled,
+    /// The broker failed to take the request.
+    Broker,
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureClass::Transfer => write!(f, "transfer failure"),
+            FailureClass::WorkerLost => write!(f, "worker lost"),
+            FailureClass::OutOfMemory => write!(f, "out of memory"),
+            FailureClass::Command => write!(f, "command failure"),
+            FailureClass::Rejected => write!(f, "rejected"),
+            FailureClass::ResourcesUnavailable => write!(f, "resources unavailable"),
+            FailureClass::Cancelled => write!(f, "cancelled"),
+            FailureClass::Broker => write!(f, "broker failure"),
+        }
+    }
+}
+
+/// Classify a run by how it finished, or none if it did not fail.
+pub fn classify_status(
+    status: &ExitStatus,
+    cancellation: Option<&Cancellation>,
+) -> Option<FailureClass> {
+    if let Some(cancellation) = cancellation {
+        return Some(match cancellation.reason {
+            CancelReason::ClientCancel => FailureClass::Cancelled,
+            CancelReason::Deadline => FailureClass::Command,
+            CancelReason::Preemption | CancelReason::Shutdown => FailureClass::WorkerLost,
+        });
+    }
+    match status {
+        status if status.success() => None,
+        // SIGKILL not sent by the worker comes from the oom-killer, directly or through a
+        // shell exiting with 128 + 9
+        ExitStatus::Signaled { signal: 9, .. } | ExitStatus::Exited { code: 137 } => {
+            Some(FailureClass::OutOfMemory)
+        }
+        ExitStatus::Unknown => Some(FailureClass::WorkerLost),
+        _ => Some(FailureClass::Command),
+    }
+}
+
+/// Classify a finished run, or none if it did not fail.
+///
+/// A successful run whose outputs failed to be downloaded is taken as a transfer failure.
+pub fn classify_outcome(outcome: &RunOutcome) -> Option<FailureClass> {
+    // a command exceeding the limits it was given fails by itself, whatever killed it
+    if outcome.limit_exceeded.is_some() {
+        return Some(FailureClass::Command);
+    }
+    // a dry run is done once resolved, never having run the command to fail
+    if outcome.recipe.is_some() {
+        return None;
+    }
+    classify_status(&outcome.status, outcome.cancellation.as_ref()).or_else(|| {
+        (!outcome.artifacts.iter().all(|artifact| artifact.is_ok()))
+            .then_some(FailureClass::Transfer)
+    })
+}
+
+/// Classify a run which failed with an error instead of an outcome.
+pub fn classify_error(err: &anyhow::Error) -> FailureClass {
+    if let Some(err) = err.downcast_ref::<ServerError>() {
+        return err.failure.unwrap_or(FailureClass::Rejected);
+    }
+    if err.is::<WorkerLost>() {
+        return FailureClass::WorkerLost;
+    }
+    if err.is::<BrokerFailed>() {
+        return FailureClass::Broker;
+    }
+    if err.is::<TransferFailed>() {
+        return FailureClass::Transfer;
+    }
+    if err.is::<TimedOut>() {
+        return FailureClass::Command;
+    }
+    if err.is::<ResourcesUnavailable>() {
+        return FailureClass::ResourcesUnavailable;
+    }
+    let refused = err.is::<PrecheckFailed>()
+        || err.is::<OutputUnwritable>()
+        || err.is::<UnsupportedFeatures>()
+        || err.is::<CommandNotAllowed>()
+        || err.is::<EnvNotAllowed>();
+    if refused || err.is::<Incompatible>() || err.is::<Backpressure>() {
+        return FailureClass::Rejected;
+    }
+    let is_transfer = err
+        .chain()
+        .any(|cause| cause.is::<mongodb::error::Error>() || cause.is::<std::io::Error>());
+    if is_transfer {
+        FailureClass::Transfer
+    } else {
+        FailureClass::Rejected
+    }
+}
+
+/// Error of a task whose response never came back from the worker.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerLost {
+    pub task_id: String,
+    pub cause: String,
+}
+
+impl fmt::Display for WorkerLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Lost the worker of task {}: {}",
+            self.task_id, self.cause
+        )
+    }
+}
+
+impl std::error::Error for WorkerLost {}
+
+/// Error of a request the broker failed to take, such as while the broker is restarting.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerFailed {
+    pub queue: String,
+    pub cause: String,
+}
+
+impl fmt::Display for BrokerFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to send the request to queue {}: {}",
+            self.queue, self.cause
+        )
+    }
+}
+
+impl std::error::Error for BrokerFailed {}
+
+/// Error of a file failed to be moved to or from the cloud, even after retrying.
+///
+/// Returned wrapped in [`anyhow::Error`], from which it can be recovered by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferFailed {
+    /// What was being done, such as `download @host:/tmp/a.txt`.
+    pub what: String,
+    pub cause: String,
+}
+
+impl TransferFailed {
+    pub(crate) fn new(what: &str, err: anyhow::Error) -> TransferFailed {
+        TransferFailed {
+            what: what.to_owned(),
+            cause: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for TransferFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to {}: {}", self.what, self.cause)
+    }
+}
+
+impl std::error::Error for TransferFailed {}
+
+/// How many times a class of failures is retried, and how long to wait in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const NEVER: RetryPolicy = RetryPolicy {
+        max_retries: 0,
+        backoff: Duration::ZERO,
+    };
+
+    /// Wait before the retry after `retries` retries already, or none if out of retries.
+    pub fn backoff(&self, retries: u32) -> Option<Duration> {
+        (retries < self.max_retries).then(|| self.backoff * 2u32.saturating_pow(retries))
+    }
+}
+
+/// The policies of all the classes of failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicies {
+    policies: HashMap<FailureClass, RetryPolicy>,
+}
+
+impl Default for RetryPolicies {
+    /// Retry the failures of the infrastructure a few times, but never the deterministic ones.
+    fn default() -> Self {
+        let policy = |max_retries, backoff_secs| RetryPolicy {
+            max_retries,
+            backoff: Duration::from_secs(backoff_secs),
+        };
+        RetryPolicies {
+            policies: HashMap::from([
+                (FailureClass::Transfer, policy(3, 1)),
+                (FailureClass::WorkerLost, policy(2, 5)),
+                (FailureClass::OutOfMemory, policy(1, 10)),
+                (FailureClass::ResourcesUnavailable, policy(3, 10)),
+                (FailureClass::Broker, policy(3, 2)),
+            ]),
+        }
+    }
+}
+
+impl RetryPolicies {
+    /// Never retry anything.
+    pub fn never() -> RetryPolicies {
+        RetryPolicies {
+            policies: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self, class: FailureClass) -> RetryPolicy {
+        self.policies
+            .get(&class)
+            .copied()
+            .unwrap_or(RetryPolicy::NEVER)
+    }
+
+    /// Override the policy of `class`.
+    pub fn with(mut self, class: FailureClass, policy: RetryPolicy) -> RetryPolicies {
+        self.policies.insert(class, policy);
+        self
+    }
+
+    /// Override the max numbers of retries of the given classes, keeping their backoffs.
+    pub fn with_max_retries(mut self, overrides: &HashMap<FailureClass, u32>) -> RetryPolicies {
+        for (class, max_retries) in overrides {
+            let backoff = match self.policy(*class).backoff {
+                Duration::ZERO => Duration::from_secs(1),
+                backoff => backoff,
+            };
+            self.policies.insert(
+                *class,
+                RetryPolicy {
+                    max_retries: *max_retries,
+                    backoff,
+                },
+            );
+        }
+        self
+    }
+}
+
+/// A failure a single run is retried on, see [`RunOptions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetryOn {
+    /// The command exited with one of the codes, such as those of a flaky test runner.
+    ExitCodes(Vec<i32>),
+    /// Any failure of the class.
+    Class(FailureClass),
+}
+
+impl RetryOn {
+    fn matches(&self, res: &anyhow::Result<RunOutcome>, class: FailureClass) -> bool {
+        match (self, res) {
+            (RetryOn::ExitCodes(codes), Ok(outcome)) => matches!(
+                outcome.status,
+                ExitStatus::Exited { code } if codes.contains(&code)
+            ),
+            (RetryOn::ExitCodes(_), Err(_)) => false,
+            (RetryOn::Class(retried), _) => *retried == class,
+        }
+    }
+}
+
+/// How hard a single run is retried, on top of the policies of the client.
+///
+/// The failures matching any of `retry_on` are retried up to `max_retries` times, waiting
+/// `backoff` before the first retry and twice as long before every further one, while the
+/// others are retried as the client says. The default retries nothing more than the client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunOptions {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl RunOptions {
+    /// The policy retrying the run finished as `res`, which failed by `class`.
+    pub fn policy(
+        &self,
+        res: &anyhow::Result<RunOutcome>,
+        class: FailureClass,
+        policies: &RetryPolicies,
+    ) -> RetryPolicy {
+        if self
+            .retry_on
+            .iter()
+            .any(|retry_on| retry_on.matches(res, class))
+        {
+            RetryPolicy {
+                max_retries: self.max_retries,
+                backoff: self.backoff,
+            }
+        } else {
+            policies.policy(class)
+        }
+    }
+}
+
+/// Parse the overrides given as CLASS=MAX_RETRIES.
+pub fn parse_max_retries(specs: &[String]) -> anyhow::Result<HashMap<FailureClass, u32>> {
+    use clap::ValueEnum;
+
+    let mut overrides = HashMap::new();
+    for spec in specs {
+        let (class, max_retries) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expect CLASS=MAX_RETRIES, got {}", spec))?;
+        let class = FailureClass::from_str(class.trim(), true)
+            .map_err(|err| anyhow::anyhow!("Unknown failure class {}: {}", class, err))?;
+        overrides.insert(class, max_retries.trim().parse()?);
+    }
+    Ok(overrides)
+}
+
+/// Run `op` again as long as it fails and the policy allows.
+pub(crate) async fn retrying<T, F, Fut>(
+    policy: RetryPolicy,
+    what: &str,
+    mut op: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        let err = match op().await {
+            Ok(res) => return Ok(res),
+            Err(err) => err,
+        };
+        match policy.backoff(retries) {
+            Some(backoff) => {
+                warn!("Failed to {}, retry in {:?}: {}", what, backoff, err);
+                tokio::time::sleep(backoff).await;
+                retries += 1;
+            }
+            None => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_status() {
+        let classify = |status| classify_status(&status, None);
+        assert_eq!(classify(ExitStatus::Exited { code: 0 }), None);
+        assert_eq!(
+            classify(ExitStatus::Exited { code: 1 }),
+            Some(FailureClass::Command)
+        );
+        assert_eq!(
+            classify(ExitStatus::Exited { code: 137 }),
+            Some(FailureClass::OutOfMemory)
+        );
+        assert_eq!(
+            classify(ExitStatus::Signaled {
+                signal: 9,
+                core_dumped: false
+            }),
+            Some(FailureClass::OutOfMemory)
+        );
+
+        // killed by the worker itself rather than the system
+        let cancellation = Cancellation::new(CancelReason::Shutdown, true);
+        let status = ExitStatus::Signaled {
+            signal: 9,
+            core_dumped: false,
+        };
+        assert_eq!(
+            classify_status(&status, Some(&cancellation)),
+            Some(FailureClass::WorkerLost)
+        );
+    }
+
+    #[test]
+    fn test_retry_policies() {
+        let policies = RetryPolicies::default();
+        assert_eq!(policies.policy(FailureClass::Command), RetryPolicy::NEVER);
+
+        let transfer = policies.policy(FailureClass::Transfer);
+        assert_eq!(transfer.backoff(0), Some(Duration::from_secs(1)));
+        assert_eq!(transfer.backoff(2), Some(Duration::from_secs(4)));
+        assert_eq!(transfer.backoff(3), None);
+
+        let overrides =
+            parse_max_retries(&["command=2".to_owned(), "transfer=0".to_owned()]).unwrap();
+        let policies = policies.with_max_retries(&overrides);
+        assert_eq!(policies.policy(FailureClass::Command).max_retries, 2);
+        assert_eq!(policies.policy(FailureClass::Transfer).backoff(0), None);
+        assert!(parse_max_retries(&["typo=1".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_run_options() {
+        let policies = RetryPolicies::never();
+        let options = RunOptions {
+            max_retries: 4,
+            backoff: Duration::from_millis(100),
+            retry_on: vec![
+                RetryOn::ExitCodes(vec![75]),
+                RetryOn::Class(FailureClass::Broker),
+            ],
+        };
+
+        let broker: anyhow::Result<RunOutcome> = Err(BrokerFailed {
+            queue: "sh".to_owned(),
+            cause: "connection refused".to_owned(),
+        }
+        .into());
+        let class = classify_error(broker.as_ref().unwrap_err());
+        assert_eq!(class, FailureClass::Broker);
+        let policy = options.policy(&broker, class, &policies);
+        assert_eq!(policy.backoff(3), Some(Duration::from_millis(800)));
+
+        // left to the client if matching none
+        let lost: anyhow::Result<RunOutcome> = Err(WorkerLost {
+            task_id: "t".to_owned(),
+            cause: "gone".to_owned(),
+        }
+        .into());
+        let policy = options.policy(&lost, FailureClass::WorkerLost, &policies);
+        assert_eq!(policy, RetryPolicy::NEVER);
+    }
+}
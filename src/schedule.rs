@@ -0,0 +1,356 @@
+//! Recurring runs, fired by the workers on the cron expressions they are defined with.
+//!
+//! A recurring run is defined in the cloud by its name, with the request to send each time,
+//! and fired by the workers started with `--scheduler`, which check for the due definitions
+//! every [`POLL_INTERVAL`]. A due run is fired once no matter how many workers check, as the
+//! worker firing it claims it by advancing its next time, and a run missed while no worker
+//! was checking is fired once, late, rather than once per miss.
+//!
+//! The request is stored as signed by the client defining the run, and the worker firing it
+//! verifies the signature before sending the request as a client would, so that writing the
+//! collection of the runs does not grant running anything. The signature is verified however
+//! long ago it was issued, as the run recurs, hence is of a scheme only the stored requests
+//! are signed under, see [`crate::middles::auth::HmacAuth`]. The requests taking local params are refused,
+//! which would be the files of the worker firing them. The outputs of each run are named with
+//! the time it was due at, such as `report.csv` as `report-20261016T093000Z.csv`, so that the
+//! runs do not overwrite each other.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{
+    FindOneAndUpdateOptions, FindOptions, ReplaceOptions, ReturnDocument, UpdateOptions,
+};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+use crate::client::Client;
+use crate::params::Param;
+use crate::protocol::RunRequest;
+
+/// Interval of checking for the due runs.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A recurring run as defined in the cloud.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledRun {
+    #[serde(rename = "_id")]
+    pub name: String,
+    /// Cron expression of the times to fire the run at, in UTC.
+    pub cron: String,
+    /// Queue to send the run to, default to the name of the command.
+    #[serde(default)]
+    pub queue: Option<String>,
+    /// The request as serialized, and signed by the client defining the run if signing.
+    pub request: String,
+    /// Time the run is due next.
+    #[serde(default)]
+    pub next_at: Option<DateTime>,
+    #[serde(default)]
+    pub last_fired_at: Option<DateTime>,
+    /// Id of the task the run was last sent as.
+    #[serde(default)]
+    pub last_task_id: Option<String>,
+}
+
+/// Parse a cron expression, either of the 5 fields of crontab, or of 6 or 7 fields led by
+/// the seconds.
+pub fn parse_cron(expr: &str) -> anyhow::Result<cron::Schedule> {
+    let expr = expr.trim();
+    let expr = match expr.split_whitespace().count() {
+        5 => format!("0 {}", expr),
+        _ => expr.to_owned(),
+    };
+    cron::Schedule::from_str(expr.as_str())
+        .map_err(|err| anyhow::anyhow!("Invalid cron expression {}: {}", expr, err))
+}
+
+/// Refuse the request of a recurring run taking local params.
+pub(crate) fn check_cloud_only(request: &RunRequest) -> anyhow::Result<()> {
+    let paths = request.local_paths(|_| true);
+    anyhow::ensure!(
+        paths.is_empty(),
+        "Refused recurring run of the local paths {:?}, which would be those on the worker",
+        paths
+    );
+    Ok(())
+}
+
+/// The first time after `time` matching the cron expression `expr`.
+fn next_time(expr: &str, time: DateTime) -> anyhow::Result<DateTime> {
+    let after = chrono::DateTime::<chrono::Utc>::from(time.to_system_time());
+    let next = parse_cron(expr)?
+        .after(&after)
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Cron expression {} matches no time ahead", expr))?;
+    Ok(DateTime::from_millis(next.timestamp_millis()))
+}
+
+/// The collection of the recurring runs, shared by the clients and the workers.
+#[derive(Clone)]
+pub struct Schedules {
+    coll: Collection<ScheduledRun>,
+}
+
+impl Schedules {
+    pub fn new(coll: Collection<ScheduledRun>) -> Schedules {
+        Schedules { coll }
+    }
+
+    /// Define the run of `name` to fire `request`, as serialized and signed, on the cron
+    /// expression `cron`, replacing the run of the name if any, and return the time it is due
+    /// first.
+    pub async fn put(
+        &self,
+        name: &str,
+        cron: &str,
+        request: String,
+        queue: Option<String>,
+    ) -> anyhow::Result<DateTime> {
+        let next_at = next_time(cron, DateTime::now())?;
+        let run = ScheduledRun {
+            name: name.to_owned(),
+            cron: cron.to_owned(),
+            queue,
+            request,
+            next_at: Some(next_at),
+            last_fired_at: None,
+            last_task_id: None,
+        };
+        self.coll
+            .replace_one(
+                doc! { "_id": name },
+                run,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(next_at)
+    }
+
+    /// Remove the run of `name`, returning false if there was none.
+    pub async fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let res = self.coll.delete_one(doc! { "_id": name }, None).await?;
+        Ok(res.deleted_count > 0)
+    }
+
+    /// The recurring runs, the soonest due first.
+    pub async fn list(&self) -> anyhow::Result<Vec<ScheduledRun>> {
+        use futures::TryStreamExt;
+
+        let options = FindOptions::builder().sort(doc! { "next_at": 1 }).build();
+        Ok(self.coll.find(None, options).await?.try_collect().await?)
+    }
+
+    /// Claim a due run, if any, by advancing its next time past now, and return it together
+    /// with the time it was due at.
+    ///
+    /// A run claimed by another worker first is left to it.
+    pub(crate) async fn claim_due(&self) -> anyhow::Result<Option<(ScheduledRun, DateTime)>> {
+        let now = DateTime::now();
+        let run = match self
+            .coll
+            .find_one(doc! { "next_at": { "$lte": now } }, None)
+            .await?
+        {
+            Some(run) => run,
+            None => return Ok(None),
+        };
+        let due_at = run.next_at.unwrap_or(now);
+        let next_at = match next_time(run.cron.as_str(), now) {
+            Ok(next_at) => Some(next_at),
+            Err(err) => {
+                warn!("Stop firing run {}: {}", run.name, err);
+                None
+            }
+        };
+        let claimed = self
+            .coll
+            .find_one_and_update(
+                doc! { "_id": run.name.as_str(), "next_at": due_at },
+                doc! { "$set": { "next_at": next_at, "last_fired_at": now } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+        Ok(claimed.map(|run| (run, due_at)))
+    }
+
+    /// Record the task the run of `name` was sent as.
+    async fn fired(&self, name: &str, task_id: &str) -> anyhow::Result<()> {
+        self.coll
+            .update_one(
+                doc! { "_id": name },
+                doc! { "$set": { "last_task_id": task_id } },
+                UpdateOptions::builder().upsert(false).build(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Fire the due runs by the `client` every [`POLL_INTERVAL`], forever.
+pub(crate) async fn fire_periodically(client: Arc<Client>, schedules: Schedules) {
+    loop {
+        loop {
+            match schedules.claim_due().await {
+                Ok(Some((run, due_at))) => {
+                    let (client, schedules) = (client.clone(), schedules.clone());
+                    tokio::spawn(async move { fire(&client, &schedules, run, due_at).await });
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Failed to check for the due runs: {}", err);
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Send the request of the `run` due at `due_at`, with its outputs stamped with the time.
+async fn fire(client: &Client, schedules: &Schedules, run: ScheduledRun, due_at: DateTime) {
+    let request = match client.scheduled_request(&run).await {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("Refused the request of run {}: {:#}", run.name, err);
+            return;
+        }
+    };
+    let stamp = chrono::DateTime::<chrono::Utc>::from(due_at.to_system_time())
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string();
+    let request = request.map_params(|param| stamp_outputs(param, stamp.as_str()));
+
+    info!("Fire run {} due at {}", run.name, stamp);
+    let task_id = std::sync::Mutex::new(None);
+    let res = client
+        .run_watched(request, run.queue.clone(), &|id| {
+            *task_id.lock().unwrap() = Some(id.to_owned());
+        })
+        .await;
+    if let Some(task_id) = task_id.into_inner().unwrap() {
+        schedules
+            .fired(run.name.as_str(), task_id.as_str())
+            .await
+            .unwrap_or_else(|err| warn!("Failed to record the task of run {}: {}", run.name, err));
+    }
+    match res {
+        Ok(outcome) => debug!("Run {} due at {} {}", run.name, stamp, outcome.status),
+        Err(err) => warn!("Run {} due at {} failed: {}", run.name, stamp, err),
+    }
+}
+
+/// The param with its output paths, local or cloud, stamped with `stamp`.
+fn stamp_outputs(param: Param, stamp: &str) -> Param {
+    let stamped = |filepath: &str| stamped(filepath, stamp);
+    match param {
+        Param::FormatParam { tmpl, args } => Param::FormatParam {
+            tmpl,
+            args: args
+                .into_iter()
+                .map(|(key, arg)| (key, stamp_outputs(arg, stamp)))
+                .collect(),
+        },
+        Param::OutCloudFileParam { filepath, hostname } => Param::OutCloudFileParam {
+            filepath: stamped(filepath.as_str()),
+            hostname,
+        },
+        Param::OutCloudDirParam { filepath, hostname } => Param::OutCloudDirParam {
+            filepath: stamped(filepath.as_str()),
+            hostname,
+        },
+        Param::OutCloudGlobParam {
+            pattern,
+            filepath,
+            hostname,
+        } => Param::OutCloudGlobParam {
+            pattern,
+            filepath: stamped(filepath.as_str()),
+            hostname,
+        },
+        param => param.relocate_outputs(&stamped),
+    }
+}
+
+/// The path with `stamp` inserted into its file name before the extensions.
+fn stamped(filepath: &str, stamp: &str) -> String {
+    let name_start = filepath
+        .rfind(|c| c == '/' || c == '\\')
+        .map_or(0, |sep| sep + 1);
+    let (dir, name) = filepath.split_at(name_start);
+    // the leading dot of a hidden file is not taken as of an extension
+    let (stem, extension) = match name[1.min(name.len())..].find('.') {
+        Some(dot) => name.split_at(dot + 1),
+        None => (name, ""),
+    };
+    format!("{}{}-{}{}", dir, stem, stamp, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_next_time() {
+        // 2026-10-16T09:10:00Z
+        let time = DateTime::from_millis(1_792_141_800_000);
+        let next = next_time("30 9 * * *", time).unwrap();
+        assert_eq!(next.timestamp_millis(), 1_792_141_800_000 + 20 * 60 * 1000);
+        assert!(parse_cron("0 0 0 * * * *").is_ok());
+        assert!(parse_cron("every day").is_err());
+    }
+
+    #[test]
+    fn test_stamp_outputs() {
+        let stamp = "20261016T093000Z";
+        assert_eq!(
+            stamped("out/report.csv", stamp),
+            "out/report-20261016T093000Z.csv"
+        );
+        assert_eq!(
+            stamped("logs.tar.gz", stamp),
+            "logs-20261016T093000Z.tar.gz"
+        );
+        assert_eq!(
+            stamped("C:\\out\\.env", stamp),
+            "C:\\out\\.env-20261016T093000Z"
+        );
+
+        let param = Param::OutCloudFileParam {
+            filepath: "reports/daily.csv".to_owned(),
+            hostname: "cmdproxy".to_owned(),
+        };
+        assert_eq!(
+            stamp_outputs(param, stamp).filepath(),
+            "reports/daily-20261016T093000Z.csv"
+        );
+        let input = Param::ipath("data.csv");
+        assert_eq!(stamp_outputs(input.clone(), stamp), input);
+    }
+
+    #[test]
+    fn test_check_cloud_only() {
+        let request = RunRequest::builder()
+            .command(Param::cmd_name("report"))
+            .args(vec![
+                Param::ipath("data.csv").as_cloud(),
+                Param::opath("daily.csv").as_cloud(),
+            ])
+            .build();
+        assert!(check_cloud_only(&request).is_ok());
+
+        let args = HashMap::from([("input", Param::ipath("/etc/shadow"))]);
+        let request = RunRequest::builder()
+            .command(Param::cmd_name("report"))
+            .args(vec![Param::format("--input={input}", args)])
+            .build();
+        assert!(check_cloud_only(&request).is_err());
+    }
+}
@@ -1,67 +1,1118 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::debug;
-use tempfile::tempdir;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::{Child, ChildStdin};
+use tokio::task::JoinHandle;
 
 use crate::apply_middles;
+use crate::approval;
+use crate::batch::{self, BatchConf};
+use crate::composite::ResolvedStep;
 use crate::configs::CmdProxyServerConf;
-use crate::middles::{invoke, serde, Middle};
-use crate::protocol::{RunRecipe, RunResponse};
+use crate::container::{Container, ContainerConf};
+use crate::fairness::{FairSharePolicy, Slot};
+use crate::history::TaskHistory;
+use crate::hooks::{
+    enabled_pre_processors, post_process, pre_process, PostProcessor, PreProcessor,
+};
+use crate::limits::Confinement;
+use crate::middles::auth::{AuthMiddle, HmacAuth};
+use crate::middles::{auth, invoke, serde, Middle};
+use crate::postmortem::Watch;
+use crate::preemption::{signal, PreemptionMode, PreemptionPolicy, Registration};
+use crate::process_group;
+use crate::protocol::{CancelReason, Cancellation, ExitStatus, RunRecipe, RunResponse, Stdin};
+use crate::recording::{RecordMode, Recorder};
+use crate::retry::{classify_status, FailureClass, RetryPolicies};
+use crate::sla;
+use crate::storage::Storage;
+use crate::streams::{OutputStreams, StreamKind};
+use crate::tasks::SERVER_APP;
+use crate::transfer::{RemoteTransfer, Transfer};
+use crate::usage::Sampling;
+use crate::warm::{self, WarmConf};
+use crate::workspace;
+
+/// Interval of checking if the running task has been cancelled.
+const REVOKE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a command cancelled or timed out is given to exit on SIGTERM before being
+/// killed, if not configured.
+const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(10);
+
+/// Tenant of the runs of the requests not signed by their clients, sharing a single share.
+const ANONYMOUS_TENANT: &str = "anonymous";
+
+/// Set once the worker is stopping, after which the running commands are killed.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Cancel the runs in progress, as the worker is stopping.
+pub(crate) fn shut_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+type OutputSink = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where an output of the command ends up: the redirected file, or else `default`.
+///
+/// The redirected file is appended to if `append`, such as by the later steps of a
+/// composite command, or else truncated.
+fn output_sink(
+    path: Option<&String>,
+    append: bool,
+    default: OutputSink,
+) -> std::io::Result<OutputSink> {
+    Ok(match path {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)?;
+            Box::new(tokio::fs::File::from_std(file))
+        }
+        None => default,
+    })
+}
+
+type InputSource = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Where the stdin of the command is read from, if fed at all.
+async fn input_source(stdin: Option<&Stdin<String>>) -> std::io::Result<Option<InputSource>> {
+    let source: InputSource = match stdin {
+        Some(Stdin::Content(content)) => Box::new(std::io::Cursor::new(content.clone())),
+        Some(Stdin::File(path)) => Box::new(tokio::fs::File::open(path).await?),
+        None => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+/// Feed `source` to the stdin of the command in the background, which is closed once all is
+/// fed, or given up once the command stops reading.
+fn spawn_feed(mut source: InputSource, mut sink: ChildStdin) {
+    tokio::spawn(async move {
+        match tokio::io::copy(&mut source, &mut sink).await {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                debug!("  failed to feed the stdin: {}", err)
+            }
+            _ => {}
+        }
+    });
+}
+
+fn spawn_pump<R: AsyncRead + Send + Unpin + 'static>(
+    outputs: &OutputStreams,
+    task_id: &str,
+    kind: StreamKind,
+    source: R,
+    sink: OutputSink,
+) -> JoinHandle<std::io::Result<()>> {
+    let outputs = outputs.clone();
+    let task_id = task_id.to_owned();
+    tokio::spawn(async move { outputs.pump(task_id.as_str(), kind, source, sink).await })
+}
+
+/// Everything needed for running the command of a task.
+struct Execution {
+    task_id: String,
+    history: TaskHistory,
+    outputs: OutputStreams,
+    cancelled: Arc<AtomicBool>,
+    preemption: Option<PreemptionPolicy>,
+    /// How long the processes left by a command may run on after it exits, if supervised.
+    group_grace: Option<Duration>,
+    /// How long a command cancelled or timed out is given to exit on SIGTERM.
+    kill_grace: Duration,
+    fair_share: Option<FairSharePolicy>,
+    retry: RetryPolicies,
+    storage: Storage,
+    /// Bytes of the cores dumped by the commands to upload for the post-mortems, if any.
+    core_bytes: Option<u64>,
+    /// Cgroup v2 to confine the commands limited in memory under, if any.
+    cgroup_root: Option<PathBuf>,
+    /// Workspace of the run, mounted into the containers of the commands.
+    workspace: PathBuf,
+    /// Whether the commands not run in containers are refused.
+    require_container: bool,
+    warm_commands: HashMap<String, WarmConf>,
+    batch_commands: HashMap<String, BatchConf>,
+    composite_commands: HashMap<String, Vec<ResolvedStep>>,
+    container_commands: HashMap<String, ContainerConf>,
+    /// Secret the approvals are verified against, without which the runs requiring them are
+    /// refused.
+    approval: Option<HmacAuth>,
+    /// Id of the client the request is signed by, as verified by the auth middle, without
+    /// which the runs requiring approvals are refused.
+    submitter: Arc<OnceCell<String>>,
+}
+
+impl Execution {
+    async fn execute(&self, run_spec: RunRecipe) -> anyhow::Result<RunResponse> {
+        debug!("Running command with spec as:\n{:#?}", run_spec);
+
+        if approval::requires_approval(&run_spec.labels) {
+            let auth = match &self.approval {
+                Some(auth) => auth,
+                None => {
+                    return Ok(RunResponse::from_exc(
+                        "Refused run requiring approval by a worker verifying no approvals"
+                            .to_owned(),
+                    ))
+                }
+            };
+            let submitter = match self.submitter.get() {
+                Some(submitter) => submitter.as_str(),
+                None => {
+                    return Ok(RunResponse::from_exc(
+                        "Refused run requiring approval of a request not signed by its client"
+                            .to_owned(),
+                    ))
+                }
+            };
+            if let Err(reason) = self.wait_for_approval(auth, submitter).await {
+                debug!(
+                    "  task {} waiting for approval is given up: {}",
+                    self.task_id, reason
+                );
+                let mut response = RunResponse::from_status(ExitStatus::Unknown);
+                response.cancellation = Some(self.cancel(reason, false));
+                return Ok(response);
+            }
+        }
+
+        let _slot = match &self.fair_share {
+            Some(policy) => match self.wait_for_slot(policy, &run_spec).await {
+                Ok(slot) => Some(slot),
+                Err(reason) => {
+                    debug!(
+                        "  task {} waiting for a slot is given up: {}",
+                        self.task_id, reason
+                    );
+                    let mut response = RunResponse::from_status(ExitStatus::Unknown);
+                    response.cancellation = Some(self.cancel(reason, false));
+                    return Ok(response);
+                }
+            },
+            None => None,
+        };
+
+        if let Some(steps) = self.composite_commands.get(run_spec.command.as_str()) {
+            return self.execute_composite(steps, run_spec).await;
+        }
+        self.execute_step(run_spec, false).await
+    }
+
+    /// Run the steps of a composite command in turn, stopping at the first one which fails
+    /// or is cancelled, whose response is taken as the response of the whole.
+    ///
+    /// The timeout of the request applies to each step on its own, and so does the stdin,
+    /// which is fed to each step in full.
+    async fn execute_composite(
+        &self,
+        steps: &[ResolvedStep],
+        run_spec: RunRecipe,
+    ) -> anyhow::Result<RunResponse> {
+        let cwd = run_spec.cwd.as_deref().unwrap_or(".");
+        let mut response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
+        for (i, step) in steps.iter().enumerate() {
+            let step_spec = RunRecipe {
+                command: step.path.clone(),
+                args: step.expand_args(&run_spec.args, cwd)?,
+                cwd: run_spec.cwd.clone(),
+                env: run_spec.env.clone(),
+                stdin: run_spec.stdin.clone(),
+                stdout: run_spec.stdout.clone(),
+                stderr: run_spec.stderr.clone(),
+                priority: run_spec.priority,
+                timeout: run_spec.timeout,
+                precheck: None,
+                labels: run_spec.labels.clone(),
+                input_bytes: None,
+                input_sizes: HashMap::new(),
+                archive: run_spec.archive,
+                preserve: run_spec.preserve.clone(),
+                input_attrs: run_spec.input_attrs.clone(),
+                enqueued_at: run_spec.enqueued_at,
+                limits: run_spec.limits,
+                features: run_spec.features.clone(),
+                required_features: run_spec.required_features.clone(),
+                delta: run_spec.delta,
+                output_digests: run_spec.output_digests.clone(),
+                compression: run_spec.compression,
+                encryption_keys: run_spec.encryption_keys.clone(),
+                dry_run: false,
+            };
+            debug!("  step {}/{}: {}", i + 1, steps.len(), step.path);
+            let retries = response.retries;
+            response = self.execute_step(step_spec, i > 0).await?;
+            response.retries += retries;
+            if !response.status.success() || response.cancellation.is_some() {
+                debug!("  stop at step {} which failed", i + 1);
+                break;
+            }
+        }
+        Ok(response)
+    }
+
+    /// Run a single command, appending its outputs to the redirected files if `append`.
+    async fn execute_step(&self, run_spec: RunRecipe, append: bool) -> anyhow::Result<RunResponse> {
+        let container = self
+            .container_commands
+            .get(run_spec.command.as_str())
+            .map(|conf| Container::new(conf.clone(), &self.task_id, run_spec.limits));
+        if container.is_none() && self.require_container {
+            let status = ExitStatus::SpawnFailed {
+                reason: format!("refused to run {} outside a container", run_spec.command),
+            };
+            debug!("  finished with status {:?}", status);
+            return Ok(RunResponse::from_status(status));
+        }
+
+        // the processes shared by the warm or batched runs have no stdin of a run to read,
+        // nor are they sandboxed
+        if run_spec.stdin.is_none() && container.is_none() {
+            if let Some(conf) = self.warm_commands.get(run_spec.command.as_str()) {
+                return self.execute_warm(*conf, run_spec, append).await;
+            }
+            if let Some(conf) = self.batch_commands.get(run_spec.command.as_str()) {
+                return self.execute_batched(*conf, run_spec, append).await;
+            }
+        }
+
+        let mut registration = self.preemption.map(|policy| {
+            Registration::register(
+                self.task_id.as_str(),
+                run_spec.command.as_str(),
+                run_spec.priority,
+                policy,
+            )
+        });
+
+        let deadline = run_spec.timeout.map(|timeout| Instant::now() + timeout);
+        let mut preempted = false;
+        let mut retries = 0;
+        loop {
+            if let Some(registration) = registration.as_mut() {
+                if registration.is_held() {
+                    debug!(
+                        "  task {} is held by more urgent tasks, wait...",
+                        self.task_id
+                    );
+                }
+                let reason = loop {
+                    tokio::select! {
+                        _ = registration.released() => break None,
+                        _ = tokio::time::sleep(REVOKE_POLL_INTERVAL) => {
+                            if let Some(reason) = self.cancel_reason(deadline).await {
+                                break Some(reason);
+                            }
+                        }
+                    }
+                };
+                if let Some(mut reason) = reason {
+                    // the work of a preempted run was lost to the preemption in the first place
+                    if preempted && reason == CancelReason::Shutdown {
+                        reason = CancelReason::Preemption;
+                    }
+                    debug!("  held task {} is given up: {}", self.task_id, reason);
+                    let mut response = RunResponse::from_status(ExitStatus::Unknown);
+                    response.cancellation = Some(self.cancel(reason, true));
+                    response.retries = retries;
+                    return Ok(response);
+                }
+            }
+
+            // the outputs are piped through the worker, so that they can be streamed live
+            let stdout_sink = output_sink(
+                run_spec.stdout.as_ref(),
+                append,
+                Box::new(tokio::io::stdout()),
+            )?;
+            let stderr_sink = output_sink(
+                run_spec.stderr.as_ref(),
+                append,
+                Box::new(tokio::io::stderr()),
+            )?;
+            let stdin_source = input_source(run_spec.stdin.as_ref()).await?;
+
+            // the limits of a containerized command are enforced by the runtime
+            let limits = run_spec.limits.filter(|_| container.is_none());
+            let confinement = match limits.map(|limits| {
+                Confinement::prepare(limits, self.cgroup_root.as_deref(), &self.task_id)
+            }) {
+                Some(Ok(confinement)) => Some(confinement),
+                Some(Err(err)) => {
+                    let status = ExitStatus::SpawnFailed {
+                        reason: format!("failed to confine the command: {}", err),
+                    };
+                    debug!("  finished with status {:?}", status);
+                    return Ok(RunResponse::from_status(status));
+                }
+                None => None,
+            };
+            let (program, args) = match (&container, &confinement) {
+                (Some(container), _) => container.wrap(
+                    run_spec.command.as_str(),
+                    &run_spec.args,
+                    &self.workspace,
+                    run_spec.cwd.as_deref(),
+                    &run_spec.env.clone().unwrap_or_default(),
+                    stdin_source.is_some(),
+                ),
+                (None, Some(confinement)) => {
+                    confinement.wrap(run_spec.command.as_str(), &run_spec.args)
+                }
+                (None, None) => (run_spec.command.clone(), run_spec.args.clone()),
+            };
+
+            let mut command = tokio::process::Command::new(program);
+            // the children of the command are supervised as one group together with it
+            #[cfg(unix)]
+            if self.group_grace.is_some() {
+                command.process_group(0);
+            }
+            let mut child = match command
+                .args(&args)
+                .stdin(if stdin_source.is_some() {
+                    Stdio::piped()
+                } else {
+                    Stdio::inherit()
+                })
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .current_dir(run_spec.cwd.as_deref().unwrap_or("."))
+                .envs(run_spec.env.clone().unwrap_or_default())
+                .spawn()
+            {
+                Ok(child) => child,
+                Err(err) => {
+                    let status = ExitStatus::SpawnFailed {
+                        reason: err.to_string(),
+                    };
+                    debug!("  finished with status {:?}", status);
+                    return Ok(RunResponse::from_status(status));
+                }
+            };
+
+            let sampling = Sampling::start(child.id());
+            let watch = Watch::start(child.id());
+            if let (Some(source), Some(sink)) = (stdin_source, child.stdin.take()) {
+                spawn_feed(source, sink);
+            }
+
+            let pumps = [
+                child.stdout.take().map(|source| {
+                    spawn_pump(
+                        &self.outputs,
+                        &self.task_id,
+                        StreamKind::Stdout,
+                        source,
+                        stdout_sink,
+                    )
+                }),
+                child.stderr.take().map(|source| {
+                    spawn_pump(
+                        &self.outputs,
+                        &self.task_id,
+                        StreamKind::Stderr,
+                        source,
+                        stderr_sink,
+                    )
+                }),
+            ];
+
+            let pgid = child.id().filter(|_| self.group_grace.is_some());
+            let mut requeued = false;
+            let mut cancelled = None;
+            let mut kill_at = None;
+            let st = loop {
+                let grace_left = kill_at.map_or(Duration::ZERO, |kill_at: Instant| {
+                    kill_at.saturating_duration_since(Instant::now())
+                });
+                tokio::select! {
+                    st = child.wait() => break st,
+                    _ = tokio::time::sleep(REVOKE_POLL_INTERVAL), if cancelled.is_none() => {
+                        if let Some(reason) = self.cancel_reason(deadline).await {
+                            debug!("  task {} is {}, terminate the command", self.task_id, reason);
+                            cancelled = Some(reason);
+                            if self.terminate(&child, pgid) {
+                                kill_at = Some(Instant::now() + self.kill_grace);
+                            } else {
+                                child.kill().await.unwrap_or_default();
+                            }
+                        }
+                    }
+                    _ = tokio::time::sleep(grace_left), if kill_at.is_some() => {
+                        debug!("  task {} outlived the grace, kill the command", self.task_id);
+                        kill_at = None;
+                        child.kill().await.unwrap_or_default();
+                    }
+                    held = held_changed(registration.as_mut()) => {
+                        let mode = self.preemption.map(|policy| policy.mode);
+                        match (mode, held, child.id()) {
+                            (Some(PreemptionMode::Suspend), held, Some(pid)) => {
+                                let sig = if held { "STOP" } else { "CONT" };
+                                debug!("  send SIG{} to the command of task {}", sig, self.task_id);
+                                let res = match pgid {
+                                    Some(pgid) => process_group::signal_group(pgid, sig),
+                                    None => signal(pid, sig),
+                                };
+                                res.unwrap_or_else(|err| {
+                                    warn!("  failed to send SIG{}: {}", sig, err)
+                                });
+                            }
+                            (Some(PreemptionMode::Requeue), true, _) => {
+                                debug!("  task {} is preempted, kill the command", self.task_id);
+                                requeued = true;
+                                child.kill().await.unwrap_or_default();
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            };
+            let usage = sampling.finish();
+            // killing the client of the runtime leaves the container running
+            if let (Some(container), true) = (&container, cancelled.is_some() || requeued) {
+                container.kill().await;
+            }
+
+            // the outputs are only complete once the processes left behind have exited too
+            if let (Some(pgid), Some(grace)) = (pgid, self.group_grace) {
+                let grace = if cancelled.is_some() || requeued {
+                    Duration::ZERO
+                } else {
+                    grace
+                };
+                if process_group::reap(pgid, grace).await {
+                    warn!("  killed the processes left by task {}", self.task_id);
+                }
+            }
+
+            for pump in pumps.into_iter().flatten() {
+                pump.await?
+                    .unwrap_or_else(|err| warn!("  failed to collect the output: {}", err));
+            }
+
+            // revoked right before the command finished by itself
+            if cancelled.is_none() && self.is_revoked().await {
+                cancelled = Some(CancelReason::ClientCancel);
+            }
+            if requeued && cancelled.is_none() {
+                debug!("  run task {} again once released", self.task_id);
+                preempted = true;
+                continue;
+            }
+
+            let mut status = ExitStatus::from(st?);
+            // killed by the worker, rather than by whatever the signal would tell
+            if cancelled == Some(CancelReason::Deadline) {
+                status = ExitStatus::TimedOut;
+            }
+            debug!("  finished with status {:?}", status);
+            let cwd = Path::new(run_spec.cwd.as_deref().unwrap_or("."));
+            let mut postmortem = watch.finish(&status, cwd).filter(|_| cancelled.is_none());
+            let limit_exceeded = match (&container, &confinement) {
+                (Some(container), _) => container.exceeded(&status),
+                (None, Some(confinement)) => confinement.exceeded(&status, usage.cpu_ms),
+                (None, None) => None,
+            }
+            .filter(|_| cancelled.is_none());
+            // exceeding a limit again is all a retry would do
+            let class = classify_status(&status, None)
+                .filter(|_| cancelled.is_none() && limit_exceeded.is_none());
+            if let Some(class) = class {
+                if let Some(backoff) = self.retry.policy(class).backoff(retries) {
+                    warn!(
+                        "  task {} failed by {}, run again in {:?}",
+                        self.task_id, class, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    retries += 1;
+                    continue;
+                }
+            }
+            if let (Some(postmortem), Some(core_bytes)) = (postmortem.as_mut(), self.core_bytes) {
+                postmortem
+                    .attach_core(self.storage.clone(), self.task_id.as_str(), core_bytes)
+                    .await
+                    .unwrap_or_else(|err| warn!("  failed to upload the core: {}", err));
+            }
+            let mut response = RunResponse::from_status(status);
+            // the command has been reaped by now, killed or not
+            response.cancellation = cancelled.map(|reason| self.cancel(reason, true));
+            response.usage = Some(usage);
+            response.postmortem = postmortem;
+            response.retries = retries;
+            if let Some(limit) = limit_exceeded {
+                debug!("  killed: {}", limit);
+                response.limit_exceeded = Some(limit);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Run the command by a warm daemon, which is neither preempted nor killed on revoking,
+    /// as the commands kept warm are expected to be short.
+    async fn execute_warm(
+        &self,
+        conf: WarmConf,
+        run_spec: RunRecipe,
+        append: bool,
+    ) -> anyhow::Result<RunResponse> {
+        let pool = warm::pool(run_spec.command.as_str(), conf);
+        let response = match pool.run(&run_spec).await {
+            Ok(response) => response,
+            Err(err) => {
+                let status = ExitStatus::SpawnFailed {
+                    reason: err.to_string(),
+                };
+                debug!("  finished with status {:?}", status);
+                return Ok(RunResponse::from_status(status));
+            }
+        };
+
+        let status = ExitStatus::Exited {
+            code: response.code,
+        };
+        self.finish_buffered(
+            &run_spec,
+            status,
+            response.stdout.as_bytes(),
+            response.stderr.as_bytes(),
+            append,
+        )
+        .await
+    }
+
+    /// Run the command together with the other requests of the same command arriving at
+    /// about the same time, which is neither preempted nor killed on revoking.
+    async fn execute_batched(
+        &self,
+        conf: BatchConf,
+        run_spec: RunRecipe,
+        append: bool,
+    ) -> anyhow::Result<RunResponse> {
+        let share = batch::run_batched(conf, &run_spec).await?;
+        self.finish_buffered(
+            &run_spec,
+            share.status,
+            share.stdout.as_slice(),
+            share.stderr.as_slice(),
+            append,
+        )
+        .await
+    }
+
+    /// Finish a run whose outputs were buffered rather than streamed.
+    async fn finish_buffered(
+        &self,
+        run_spec: &RunRecipe,
+        status: ExitStatus,
+        stdout: &[u8],
+        stderr: &[u8],
+        append: bool,
+    ) -> anyhow::Result<RunResponse> {
+        let stdout_sink = output_sink(
+            run_spec.stdout.as_ref(),
+            append,
+            Box::new(tokio::io::stdout()),
+        )?;
+        let stderr_sink = output_sink(
+            run_spec.stderr.as_ref(),
+            append,
+            Box::new(tokio::io::stderr()),
+        )?;
+        let task_id = self.task_id.as_str();
+        self.outputs
+            .pump(task_id, StreamKind::Stdout, stdout, stdout_sink)
+            .await?;
+        self.outputs
+            .pump(task_id, StreamKind::Stderr, stderr, stderr_sink)
+            .await?;
+
+        debug!("  finished with status {:?}", status);
+        let mut response = RunResponse::from_status(status);
+        if self.is_revoked().await {
+            response.cancellation = Some(self.cancel(CancelReason::ClientCancel, true));
+        }
+        Ok(response)
+    }
+
+    /// Why the run should be given up now, if it should.
+    async fn cancel_reason(&self, deadline: Option<Instant>) -> Option<CancelReason> {
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            Some(CancelReason::Shutdown)
+        } else if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+            Some(CancelReason::Deadline)
+        } else if self.is_revoked().await {
+            Some(CancelReason::ClientCancel)
+        } else {
+            None
+        }
+    }
+
+    /// Park the run until approved by an operator, unless it is cancelled meanwhile, which is
+    /// not timed out by waiting, just as in the queue.
+    async fn wait_for_approval(
+        &self,
+        auth: &HmacAuth,
+        submitter: &str,
+    ) -> Result<(), CancelReason> {
+        let task_id = self.task_id.as_str();
+        if let Err(err) = self.history.parked(task_id).await {
+            warn!(
+                "  failed to record the parking of task {}: {}",
+                task_id, err
+            );
+        }
+        info!("Task {} is parked until approved", task_id);
+        loop {
+            let approved = match self.history.get(task_id).await {
+                Ok(Some(record)) => approval::is_approved(auth, &record, submitter).await,
+                Ok(None) => Ok(false),
+                Err(err) => Err(err),
+            };
+            match approved {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(err) => warn!(
+                    "  failed to check the approval of task {}: {}",
+                    task_id, err
+                ),
+            }
+            if let Some(reason) = self.cancel_reason(None).await {
+                return Err(reason);
+            }
+            tokio::time::sleep(REVOKE_POLL_INTERVAL).await;
+        }
+        info!("Task {} is approved, run it", task_id);
+        if let Err(err) = self.history.resumed(task_id).await {
+            warn!(
+                "  failed to record the resuming of task {}: {}",
+                task_id, err
+            );
+        }
+        Ok(())
+    }
+
+    /// The tenant of the run by the label of the policy, or else the client signing it.
+    fn tenant(&self, policy: &FairSharePolicy, run_spec: &RunRecipe) -> String {
+        let label = policy
+            .label
+            .as_ref()
+            .and_then(|label| run_spec.labels.get(label));
+        match label {
+            Some(tenant) => tenant.clone(),
+            // the client recorded in the history is not verified, while the signer is
+            None => self
+                .submitter
+                .get()
+                .cloned()
+                .unwrap_or_else(|| ANONYMOUS_TENANT.to_owned()),
+        }
+    }
+
+    /// Wait for the turn of the tenant of the run to take a slot of the worker, unless the run
+    /// is cancelled meanwhile, which is not timed out by waiting, just as in the queue.
+    async fn wait_for_slot(
+        &self,
+        policy: &FairSharePolicy,
+        run_spec: &RunRecipe,
+    ) -> Result<Slot, CancelReason> {
+        let tenant = self.tenant(policy, run_spec);
+        let acquire = Slot::acquire(tenant.as_str(), run_spec.priority, policy);
+        tokio::pin!(acquire);
+        loop {
+            tokio::select! {
+                slot = &mut acquire => return Ok(slot),
+                _ = tokio::time::sleep(REVOKE_POLL_INTERVAL) => {
+                    if let Some(reason) = self.cancel_reason(None).await {
+                        return Err(reason);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ask the command, and the group led by it if supervised, to exit by SIGTERM, so that it
+    /// can still flush the outputs it has made, returning false if not asked.
+    fn terminate(&self, child: &Child, pgid: Option<u32>) -> bool {
+        let res = match (pgid, child.id()) {
+            _ if self.kill_grace.is_zero() => return false,
+            (Some(pgid), _) => process_group::signal_group(pgid, "TERM"),
+            (None, Some(pid)) => signal(pid, "TERM"),
+            (None, None) => return false,
+        };
+        res.map_err(|err| warn!("  failed to send SIGTERM: {}", err))
+            .is_ok()
+    }
+
+    /// Mark the run as cancelled, so that its outputs are discarded rather than committed,
+    /// except for a run timed out, whose partial outputs are committed for the inspection.
+    fn cancel(&self, reason: CancelReason, child_killed: bool) -> Cancellation {
+        if reason != CancelReason::Deadline {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+        Cancellation::new(reason, child_killed)
+    }
+
+    async fn is_revoked(&self) -> bool {
+        self.history
+            .is_revoked(self.task_id.as_str())
+            .await
+            .unwrap_or(false)
+    }
+}
+
+/// Wait until the registration is held or released, or forever if not registered.
+async fn held_changed(registration: Option<&mut Registration>) -> bool {
+    match registration {
+        Some(registration) => registration.changed().await,
+        None => std::future::pending().await,
+    }
+}
 
 pub struct Server {
     conf: CmdProxyServerConf,
+    auth: Arc<dyn AuthMiddle>,
+    pre_processors: HashMap<String, Arc<dyn PreProcessor>>,
+    post_processors: Vec<Arc<dyn PostProcessor>>,
 }
 
 impl Server {
-    pub(crate) async fn new(conf: CmdProxyServerConf) -> Server {
-        Server { conf }
+    pub(crate) async fn new(conf: CmdProxyServerConf, auth: Arc<dyn AuthMiddle>) -> Server {
+        Server {
+            conf,
+            auth,
+            pre_processors: HashMap::new(),
+            post_processors: Vec::new(),
+        }
     }
 
-    pub(crate) async fn run(self, serialized_run_request: String) -> String {
-        let workspace = tempdir().unwrap();
-        let bucket = self.conf.cloud.grid_fs().await;
-
-        let real_run = |run_spec: RunRecipe| async move {
-            debug!("Running command with spec as:\n{:#?}", run_spec);
-
-            let stdout = run_spec
-                .stdout
-                .as_ref()
-                .map(|path| Stdio::from(File::create(path).unwrap()))
-                .unwrap_or_else(Stdio::inherit);
-            let stderr = run_spec
-                .stderr
-                .as_ref()
-                .map(|path| Stdio::from(File::create(path).unwrap()))
-                .unwrap_or_else(Stdio::inherit);
-
-            let mut command = std::process::Command::new(run_spec.command);
-            let st = command
-                .args(&run_spec.args)
-                .stdout(stdout)
-                .stderr(stderr)
-                .current_dir(run_spec.cwd.unwrap_or_else(|| ".".to_owned()))
-                .envs(run_spec.env.unwrap_or_default())
-                .status();
-
-            let return_code = st?.code().unwrap_or(0);
-            debug!("  returned with code {return_code}");
-            Ok(RunResponse {
-                return_code,
-                exc: None,
-            })
+    /// Register the pre-processors by name, to be enabled per queue in the config.
+    pub(crate) fn with_pre_processors(
+        mut self,
+        processors: HashMap<String, Arc<dyn PreProcessor>>,
+    ) -> Server {
+        self.pre_processors = processors;
+        self
+    }
+
+    pub(crate) fn with_post_processors(
+        mut self,
+        processors: Vec<Arc<dyn PostProcessor>>,
+    ) -> Server {
+        self.post_processors = processors;
+        self
+    }
+
+    pub(crate) async fn run(self, task_id: String, serialized_run_request: String) -> String {
+        let history = match self.conf.cloud.tasks().await {
+            Ok(history) => history,
+            Err(err) => return serde_json::to_string(&RunResponse::from_error(&err)).unwrap(),
+        };
+        let worker = match hostname::get() {
+            Ok(hostname) => hostname.to_string_lossy().into_owned(),
+            Err(err) => {
+                // keeping the io error as the cause, by which the run is retried elsewhere
+                let context = format!("Failed to get the hostname: {}", err);
+                let err = anyhow::Error::from(err).context(context);
+                return serde_json::to_string(&RunResponse::from_error(&err)).unwrap();
+            }
+        };
+        match history.started(task_id.as_str(), worker.as_str()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Task {} has been revoked, skip it", task_id);
+                let mut response = RunResponse::from_exc("Task has been revoked".to_owned());
+                // nothing has been done for the task yet
+                response.cancellation = Some(Cancellation {
+                    reason: CancelReason::ClientCancel,
+                    child_killed: true,
+                    outputs_discarded: true,
+                    inputs_released: true,
+                });
+                return serde_json::to_string(&response).unwrap();
+            }
+            Err(err) => warn!("Failed to record the start of task {}: {}", task_id, err),
+        }
+
+        let workspace = match workspace::create(&self.conf.workspace_root(), task_id.as_str()) {
+            Ok(workspace) => workspace,
+            Err(err) => {
+                let context = format!("Failed to create the workspace: {}", err);
+                let err = anyhow::Error::from(err).context(context);
+                return serde_json::to_string(&RunResponse::from_error(&err)).unwrap();
+            }
+        };
+        let storage = match self.conf.cloud.storage().await {
+            Ok(storage) => storage,
+            Err(err) => return serde_json::to_string(&RunResponse::from_error(&err)).unwrap(),
+        };
+        let outputs = match self.conf.cloud.outputs().await {
+            Ok(outputs) => outputs,
+            Err(err) => return serde_json::to_string(&RunResponse::from_error(&err)).unwrap(),
         };
 
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let submitter = Arc::new(OnceCell::new());
+        let execution = Execution {
+            task_id: task_id.clone(),
+            history: history.clone(),
+            outputs,
+            cancelled: cancelled.clone(),
+            preemption: self.conf.preemption,
+            group_grace: self.conf.group_grace.map(Duration::from_secs),
+            kill_grace: self
+                .conf
+                .kill_grace
+                .map_or(DEFAULT_KILL_GRACE, Duration::from_secs),
+            fair_share: self.conf.fair_share.clone(),
+            retry: self.conf.retry.clone(),
+            storage: storage.clone(),
+            core_bytes: self.conf.core_bytes,
+            cgroup_root: self.conf.cgroup_root.clone(),
+            workspace: workspace.path().to_owned(),
+            require_container: self.conf.require_container,
+            warm_commands: self.conf.warm_commands(),
+            batch_commands: self.conf.batch_commands(),
+            composite_commands: self.conf.composite_commands(),
+            container_commands: self.conf.container_commands(),
+            approval: self.conf.approval_signing.clone(),
+            submitter: submitter.clone(),
+        };
+        let pre_processors_of = {
+            let palette = self.conf.command_palette();
+            let celery = self.conf.celery.clone();
+            let enabled = self.conf.pre_processors.clone();
+            let registered = self.pre_processors;
+            move |command: &str| {
+                enabled_pre_processors(
+                    command,
+                    &palette,
+                    |name| celery.queue(name),
+                    &enabled,
+                    &registered,
+                )
+            }
+        };
+        let workspace_path = workspace.path().to_owned();
+        let post_processors = self.post_processors;
+        let real_run = |mut run_spec: RunRecipe| async move {
+            let pre_processors = match pre_processors_of(run_spec.command.as_str()) {
+                Ok(pre_processors) => pre_processors,
+                Err(err) => return Ok(RunResponse::from_exc(err.to_string())),
+            };
+            if let Err(err) = pre_process(&pre_processors, &mut run_spec).await {
+                debug!("  {}", err);
+                return Ok(RunResponse::from_exc(err.to_string()));
+            }
+            if run_spec.dry_run {
+                debug!("  dry run, report the recipe instead of running it");
+                return Ok(RunResponse {
+                    recipe: Some(run_spec),
+                    ..RunResponse::from_status(ExitStatus::Unknown)
+                });
+            }
+            let recorder = RecordMode::of(&run_spec.labels)
+                .map(|mode| Recorder::start(mode, &workspace_path))
+                .transpose()
+                .unwrap_or_else(|err| {
+                    warn!("  failed to record the inputs: {}", err);
+                    None
+                });
+            let mut response = execution.execute(run_spec.clone()).await;
+            if let Ok(response) = response.as_mut() {
+                post_process(&post_processors, &workspace_path, &run_spec, response).await;
+                if let Some(recorder) = recorder {
+                    let storage = execution.storage.clone();
+                    let task_id = execution.task_id.as_str();
+                    response.recording_url = recorder
+                        .finish(storage, task_id, &run_spec, &response.status)
+                        .await
+                        .unwrap_or_else(|err| {
+                            warn!("  failed to record the run: {}", err);
+                            None
+                        });
+                }
+            }
+            response
+        };
+
+        let transfer = self.conf.transfer.as_ref().map(|transfer| {
+            Arc::new(RemoteTransfer {
+                app: SERVER_APP.get().unwrap().clone(),
+                queue: self.conf.celery.queue(transfer.queue.as_str()),
+                auth: self.auth.clone(),
+            }) as Arc<dyn Transfer>
+        });
         let conf = invoke::server_end::Config {
-            command_palette: self.conf.command_palette,
+            command_palette: self.conf.command_palette(),
+            transfer,
+            retry: self.conf.retry.policy(FailureClass::Transfer),
+            cancelled,
+            task_id: task_id.clone(),
+            admission: self.conf.admission,
+            command_policy: self.conf.command_policy.clone(),
+            preserve_ownership: self.conf.preserve_ownership,
         };
         let res = apply_middles!(
             serialized_run_request,
-            >=< [ serde::server_end::MiddleImpl::new() ]
-            >=< [ invoke::server_end::MiddleImpl::new(bucket, workspace, conf) ]
+            >=< [ auth::server_end::MiddleImpl::new(self.auth).with_signer(submitter) ]
+            >=< [ serde::server_end::MiddleImpl::new(storage.clone(), self.conf.payload_limits) ]
+            >=< [ invoke::server_end::MiddleImpl::new(storage, workspace, conf) ]
             >>= real_run
         );
-        res.expect("Unreachable: please embedding all the errors into serialization!")
+        // errors raised before the serde middle, such as an authentication failure, still
+        // need to be reported as a response
+        let serialized_response = res
+            .unwrap_or_else(|err| serde_json::to_string(&RunResponse::from_error(&err)).unwrap());
+
+        // a spilled response is not parsable here, which only loses the status in history
+        let response = serde_json::from_str::<RunResponse>(serialized_response.as_str()).ok();
+        history
+            .finished(
+                task_id.as_str(),
+                response.as_ref().map(|response| &response.status),
+                response
+                    .as_ref()
+                    .and_then(|response| response.exc.as_deref()),
+                response
+                    .as_ref()
+                    .map(|response| response.artifacts.as_slice())
+                    .unwrap_or_default(),
+                response
+                    .as_ref()
+                    .and_then(|response| response.usage.as_ref()),
+            )
+            .await
+            .unwrap_or_else(|err| warn!("Failed to record the end of task {}: {}", task_id, err));
+        if let Some(policy) = &self.conf.sla {
+            sla::observe(policy, worker.as_str(), task_id.as_str(), response.as_ref());
+        }
+
+        serialized_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chain_ext::mongodb_gridfs::DatabaseExt;
+    use tempfile::tempdir;
+    use test_utilities::docker;
+
+    use crate::retry::RetryPolicy;
+    use crate::storage::GridFsStorage;
+
+    use super::*;
+
+    fn execution(db: &mongodb::Database, workspace: &Path) -> Execution {
+        Execution {
+            task_id: "task".to_owned(),
+            history: TaskHistory::new(db.collection("tasks")),
+            outputs: OutputStreams::new(db.collection("outputs")),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            preemption: None,
+            group_grace: None,
+            kill_grace: Duration::from_secs(1),
+            fair_share: None,
+            retry: RetryPolicies::never(),
+            storage: GridFsStorage::shared(db.bucket(None)),
+            core_bytes: None,
+            cgroup_root: None,
+            workspace: workspace.to_owned(),
+            require_container: false,
+            warm_commands: HashMap::new(),
+            batch_commands: HashMap::new(),
+            composite_commands: HashMap::new(),
+            container_commands: HashMap::new(),
+            approval: None,
+            submitter: Arc::new(OnceCell::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_counts_retries() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-execute_retries")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+
+        let db = mongodb::Client::with_uri_str(container.url())
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db");
+        let workspace = tempdir().unwrap();
+        let retry = RetryPolicies::never().with(
+            FailureClass::Command,
+            RetryPolicy {
+                max_retries: 2,
+                backoff: Duration::ZERO,
+            },
+        );
+        let execution = Execution {
+            retry,
+            ..execution(&db, workspace.path())
+        };
+
+        let failing = RunRecipe::builder()
+            .command("/bin/sh".to_owned())
+            .args(vec!["-c".to_owned(), "exit 3".to_owned()])
+            .build();
+        let response = execution.execute_step(failing, false).await.unwrap();
+        assert_eq!(response.status, ExitStatus::Exited { code: 3 });
+        assert_eq!(response.retries, 2);
+
+        let succeeding = RunRecipe::builder()
+            .command("/bin/sh".to_owned())
+            .args(vec!["-c".to_owned(), "exit 0".to_owned()])
+            .build();
+        let response = execution.execute_step(succeeding, false).await.unwrap();
+        assert_eq!(response.retries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_of_signer() {
+        // never connected to
+        let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap()
+            .database("cmdproxy-test-db");
+        let workspace = tempdir().unwrap();
+        let execution = execution(&db, workspace.path());
+        let policy = FairSharePolicy {
+            slots: 1,
+            label: Some("team".to_owned()),
+            weights: HashMap::new(),
+        };
+        let run_spec = |labels: &[(&str, &str)]| {
+            let mut run_spec = RunRecipe::builder()
+                .command("/bin/true".to_owned())
+                .args(vec![])
+                .build();
+            run_spec.labels = labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            run_spec
+        };
+
+        // by the label, or else by the signer, whatever the client recorded claims
+        let labeled = run_spec(&[("team", "infra")]);
+        assert_eq!(execution.tenant(&policy, &labeled), "infra");
+        let unlabeled = run_spec(&[]);
+        assert_eq!(execution.tenant(&policy, &unlabeled), ANONYMOUS_TENANT);
+        execution.submitter.set("ci".to_owned()).unwrap();
+        assert_eq!(execution.tenant(&policy, &unlabeled), "ci");
     }
 }
@@ -0,0 +1,258 @@
+//! Service level thresholds watched by a worker, alerting the operators as soon as the runs
+//! it serves degrade, rather than once the users complain.
+//!
+//! Two thresholds are watched, each only if given: how long a request may wait in the queue
+//! before picked, alerted per run exceeding it, and the share of the runs which may fail among
+//! the latest ones, alerted once the window turns over it and again only after it has
+//! recovered. An alert is always logged and counted in [`crate::metrics::sla_alerts`], which
+//! is advertised in the registry of the workers, and posted in json to the webhook if any.
+//! The waits are as accurate as the clocks of the clients and the workers are in sync.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::metrics;
+use crate::protocol::RunResponse;
+use crate::units;
+
+/// Time the worker waits for the webhook to take an alert.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which thresholds a worker watches, and where it alerts when exceeded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlaPolicy {
+    /// Seconds a request may wait in the queue before picked, or unlimited if not given.
+    #[serde(default, deserialize_with = "units::deserialize_opt_secs")]
+    pub max_queue_wait: Option<u64>,
+    /// Percent of the runs in the window which may fail, or unlimited if not given.
+    #[serde(default)]
+    pub max_failure_percent: Option<u32>,
+    /// Number of the latest runs the failure rate is taken over.
+    #[serde(default = "default_window")]
+    pub window: usize,
+    /// Url the alerts are posted to in json, besides being logged.
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_window() -> usize {
+    20
+}
+
+impl Default for SlaPolicy {
+    fn default() -> Self {
+        SlaPolicy {
+            max_queue_wait: None,
+            max_failure_percent: None,
+            window: default_window(),
+            webhook: None,
+        }
+    }
+}
+
+/// A threshold exceeded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SlaAlert {
+    /// The request of a task waited in the queue for longer than allowed.
+    QueueWait {
+        task_id: String,
+        waited_secs: u64,
+        max_secs: u64,
+    },
+    /// Too many of the latest runs failed.
+    FailureRate {
+        failures: usize,
+        runs: usize,
+        max_percent: u32,
+    },
+}
+
+impl fmt::Display for SlaAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlaAlert::QueueWait {
+                task_id,
+                waited_secs,
+                max_secs,
+            } => write!(
+                f,
+                "Task {} waited {}s in the queue, over {}s",
+                task_id, waited_secs, max_secs
+            ),
+            SlaAlert::FailureRate {
+                failures,
+                runs,
+                max_percent,
+            } => write!(
+                f,
+                "{} of the latest {} runs failed, over {}%",
+                failures, runs, max_percent
+            ),
+        }
+    }
+}
+
+/// Outcomes of the latest runs of this worker.
+#[derive(Debug, Default)]
+struct FailureWindow {
+    failed: VecDeque<bool>,
+    /// Whether the rate is over the max, alerted already.
+    breached: bool,
+}
+
+impl FailureWindow {
+    /// Count a run, and return the failures and the runs once the window turns over
+    /// `max_percent`, which is not alerted again until the rate has fallen back.
+    fn record(&mut self, failed: bool, window: usize, max_percent: u32) -> Option<(usize, usize)> {
+        let window = window.max(1);
+        self.failed.push_back(failed);
+        while self.failed.len() > window {
+            self.failed.pop_front();
+        }
+        // a rate over a few runs only is just noise
+        if self.failed.len() < window {
+            return None;
+        }
+        let failures = self.failed.iter().filter(|failed| **failed).count();
+        let over = failures * 100 > max_percent as usize * window;
+        let turned = over && !self.breached;
+        self.breached = over;
+        turned.then_some((failures, window))
+    }
+}
+
+static FAILURES: Lazy<Mutex<FailureWindow>> = Lazy::new(Mutex::default);
+
+/// The thresholds of the `policy` the run of the task exceeded, counting it in the window.
+///
+/// The runs without a parsable response, such as a spilled one, are not counted, nor are the
+/// dry runs, which run nothing.
+fn check(policy: &SlaPolicy, task_id: &str, response: Option<&RunResponse>) -> Vec<SlaAlert> {
+    let response = match response {
+        Some(response) if response.recipe.is_none() => response,
+        _ => return Vec::new(),
+    };
+    let mut alerts = Vec::new();
+    let waited = response.timing.and_then(|timing| timing.queue_wait());
+    if let (Some(waited), Some(max_secs)) = (waited, policy.max_queue_wait) {
+        if waited > Duration::from_secs(max_secs) {
+            alerts.push(SlaAlert::QueueWait {
+                task_id: task_id.to_owned(),
+                waited_secs: waited.as_secs(),
+                max_secs,
+            });
+        }
+    }
+    if let Some(max_percent) = policy.max_failure_percent {
+        let failed = response.exc.is_some() || !response.status.success();
+        let turned = FAILURES
+            .lock()
+            .unwrap()
+            .record(failed, policy.window, max_percent);
+        if let Some((failures, runs)) = turned {
+            alerts.push(SlaAlert::FailureRate {
+                failures,
+                runs,
+                max_percent,
+            });
+        }
+    }
+    alerts
+}
+
+/// Check the run of the task finished by the `worker` against the `policy`, and alert on
+/// every threshold exceeded. The webhook is posted to in the background, never holding the
+/// response back.
+pub(crate) fn observe(
+    policy: &SlaPolicy,
+    worker: &str,
+    task_id: &str,
+    response: Option<&RunResponse>,
+) {
+    for alert in check(policy, task_id, response) {
+        warn!("SLA alert: {}", alert);
+        metrics::add_sla_alert();
+        if let Some(webhook) = policy.webhook.clone() {
+            let body = json!({ "worker": worker, "alert": alert });
+            tokio::spawn(async move {
+                post(webhook.as_str(), &body)
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to post the SLA alert: {}", err));
+            });
+        }
+    }
+}
+
+async fn post(url: &str, body: &serde_json::Value) -> anyhow::Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(body)?)
+        .timeout(WEBHOOK_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{ExitStatus, RunTiming};
+
+    use super::*;
+
+    #[test]
+    fn test_failure_window() {
+        let mut window = FailureWindow::default();
+        assert_eq!(window.record(true, 4, 50), None);
+        assert_eq!(window.record(true, 4, 50), None);
+        assert_eq!(window.record(true, 4, 50), None);
+        assert_eq!(window.record(false, 4, 50), Some((3, 4)));
+        // alerted once only while breached
+        assert_eq!(window.record(true, 4, 50), None);
+        assert_eq!(window.record(false, 4, 50), None);
+        assert_eq!(window.record(false, 4, 50), None);
+        assert!(!window.breached);
+        assert_eq!(window.record(true, 4, 50), None);
+        assert_eq!(window.record(true, 4, 50), None);
+        assert_eq!(window.record(true, 4, 50), Some((3, 4)));
+    }
+
+    #[test]
+    fn test_check_queue_wait() {
+        let policy = SlaPolicy {
+            max_queue_wait: Some(60),
+            ..SlaPolicy::default()
+        };
+        let mut response = RunResponse::from_status(ExitStatus::Exited { code: 0 });
+        response.timing = Some(RunTiming {
+            enqueued_at: Some(1_000),
+            started_at: 91_000,
+            finished_at: 92_000,
+        });
+        assert_eq!(
+            check(&policy, "task", Some(&response)),
+            vec![SlaAlert::QueueWait {
+                task_id: "task".to_owned(),
+                waited_secs: 90,
+                max_secs: 60,
+            }]
+        );
+
+        response.timing = Some(RunTiming {
+            enqueued_at: Some(1_000),
+            started_at: 2_000,
+            finished_at: 92_000,
+        });
+        assert!(check(&policy, "task", Some(&response)).is_empty());
+        assert!(check(&policy, "task", None).is_empty());
+    }
+}
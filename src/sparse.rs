@@ -0,0 +1,158 @@
+//! Transfers of the sparse files, such as disk images and core dumps, keeping their holes.
+//!
+//! A sparse file is packed into its data alone before uploaded, headed by the map of where
+//! the data lies in the file, and unpacked by seeking over the holes when downloaded, so that
+//! the file takes the space of its data rather than of its logical size both on the storage
+//! and on the disk of the downloader. The map lives in the packed file rather than in the
+//! metadata, which some object stores cap at a few kilobytes.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Granularity of the holes, which is a multiple of the common filesystem block sizes.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Files smaller than this are transferred as is, holes or not.
+const MIN_SPARSE_SIZE: u64 = 1024 * 1024;
+
+const MAGIC: &[u8; 8] = b"CPSPARSE";
+
+/// Whether the file at `path` is large, and takes much less space on the disk than its size.
+#[cfg(unix)]
+pub(crate) fn is_sparse(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match path.metadata() {
+        // the blocks are counted in 512 bytes, no matter the block size of the filesystem
+        Ok(metadata) => {
+            metadata.is_file()
+                && metadata.len() >= MIN_SPARSE_SIZE
+                && metadata.blocks() * 512 < metadata.len() / 2
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_sparse(_path: &Path) -> bool {
+    false
+}
+
+/// Pack the data of the file at `src` into `dst`, headed by the map of the data.
+///
+/// The blocks of zeros are taken as holes, whether allocated or not.
+pub(crate) fn pack(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let length = src.metadata()?.len();
+    let mut extents: Vec<(u64, u64)> = vec![];
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = read_block(&mut reader, buffer.as_mut_slice())?;
+        if read == 0 {
+            break;
+        }
+        if buffer[..read].iter().any(|byte| *byte != 0) {
+            match extents.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += read as u64,
+                _ => extents.push((offset, read as u64)),
+            }
+        }
+        offset += read as u64;
+    }
+
+    let mut writer = BufWriter::new(File::create(dst)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(&(extents.len() as u64).to_le_bytes())?;
+    for (start, len) in &extents {
+        writer.write_all(&start.to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+    }
+    let mut reader = File::open(src)?;
+    for (start, len) in &extents {
+        reader.seek(SeekFrom::Start(*start))?;
+        let copied = std::io::copy(&mut (&mut reader).take(*len), &mut writer)?;
+        anyhow::ensure!(copied == *len, "File {} shrank while packed", src.display());
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Unpack the file packed by [`pack`] at `src` into `dst`, leaving holes between the data.
+pub(crate) fn unpack(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(
+        &magic == MAGIC,
+        "No packed sparse file at {}",
+        src.display()
+    );
+    let length = read_u64(&mut reader)?;
+    let count = read_u64(&mut reader)?;
+    let extents = (0..count)
+        .map(|_| Ok((read_u64(&mut reader)?, read_u64(&mut reader)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut writer = File::create(dst)?;
+    writer.set_len(length)?;
+    for (start, len) in extents {
+        writer.seek(SeekFrom::Start(start))?;
+        let copied = std::io::copy(&mut (&mut reader).take(len), &mut writer)?;
+        anyhow::ensure!(
+            copied == len,
+            "Packed sparse file {} is truncated",
+            src.display()
+        );
+    }
+    Ok(())
+}
+
+/// Fill `buffer` as much as the reader has, returning how much is read.
+fn read_block(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_unpack() {
+        let src = tempfile::NamedTempFile::new().unwrap();
+        {
+            let mut file = src.as_file();
+            file.set_len(8 * MIN_SPARSE_SIZE).unwrap();
+            file.seek(SeekFrom::Start(3 * MIN_SPARSE_SIZE + 7)).unwrap();
+            file.write_all(b"core").unwrap();
+            file.seek(SeekFrom::End(-4)).unwrap();
+            file.write_all(b"dump").unwrap();
+        }
+
+        let packed = tempfile::NamedTempFile::new().unwrap();
+        pack(src.path(), packed.path()).unwrap();
+        assert!(packed.path().metadata().unwrap().len() <= 2 * BLOCK_SIZE as u64 + 64);
+
+        let dst = tempfile::NamedTempFile::new().unwrap();
+        unpack(packed.path(), dst.path()).unwrap();
+        assert_eq!(
+            std::fs::read(dst.path()).unwrap(),
+            std::fs::read(src.path()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Files kept as the block blobs of a container of the Azure Blob Storage.
+
+use std::path::Path;
+use std::time::Duration;
+
+use celery::export::async_trait;
+use mongodb::bson::Document;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use tokio::io::AsyncWriteExt;
+
+use super::{checked, decode_metadata, encode_metadata, object_key, FileStorage};
+
+/// Version of the REST API the requests are made in.
+const API_VERSION: &str = "2021-08-06";
+
+const METADATA_HEADER: &str = "x-ms-meta-cmdproxy";
+
+/// How often to check a copy which the service has not finished when responding.
+const COPY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Store the files as blobs in a container, authorized by a shared access signature.
+pub struct AzureStorage {
+    http: reqwest::Client,
+    /// Url of the container, such as `https://<account>.blob.core.windows.net/<container>`.
+    container_url: String,
+    /// Shared access signature granting the access to the container, as a query string.
+    sas_token: String,
+    /// Folder of the blobs, isolating the namespaces sharing the container.
+    namespace: String,
+}
+
+impl AzureStorage {
+    pub fn new(account: &str, container: &str, sas_token: &str, namespace: &str) -> AzureStorage {
+        AzureStorage {
+            http: reqwest::Client::new(),
+            container_url: format!("https://{}.blob.core.windows.net/{}", account, container),
+            sas_token: sas_token.trim_start_matches('?').to_owned(),
+            namespace: namespace.to_owned(),
+        }
+    }
+
+    fn blob_url(&self, url: &str) -> String {
+        format!(
+            "{}/{}?{}",
+            self.container_url,
+            object_key(&self.namespace, url),
+            self.sas_token
+        )
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.http
+            .request(method, self.blob_url(url))
+            .header("x-ms-version", API_VERSION)
+    }
+
+    /// The response of the blob at `url`, or none if there is no such blob.
+    async fn head(&self, url: &str) -> anyhow::Result<Option<Response>> {
+        let response = self.request(Method::HEAD, url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(checked(url, response).await?))
+    }
+
+    async fn get(&self, url: &str) -> anyhow::Result<Response> {
+        let response = self.request(Method::GET, url).send().await?;
+        checked(url, response).await
+    }
+
+    fn put_blob(&self, url: &str, metadata: Option<Document>) -> anyhow::Result<RequestBuilder> {
+        let mut builder = self
+            .request(Method::PUT, url)
+            .header("x-ms-blob-type", "BlockBlob");
+        if let Some(metadata) = metadata {
+            builder = builder.header(METADATA_HEADER, encode_metadata(&metadata)?);
+        }
+        Ok(builder)
+    }
+}
+
+#[async_trait]
+impl FileStorage for AzureStorage {
+    fn backend(&self) -> &'static str {
+        "azure"
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(self.head(url).await?.is_some())
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let response = self
+            .put_blob(url, metadata)?
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(file)
+            .send()
+            .await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let mut response = self.get(url).await?;
+        let metadata = decode_metadata(&response, METADATA_HEADER)?;
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(metadata)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        match self.head(url).await? {
+            Some(response) => decode_metadata(&response, METADATA_HEADER),
+            None => anyhow::bail!("No file {} on the storage", url),
+        }
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        let response = self.request(Method::DELETE, url).send().await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        // blobs cannot be renamed, but copied within the account, which may finish later
+        let response = self
+            .request(Method::PUT, to)
+            .header("x-ms-copy-source", self.blob_url(from))
+            .send()
+            .await?;
+        let mut response = checked(to, response).await?;
+        loop {
+            match copy_status(&response).as_deref() {
+                Some("success") | None => break,
+                Some("pending") => tokio::time::sleep(COPY_POLL_INTERVAL).await,
+                Some(status) => anyhow::bail!("Failed to copy {} to {}: {}", from, to, status),
+            }
+            response = self
+                .head(to)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Copy of {} to {} has gone", from, to))?;
+        }
+        self.delete(from).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        Ok(self.get(url).await?.text().await?)
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        let response = self
+            .put_blob(url, None)?
+            .body(content.to_owned())
+            .send()
+            .await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+}
+
+fn copy_status(response: &Response) -> Option<String> {
+    let status = response.headers().get("x-ms-copy-status")?;
+    status.to_str().ok().map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use crate::storage;
+
+    use super::*;
+
+    #[test]
+    fn test_blob_url() {
+        let azure = AzureStorage::new("acct", "outputs", "?sv=2021&sig=abc", "");
+        assert_eq!(
+            azure.blob_url("@host:/out/a b.txt"),
+            "https://acct.blob.core.windows.net/outputs/%40host%3A/out/a%20b.txt?sv=2021&sig=abc"
+        );
+
+        // the namespaces are kept apart by their folders
+        let azure = AzureStorage::new("acct", "outputs", "sv=2021&sig=abc", "staging");
+        assert_eq!(
+            azure.blob_url("@host:/a.txt"),
+            "https://acct.blob.core.windows.net/outputs/staging/%40host%3A/a.txt?sv=2021&sig=abc"
+        );
+    }
+
+    #[test]
+    fn test_put_blob() {
+        let azure = AzureStorage::new("acct", "outputs", "sig=abc", "");
+        let request = azure
+            .put_blob("@host:/a.txt", Some(doc! { "task_id": "a" }))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.method(), Method::PUT);
+        let headers = request.headers();
+        assert_eq!(headers["x-ms-version"], API_VERSION);
+        assert_eq!(headers["x-ms-blob-type"], "BlockBlob");
+        assert!(headers.contains_key(METADATA_HEADER));
+
+        let request = azure
+            .put_blob("@host:/a.txt", None)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(!request.headers().contains_key(METADATA_HEADER));
+    }
+
+    #[test]
+    fn test_open_url() {
+        let error_of = |url: &str| storage::open(url, "").err().map(|err| err.to_string());
+        assert_eq!(
+            error_of("azure://acct"),
+            Some("Storage url azure://acct has no container".to_owned())
+        );
+        assert_eq!(
+            error_of("acct/outputs"),
+            Some("Storage url acct/outputs has no scheme".to_owned())
+        );
+        assert_eq!(
+            error_of("ftp://acct/outputs"),
+            Some("Unsupported storage url ftp://acct/outputs".to_owned())
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Transparent compression of the files transferred, such as the text inputs which compress
+//! by an order of magnitude, where the transfers dominate the runs.
+//!
+//! The uploads through a [`CompressingStorage`] given a [`Compression`] are compressed by its
+//! codec if no smaller than its threshold, tagged with the codec in the `content_encoding` of
+//! their metadata, and kept as is if they do not shrink. The downloads decompress the tagged
+//! files whether or not the downloader compresses its own uploads. The folders are compressed
+//! by their archives already, hence never compressed again.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use celery::export::async_trait;
+use log::debug;
+use mongodb::bson::Document;
+use serde::{Deserialize, Serialize};
+
+use crate::params::Param;
+use crate::storage::{FileStorage, Storage};
+use crate::units;
+
+/// Level of the zstd compression, which is zstd's default.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codec compressing the files transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// Tag of the metadata of the files compressed by this codec.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// The codec of the files tagged with `content_encoding`, if any.
+    pub fn from_content_encoding(content_encoding: &str) -> Option<Codec> {
+        [Codec::Gzip, Codec::Zstd]
+            .into_iter()
+            .find(|codec| codec.content_encoding() == content_encoding)
+    }
+
+    fn compress(self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        let mut src = File::open(src)?;
+        match self {
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(File::create(dst)?, flate2::Compression::fast());
+                std::io::copy(&mut src, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(File::create(dst)?, ZSTD_LEVEL)?;
+                std::io::copy(&mut src, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decompress(self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        let src = BufReader::new(File::open(src)?);
+        let mut dst = File::create(dst)?;
+        match self {
+            Codec::Gzip => std::io::copy(&mut flate2::bufread::GzDecoder::new(src), &mut dst)?,
+            Codec::Zstd => std::io::copy(&mut zstd::Decoder::with_buffer(src)?, &mut dst)?,
+        };
+        Ok(())
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.content_encoding())
+    }
+}
+
+/// How the files uploaded are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    pub codec: Codec,
+    /// Bytes of the smallest file compressed, below which a file is not worth it.
+    #[serde(
+        default = "default_min_size",
+        deserialize_with = "units::deserialize_size"
+    )]
+    pub min_size: u64,
+}
+
+fn default_min_size() -> u64 {
+    64 * 1024
+}
+
+impl Compression {
+    pub fn new(codec: Codec) -> Compression {
+        Compression {
+            codec,
+            min_size: default_min_size(),
+        }
+    }
+
+    pub fn with_min_size(self, min_size: u64) -> Compression {
+        Compression { min_size, ..self }
+    }
+}
+
+/// Compress the files uploaded through the inner storage, if given a compression, and
+/// decompress the compressed files downloaded.
+pub struct CompressingStorage {
+    inner: Storage,
+    compression: Option<Compression>,
+}
+
+impl CompressingStorage {
+    pub fn new(inner: Storage, compression: Option<Compression>) -> CompressingStorage {
+        CompressingStorage { inner, compression }
+    }
+
+    /// The codec to compress the file at `path` by, uploading to `url`.
+    fn codec_for(&self, url: &str, path: &Path, metadata: Option<&Document>) -> Option<Codec> {
+        let compression = self.compression?;
+        // the shared files are used in place by the peers, and the others are encoded already
+        let shared = Param::from_cloud_url(url).map_or(false, |param| param.is_shared());
+        let encoded = metadata.map_or(false, |metadata| {
+            metadata.contains_key("content_type") || metadata.contains_key("content_encoding")
+        });
+        let size = path.metadata().map_or(0, |metadata| metadata.len());
+        (!shared && !encoded && size > 0 && size >= compression.min_size)
+            .then_some(compression.codec)
+    }
+}
+
+#[async_trait]
+impl FileStorage for CompressingStorage {
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        self.inner.exists(url).await
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let codec = match self.codec_for(url, path, metadata.as_ref()) {
+            Some(codec) => codec,
+            None => return self.inner.upload(url, path, metadata).await,
+        };
+
+        let compressed = tempfile::NamedTempFile::new()?;
+        let (src, dst) = (path.to_path_buf(), compressed.path().to_path_buf());
+        tokio::task::spawn_blocking(move || codec.compress(&src, &dst)).await??;
+        let size = path.metadata()?.len();
+        let compressed_size = compressed.path().metadata()?.len();
+        if compressed_size >= size {
+            debug!("Upload {} as is, not shrunk by {}", url, codec);
+            return self.inner.upload(url, path, metadata).await;
+        }
+        debug!(
+            "Compressed {} from {} to {} bytes by {}",
+            url, size, compressed_size, codec
+        );
+        let mut metadata = metadata.unwrap_or_default();
+        metadata.insert("content_encoding", codec.content_encoding());
+        self.inner
+            .upload(url, compressed.path(), Some(metadata))
+            .await
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let mut metadata = self.inner.download(url, path).await?;
+        let codec = match metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get_str("content_encoding").ok())
+            .and_then(Codec::from_content_encoding)
+        {
+            Some(codec) => codec,
+            None => return Ok(metadata),
+        };
+
+        // aside the target, so that it is renamed within the same filesystem
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty());
+        let compressed = tempfile::NamedTempFile::new_in(parent.unwrap_or(Path::new(".")))?;
+        tokio::fs::rename(path, compressed.path()).await?;
+        let (src, dst) = (compressed.path().to_path_buf(), path.to_path_buf());
+        tokio::task::spawn_blocking(move || codec.decompress(&src, &dst)).await??;
+        if let Some(metadata) = metadata.as_mut() {
+            metadata.remove("content_encoding");
+        }
+        Ok(metadata)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        self.inner.metadata(url).await
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        self.inner.delete(url).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        self.inner.read_string(url).await
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        self.inner.write_string(url, content).await
+    }
+
+    fn share(&self, path: &Path) -> Option<String> {
+        self.inner.share(path)
+    }
+
+    fn shared_path(&self, relpath: &str) -> Option<PathBuf> {
+        self.inner.shared_path(relpath)
+    }
+
+    fn encrypting_by(&self, name: &str) -> anyhow::Result<Storage> {
+        Ok(Arc::new(CompressingStorage::new(
+            self.inner.encrypting_by(name)?,
+            self.compression,
+        )))
+    }
+
+    fn blob_name(&self, digest: &str) -> String {
+        self.inner.blob_name(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::storage::SharedFsStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_compress() {
+        let root = tempfile::tempdir().unwrap();
+        let inner: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(input.path(), "ACGT".repeat(64 * 1024)).unwrap();
+
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let url = format!("@host:/reads.{}.txt", codec);
+            let compressing = CompressingStorage::new(inner.clone(), Some(Compression::new(codec)));
+            compressing
+                .upload(url.as_str(), input.path(), None)
+                .await
+                .unwrap();
+            let metadata = inner.metadata(url.as_str()).await.unwrap().unwrap();
+            assert_eq!(
+                metadata.get_str("content_encoding"),
+                Ok(codec.content_encoding())
+            );
+
+            // decompressed by the downloaders not compressing their uploads as well
+            let output = tempfile::NamedTempFile::new().unwrap();
+            let plain = CompressingStorage::new(inner.clone(), None);
+            let metadata = plain.download(url.as_str(), output.path()).await.unwrap();
+            assert_eq!(metadata, Some(Document::new()));
+            assert_eq!(
+                std::fs::read(output.path()).unwrap(),
+                std::fs::read(input.path()).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Compression of the small files by a zstd dictionary shared through the storage.
+//!
+//! Files such as generated configs or test cases are too small for zstd to learn much from
+//! each alone, but share most of their content with each other. A dictionary trained on
+//! samples of them is published to the storage by its name, see [`publish`], and the uploads
+//! through a [`DictionaryStorage`] configured with the name are compressed by it, tagged in
+//! their metadata with the dictionary. Downloads decompress the tagged files whether or not
+//! the downloader is configured with a dictionary itself.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use celery::export::async_trait;
+use log::debug;
+use mongodb::bson::Document;
+use once_cell::sync::Lazy;
+
+use crate::params::Param;
+use crate::storage::{FileStorage, Storage};
+
+/// Files larger than this have enough content of their own to be compressed without help.
+pub const MAX_COMPRESSED_SIZE: u64 = 1024 * 1024;
+
+/// Level of the compression by a dictionary, which is zstd's default.
+const LEVEL: i32 = 3;
+
+/// Tag of the metadata of the files compressed by a dictionary.
+const ENCODING: &str = "zstd-dictionary";
+
+/// The dictionaries fetched from the storage so far, by their names.
+static DICTIONARIES: Lazy<Mutex<HashMap<String, Arc<Vec<u8>>>>> = Lazy::new(Default::default);
+
+/// Where the dictionary of `name` is kept on the storage.
+fn dictionary_param(name: &str) -> Param {
+    Param::OutCloudFileParam {
+        filepath: format!("dictionaries/{}", name),
+        hostname: "cmdproxy".to_owned(),
+    }
+}
+
+/// Train a dictionary of at most `max_size` bytes on the files at `samples`.
+pub fn train(samples: &[PathBuf], max_size: usize) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(!samples.is_empty(), "No samples to train a dictionary on");
+    Ok(zstd::dict::from_files(samples, max_size)?)
+}
+
+/// Publish `dictionary` to the storage under `name`, replacing the dictionary there if any.
+///
+/// The files compressed by the replaced dictionary cannot be decompressed anymore, hence a
+/// retrained dictionary had better be published under a new name.
+pub async fn publish(
+    storage: &dyn FileStorage,
+    name: &str,
+    dictionary: &[u8],
+) -> anyhow::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    tokio::fs::write(file.path(), dictionary).await?;
+    storage
+        .upload(
+            dictionary_param(name).cloud_url().as_str(),
+            file.path(),
+            None,
+        )
+        .await?;
+    DICTIONARIES.lock().unwrap().remove(name);
+    Ok(())
+}
+
+/// The dictionary of `name` on the storage, fetched once per process.
+async fn fetch(storage: &dyn FileStorage, name: &str) -> anyhow::Result<Arc<Vec<u8>>> {
+    if let Some(dictionary) = DICTIONARIES.lock().unwrap().get(name) {
+        return Ok(dictionary.clone());
+    }
+
+    let url = dictionary_param(name).cloud_url();
+    anyhow::ensure!(
+        storage.exists(url.as_str()).await?,
+        "No compression dictionary {} on the storage",
+        name
+    );
+    let file = tempfile::NamedTempFile::new()?;
+    storage.download(url.as_str(), file.path()).await?;
+    let dictionary = Arc::new(tokio::fs::read(file.path()).await?);
+    DICTIONARIES
+        .lock()
+        .unwrap()
+        .insert(name.to_owned(), dictionary.clone());
+    Ok(dictionary)
+}
+
+fn compress(src: &Path, dst: &Path, dictionary: &[u8]) -> anyhow::Result<()> {
+    let mut encoder = zstd::Encoder::with_dictionary(File::create(dst)?, LEVEL, dictionary)?;
+    std::io::copy(&mut File::open(src)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+fn decompress(src: &Path, dst: &Path, dictionary: &[u8]) -> anyhow::Result<()> {
+    let mut decoder = zstd::Decoder::with_dictionary(BufReader::new(File::open(src)?), dictionary)?;
+    std::io::copy(&mut decoder, &mut File::create(dst)?)?;
+    Ok(())
+}
+
+/// Compress the small files uploaded through the inner storage by the dictionary of a name,
+/// if given, and decompress the compressed files downloaded.
+pub struct DictionaryStorage {
+    inner: Storage,
+    dictionary: Option<String>,
+}
+
+impl DictionaryStorage {
+    pub fn new(inner: Storage, dictionary: Option<String>) -> DictionaryStorage {
+        DictionaryStorage { inner, dictionary }
+    }
+
+    /// The name of the dictionary to compress the file at `path` by, uploading to `url`.
+    fn dictionary_for(&self, url: &str, path: &Path, metadata: Option<&Document>) -> Option<&str> {
+        let dictionary = self.dictionary.as_deref()?;
+        // the shared files are used in place by the peers, and the zipped ones are compressed
+        let shared = Param::from_cloud_url(url).map_or(false, |param| param.is_shared());
+        let zipped = metadata.map_or(false, |metadata| {
+            metadata.contains_key("content_type") || metadata.contains_key("content_encoding")
+        });
+        let size = path.metadata().map_or(0, |metadata| metadata.len());
+        (!shared && !zipped && size > 0 && size <= MAX_COMPRESSED_SIZE).then_some(dictionary)
+    }
+}
+
+#[async_trait]
+impl FileStorage for DictionaryStorage {
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        self.inner.exists(url).await
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let name = match self.dictionary_for(url, path, metadata.as_ref()) {
+            Some(name) => name,
+            None => return self.inner.upload(url, path, metadata).await,
+        };
+
+        let dictionary = fetch(self.inner.as_ref(), name).await?;
+        let compressed = tempfile::NamedTempFile::new()?;
+        compress(path, compressed.path(), dictionary.as_slice())?;
+        debug!(
+            "Compressed {} from {} to {} bytes by dictionary {}",
+            url,
+            path.metadata()?.len(),
+            compressed.path().metadata()?.len(),
+            name
+        );
+        let mut metadata = metadata.unwrap_or_default();
+        metadata.insert("content_encoding", ENCODING);
+        metadata.insert("dictionary", name);
+        self.inner
+            .upload(url, compressed.path(), Some(metadata))
+            .await
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let mut metadata = self.inner.download(url, path).await?;
+        let name = match &metadata {
+            Some(doc) if doc.get_str("content_encoding") == Ok(ENCODING) => {
+                doc.get_str("dictionary")?.to_owned()
+            }
+            _ => return Ok(metadata),
+        };
+
+        let dictionary = fetch(self.inner.as_ref(), name.as_str()).await?;
+        // aside the target, so that it is renamed within the same filesystem
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty());
+        let compressed = tempfile::NamedTempFile::new_in(parent.unwrap_or(Path::new(".")))?;
+        tokio::fs::rename(path, compressed.path()).await?;
+        decompress(compressed.path(), path, dictionary.as_slice())?;
+        if let Some(metadata) = metadata.as_mut() {
+            metadata.remove("content_encoding");
+            metadata.remove("dictionary");
+        }
+        Ok(metadata)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        self.inner.metadata(url).await
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        self.inner.delete(url).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        self.inner.read_string(url).await
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        self.inner.write_string(url, content).await
+    }
+
+    fn share(&self, path: &Path) -> Option<String> {
+        self.inner.share(path)
+    }
+
+    fn shared_path(&self, relpath: &str) -> Option<PathBuf> {
+        self.inner.shared_path(relpath)
+    }
+
+    fn encrypting_by(&self, name: &str) -> anyhow::Result<Storage> {
+        Ok(Arc::new(DictionaryStorage::new(
+            self.inner.encrypting_by(name)?,
+            self.dictionary.clone(),
+        )))
+    }
+
+    fn blob_name(&self, digest: &str) -> String {
+        self.inner.blob_name(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::SharedFsStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_compress_by_dictionary() {
+        let root = tempfile::tempdir().unwrap();
+        let inner: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+
+        let samples_dir = tempfile::tempdir().unwrap();
+        let samples = (0..200)
+            .map(|i| {
+                let path = samples_dir.path().join(format!("case-{}.conf", i));
+                let content = format!(
+                    "[case]\nname = case-{}\nseed = {}\ntimeout = 30\nretries = 3\n",
+                    i,
+                    i * 7919
+                );
+                std::fs::write(&path, content.repeat(4)).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let dictionary = train(samples.as_slice(), 4096).unwrap();
+        publish(inner.as_ref(), "conf", dictionary.as_slice())
+            .await
+            .unwrap();
+
+        let url = "@host:/cases/case-7.conf";
+        let compressing = DictionaryStorage::new(inner.clone(), Some("conf".to_owned()));
+        compressing
+            .upload(url, samples[7].as_path(), None)
+            .await
+            .unwrap();
+        let metadata = inner.metadata(url).await.unwrap().unwrap();
+        assert_eq!(metadata.get_str("dictionary"), Ok("conf"));
+
+        // decompressed by the downloaders not configured with the dictionary as well
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let plain = DictionaryStorage::new(inner.clone(), None);
+        let metadata = plain.download(url, output.path()).await.unwrap();
+        assert_eq!(metadata, Some(Document::new()));
+        assert_eq!(
+            std::fs::read(output.path()).unwrap(),
+            std::fs::read(&samples[7]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Encryption at rest of the files kept on the storage, so that no plaintext of them is ever
+//! stored in the shared backends.
+//!
+//! The uploads through an [`EncryptingStorage`] given a key are encrypted by AES-256-GCM
+//! under a fresh nonce each, stored ahead of the ciphertext, and tagged in their metadata
+//! with the cipher and the id of the key. The downloads decrypt the tagged files, failing if
+//! the key is missing or another than the one encrypting them. The clients and the servers
+//! are hence to share the key, from `$CMDPROXY_ENCRYPTION_KEY` or a key file.
+//!
+//! Besides the default key, the peers may share a keyring of named keys, such as one per
+//! tenant. A request picks a key of the keyring for any of its params by the path of the
+//! param, see [`crate::protocol::RunSpecification::encryption_keys`], so that the
+//! confidential files of a tenant are encrypted by the key of its own, while the others of
+//! the same run are by the default key. The downloads decrypt by whichever key of the
+//! default and the keyring the file is tagged with.
+//!
+//! The inputs encrypted by a picked key are not shared with the other runs over the same
+//! content: their blobs are named after the id of the key and a digest keyed by it, which
+//! tells nothing of the content to those without the key, and are uploaded anew each run.
+//!
+//! The keys are picked by the paths of the params and read from a folder of key files,
+//! rather than by ids carried on the params themselves and resolved by a secrets provider,
+//! which would change every variant of [`Param`] and its wire format.
+//!
+//! A file is encrypted whole in memory, which the large files, uploaded in chunks, keep to
+//! the size of a chunk.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use celery::export::async_trait;
+use hmac::{Hmac, Mac};
+use mongodb::bson::Document;
+use sha2::{Digest, Sha256};
+
+use crate::params::Param;
+use crate::storage::{FileStorage, Storage};
+
+/// Tag of the metadata of the files encrypted.
+const CIPHER: &str = "aes-256-gcm";
+
+/// Bytes of the nonce ahead of the ciphertext.
+const NONCE_SIZE: usize = 12;
+
+/// Key encrypting the files at rest, in AES-256.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl FromStr for EncryptionKey {
+    type Err = anyhow::Error;
+
+    /// Parse the key from its raw bytes encoded in base64.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 32] = BASE64
+            .decode(s.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("Encryption key must be 32 bytes"))?;
+        Ok(EncryptionKey(bytes))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({})", self.id())
+    }
+}
+
+impl EncryptionKey {
+    /// Load the key encoded in base64 from the file at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<EncryptionKey> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Load the keyring of the folder at `dir`, where each file is a key named by the file.
+    pub fn load_keyring(dir: &Path) -> anyhow::Result<HashMap<String, EncryptionKey>> {
+        let mut keyring = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("Key file {} is not named in unicode", path.display()))?;
+            let key = EncryptionKey::load(path.as_path())
+                .map_err(|err| anyhow!("Malformed key file {}: {}", path.display(), err))?;
+            keyring.insert(name.to_owned(), key);
+        }
+        Ok(keyring)
+    }
+
+    /// Id of the key tagging the files it encrypts, which tells the keys apart without
+    /// revealing them.
+    pub fn id(&self) -> String {
+        let digest = Sha256::digest(self.0);
+        digest[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt"))?;
+        Ok([nonce.as_slice(), ciphertext.as_slice()].concat())
+    }
+
+    /// Digest of `data` keyed by this key, in hex.
+    fn keyed_digest(&self, data: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(data.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn decrypt(&self, encrypted: &[u8]) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(encrypted.len() >= NONCE_SIZE, "Encrypted file truncated");
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt, corrupted or of another key"))
+    }
+}
+
+/// Name of the key of `keys` encrypting the file of the param at `filepath`, which is that of
+/// the param itself, or else of the nearest folder param containing it, such as a glob.
+pub(crate) fn key_name_for<'a>(
+    keys: &'a HashMap<String, String>,
+    filepath: &str,
+) -> Option<&'a str> {
+    keys.iter()
+        .filter(|(path, _)| {
+            filepath.strip_prefix(path.as_str()).map_or(false, |rest| {
+                rest.is_empty() || rest.starts_with('/') || rest.starts_with('\\')
+            })
+        })
+        .max_by_key(|(path, _)| path.len())
+        .map(|(_, name)| name.as_str())
+}
+
+/// Encrypt the files uploaded through the inner storage by the key, if given, and decrypt
+/// the encrypted files downloaded by that key or any of the keyring.
+pub struct EncryptingStorage {
+    inner: Storage,
+    key: Option<EncryptionKey>,
+    keyring: Arc<HashMap<String, EncryptionKey>>,
+    /// The key of the keyring encrypting the uploads instead of the default one, if picked.
+    picked: Option<EncryptionKey>,
+}
+
+impl EncryptingStorage {
+    pub fn new(inner: Storage, key: Option<EncryptionKey>) -> EncryptingStorage {
+        EncryptingStorage {
+            inner,
+            key,
+            keyring: Arc::default(),
+            picked: None,
+        }
+    }
+
+    /// Take the named keys of `keyring` for the params selecting them.
+    pub fn with_keyring(mut self, keyring: HashMap<String, EncryptionKey>) -> EncryptingStorage {
+        self.keyring = Arc::new(keyring);
+        self
+    }
+
+    /// The key to encrypt the file uploaded to `url` by, unless used in place by the peers.
+    fn key_for(&self, url: &str) -> Option<&EncryptionKey> {
+        let shared = Param::from_cloud_url(url).map_or(false, |param| param.is_shared());
+        self.picked
+            .as_ref()
+            .or(self.key.as_ref())
+            .filter(|_| !shared)
+    }
+
+    /// The key to decrypt the file of `metadata` by, if it is encrypted.
+    fn key_of(
+        &self,
+        url: &str,
+        metadata: Option<&Document>,
+    ) -> anyhow::Result<Option<&EncryptionKey>> {
+        let metadata = match metadata {
+            Some(metadata) if metadata.get_str("encryption") == Ok(CIPHER) => metadata,
+            _ => return Ok(None),
+        };
+        let key_id = metadata.get_str("key_id").unwrap_or_default();
+        let key = self
+            .key
+            .iter()
+            .chain(self.keyring.values())
+            .find(|key| key.id() == key_id)
+            .ok_or_else(|| anyhow!("{} is encrypted by key {}, which is not known", url, key_id))?;
+        Ok(Some(key))
+    }
+
+    async fn upload_encrypted(
+        &self,
+        url: &str,
+        plaintext: &[u8],
+        metadata: Option<Document>,
+        key: &EncryptionKey,
+    ) -> anyhow::Result<()> {
+        let encrypted = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(encrypted.path(), key.encrypt(plaintext)?).await?;
+        let mut metadata = metadata.unwrap_or_default();
+        metadata.insert("encryption", CIPHER);
+        metadata.insert("key_id", key.id());
+        self.inner
+            .upload(url, encrypted.path(), Some(metadata))
+            .await
+    }
+}
+
+#[async_trait]
+impl FileStorage for EncryptingStorage {
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        self.inner.exists(url).await
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        match self.key_for(url) {
+            Some(key) => {
+                let plaintext = tokio::fs::read(path).await?;
+                self.upload_encrypted(url, plaintext.as_slice(), metadata, key)
+                    .await
+            }
+            None => self.inner.upload(url, path, metadata).await,
+        }
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let mut metadata = self.inner.download(url, path).await?;
+        if let Some(key) = self.key_of(url, metadata.as_ref())? {
+            let plaintext = key.decrypt(tokio::fs::read(path).await?.as_slice())?;
+            tokio::fs::write(path, plaintext).await?;
+            if let Some(metadata) = metadata.as_mut() {
+                metadata.remove("encryption");
+                metadata.remove("key_id");
+            }
+        }
+        Ok(metadata)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        self.inner.metadata(url).await
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        self.inner.delete(url).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        let metadata = self.inner.metadata(url).await?;
+        if self.key_of(url, metadata.as_ref())?.is_none() {
+            return self.inner.read_string(url).await;
+        }
+        let file = tempfile::NamedTempFile::new()?;
+        self.download(url, file.path()).await?;
+        Ok(tokio::fs::read_to_string(file.path()).await?)
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        match self.key_for(url) {
+            Some(key) => {
+                self.upload_encrypted(url, content.as_bytes(), None, key)
+                    .await
+            }
+            None => self.inner.write_string(url, content).await,
+        }
+    }
+
+    fn share(&self, path: &Path) -> Option<String> {
+        self.inner.share(path)
+    }
+
+    fn shared_path(&self, relpath: &str) -> Option<PathBuf> {
+        self.inner.shared_path(relpath)
+    }
+
+    fn encrypting_by(&self, name: &str) -> anyhow::Result<Storage> {
+        let key = self
+            .keyring
+            .get(name)
+            .ok_or_else(|| anyhow!("No key {} in the keyring to encrypt by", name))?;
+        Ok(Arc::new(EncryptingStorage {
+            inner: self.inner.clone(),
+            key: self.key.clone(),
+            keyring: self.keyring.clone(),
+            picked: Some(key.clone()),
+        }))
+    }
+
+    fn blob_name(&self, digest: &str) -> String {
+        match &self.picked {
+            Some(key) => format!("{}/{}", key.id(), key.keyed_digest(digest)),
+            None => self.inner.blob_name(digest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::storage::SharedFsStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encrypt() {
+        let root = tempfile::tempdir().unwrap();
+        let inner: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let key: EncryptionKey = BASE64.encode([7u8; 32]).parse().unwrap();
+        let encrypting = EncryptingStorage::new(inner.clone(), Some(key.clone()));
+
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(input.path(), "customer record").unwrap();
+        let url = "@host:/records.csv";
+        encrypting.upload(url, input.path(), None).await.unwrap();
+        let metadata = inner.metadata(url).await.unwrap().unwrap();
+        assert_eq!(metadata.get_str("key_id"), Ok(key.id().as_str()));
+
+        // no plaintext at rest
+        let stored = tempfile::NamedTempFile::new().unwrap();
+        inner.download(url, stored.path()).await.unwrap();
+        assert_ne!(std::fs::read(stored.path()).unwrap(), b"customer record");
+
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let metadata = encrypting.download(url, output.path()).await.unwrap();
+        assert_eq!(metadata, Some(Document::new()));
+        assert_eq!(std::fs::read(output.path()).unwrap(), b"customer record");
+
+        encrypting
+            .write_string("@host:/log", "secret")
+            .await
+            .unwrap();
+        assert_eq!(
+            encrypting.read_string("@host:/log").await.unwrap(),
+            "secret"
+        );
+
+        let other: EncryptionKey = BASE64.encode([8u8; 32]).parse().unwrap();
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let wrong = EncryptingStorage::new(inner, Some(other));
+        assert!(wrong.download(url, output.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_by_keyring() {
+        let root = tempfile::tempdir().unwrap();
+        let inner: Storage = Arc::new(SharedFsStorage::new(root.path().to_path_buf(), ""));
+        let shared: EncryptionKey = BASE64.encode([7u8; 32]).parse().unwrap();
+        let tenant: EncryptionKey = BASE64.encode([9u8; 32]).parse().unwrap();
+        let encrypting = EncryptingStorage::new(inner.clone(), Some(shared.clone()))
+            .with_keyring(HashMap::from([("tenant".to_owned(), tenant.clone())]));
+        assert!(encrypting.encrypting_by("nobody").is_err());
+
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(input.path(), "tenant record").unwrap();
+        let url = "@host:/tenant.csv";
+        let by_tenant = encrypting.encrypting_by("tenant").unwrap();
+        by_tenant.upload(url, input.path(), None).await.unwrap();
+        let metadata = inner.metadata(url).await.unwrap().unwrap();
+        assert_eq!(metadata.get_str("key_id"), Ok(tenant.id().as_str()));
+
+        let output = tempfile::NamedTempFile::new().unwrap();
+        encrypting.download(url, output.path()).await.unwrap();
+        assert_eq!(std::fs::read(output.path()).unwrap(), b"tenant record");
+
+        // the peers without the key of the tenant cannot read its files
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let outsider = EncryptingStorage::new(inner, Some(shared));
+        assert!(outsider.download(url, output.path()).await.is_err());
+
+        // nor tell the content of its blobs by their names
+        let digest = "ab".repeat(32);
+        assert_eq!(encrypting.blob_name(digest.as_str()), digest);
+        let name = by_tenant.blob_name(digest.as_str());
+        assert!(name.starts_with(format!("{}/", tenant.id()).as_str()));
+        assert!(!name.contains(digest.as_str()));
+    }
+
+    #[test]
+    fn test_key_name_for() {
+        let keys = HashMap::from([
+            ("/data/secret".to_owned(), "tenant".to_owned()),
+            ("/data/secret/public".to_owned(), "shared".to_owned()),
+        ]);
+        assert_eq!(key_name_for(&keys, "/data/secret"), Some("tenant"));
+        assert_eq!(key_name_for(&keys, "/data/secret/a.csv"), Some("tenant"));
+        assert_eq!(
+            key_name_for(&keys, "/data/secret/public/b.csv"),
+            Some("shared")
+        );
+        assert_eq!(key_name_for(&keys, "/data/secrets.csv"), None);
+    }
+}
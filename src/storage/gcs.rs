@@ -0,0 +1,240 @@
+//! Files kept as the objects of a bucket of the Google Cloud Storage, through its XML API.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use celery::export::async_trait;
+use mongodb::bson::Document;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::{checked, decode_metadata, encode_metadata, object_key, FileStorage};
+
+const ENDPOINT: &str = "https://storage.googleapis.com";
+
+const METADATA_HEADER: &str = "x-goog-meta-cmdproxy";
+
+/// Where the default service account of a Google Cloud instance gets its access tokens.
+const TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// Renew the access token this long before it expires.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Store the files as objects in a bucket, authorized by an OAuth 2.0 access token.
+///
+/// The token is taken from `$GOOGLE_OAUTH_ACCESS_TOKEN` if set, or else from the metadata
+/// server of the instance, which is renewed as it expires.
+pub struct GcsStorage {
+    http: reqwest::Client,
+    bucket: String,
+    /// Folder of the objects, isolating the namespaces sharing the bucket.
+    namespace: String,
+    /// The access token, and when to renew it if fetched from the metadata server.
+    token: Mutex<Option<(String, Option<Instant>)>>,
+}
+
+impl GcsStorage {
+    pub fn new(bucket: &str, namespace: &str) -> GcsStorage {
+        let token = std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN")
+            .ok()
+            .map(|token| (token, None));
+        GcsStorage {
+            http: reqwest::Client::new(),
+            bucket: bucket.to_owned(),
+            namespace: namespace.to_owned(),
+            token: Mutex::new(token),
+        }
+    }
+
+    fn object_path(&self, url: &str) -> String {
+        format!("/{}/{}", self.bucket, object_key(&self.namespace, url))
+    }
+
+    async fn token(&self) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        match token.as_ref() {
+            Some((token, None)) => return Ok(token.clone()),
+            Some((token, Some(renew_at))) if Instant::now() < *renew_at => return Ok(token.clone()),
+            _ => {}
+        }
+
+        let response = self
+            .http
+            .get(TOKEN_URL)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await?;
+        let fetched: AccessToken = checked(TOKEN_URL, response).await?.json().await?;
+        let renew_at = Instant::now() + Duration::from_secs(fetched.expires_in);
+        *token = Some((
+            fetched.access_token.clone(),
+            renew_at.checked_sub(TOKEN_MARGIN),
+        ));
+        Ok(fetched.access_token)
+    }
+
+    async fn request(&self, method: Method, url: &str) -> anyhow::Result<RequestBuilder> {
+        Ok(self
+            .http
+            .request(method, format!("{}{}", ENDPOINT, self.object_path(url)))
+            .bearer_auth(self.token().await?))
+    }
+
+    /// The response of the object at `url`, or none if there is no such object.
+    async fn head(&self, url: &str) -> anyhow::Result<Option<Response>> {
+        let response = self.request(Method::HEAD, url).await?.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(checked(url, response).await?))
+    }
+
+    async fn get(&self, url: &str) -> anyhow::Result<Response> {
+        let response = self.request(Method::GET, url).await?.send().await?;
+        checked(url, response).await
+    }
+}
+
+#[async_trait]
+impl FileStorage for GcsStorage {
+    fn backend(&self) -> &'static str {
+        "gcs"
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(self.head(url).await?.is_some())
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let mut builder = self.request(Method::PUT, url).await?;
+        if let Some(metadata) = metadata {
+            builder = builder.header(METADATA_HEADER, encode_metadata(&metadata)?);
+        }
+        let response = builder
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(file)
+            .send()
+            .await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let mut response = self.get(url).await?;
+        let metadata = decode_metadata(&response, METADATA_HEADER)?;
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(metadata)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        match self.head(url).await? {
+            Some(response) => decode_metadata(&response, METADATA_HEADER),
+            None => anyhow::bail!("No file {} on the storage", url),
+        }
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        let response = self.request(Method::DELETE, url).await?.send().await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        // objects cannot be renamed, but copied within the bucket without downloading them
+        let response = self
+            .request(Method::PUT, to)
+            .await?
+            .header("x-goog-copy-source", self.object_path(from))
+            .send()
+            .await?;
+        checked(to, response).await?;
+        self.delete(from).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        Ok(self.get(url).await?.text().await?)
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        let response = self
+            .request(Method::PUT, url)
+            .await?
+            .body(content.to_owned())
+            .send()
+            .await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(namespace: &str, token: Option<(String, Option<Instant>)>) -> GcsStorage {
+        GcsStorage {
+            http: reqwest::Client::new(),
+            bucket: "bucket".to_owned(),
+            namespace: namespace.to_owned(),
+            token: Mutex::new(token),
+        }
+    }
+
+    #[test]
+    fn test_object_path() {
+        assert_eq!(
+            storage("", None).object_path("@host:/out/a b.txt"),
+            "/bucket/%40host%3A/out/a%20b.txt"
+        );
+        // the namespaces are kept apart by their folders
+        assert_eq!(
+            storage("staging", None).object_path("@host:/a.txt"),
+            "/bucket/staging/%40host%3A/a.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_by_token() {
+        // as given by $GOOGLE_OAUTH_ACCESS_TOKEN, never renewed
+        let gcs = storage("", Some(("given".to_owned(), None)));
+        let request = gcs
+            .request(Method::GET, "@host:/a.txt")
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://storage.googleapis.com/bucket/%40host%3A/a.txt"
+        );
+        assert_eq!(
+            request.headers()[reqwest::header::AUTHORIZATION],
+            "Bearer given"
+        );
+
+        // as fetched from the metadata server, until due to be renewed
+        let renew_at = Instant::now() + Duration::from_secs(60 * 60);
+        let gcs = storage("", Some(("fetched".to_owned(), Some(renew_at))));
+        assert_eq!(gcs.token().await.unwrap(), "fetched");
+    }
+}
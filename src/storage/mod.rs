@@ -0,0 +1,752 @@
+//! Backends storing the files exchanged between the clients and the servers.
+//!
+//! Files are addressed by their cloud urls, see [`Param::cloud_url`](crate::params::Param),
+//! and may carry a small metadata document, e.g. the provenance of an output. The GridFS of
+//! the mongodb is the default backend; an S3-compatible object store can be used instead,
+//! keeping the large files off the database, or a filesystem shared by the clients and the
+//! servers, skipping the transfers of the files on it altogether. The Azure Blob Storage and
+//! the Google Cloud Storage are available with the features `azure` and `gcs`, see [`open`].
+//! Any of them may compress the small files by a shared dictionary, see [`dictionary`], or
+//! the large ones by gzip or zstd, see [`compression`], may encrypt the files at rest, see
+//! [`encryption`], and the GridFS and the S3 may read the files from the replicas near the
+//! reader, see [`replica`].
+
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use celery::export::async_trait;
+use hmac::{Hmac, Mac};
+use mongodb::bson::Document;
+use mongodb_gridfs::options::GridFSUploadOptions;
+use mongodb_gridfs::GridFSBucket;
+use mongodb_gridfs_ext::bucket::common::GridFSBucketExt;
+use mongodb_gridfs_ext::bucket::file_sync::FileSync;
+use mongodb_gridfs_ext::error::Result as GridFSExtResult;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::params::Param;
+
+#[cfg(feature = "azure")]
+mod azure;
+pub mod compression;
+pub mod dictionary;
+pub mod encryption;
+#[cfg(feature = "gcs")]
+mod gcs;
+pub mod replica;
+
+#[cfg(feature = "azure")]
+pub use azure::AzureStorage;
+pub use compression::CompressingStorage;
+pub use dictionary::DictionaryStorage;
+pub use encryption::{EncryptingStorage, EncryptionKey};
+#[cfg(feature = "gcs")]
+pub use gcs::GcsStorage;
+pub use replica::ReplicaStorage;
+
+/// A store of files by their cloud urls.
+#[async_trait]
+pub trait FileStorage: Send + Sync {
+    /// Name of the backend, as tagged on the traces of the storage operations.
+    fn backend(&self) -> &'static str;
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool>;
+
+    /// Upload the file at `path` to `url`, replacing the file there if any.
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()>;
+
+    /// Download the file at `url` to `path`, and return its metadata.
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>>;
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>>;
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()>;
+
+    /// Move the file at `from` to `to`, where there must be no file yet.
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()>;
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String>;
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()>;
+
+    /// The path of the local file at `path` relative to the filesystem shared with the peers,
+    /// if it is on it, so that the peers use it in place instead of transferring it.
+    fn share(&self, _path: &Path) -> Option<String> {
+        None
+    }
+
+    /// The local path of the file at `relpath` on the filesystem shared with the peers.
+    fn shared_path(&self, _relpath: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// The same storage encrypting the uploads by the key `name` of the keyring instead of
+    /// the default key, see [`encryption`], failing if there is no such key.
+    fn encrypting_by(&self, name: &str) -> anyhow::Result<Storage> {
+        Err(anyhow::anyhow!("No keyring to encrypt by key {}", name))
+    }
+
+    /// Name of the blob of the content of sha256 `digest` uploaded through this storage, which
+    /// is the digest itself unless encrypted by a picked key, see [`encryption`].
+    fn blob_name(&self, digest: &str) -> String {
+        digest.to_owned()
+    }
+}
+
+/// A storage shared by the middles and the tasks.
+pub type Storage = Arc<dyn FileStorage>;
+
+/// Open the object store at `url`, keeping the files of `namespace` apart, where `url` is
+///
+/// - `azure://<account>/<container>` for a container of the Azure Blob Storage, authorized
+///   by the shared access signature in `$AZURE_STORAGE_SAS_TOKEN`;
+/// - `gs://<bucket>` for a bucket of the Google Cloud Storage, authorized by the access token
+///   in `$GOOGLE_OAUTH_ACCESS_TOKEN`, or else of the service account of the instance.
+pub fn open(url: &str, namespace: &str) -> anyhow::Result<Storage> {
+    let (scheme, location) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("Storage url {} has no scheme", url))?;
+    let location = location.trim_end_matches('/');
+    match scheme {
+        #[cfg(feature = "azure")]
+        "azure" => {
+            let (account, container) = location
+                .split_once('/')
+                .ok_or_else(|| anyhow::anyhow!("Storage url {} has no container", url))?;
+            let sas_token = std::env::var("AZURE_STORAGE_SAS_TOKEN")
+                .map_err(|_| anyhow::anyhow!("No $AZURE_STORAGE_SAS_TOKEN to access {}", url))?;
+            Ok(Arc::new(AzureStorage::new(
+                account, container, &sas_token, namespace,
+            )))
+        }
+        #[cfg(feature = "gcs")]
+        "gs" => Ok(Arc::new(GcsStorage::new(location, namespace))),
+        #[cfg(not(feature = "azure"))]
+        "azure" => anyhow::bail!("Built without the feature azure to open {}", url),
+        #[cfg(not(feature = "gcs"))]
+        "gs" => anyhow::bail!("Built without the feature gcs to open {}", url),
+        _ => anyhow::bail!("Unsupported storage url {}", url),
+    }
+}
+
+/// Store the files in a GridFS bucket.
+#[derive(Clone)]
+pub struct GridFsStorage {
+    bucket: GridFSBucket,
+}
+
+impl GridFsStorage {
+    pub fn new(bucket: GridFSBucket) -> GridFsStorage {
+        GridFsStorage { bucket }
+    }
+
+    /// The shared storage of `bucket`.
+    pub fn shared(bucket: GridFSBucket) -> Storage {
+        Arc::new(GridFsStorage::new(bucket))
+    }
+}
+
+#[async_trait]
+impl FileStorage for GridFsStorage {
+    fn backend(&self) -> &'static str {
+        "gridfs"
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(self.bucket.clone().exists(url).await?)
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let options = metadata.map(|metadata| {
+            GridFSUploadOptions::builder()
+                .metadata(Some(metadata))
+                .build()
+        });
+        self.bucket.clone().upload_from(url, path, options).await?;
+        Ok(())
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let bucket = self.bucket.clone();
+        let oid = bucket.download_to(url, path).await?;
+        Ok(bucket.metadata(oid).await?)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        let bucket = self.bucket.clone();
+        let oid = bucket.id(url).await?;
+        Ok(bucket.metadata(oid).await?)
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        let bucket = self.bucket.clone();
+        let oid = bucket.id(url).await?;
+        let res: GridFSExtResult<()> = bucket.delete(oid).await.map_err(Into::into);
+        Ok(res?)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let bucket = self.bucket.clone();
+        let oid = bucket.id(from).await?;
+        bucket.rename(oid, to).await?;
+        Ok(())
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        Ok(self.bucket.clone().read_string(url).await?)
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        self.bucket.clone().write_string(url, content).await?;
+        Ok(())
+    }
+}
+
+/// Where the files are stored in an S3-compatible object store.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S3Conf {
+    /// Url of the service, such as `https://s3.us-east-1.amazonaws.com`, without any path.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Bucket replicating this one near the reader, which the files are read from instead.
+    #[serde(default)]
+    pub read_replica: Option<S3Replica>,
+}
+
+/// A bucket replicating the bucket of a [`S3Conf`], accessed by the same credentials.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct S3Replica {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+}
+
+impl S3Conf {
+    /// The conf of the replica of the bucket, if any.
+    pub fn replica(&self) -> Option<S3Conf> {
+        let replica = self.read_replica.clone()?;
+        Some(S3Conf {
+            endpoint: replica.endpoint,
+            bucket: replica.bucket,
+            region: replica.region,
+            access_key: self.access_key.clone(),
+            secret_key: self.secret_key.clone(),
+            read_replica: None,
+        })
+    }
+}
+
+fn default_region() -> String {
+    "us-east-1".to_owned()
+}
+
+impl fmt::Debug for S3Conf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Conf")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("secret_key", &"<redacted>")
+            .field("read_replica", &self.read_replica)
+            .finish()
+    }
+}
+
+/// The payload is not hashed, so that the uploads can be streamed.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The metadata is kept in a user-defined header of the object, as base64-encoded json.
+const METADATA_HEADER: &str = "x-amz-meta-cmdproxy";
+
+/// Store the files as objects in an S3-compatible bucket, with path-style requests signed
+/// by the signature version 4.
+pub struct S3Storage {
+    http: reqwest::Client,
+    conf: S3Conf,
+    /// Prefix of the keys of the objects, isolating the namespaces sharing the bucket.
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(conf: S3Conf, namespace: &str) -> S3Storage {
+        S3Storage {
+            http: reqwest::Client::new(),
+            conf,
+            prefix: namespace.to_owned(),
+        }
+    }
+
+    fn object_path(&self, url: &str) -> String {
+        format!("/{}/{}", self.conf.bucket, object_key(&self.prefix, url))
+    }
+
+    /// A request on the object of `url`, signed together with the extra `headers`, whose
+    /// names must be in lowercase.
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        mut headers: Vec<(String, String)>,
+    ) -> anyhow::Result<RequestBuilder> {
+        let endpoint = reqwest::Url::parse(self.conf.endpoint.as_str())?;
+        let host = endpoint
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("No host in S3 endpoint {}", self.conf.endpoint))?;
+        let host = match endpoint.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        let path = self.object_path(url);
+
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        headers.push(("host".to_owned(), host));
+        headers.push((
+            "x-amz-content-sha256".to_owned(),
+            UNSIGNED_PAYLOAD.to_owned(),
+        ));
+        headers.push(("x-amz-date".to_owned(), amz_date.clone()));
+        headers.sort();
+
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, path, canonical_headers, signed_headers, UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.conf.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.conf.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                hmac(
+                    format!("AWS4{}", self.conf.secret_key).as_bytes(),
+                    date.as_str(),
+                ),
+                |key, part| hmac(&key, part),
+            );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.conf.access_key,
+            scope,
+            signed_headers,
+            hex(&hmac(&signing_key, string_to_sign.as_str()))
+        );
+
+        let mut builder = self.http.request(
+            method,
+            format!("{}{}", self.conf.endpoint.trim_end_matches('/'), path),
+        );
+        for (name, value) in headers.iter().filter(|(name, _)| name != "host") {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        Ok(builder.header("authorization", authorization))
+    }
+
+    fn metadata_headers(metadata: Option<Document>) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(match metadata {
+            Some(metadata) => vec![(METADATA_HEADER.to_owned(), encode_metadata(&metadata)?)],
+            None => vec![],
+        })
+    }
+
+    /// The response of the object at `url`, or none if there is no such object.
+    async fn head(&self, url: &str) -> anyhow::Result<Option<Response>> {
+        let response = self.request(Method::HEAD, url, vec![])?.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(checked(url, response).await?))
+    }
+
+    async fn get(&self, url: &str) -> anyhow::Result<Response> {
+        let response = self.request(Method::GET, url, vec![])?.send().await?;
+        checked(url, response).await
+    }
+}
+
+#[async_trait]
+impl FileStorage for S3Storage {
+    fn backend(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(self.head(url).await?.is_some())
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let response = self
+            .request(Method::PUT, url, S3Storage::metadata_headers(metadata)?)?
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(file)
+            .send()
+            .await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let mut response = self.get(url).await?;
+        let metadata = decode_metadata(&response, METADATA_HEADER)?;
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(metadata)
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        match self.head(url).await? {
+            Some(response) => decode_metadata(&response, METADATA_HEADER),
+            None => anyhow::bail!("No file {} on the storage", url),
+        }
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        let response = self.request(Method::DELETE, url, vec![])?.send().await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        // objects cannot be renamed, but copied within the bucket without downloading them
+        let source = ("x-amz-copy-source".to_owned(), self.object_path(from));
+        let response = self.request(Method::PUT, to, vec![source])?.send().await?;
+        checked(to, response).await?;
+        self.delete(from).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        Ok(self.get(url).await?.text().await?)
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        let response = self
+            .request(Method::PUT, url, vec![])?
+            .body(content.to_owned())
+            .send()
+            .await?;
+        checked(url, response).await?;
+        Ok(())
+    }
+}
+
+/// Keep the files on a filesystem shared by the clients and the servers, such as an NFS mount,
+/// on which the local files are passed by their paths, see [`FileStorage::share`].
+///
+/// The other files, such as the spilled responses, are kept in a hidden folder on the shared
+/// filesystem, so that they never go through the database either.
+pub struct SharedFsStorage {
+    /// Mount point of the shared filesystem on this host.
+    root: PathBuf,
+    /// Folder keeping the files which are not passed by their paths.
+    store: PathBuf,
+}
+
+impl SharedFsStorage {
+    pub fn new(root: PathBuf, namespace: &str) -> SharedFsStorage {
+        let root = match std::env::current_dir() {
+            Ok(cwd) => cwd.join(root),
+            Err(_) => root,
+        };
+        let mut store = root.join(".cmdproxy");
+        if !namespace.is_empty() {
+            store.push(namespace);
+        }
+        SharedFsStorage { root, store }
+    }
+
+    /// Where the file at `url` is kept.
+    fn path(&self, url: &str) -> anyhow::Result<PathBuf> {
+        match Param::from_cloud_url(url) {
+            Some(param) if param.is_shared() => self
+                .shared_path(param.filepath())
+                .ok_or_else(|| anyhow::anyhow!("Path of {} escapes the shared filesystem", url)),
+            _ => Ok(self.store.join(uri_encode(url).replace('/', "%2F"))),
+        }
+    }
+}
+
+#[async_trait]
+impl FileStorage for SharedFsStorage {
+    fn backend(&self) -> &'static str {
+        "shared-fs"
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(self.path(url)?.exists())
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        let target = self.path(url)?;
+        let parent = target.parent().unwrap();
+        tokio::fs::create_dir_all(parent).await?;
+        // copied aside first, so that the readers never see a partial file
+        let copying = tempfile::NamedTempFile::new_in(parent)?;
+        tokio::fs::copy(path, copying.path()).await?;
+        copying.persist(&target)?;
+        write_metadata(target.as_path(), metadata).await
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        let source = self.path(url)?;
+        tokio::fs::copy(&source, path).await?;
+        read_metadata(source.as_path()).await
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        let path = self.path(url)?;
+        anyhow::ensure!(path.exists(), "No file {} on the storage", url);
+        read_metadata(path.as_path()).await
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        let path = self.path(url)?;
+        // the outputs written in place may be folders
+        if path.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+        write_metadata(path.as_path(), None).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let (from, to) = (self.path(from)?, self.path(to)?);
+        tokio::fs::create_dir_all(to.parent().unwrap()).await?;
+        tokio::fs::rename(&from, &to).await?;
+        let metadata = read_metadata(from.as_path()).await?;
+        write_metadata(from.as_path(), None).await?;
+        write_metadata(to.as_path(), metadata).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        Ok(tokio::fs::read_to_string(self.path(url)?).await?)
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        let path = self.path(url)?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        Ok(tokio::fs::write(path, content).await?)
+    }
+
+    fn share(&self, path: &Path) -> Option<String> {
+        let path = std::env::current_dir().ok()?.join(path);
+        let relpath = path.strip_prefix(&self.root).ok()?;
+        let components = relpath
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(components.join("/"))
+    }
+
+    fn shared_path(&self, relpath: &str) -> Option<PathBuf> {
+        let relpath = Path::new(relpath);
+        relpath
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+            .then(|| self.root.join(relpath))
+    }
+}
+
+/// The metadata of the file at `path` is kept in a json file beside it.
+fn metadata_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".meta.json");
+    path.with_file_name(name)
+}
+
+async fn read_metadata(path: &Path) -> anyhow::Result<Option<Document>> {
+    match tokio::fs::read(metadata_path(path)).await {
+        Ok(content) => Ok(Some(serde_json::from_slice(content.as_slice())?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write the metadata of the file at `path`, or remove it if none.
+async fn write_metadata(path: &Path, metadata: Option<Document>) -> anyhow::Result<()> {
+    let metadata_path = metadata_path(path);
+    match metadata {
+        Some(metadata) => tokio::fs::write(metadata_path, serde_json::to_vec(&metadata)?).await?,
+        None => {
+            if let Err(err) = tokio::fs::remove_file(metadata_path).await {
+                anyhow::ensure!(err.kind() == std::io::ErrorKind::NotFound, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The response if successful, or else the error told by the service.
+async fn checked(url: &str, response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("Request on {} failed with {}: {}", url, status, body)
+}
+
+/// The metadata as the value of a user-defined header of an object, in base64-encoded json.
+fn encode_metadata(metadata: &Document) -> anyhow::Result<String> {
+    Ok(BASE64.encode(serde_json::to_vec(metadata)?))
+}
+
+/// The metadata kept in the user-defined `header` of the object of the response, if any.
+fn decode_metadata(response: &Response, header: &str) -> anyhow::Result<Option<Document>> {
+    match response.headers().get(header) {
+        Some(value) => Ok(Some(serde_json::from_slice(
+            BASE64.decode(value.to_str()?)?.as_slice(),
+        )?)),
+        None => Ok(None),
+    }
+}
+
+/// The key of the object keeping the file at `url`, percent-encoded, under the folder of the
+/// namespace if any, so that the namespaces can share a bucket.
+fn object_key(namespace: &str, url: &str) -> String {
+    if namespace.is_empty() {
+        uri_encode(url)
+    } else {
+        uri_encode(format!("{}/{}", namespace, url).as_str())
+    }
+}
+
+/// Percent-encode the key of an object, as the canonical uri of the signature version 4.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("ns/@host:/tmp/a b.txt"),
+            "ns/%40host%3A/tmp/a%20b.txt"
+        );
+    }
+
+    #[test]
+    fn test_shared_fs_paths() {
+        let storage = SharedFsStorage::new(PathBuf::from("/mnt/share"), "ns");
+        assert_eq!(
+            storage.share(Path::new("/mnt/share/data/a.bin")),
+            Some("data/a.bin".to_owned())
+        );
+        assert_eq!(storage.share(Path::new("/tmp/a.bin")), None);
+        assert_eq!(storage.share(Path::new("/mnt/share/../etc/passwd")), None);
+        assert_eq!(
+            storage.shared_path("data/a.bin"),
+            Some(PathBuf::from("/mnt/share/data/a.bin"))
+        );
+        assert_eq!(storage.shared_path("../etc/passwd"), None);
+
+        let shared = Param::ipath("/mnt/share/data/a.bin").as_shared("data/a.bin");
+        assert_eq!(
+            storage.path(shared.cloud_url().as_str()).unwrap(),
+            PathBuf::from("/mnt/share/data/a.bin")
+        );
+        assert_eq!(
+            storage.path("@host:/tmp/a.bin").unwrap(),
+            PathBuf::from("/mnt/share/.cmdproxy/ns/%40host%3A%2Ftmp%2Fa.bin")
+        );
+    }
+
+    #[test]
+    fn test_open() {
+        assert!(open("/tmp/storage", "").is_err());
+        assert!(open("ftp://host/files", "").is_err());
+        assert_eq!(
+            open("gs://bucket", "")
+                .map(|storage| storage.backend())
+                .ok(),
+            cfg!(feature = "gcs").then_some("gcs")
+        );
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // the example of the signing key derivation in the aws documentation
+        let date = hmac(b"AWS4wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215");
+        let key = ["us-east-1", "iam", "aws4_request"]
+            .iter()
+            .fold(date, |key, part| hmac(&key, part));
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+}
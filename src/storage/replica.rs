@@ -0,0 +1,125 @@
+//! Routing of the reads of the files to a replica near the reader, such as the nearest member
+//! of the replica set of the mongodb or a bucket replicated to the region of the reader,
+//! while the writes go to the primary.
+//!
+//! A replica lags behind its primary, hence a file failed to be read from the replica, such
+//! as one uploaded by the peer a moment ago and not replicated yet, is read from the primary
+//! instead.
+
+use std::path::{Path, PathBuf};
+
+use celery::export::async_trait;
+use log::debug;
+use mongodb::bson::Document;
+
+use crate::storage::{FileStorage, Storage};
+
+/// Read the files from the replica, and write them to the primary.
+pub struct ReplicaStorage {
+    primary: Storage,
+    replica: Storage,
+}
+
+impl ReplicaStorage {
+    pub fn new(primary: Storage, replica: Storage) -> ReplicaStorage {
+        ReplicaStorage { primary, replica }
+    }
+}
+
+#[async_trait]
+impl FileStorage for ReplicaStorage {
+    fn backend(&self) -> &'static str {
+        self.primary.backend()
+    }
+
+    async fn exists(&self, url: &str) -> anyhow::Result<bool> {
+        // checked before writing, hence only the primary tells
+        self.primary.exists(url).await
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: Option<Document>,
+    ) -> anyhow::Result<()> {
+        self.primary.upload(url, path, metadata).await
+    }
+
+    async fn download(&self, url: &str, path: &Path) -> anyhow::Result<Option<Document>> {
+        match self.replica.download(url, path).await {
+            Ok(metadata) => Ok(metadata),
+            Err(err) => {
+                debug!(
+                    "Download {} from the primary, failed on the replica: {}",
+                    url, err
+                );
+                self.primary.download(url, path).await
+            }
+        }
+    }
+
+    async fn metadata(&self, url: &str) -> anyhow::Result<Option<Document>> {
+        self.primary.metadata(url).await
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        self.primary.delete(url).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.primary.rename(from, to).await
+    }
+
+    async fn read_string(&self, url: &str) -> anyhow::Result<String> {
+        match self.replica.read_string(url).await {
+            Ok(content) => Ok(content),
+            Err(err) => {
+                debug!(
+                    "Read {} from the primary, failed on the replica: {}",
+                    url, err
+                );
+                self.primary.read_string(url).await
+            }
+        }
+    }
+
+    async fn write_string(&self, url: &str, content: &str) -> anyhow::Result<()> {
+        self.primary.write_string(url, content).await
+    }
+
+    fn share(&self, path: &Path) -> Option<String> {
+        self.primary.share(path)
+    }
+
+    fn shared_path(&self, relpath: &str) -> Option<PathBuf> {
+        self.primary.shared_path(relpath)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::SharedFsStorage;
+
+    #[tokio::test]
+    async fn test_replica_storage() {
+        let primary_root = tempfile::tempdir().unwrap();
+        let replica_root = tempfile::tempdir().unwrap();
+        let primary: Storage =
+            Arc::new(SharedFsStorage::new(primary_root.path().to_path_buf(), ""));
+        let replica: Storage =
+            Arc::new(SharedFsStorage::new(replica_root.path().to_path_buf(), ""));
+        let storage = ReplicaStorage::new(primary.clone(), replica.clone());
+
+        storage.write_string("a.txt", "primary").await.unwrap();
+        assert!(!replica.exists("a.txt").await.unwrap());
+        // not replicated yet
+        assert_eq!(storage.read_string("a.txt").await.unwrap(), "primary");
+
+        replica.write_string("a.txt", "replica").await.unwrap();
+        assert_eq!(storage.read_string("a.txt").await.unwrap(), "replica");
+    }
+}
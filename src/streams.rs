@@ -0,0 +1,172 @@
+use futures::TryStreamExt;
+use log::warn;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Which output of the command a chunk comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamKind {
+    Stdout,
+    Stderr,
+}
+
+/// A piece of the output of a running command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub task_id: String,
+    pub kind: StreamKind,
+    /// Order of the chunk among all the chunks of the same output.
+    pub seq: i64,
+    pub data: Vec<u8>,
+    pub at: DateTime,
+}
+
+/// The collection where workers publish the live output of the commands they are running.
+#[derive(Clone)]
+pub struct OutputStreams {
+    coll: Collection<OutputChunk>,
+}
+
+impl OutputStreams {
+    pub fn new(coll: Collection<OutputChunk>) -> OutputStreams {
+        OutputStreams { coll }
+    }
+
+    /// Copy everything from `reader` to `writer`, publishing it chunk by chunk meanwhile.
+    ///
+    /// Failing to publish does not interrupt the copying, since the output is only streamed
+    /// for observing.
+    pub(crate) async fn pump<R, W>(
+        &self,
+        task_id: &str,
+        kind: StreamKind,
+        mut reader: R,
+        mut writer: W,
+    ) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; 8192];
+        let mut seq = 0;
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buf[..n]).await?;
+
+            let chunk = OutputChunk {
+                task_id: task_id.to_owned(),
+                kind,
+                seq,
+                data: buf[..n].to_vec(),
+                at: DateTime::now(),
+            };
+            if let Err(err) = self.coll.insert_one(chunk, None).await {
+                warn!("Failed to publish output of task {}: {}", task_id, err);
+            }
+            seq += 1;
+        }
+        writer.flush().await
+    }
+
+    /// Chunks of the task published after the given ones, in the order of publishing.
+    pub async fn after(
+        &self,
+        task_id: &str,
+        kind: StreamKind,
+        seq: Option<i64>,
+    ) -> anyhow::Result<Vec<OutputChunk>> {
+        let mut filter = doc! {
+            "task_id": task_id,
+            "kind": mongodb::bson::to_bson(&kind)?,
+        };
+        if let Some(seq) = seq {
+            filter.insert("seq", doc! { "$gt": seq });
+        }
+        let options = FindOptions::builder().sort(doc! { "seq": 1 }).build();
+        Ok(self.coll.find(filter, options).await?.try_collect().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_utilities::docker;
+
+    use super::*;
+
+    fn data_of(chunks: &[OutputChunk]) -> Vec<u8> {
+        chunks.iter().flat_map(|chunk| chunk.data.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_chunks_in_order() {
+        let container = docker::Builder::new("mongo")
+            .name("cmdproxy-test-streams")
+            .bind_port_as_default(Some("0"), "27017")
+            .build_disposable()
+            .await;
+        let outputs = OutputStreams::new(
+            mongodb::Client::with_uri_str(container.url())
+                .await
+                .unwrap()
+                .database("cmdproxy-test-db")
+                .collection("outputs"),
+        );
+
+        // longer than a chunk, so that it is published in three
+        let stdout: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let mut copied = vec![];
+        outputs
+            .pump("a", StreamKind::Stdout, stdout.as_slice(), &mut copied)
+            .await
+            .unwrap();
+        assert_eq!(copied, stdout);
+        let mut copied = vec![];
+        outputs
+            .pump("a", StreamKind::Stderr, &b"oops"[..], &mut copied)
+            .await
+            .unwrap();
+        outputs
+            .pump("b", StreamKind::Stdout, &b"other"[..], &mut copied)
+            .await
+            .unwrap();
+
+        let chunks = outputs.after("a", StreamKind::Stdout, None).await.unwrap();
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.seq).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(data_of(&chunks), stdout);
+
+        // only those after the ones seen already
+        let chunks = outputs
+            .after("a", StreamKind::Stdout, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(data_of(&chunks), stdout[8192..]);
+        let chunks = outputs
+            .after("a", StreamKind::Stdout, Some(2))
+            .await
+            .unwrap();
+        assert!(chunks.is_empty());
+
+        // each output of each task on its own
+        let chunks = outputs.after("a", StreamKind::Stderr, None).await.unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            (chunks[0].seq, chunks[0].data.as_slice()),
+            (0, &b"oops"[..])
+        );
+        let chunks = outputs.after("b", StreamKind::Stdout, None).await.unwrap();
+        assert_eq!(data_of(&chunks), b"other");
+    }
+}
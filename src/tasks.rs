@@ -1,15 +1,95 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use celery::prelude::TaskResult;
 use once_cell::sync::OnceCell;
 
+use crate::broker::{self, CeleryApp};
 use crate::configs::CmdProxyServerConf;
+use crate::hooks::{PostProcessor, PreProcessor};
+use crate::middles::auth::{self, AuthMiddle, NoAuth};
+use crate::middles::Middle;
+use crate::protocol::RunResponse;
 use crate::server::Server;
+use crate::transfer::{TransferOp, TransferResult};
 
 pub static SERVER_CONF: OnceCell<CmdProxyServerConf> = OnceCell::new();
 
-#[celery::task]
-pub async fn run(serialized_run_request: String) -> TaskResult<String> {
+/// The app of the worker, through which the worker sends tasks on its own, e.g. transfers.
+pub static SERVER_APP: OnceCell<CeleryApp> = OnceCell::new();
+
+/// Authentication scheme verifying the incoming requests, if never set the HMAC of the
+/// secret in the server config if any, or else [`NoAuth`].
+pub static SERVER_AUTH: OnceCell<Arc<dyn AuthMiddle>> = OnceCell::new();
+
+/// Pre-processors by the names the server config enables them by, none if never set.
+pub static SERVER_PRE_PROCESSORS: OnceCell<HashMap<String, Arc<dyn PreProcessor>>> =
+    OnceCell::new();
+
+/// Steps run in order after each command exits, none if never set.
+pub static SERVER_POST_PROCESSORS: OnceCell<Vec<Arc<dyn PostProcessor>>> = OnceCell::new();
+
+/// The authentication scheme verifying the incoming requests and ops, see [`SERVER_AUTH`].
+fn server_auth(conf: &CmdProxyServerConf) -> Arc<dyn AuthMiddle> {
+    SERVER_AUTH
+        .get_or_init(|| -> Arc<dyn AuthMiddle> {
+            match conf.request_signing.clone() {
+                Some(auth) => Arc::new(auth),
+                None => Arc::new(NoAuth),
+            }
+        })
+        .clone()
+}
+
+/// Serve the run of the request, refused unless sent under the task name of the worker, see
+/// [`broker::run_signature`].
+#[celery::task(bind = true)]
+pub async fn run(
+    task: &Self,
+    serialized_run_request: String,
+    task_name: String,
+) -> TaskResult<String> {
     let conf = SERVER_CONF.get().unwrap().clone();
-    let server = Server::new(conf).await;
-    let serialized_response = server.run(serialized_run_request).await;
+    if let Err(err) = broker::check_task_name(&conf.celery, task_name.as_str()) {
+        return Ok(serde_json::to_string(&RunResponse::from_error(&err)).unwrap());
+    }
+    let auth = server_auth(&conf);
+    let server = Server::new(conf, auth)
+        .await
+        .with_pre_processors(SERVER_PRE_PROCESSORS.get().cloned().unwrap_or_default())
+        .with_post_processors(SERVER_POST_PROCESSORS.get().cloned().unwrap_or_default());
+    let serialized_response = server
+        .run(task.request().id.clone(), serialized_run_request)
+        .await;
     Ok(serialized_response)
 }
+
+/// Perform a [`TransferOp`] on behalf of an execution worker sharing the storage, once
+/// verified as the requests are.
+#[celery::task]
+pub async fn transfer(serialized_op: String) -> TaskResult<String> {
+    let conf = SERVER_CONF.get().unwrap();
+    let res = perform_transfer(conf, serialized_op).await;
+    let res = match res {
+        Ok(staged_url) => TransferResult {
+            staged_url,
+            exc: None,
+        },
+        Err(err) => TransferResult {
+            staged_url: None,
+            exc: Some(err.to_string()),
+        },
+    };
+    Ok(serde_json::to_string(&res).unwrap())
+}
+
+async fn perform_transfer(
+    conf: &CmdProxyServerConf,
+    serialized_op: String,
+) -> anyhow::Result<Option<String>> {
+    let serialized_op = auth::server_end::MiddleImpl::new(server_auth(conf))
+        .transform_request(serialized_op)
+        .await?;
+    let op: TransferOp = serde_json::from_str(serialized_op.as_str())?;
+    op.perform(conf.cloud.storage().await?).await
+}
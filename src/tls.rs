@@ -0,0 +1,143 @@
+//! TLS of the connections to the broker and to the mongodb, such as the managed ones which
+//! require it, configured explicitly rather than spelled into their urls by hand.
+//!
+//! The options are rendered into the urls, which is how the drivers take them: the mongodb
+//! by the `tls*` options of its connection strings, for the remote-fs and the result backend
+//! alike, and the broker by the `rediss://` or `amqps://` scheme, with the redis skipping the
+//! verification by the `#insecure` fragment. The brokers verify the servers against the CA
+//! bundle at `$SSL_CERT_FILE`, which their TLS stacks read, and take no client certificate.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::broker::BrokerKind;
+
+/// How the connection to a server is secured by TLS.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConf {
+    /// Bundle of the CAs the certificate of the server is verified against, in PEM, or the
+    /// system roots if not given.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Certificate of the client followed by its private key in PEM, if the server requires
+    /// one, which the mongodb takes in one file.
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+    /// Skip verifying the certificate of the server and its hostname, such as for the
+    /// servers of self-signed certificates in tests.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+impl TlsConf {
+    /// The connection string `url` of the mongodb connecting over TLS by this conf.
+    pub fn mongo_url(&self, url: &str) -> String {
+        let mut options = vec!["tls=true".to_owned()];
+        if let Some(ca_file) = &self.ca_file {
+            options.push(format!("tlsCAFile={}", encode(ca_file)));
+        }
+        if let Some(cert_file) = &self.cert_file {
+            options.push(format!("tlsCertificateKeyFile={}", encode(cert_file)));
+        }
+        if self.insecure {
+            options.push("tlsInsecure=true".to_owned());
+        }
+
+        let (hosts, query) = url.split_once('?').unwrap_or((url, ""));
+        // the options follow the slash after the hosts
+        let slash = match hosts.split_once("://") {
+            Some((_, rest)) if !rest.contains('/') => "/",
+            _ => "",
+        };
+        let query = Some(query)
+            .filter(|query| !query.is_empty())
+            .into_iter()
+            .chain(options.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{}{}?{}", hosts, slash, query)
+    }
+
+    /// The url of the broker connecting over TLS by this conf.
+    pub fn broker_url(&self, url: &str) -> anyhow::Result<String> {
+        anyhow::ensure!(
+            self.cert_file.is_none(),
+            "The broker takes no client certificate"
+        );
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| anyhow::anyhow!("Unsupported broker url {}", url))?;
+        Ok(match BrokerKind::of(url)? {
+            BrokerKind::Redis => {
+                let url = format!("rediss://{}", rest);
+                if self.insecure && !url.ends_with("#insecure") {
+                    format!("{}#insecure", url)
+                } else {
+                    url
+                }
+            }
+            BrokerKind::Amqp => {
+                anyhow::ensure!(
+                    !self.insecure,
+                    "The broker over {} cannot skip the verification",
+                    scheme
+                );
+                format!("amqps://{}", rest)
+            }
+        })
+    }
+
+    /// Trust the CA bundle of this conf, if any, for the connections to the broker, which is
+    /// for the whole process since the brokers read it from `$SSL_CERT_FILE`.
+    pub(crate) fn trust_ca_file(&self) {
+        if let Some(ca_file) = &self.ca_file {
+            std::env::set_var("SSL_CERT_FILE", ca_file);
+        }
+    }
+}
+
+/// Encode the `path` as a value of an option of a connection string.
+fn encode(path: &Path) -> String {
+    path.to_string_lossy()
+        .chars()
+        .map(|c| match c {
+            '%' | '&' | '?' | '#' | '+' | ' ' => format!("%{:02X}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls() {
+        let tls = TlsConf {
+            ca_file: Some(PathBuf::from("/etc/ssl/ca bundle.pem")),
+            cert_file: Some(PathBuf::from("/etc/ssl/client.pem")),
+            ..TlsConf::default()
+        };
+        assert_eq!(
+            tls.mongo_url("mongodb://db:27017"),
+            "mongodb://db:27017/?tls=true&tlsCAFile=/etc/ssl/ca%20bundle.pem\
+            &tlsCertificateKeyFile=/etc/ssl/client.pem"
+        );
+        assert!(tls.broker_url("redis://broker:6379").is_err());
+
+        let tls = TlsConf {
+            insecure: true,
+            ..TlsConf::default()
+        };
+        assert_eq!(
+            tls.mongo_url("mongodb://db/cmdproxy?replicaSet=rs0"),
+            "mongodb://db/cmdproxy?replicaSet=rs0&tls=true&tlsInsecure=true"
+        );
+        assert_eq!(
+            tls.broker_url("redis://broker:6379/").unwrap(),
+            "rediss://broker:6379/#insecure"
+        );
+        assert!(tls.broker_url("amqp://broker").is_err());
+    }
+}
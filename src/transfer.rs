@@ -0,0 +1,257 @@
+//! Staging the files of a run by dedicated transfer workers.
+//!
+//! An execution worker with poor connectivity to the cloud can hand the transfers over to
+//! transfer workers sharing a storage with it, e.g. running on storage-adjacent nodes. The
+//! execution worker then only reads and writes local paths on the shared storage. The ops are
+//! signed by the execution worker and verified by the transfer worker as the requests are.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use celery::export::async_trait;
+use celery::prelude::*;
+use celery::result::BaseResult;
+use celery::task::Signature;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::ArchiveFormat;
+use crate::broker::{on_app, CeleryApp};
+use crate::middles::auth::{self, AuthMiddle};
+use crate::middles::Middle;
+use crate::params::Param;
+use crate::protocol::Provenance;
+use crate::storage::Storage;
+use crate::tasks::transfer;
+
+/// A transfer between the cloud and a path on the shared storage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransferOp {
+    Download {
+        param: Param,
+        path: PathBuf,
+    },
+    UploadStaged {
+        param: Param,
+        path: PathBuf,
+        stage: String,
+        #[serde(default)]
+        provenance: Option<Provenance>,
+        #[serde(default)]
+        archive: ArchiveFormat,
+        /// Name of the key of the keyring encrypting the upload instead of the default key.
+        #[serde(default)]
+        key: Option<String>,
+    },
+}
+
+/// Result of a [`TransferOp`], with the staging url of the uploaded file if uploading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferResult {
+    pub staged_url: Option<String>,
+    pub exc: Option<String>,
+}
+
+impl TransferOp {
+    pub(crate) async fn perform(self, storage: Storage) -> anyhow::Result<Option<String>> {
+        match self {
+            TransferOp::Download { param, path } => {
+                param.download(storage, path).await?;
+                Ok(None)
+            }
+            TransferOp::UploadStaged {
+                param,
+                path,
+                stage,
+                provenance,
+                archive,
+                key,
+            } => {
+                let storage = match key {
+                    Some(name) => storage.encrypting_by(name.as_str())?,
+                    None => storage,
+                };
+                let staged_url = param
+                    .upload_staged(
+                        storage,
+                        path.as_path(),
+                        stage.as_str(),
+                        provenance.as_ref(),
+                        archive,
+                    )
+                    .await?;
+                Ok(Some(staged_url))
+            }
+        }
+    }
+}
+
+/// How the files of a run are transferred between the cloud and the workspace.
+#[async_trait]
+pub(crate) trait Transfer: Send + Sync {
+    async fn download(&self, param: &Param, path: &Path) -> anyhow::Result<()>;
+
+    async fn upload_staged(
+        &self,
+        param: &Param,
+        path: &Path,
+        stage: &str,
+        provenance: &Provenance,
+        archive: ArchiveFormat,
+        key: Option<&str>,
+    ) -> anyhow::Result<String>;
+}
+
+/// Transfer by sending [`TransferOp`]s to the transfer workers consuming `queue`.
+pub(crate) struct RemoteTransfer {
+    pub(crate) app: CeleryApp,
+    pub(crate) queue: String,
+    /// Signing the ops as the requests, so that the transfer workers verify them alike.
+    pub(crate) auth: Arc<dyn AuthMiddle>,
+}
+
+impl RemoteTransfer {
+    async fn send(&self, op: TransferOp) -> anyhow::Result<Option<String>> {
+        debug!("Send {:?} to transfer queue `{}'...", op, self.queue);
+        let serialized = auth::client_end::MiddleImpl::new(self.auth.clone())
+            .transform_request(serde_json::to_string(&op)?)
+            .await?;
+        let sig: Signature<_> = transfer::new(serialized).with_queue(self.queue.as_str());
+        let serialized = on_app!(&self.app, |app| app
+            .send_task(sig)
+            .await?
+            .wait(None)
+            .await??);
+        let res: TransferResult = serde_json::from_str(serialized.as_str())?;
+        match res.exc {
+            Some(exc) => Err(anyhow::anyhow!("Transfer failed: {}", exc)),
+            None => Ok(res.staged_url),
+        }
+    }
+}
+
+#[async_trait]
+impl Transfer for RemoteTransfer {
+    async fn download(&self, param: &Param, path: &Path) -> anyhow::Result<()> {
+        self.send(TransferOp::Download {
+            param: param.clone(),
+            path: path.to_path_buf(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn upload_staged(
+        &self,
+        param: &Param,
+        path: &Path,
+        stage: &str,
+        provenance: &Provenance,
+        archive: ArchiveFormat,
+        key: Option<&str>,
+    ) -> anyhow::Result<String> {
+        self.send(TransferOp::UploadStaged {
+            param: param.clone(),
+            path: path.to_path_buf(),
+            stage: stage.to_owned(),
+            provenance: Some(provenance.clone()),
+            archive,
+            key: key.map(str::to_owned),
+        })
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Transfer worker returned no staging url of the upload"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::middles::auth::HmacAuth;
+    use crate::storage::SharedFsStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_perform_upload_and_download() {
+        let shared = tempdir().unwrap();
+        let storage: Storage = Arc::new(SharedFsStorage::new(shared.path().to_owned(), ""));
+        let workspace = tempdir().unwrap();
+        let output = workspace.path().join("a.txt");
+        std::fs::write(&output, "hello").unwrap();
+
+        let upload = TransferOp::UploadStaged {
+            param: Param::OutCloudFileParam {
+                filepath: "/out/a.txt".to_owned(),
+                hostname: "host".to_owned(),
+            },
+            path: output,
+            stage: "s1".to_owned(),
+            provenance: None,
+            archive: ArchiveFormat::default(),
+            key: None,
+        };
+        let staged_url = upload.perform(storage.clone()).await.unwrap().unwrap();
+        assert_eq!(staged_url, "@host:/out/a.txt.staged-s1");
+        assert!(storage.exists(staged_url.as_str()).await.unwrap());
+
+        let input = workspace.path().join("b.txt");
+        let download = TransferOp::Download {
+            param: Param::InCloudFileParam {
+                filepath: "/out/a.txt.staged-s1".to_owned(),
+                hostname: "host".to_owned(),
+            },
+            path: input.clone(),
+        };
+        assert_eq!(download.perform(storage.clone()).await.unwrap(), None);
+        assert_eq!(std::fs::read_to_string(input).unwrap(), "hello");
+
+        let missing = TransferOp::Download {
+            param: Param::InCloudFileParam {
+                filepath: "/out/missing.txt".to_owned(),
+                hostname: "host".to_owned(),
+            },
+            path: workspace.path().join("c.txt"),
+        };
+        assert!(missing.perform(storage).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_signed_ops() {
+        let auth: Arc<dyn AuthMiddle> = Arc::new(HmacAuth::new("shared secret"));
+        let op = TransferOp::Download {
+            param: Param::InCloudFileParam {
+                filepath: "/in/a.txt".to_owned(),
+                hostname: "host".to_owned(),
+            },
+            path: PathBuf::from("/shared/a.txt"),
+        };
+        // signed by the execution worker as in sending, and verified as the transfer worker
+        let serialized = auth::client_end::MiddleImpl::new(auth.clone())
+            .transform_request(serde_json::to_string(&op).unwrap())
+            .await
+            .unwrap();
+        let verified = auth::server_end::MiddleImpl::new(auth.clone())
+            .transform_request(serialized.clone())
+            .await
+            .unwrap();
+        match serde_json::from_str::<TransferOp>(verified.as_str()).unwrap() {
+            TransferOp::Download { path, .. } => assert_eq!(path, PathBuf::from("/shared/a.txt")),
+            op => panic!("Unexpected op {:?}", op),
+        }
+
+        // an op redirected to another path is refused
+        let tampered = serialized.replace("/shared/a.txt", "/etc/passwd");
+        assert_ne!(tampered, serialized);
+        let refused = auth::server_end::MiddleImpl::new(auth.clone())
+            .transform_request(tampered)
+            .await;
+        assert!(refused.is_err());
+
+        // and so is an unsigned one
+        let refused = auth::server_end::MiddleImpl::new(auth)
+            .transform_request(serde_json::to_string(&op).unwrap())
+            .await;
+        assert!(refused.is_err());
+    }
+}
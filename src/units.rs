@@ -0,0 +1,176 @@
+//! Units of the sizes and the durations in the configs, the job files and the arguments, so
+//! that they can be given as `500MB` or `2h30m` rather than raw numbers, while a bare number
+//! is still taken as bytes or seconds.
+//!
+//! The sizes are binary, of which `K`, `KB` and `KiB` are all 1024 bytes. The durations are
+//! sequences of numbers each followed by one of `ms`, `s`, `m`, `h`, `d` and `w`.
+
+use std::time::Duration;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+/// Parse a size such as `512`, `64K`, `1.5GB` or `500MiB` into bytes.
+pub fn parse_size<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size `{}'", s))?;
+    let scale: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Invalid unit of size `{}'", s)),
+    };
+    let bytes = (number * scale as f64).round() as u64;
+    T::try_from(bytes).map_err(|_| format!("Size too large `{}'", s))
+}
+
+/// Parse a duration such as `45`, `500ms`, `30m`, `2h30m` or `1w`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid duration `{}'", s);
+    if s.is_empty() {
+        return Err(invalid());
+    }
+    let mut millis = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (value, tail) = rest.split_at(split);
+        let value: u64 = value.parse().map_err(|_| invalid())?;
+        let split = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(split);
+        let scale = match unit {
+            "ms" => 1,
+            "" | "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            "w" => 7 * 24 * 60 * 60 * 1000,
+            _ => return Err(format!("Invalid unit of duration `{}'", s)),
+        };
+        millis = value
+            .checked_mul(scale)
+            .and_then(|value| millis.checked_add(value))
+            .ok_or_else(invalid)?;
+        rest = tail;
+    }
+    Ok(Duration::from_millis(millis))
+}
+
+/// Parse a duration as by [`parse_duration`] into whole seconds.
+pub fn parse_secs(s: &str) -> Result<u64, String> {
+    parse_duration(s).map(|duration| duration.as_secs())
+}
+
+/// A size or a duration as given, either a bare number or a string with the unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Number(u64),
+    Text(String),
+}
+
+impl Raw {
+    fn size<T: TryFrom<u64>, E: Error>(self) -> Result<T, E> {
+        match self {
+            Raw::Number(bytes) => {
+                T::try_from(bytes).map_err(|_| E::custom(format!("Size too large {}", bytes)))
+            }
+            Raw::Text(text) => parse_size(text.as_str()).map_err(E::custom),
+        }
+    }
+
+    fn secs<E: Error>(self) -> Result<u64, E> {
+        match self {
+            Raw::Number(secs) => Ok(secs),
+            Raw::Text(text) => parse_secs(text.as_str()).map_err(E::custom),
+        }
+    }
+}
+
+/// Deserialize a size in bytes, with or without the unit, see [`parse_size`].
+pub fn deserialize_size<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    Raw::deserialize(deserializer)?.size()
+}
+
+/// Same as [`deserialize_size`], but of an optional size.
+pub fn deserialize_opt_size<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    Option::<Raw>::deserialize(deserializer)?
+        .map(Raw::size)
+        .transpose()
+}
+
+/// Deserialize a duration in seconds, with or without the unit, see [`parse_duration`].
+pub fn deserialize_secs<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Raw::deserialize(deserializer)?.secs()
+}
+
+/// Same as [`deserialize_secs`], but of an optional duration.
+pub fn deserialize_opt_secs<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Raw>::deserialize(deserializer)?
+        .map(Raw::secs)
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_size::<u64>("512"), Ok(512));
+        assert_eq!(parse_size::<u64>("64K"), Ok(64 << 10));
+        assert_eq!(parse_size::<u64>("500MB"), Ok(500 << 20));
+        assert_eq!(parse_size::<u64>("1.5GiB"), Ok(3 << 29));
+        assert!(parse_size::<u64>("12 parsecs").is_err());
+
+        assert_eq!(parse_secs("45"), Ok(45));
+        assert_eq!(parse_secs("2h30m"), Ok(9000));
+        assert_eq!(parse_secs("1w"), Ok(7 * 24 * 3600));
+        assert_eq!(parse_duration("1s500ms"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3 fortnights").is_err());
+    }
+
+    #[test]
+    fn test_deserialize() {
+        #[derive(Deserialize)]
+        struct Limits {
+            #[serde(default, deserialize_with = "deserialize_opt_secs")]
+            timeout: Option<u64>,
+            #[serde(deserialize_with = "deserialize_size")]
+            memory: usize,
+        }
+
+        let limits: Limits = serde_yaml::from_str("timeout: 1h\nmemory: 2G\n").unwrap();
+        assert_eq!((limits.timeout, limits.memory), (Some(3600), 2 << 30));
+        let limits: Limits = serde_yaml::from_str("memory: 1024\n").unwrap();
+        assert_eq!((limits.timeout, limits.memory), (None, 1024));
+    }
+}
@@ -0,0 +1,166 @@
+//! Resources used by the runs, recorded in the history for capacity planning.
+//!
+//! The memory and cpu of a command are sampled from `/proc` while it runs, on linux only, as
+//! the process is reaped by the runtime rather than waited for by the worker itself. Hence a
+//! command exiting between two samples may be accounted a little less than it used, and the
+//! processes it forks are accounted by their cpu time once reaped, but never by their memory.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+/// Resources used by a run, as recorded in its history.
+///
+/// The numbers are kept plain, in milliseconds and bytes, so that the history can be
+/// aggregated by the database, see [`crate::history::TaskHistory::usage_summary`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// Name of the command run, as in the command palette.
+    #[serde(default)]
+    pub command: String,
+    /// Wall time of running the command, excluding the transfers.
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// Cpu time of the command and its reaped children, in user and system modes.
+    #[serde(default)]
+    pub cpu_ms: Option<u64>,
+    /// Peak resident memory of the command.
+    #[serde(default)]
+    pub max_rss_bytes: Option<u64>,
+    /// Bytes of the inputs downloaded by the worker.
+    #[serde(default)]
+    pub downloaded_bytes: u64,
+    /// Bytes of the outputs uploaded by the worker.
+    #[serde(default)]
+    pub uploaded_bytes: u64,
+}
+
+/// Interval of sampling a running command.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Clock ticks per second, in which `/proc` tells the cpu times.
+static CLOCK_TICKS: Lazy<u64> = Lazy::new(|| {
+    std::process::Command::new("getconf")
+        .arg("CLK_TCK")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|ticks| ticks.trim().parse().ok())
+        .filter(|ticks| *ticks > 0)
+        .unwrap_or(100)
+});
+
+/// Sampling of a running process in the background, from its start to its finish.
+pub(crate) struct Sampling {
+    sampler: Arc<Mutex<Sampler>>,
+    task: JoinHandle<()>,
+    started: Instant,
+}
+
+impl Sampling {
+    /// Start sampling the process of `pid`, or only timing it if its pid is not known.
+    pub(crate) fn start(pid: Option<u32>) -> Sampling {
+        let sampler = Arc::new(Mutex::new(Sampler {
+            pid,
+            ..Sampler::default()
+        }));
+        sampler.lock().unwrap().sample();
+        let task = tokio::spawn({
+            let sampler = sampler.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(SAMPLE_INTERVAL).await;
+                    sampler.lock().unwrap().sample();
+                }
+            }
+        });
+        Sampling {
+            sampler,
+            task,
+            started: Instant::now(),
+        }
+    }
+
+    /// Stop sampling the process, which has exited, and tell what it used.
+    pub(crate) fn finish(self) -> ResourceUsage {
+        // never sampled again, as the pid of the reaped process may be taken by another
+        self.task.abort();
+        let sampler = self.sampler.lock().unwrap();
+        sampler.usage(self.started.elapsed())
+    }
+}
+
+/// Peaks of the memory and cpu of a running process, as sampled so far.
+#[derive(Debug, Default)]
+struct Sampler {
+    pid: Option<u32>,
+    max_rss_bytes: Option<u64>,
+    cpu_ticks: Option<u64>,
+}
+
+impl Sampler {
+    /// Take a sample of the process, which is skipped if the process is gone.
+    fn sample(&mut self) {
+        let pid = match self.pid {
+            Some(pid) if cfg!(target_os = "linux") => pid,
+            _ => return,
+        };
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid));
+        if let Some(rss) = status.ok().as_deref().and_then(parse_peak_rss) {
+            self.max_rss_bytes = self.max_rss_bytes.max(Some(rss));
+        }
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid));
+        if let Some(ticks) = stat.ok().as_deref().and_then(parse_cpu_ticks) {
+            self.cpu_ticks = self.cpu_ticks.max(Some(ticks));
+        }
+    }
+
+    /// The usage of a run of `duration` as sampled.
+    fn usage(&self, duration: Duration) -> ResourceUsage {
+        ResourceUsage {
+            duration_ms: duration.as_millis() as u64,
+            cpu_ms: self.cpu_ticks.map(|ticks| ticks * 1000 / *CLOCK_TICKS),
+            max_rss_bytes: self.max_rss_bytes,
+            ..ResourceUsage::default()
+        }
+    }
+}
+
+/// Parse the peak resident memory, `VmHWM`, in `/proc/<pid>/status` into bytes.
+fn parse_peak_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Parse the user and system cpu ticks of the process and its reaped children in
+/// `/proc/<pid>/stat`.
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    // the name in parentheses may contain spaces, hence the fields are counted after it
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // utime, stime, cutime and cstime, the 14th to 17th fields counting the pid and name
+    fields
+        .get(11..15)?
+        .iter()
+        .map(|field| field.parse::<u64>().ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let status = "Name:\tcc1\nVmPeak:\t  20000 kB\nVmHWM:\t    1500 kB\nVmRSS:\t 1200 kB\n";
+        assert_eq!(parse_peak_rss(status), Some(1500 * 1024));
+
+        let stat = "4242 (cc1 plus) R 1 4242 4242 0 -1 4194304 500 0 0 0 70 30 2 1 20 0 1 0";
+        assert_eq!(parse_cpu_ticks(stat), Some(103));
+        assert_eq!(parse_cpu_ticks("4242 (cc1"), None);
+    }
+}
@@ -0,0 +1,260 @@
+//! Pools of warm processes serving the short but frequent commands.
+//!
+//! A command in server mode is spawned once as a daemon, and then fed the requests one at a
+//! time over its stdio instead of being spawned per request. Each request is written to the
+//! stdin of the daemon as one line of json, such as
+//!
+//! ```json
+//! {"args": ["--check", "main.rs"], "cwd": null, "env": {}}
+//! ```
+//!
+//! to which the daemon answers with one line of json on its stdout, such as
+//!
+//! ```json
+//! {"code": 0, "stdout": "...", "stderr": "..."}
+//! ```
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::Semaphore;
+
+use crate::protocol::RunRecipe;
+
+/// How a command is kept warm, as configured in its palette entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarmConf {
+    /// Max number of daemons, which is also the max number of requests served at once.
+    pub size: usize,
+    /// Seconds an idle daemon is kept before being killed.
+    #[serde(default = "WarmConf::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds after which a daemon is replaced with a fresh one, e.g. to bound its leaks.
+    #[serde(default)]
+    pub max_lifetime: Option<u64>,
+}
+
+impl WarmConf {
+    fn default_idle_timeout() -> u64 {
+        300
+    }
+
+    fn is_expired(&self, daemon: &Daemon) -> bool {
+        daemon.idle_since.elapsed() >= Duration::from_secs(self.idle_timeout)
+            || matches!(self.max_lifetime,
+                Some(lifetime) if daemon.spawned_at.elapsed() >= Duration::from_secs(lifetime))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WarmRequest<'a> {
+    args: &'a [String],
+    cwd: Option<&'a str>,
+    env: &'a HashMap<String, String>,
+}
+
+/// What the daemon answers to a request.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct WarmResponse {
+    pub(crate) code: i32,
+    #[serde(default)]
+    pub(crate) stdout: String,
+    #[serde(default)]
+    pub(crate) stderr: String,
+}
+
+struct Daemon {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    spawned_at: Instant,
+    idle_since: Instant,
+}
+
+impl Daemon {
+    fn spawn(command: &str) -> anyhow::Result<Daemon> {
+        debug!("Spawn warm daemon of {}...", command);
+        let mut child = tokio::process::Command::new(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Daemon {
+            child,
+            stdin,
+            stdout,
+            spawned_at: Instant::now(),
+            idle_since: Instant::now(),
+        })
+    }
+
+    async fn call(&mut self, recipe: &RunRecipe) -> anyhow::Result<WarmResponse> {
+        let mut line = serde_json::to_string(&WarmRequest {
+            args: recipe.args.as_slice(),
+            cwd: recipe.cwd.as_deref(),
+            env: recipe.env.as_ref().unwrap_or(&HashMap::new()),
+        })?;
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await?;
+
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line).await? == 0 {
+            let status = self.child.try_wait()?;
+            return Err(anyhow!("Warm daemon exited unexpectedly: {:?}", status));
+        }
+        Ok(serde_json::from_str(line.as_str())?)
+    }
+}
+
+pub(crate) struct WarmPool {
+    command: String,
+    conf: WarmConf,
+    idle: Mutex<Vec<Daemon>>,
+    permits: Semaphore,
+}
+
+impl WarmPool {
+    fn new(command: &str, conf: WarmConf) -> WarmPool {
+        WarmPool {
+            command: command.to_owned(),
+            conf,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(conf.size.max(1)),
+        }
+    }
+
+    /// Serve the request by an idle daemon, or a fresh one if none is idle.
+    ///
+    /// A daemon failing the request is killed rather than put back, as it is in an unknown
+    /// state.
+    pub(crate) async fn run(&self, recipe: &RunRecipe) -> anyhow::Result<WarmResponse> {
+        let _permit = self.permits.acquire().await?;
+        let mut daemon = match self.take_idle() {
+            Some(daemon) => daemon,
+            None => Daemon::spawn(self.command.as_str())?,
+        };
+
+        let response = daemon.call(recipe).await?;
+        daemon.idle_since = Instant::now();
+        self.idle.lock().unwrap().push(daemon);
+        Ok(response)
+    }
+
+    fn take_idle(&self) -> Option<Daemon> {
+        self.reap();
+        self.idle.lock().unwrap().pop()
+    }
+
+    /// Kill the daemons idle or alive for too long.
+    fn reap(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        let before = idle.len();
+        idle.retain(|daemon| !self.conf.is_expired(daemon));
+        if idle.len() < before {
+            debug!(
+                "Reaped {} expired warm daemons of {}",
+                before - idle.len(),
+                self.command
+            );
+        }
+    }
+
+    #[cfg(test)]
+    fn num_idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+static POOLS: Lazy<Mutex<HashMap<String, Arc<WarmPool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The pool of the command, made on first use or whenever its conf has changed.
+pub(crate) fn pool(command: &str, conf: WarmConf) -> Arc<WarmPool> {
+    let mut pools = POOLS.lock().unwrap();
+    match pools.get(command) {
+        Some(pool) if pool.conf == conf => pool.clone(),
+        _ => {
+            let pool = Arc::new(WarmPool::new(command, conf));
+            pools.insert(command.to_owned(), pool.clone());
+            tokio::spawn(reap_periodically(Arc::downgrade(&pool)));
+            pool
+        }
+    }
+}
+
+/// Reap the pool from time to time, so that the idle daemons do not outlive their timeout
+/// when no more requests come, until the pool itself is gone.
+async fn reap_periodically(pool: std::sync::Weak<WarmPool>) {
+    let interval = match pool.upgrade() {
+        Some(pool) => Duration::from_secs(pool.conf.idle_timeout.max(2) / 2),
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(interval).await;
+        match pool.upgrade() {
+            Some(pool) => pool.reap(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_warm_pool() {
+        let daemon = tempfile::Builder::new().suffix(".sh").tempfile().unwrap();
+        std::fs::write(
+            daemon.path(),
+            "#!/bin/sh\nwhile read -r line; do echo '{\"code\": 0, \"stdout\": \"hi\"}'; done\n",
+        )
+        .unwrap();
+        let daemon = daemon.into_temp_path();
+        std::process::Command::new("chmod")
+            .arg("+x")
+            .arg(&daemon)
+            .status()
+            .unwrap();
+
+        let conf = WarmConf {
+            size: 1,
+            idle_timeout: 60,
+            max_lifetime: None,
+        };
+        let pool = WarmPool::new(daemon.to_str().unwrap(), conf);
+        let recipe = RunRecipe::builder()
+            .command(daemon.to_str().unwrap().to_owned())
+            .args(vec!["a".to_owned()])
+            .build();
+        for _ in 0..2 {
+            let response = pool.run(&recipe).await.unwrap();
+            assert_eq!(response.code, 0);
+            assert_eq!(response.stdout, "hi");
+            assert_eq!(pool.num_idle(), 1);
+        }
+
+        let expired = WarmPool::new(
+            daemon.to_str().unwrap(),
+            WarmConf {
+                idle_timeout: 0,
+                ..conf
+            },
+        );
+        expired.run(&recipe).await.unwrap();
+        expired.reap();
+        assert_eq!(expired.num_idle(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Workspaces of the runs on a worker, the temp folders the inputs are downloaded into and
+//! the outputs are produced in.
+//!
+//! A workspace is named after the task of its run, and removed by its guard once the run is
+//! done. Those outliving the max lifetime, such as of a run whose guard has wedged or errored
+//! out, are removed by force by the sweeper and counted as leaked.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+use tempfile::TempDir;
+
+use crate::metrics;
+use crate::protocol::WORKSPACE_VAR;
+
+/// Prefix of the names of the workspaces, telling them apart from other temp folders.
+const PREFIX: &str = "cmdproxy-ws-";
+
+/// Make the workspace of the run of `task_id` in `root`.
+pub(crate) fn create(root: &Path, task_id: &str) -> std::io::Result<TempDir> {
+    let name: String = task_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    tempfile::Builder::new()
+        .prefix(format!("{}{}-", PREFIX, name).as_str())
+        .tempdir_in(root)
+}
+
+/// Expand the placeholders of the workspace in `value` to the path of `workspace`.
+pub(crate) fn expand(value: &str, workspace: &Path) -> String {
+    value.replace(WORKSPACE_VAR, workspace.to_string_lossy().as_ref())
+}
+
+/// Remove the workspaces in `root` older than `max_lifetime`, returning how many are removed.
+pub(crate) fn sweep(root: &Path, max_lifetime: Duration) -> std::io::Result<usize> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(PREFIX) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        // the modification time of a folder changes with its entries, hence the last resort
+        let born = meta.created().or_else(|_| meta.modified())?;
+        if now.duration_since(born).unwrap_or_default() < max_lifetime {
+            continue;
+        }
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(()) => {
+                warn!("Removed leaked workspace {}", entry.path().display());
+                removed += 1;
+            }
+            Err(err) => warn!(
+                "Failed to remove leaked workspace {}: {}",
+                entry.path().display(),
+                err
+            ),
+        }
+    }
+    Ok(removed)
+}
+
+/// Sweep the workspaces in `root` from time to time, until the future is dropped.
+pub(crate) async fn sweep_periodically(root: &Path, max_lifetime: Duration) {
+    let interval = (max_lifetime / 4).clamp(Duration::from_secs(1), Duration::from_secs(300));
+    loop {
+        match sweep(root, max_lifetime) {
+            Ok(0) => {}
+            Ok(removed) => metrics::add_leaked_workspaces(removed as u64),
+            Err(err) => debug!("Failed to sweep workspaces in {}: {}", root.display(), err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep() {
+        let root = tempfile::tempdir().unwrap();
+        let workspace = create(root.path(), "a1b2/c3").unwrap();
+        assert!(workspace
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("cmdproxy-ws-a1b2_c3-"));
+        let other = root.path().join("other");
+        std::fs::create_dir(&other).unwrap();
+
+        assert_eq!(sweep(root.path(), Duration::from_secs(3600)).unwrap(), 0);
+        assert!(workspace.path().exists());
+
+        assert_eq!(sweep(root.path(), Duration::ZERO).unwrap(), 1);
+        assert!(!workspace.path().exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_expand() {
+        let workspace = Path::new("/tmp/cmdproxy-ws-1");
+        assert_eq!(
+            expand("--out=${WORKSPACE}/out", workspace),
+            "--out=/tmp/cmdproxy-ws-1/out"
+        );
+        assert_eq!(expand("$WORKSPACE", workspace), "$WORKSPACE");
+    }
+}